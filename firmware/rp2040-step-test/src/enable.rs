@@ -0,0 +1,81 @@
+//! Drives the motor driver enable lines, either as plain outputs or, for a reduced-current hold,
+//! from the PWM slice behind each pin.
+
+use embedded_hal::digital::v2::OutputPin;
+use rp_pico::hal::{self, pac};
+use split_flap_device::idle::EnableDrive;
+
+use crate::DynOutputPin;
+
+/// Gives about 20kHz from the 125MHz system clock, above what the motors can hear.
+const PWM_TOP: u16 = 6249;
+
+pub struct EnableLines<const N: usize> {
+    pins: [DynOutputPin; N],
+    drives: [EnableDrive; N],
+}
+
+impl<const N: usize> EnableLines<N> {
+    /// Takes the PWM block out of reset, and starts with every line low.
+    pub fn new(
+        mut pins: [DynOutputPin; N],
+        pwm: pac::PWM,
+        resets: &mut pac::RESETS,
+    ) -> EnableLines<N> {
+        hal::pwm::Slices::new(pwm, resets).free();
+
+        for pin in pins.iter_mut() {
+            pin.set_low().unwrap();
+        }
+
+        EnableLines {
+            pins,
+            drives: [EnableDrive::Off; N],
+        }
+    }
+
+    pub fn drive(&self, idx: usize) -> EnableDrive {
+        self.drives[idx]
+    }
+
+    pub fn set(&mut self, idx: usize, drive: EnableDrive) {
+        if self.drives[idx] == drive {
+            return;
+        }
+
+        let gpio = self.pins[idx].id().num as usize;
+        // The pins are owned here, so nothing else touches their function or PWM slice
+        let io = unsafe { &*pac::IO_BANK0::ptr() };
+        let pwm = unsafe { &*pac::PWM::ptr() };
+
+        match drive {
+            EnableDrive::Off | EnableDrive::On => {
+                io.gpio[gpio].gpio_ctrl.modify(|_, w| w.funcsel().sio());
+
+                if drive == EnableDrive::On {
+                    self.pins[idx].set_high().unwrap();
+                } else {
+                    self.pins[idx].set_low().unwrap();
+                }
+            }
+            EnableDrive::Pwm { duty_percent } => {
+                let slice = &pwm.ch[(gpio >> 1) & 7];
+                let level = (PWM_TOP as u32 + 1) * duty_percent as u32 / 100;
+
+                slice.top.write(|w| unsafe { w.top().bits(PWM_TOP) });
+                slice.cc.modify(|_, w| unsafe {
+                    if gpio & 1 == 0 {
+                        w.a().bits(level as u16)
+                    } else {
+                        w.b().bits(level as u16)
+                    }
+                });
+                slice.csr.modify(|_, w| w.en().set_bit());
+
+                io.gpio[gpio].gpio_ctrl.modify(|_, w| w.funcsel().pwm());
+            }
+        }
+
+        self.drives[idx] = drive;
+    }
+}
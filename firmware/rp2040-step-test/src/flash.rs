@@ -0,0 +1,142 @@
+//! Access to the end of flash, reserved for the `DeviceConfig` and the state kept in a
+//! `KvStore`.  memory.x keeps the program out of it.
+
+use core::convert::Infallible;
+
+use rp_pico::hal::rom_data;
+use split_flap_device::kv_store::NorFlash;
+
+pub const SECTOR_SIZE: usize = 4096;
+
+const PAGE_SIZE: usize = 256;
+const FLASH_SIZE: usize = 2048 * 1024;
+const XIP_BASE: usize = 0x1000_0000;
+
+/// Offset of the last sector from the start of flash.
+const CONFIG_OFFSET: usize = FLASH_SIZE - SECTOR_SIZE;
+
+/// The state store takes the sectors before the config.
+const STATE_SECTORS: usize = 4;
+const STATE_OFFSET: usize = CONFIG_OFFSET - STATE_SECTORS * SECTOR_SIZE;
+
+/// 64K block erase, used by the ROM when a whole block is being erased.
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+const BLOCK_SIZE: u32 = 65536;
+
+/// The config sector, read through the XIP cache.
+pub fn config_sector() -> &'static [u8] {
+    // The sector is always mapped, and only changes in `write_config_sector`, which can't run
+    // while the returned slice is in use as the device is single threaded.
+    unsafe { core::slice::from_raw_parts((XIP_BASE + CONFIG_OFFSET) as *const u8, SECTOR_SIZE) }
+}
+
+/// Erases the config sector and programs `data` into it.
+pub fn write_config_sector(data: &[u8; SECTOR_SIZE]) {
+    let routines = Routines::lookup();
+
+    cortex_m::interrupt::free(|_| unsafe {
+        erase(&routines, CONFIG_OFFSET as u32, SECTOR_SIZE);
+        program(&routines, CONFIG_OFFSET as u32, data);
+    });
+}
+
+/// The sectors holding the state store.
+pub struct StateFlash;
+
+impl NorFlash for StateFlash {
+    type Error = Infallible;
+
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn capacity(&self) -> usize {
+        STATE_SECTORS * SECTOR_SIZE
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Infallible> {
+        let start = XIP_BASE + STATE_OFFSET + offset as usize;
+        let stored = unsafe { core::slice::from_raw_parts(start as *const u8, bytes.len()) };
+
+        bytes.copy_from_slice(stored);
+        Ok(())
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Infallible> {
+        let routines = Routines::lookup();
+        let offset = (STATE_OFFSET as u32) + from;
+
+        cortex_m::interrupt::free(|_| unsafe {
+            erase(&routines, offset, (to - from) as usize);
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Infallible> {
+        let routines = Routines::lookup();
+        let mut offset = STATE_OFFSET + offset as usize;
+        let mut bytes = bytes;
+
+        // Flash is programmed a page at a time, padded with 0xFF to leave the rest unchanged
+        while !bytes.is_empty() {
+            let page_start = offset - offset % PAGE_SIZE;
+            let in_page = (PAGE_SIZE - offset % PAGE_SIZE).min(bytes.len());
+
+            let mut page = [0xFF; PAGE_SIZE];
+            page[offset - page_start..offset - page_start + in_page]
+                .copy_from_slice(&bytes[..in_page]);
+
+            cortex_m::interrupt::free(|_| unsafe {
+                program(&routines, page_start as u32, &page);
+            });
+
+            offset += in_page;
+            bytes = &bytes[in_page..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Flash can't be read while it's being written, so the ROM routines are looked up beforehand
+/// and called from RAM with interrupts disabled.
+struct Routines {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    flash_enter_cmd_xip: unsafe extern "C" fn(),
+}
+
+impl Routines {
+    fn lookup() -> Routines {
+        Routines {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+            flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+        }
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn erase(routines: &Routines, offset: u32, len: usize) {
+    (routines.connect_internal_flash)();
+    (routines.flash_exit_xip)();
+    (routines.flash_range_erase)(offset, len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    (routines.flash_flush_cache)();
+    (routines.flash_enter_cmd_xip)();
+}
+
+/// `data` must be a whole number of pages.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program(routines: &Routines, offset: u32, data: &[u8]) {
+    (routines.connect_internal_flash)();
+    (routines.flash_exit_xip)();
+    (routines.flash_range_program)(offset, data.as_ptr(), data.len());
+    (routines.flash_flush_cache)();
+    (routines.flash_enter_cmd_xip)();
+}
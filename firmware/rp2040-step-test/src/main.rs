@@ -4,14 +4,18 @@
 #![no_std]
 #![no_main]
 
+mod enable;
+mod flash;
+
 use bsp::entry;
-use defmt::info;
+use defmt::{info, Debug2Format};
 // use defmt::*;
 use defmt_rtt as _;
 use embedded_hal::{
     adc::OneShot,
     digital::v2::{OutputPin, ToggleableOutputPin},
 };
+use enable::EnableLines;
 use panic_probe as _;
 
 // Provide an alias for our BSP so we can switch targets quickly.
@@ -24,8 +28,14 @@ use hal::{
     adc::Adc,
     adc::AdcPin,
     clocks::{init_clocks_and_plls, Clock},
+    fugit::RateExtU32,
+    gpio::{
+        bank0::{Gpio26, Gpio27, Gpio28},
+        DynPinId, FunctionSio, Pin, PullDown, PullNone, SioInput, SioOutput,
+    },
     pac,
     sio::Sio,
+    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
     watchdog::Watchdog,
 };
 
@@ -46,16 +56,162 @@ use core::{
 use heapless::String;
 
 use heapless::Vec;
-use split_flap_device::split_flap_bit_state::{SensorCalibration, SplitFlapBitState};
-
-const TARGETS: [[char; 4]; 5] = [
-    ['B', 'V', 'H', ' '],
-    ['A', 'L', 'L', 'T'],
-    ['R', 'A', 'I', 'L'],
-    ['S', ' ', ' ', ' '],
+use split_flap_device::bus::{BusNode, BusReceiver};
+use split_flap_device::clock;
+use split_flap_device::device::{text_targets, Content, Device};
+use split_flap_device::device_config::{BitConfig, ConfigError, DeviceConfig};
+use split_flap_device::idle::{EnableDrive, IdleTimer};
+use split_flap_device::kv_store::{
+    KvStore, KEY_LAST_MESSAGE, KEY_POSITIONS, KEY_REVOLUTIONS, KEY_STEP_LOSSES,
+};
+use split_flap_device::protocol::{
+    encode_frame, FrameDecoder, Message, NakReason, Received, MAX_ENCODED_LEN,
+};
+#[cfg(feature = "i2c-peripheral")]
+use split_flap_device::registers::RegisterMap;
+use split_flap_device::serial_command::{parse_command, Command};
+use split_flap_device::split_flap_bit_state::SensorCalibration;
+use split_flap_device::steps::{GearRatio, HomedSteps, Offset, StepperConfig};
+
+const TARGETS: [[u8; 4]; 5] = [
+    [b'B', b'V', b'H', b' '],
+    [b'A', b'L', b'L', b'T'],
+    [b'R', b'A', b'I', b'L'],
+    [b'S', b' ', b' ', b' '],
     [0x01, 0x02, 0x03, 0x04],
 ];
 
+type DynOutputPin = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+type SensorPin<I> = AdcPin<Pin<I, FunctionSio<SioInput>, PullNone>>;
+
+/// ADC channels a home sensor can be wired to.  Channel 3 reads the supply voltage on the Pico.
+const SENSOR_CHANNELS: u8 = 3;
+
+/// Home sensor inputs, one per ADC channel on a header pin.
+struct Sensors {
+    channel_0: SensorPin<Gpio26>,
+    channel_1: SensorPin<Gpio27>,
+    channel_2: SensorPin<Gpio28>,
+}
+
+impl Sensors {
+    fn read(&mut self, adc: &mut Adc, channel: u8) -> u32 {
+        let reading: u16 = match channel {
+            0 => adc.read(&mut self.channel_0).unwrap(),
+            1 => adc.read(&mut self.channel_1).unwrap(),
+            2 => adc.read(&mut self.channel_2).unwrap(),
+            _ => 0,
+        };
+
+        reading as u32
+    }
+}
+
+/// Whether the pins in `config` are all free for use as outputs, with none used twice.
+fn pins_available(config: &DeviceConfig<4>, spare_outputs: &[Option<DynOutputPin>]) -> bool {
+    let mut used = 0u32;
+
+    let pins_free = core::iter::once(config.step_pin)
+        .chain(config.bits.iter().map(|bit| bit.enable_pin))
+        .all(|pin| {
+            let spare = matches!(spare_outputs.get(pin as usize), Some(Some(_)));
+            if !spare || used & (1 << pin) != 0 {
+                return false;
+            }
+
+            used |= 1 << pin;
+            true
+        });
+
+    pins_free
+        && config
+            .bits
+            .iter()
+            .all(|bit| bit.sensor_channel < SENSOR_CHANNELS)
+}
+
+/// Reads a `u32` per bit saved under `key`.
+fn load_per_bit(state: &mut KvStore<flash::StateFlash>, key: u8) -> Option<[u32; 4]> {
+    let mut saved = [0u8; 16];
+
+    match state.get(key, &mut saved) {
+        Ok(Some(16)) => Some(core::array::from_fn(|idx| {
+            u32::from_le_bytes([
+                saved[idx * 4],
+                saved[idx * 4 + 1],
+                saved[idx * 4 + 2],
+                saved[idx * 4 + 3],
+            ])
+        })),
+        _ => None,
+    }
+}
+
+/// Revolutions a bit makes before its count is saved again.  A power cut loses at most this
+/// many, in exchange for far fewer flash writes.
+const REVOLUTIONS_PER_SAVE: u32 = 100;
+
+fn save_per_bit(state: &mut KvStore<flash::StateFlash>, key: u8, values: [u32; 4]) {
+    let mut bytes = [0u8; 16];
+
+    for (saved, value) in bytes.chunks_exact_mut(4).zip(values) {
+        saved.copy_from_slice(&value.to_le_bytes());
+    }
+
+    // Nothing can be done about a failed write, the old value stays in place
+    let _ = state.set(key, &bytes);
+}
+
+fn write_frame(serial: &mut SerialPort<hal::usb::UsbBus>, seq: u8, message: &Message) {
+    let mut encoded = [0u8; MAX_ENCODED_LEN];
+
+    let Ok(len) = encode_frame(seq, message, &mut encoded) else {
+        return;
+    };
+
+    let mut wr_ptr = &encoded[..len];
+    while !wr_ptr.is_empty() {
+        match serial.write(wr_ptr) {
+            Ok(len) => wr_ptr = &wr_ptr[len..],
+            // On error, just drop unwritten data.  The host will time out and retry.
+            Err(_) => break,
+        };
+    }
+}
+
+fn to_rtc_date_time(date_time: &clock::DateTime) -> hal::rtc::DateTime {
+    use hal::rtc::DayOfWeek;
+
+    hal::rtc::DateTime {
+        year: date_time.year,
+        month: date_time.month,
+        day: date_time.day,
+        day_of_week: match date_time.day_of_week() {
+            0 => DayOfWeek::Sunday,
+            1 => DayOfWeek::Monday,
+            2 => DayOfWeek::Tuesday,
+            3 => DayOfWeek::Wednesday,
+            4 => DayOfWeek::Thursday,
+            5 => DayOfWeek::Friday,
+            _ => DayOfWeek::Saturday,
+        },
+        hour: date_time.hour,
+        minute: date_time.minute,
+        second: date_time.second,
+    }
+}
+
+fn from_rtc_date_time(date_time: &hal::rtc::DateTime) -> clock::DateTime {
+    clock::DateTime {
+        year: date_time.year,
+        month: date_time.month,
+        day: date_time.day,
+        hour: date_time.hour,
+        minute: date_time.minute,
+        second: date_time.second,
+    }
+}
+
 #[entry]
 fn main() -> ! {
     // info!("Program start");
@@ -79,6 +235,7 @@ fn main() -> ! {
     .unwrap();
 
     let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let timer = hal::Timer::new(peripherals.TIMER, &mut peripherals.RESETS, &clocks);
 
     // Set up the USB driver
     let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
@@ -103,6 +260,22 @@ fn main() -> ! {
         .device_class(2) // from: https://www.usb.org/defined-class-codes
         .build();
 
+    // The RTC runs from 2024-01-01 until the host sends the real time
+    let mut rtc = hal::rtc::RealTimeClock::new(
+        peripherals.RTC,
+        clocks.rtc_clock,
+        &mut peripherals.RESETS,
+        to_rtc_date_time(&clock::DateTime {
+            year: 2024,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }),
+    )
+    .unwrap();
+
     let pins = bsp::Pins::new(
         peripherals.IO_BANK0,
         peripherals.PADS_BANK0,
@@ -112,101 +285,301 @@ fn main() -> ! {
 
     let mut adc = Adc::new(peripherals.ADC, &mut peripherals.RESETS);
 
-    // Configure one of the pins as an ADC input
-    let mut sensor_pin_0 = AdcPin::new(pins.gpio27.into_floating_input());
-    let mut sensor_pin_1 = AdcPin::new(pins.gpio27.into_floating_input());
-    let mut sensor_pin_2 = AdcPin::new(pins.gpio27.into_floating_input());
-    let mut sensor_pin_3 = AdcPin::new(pins.gpio27.into_floating_input());
+    // Every ADC input on a header pin is set up, each bit's config says which one its sensor is wired to
+    let mut sensors = Sensors {
+        channel_0: AdcPin::new(pins.gpio26.into_floating_input()),
+        channel_1: AdcPin::new(pins.gpio27.into_floating_input()),
+        channel_2: AdcPin::new(pins.gpio28.into_floating_input()),
+    };
 
     let mut led_pin = pins.led.into_push_pull_output();
 
-    let mut step = pins.gpio18.into_push_pull_output();
-
-    let mut s0en = pins.gpio19.into_push_pull_output();
-    let mut s1en = pins.gpio20.into_push_pull_output();
-    let mut s2en = pins.gpio21.into_push_pull_output();
-    let mut s3en = pins.gpio22.into_push_pull_output();
-
-    let mut sensor_0: u64 = 0;
-    let mut sensor_1: u64 = 0;
-    let mut sensor_2: u64 = 0;
-    let mut sensor_3: u64 = 0;
-
-    s0en.set_low().unwrap();
-    s1en.set_low().unwrap();
-    s2en.set_low().unwrap();
-    s3en.set_low().unwrap();
+    // Pins that the config can choose for the step and enable outputs, indexed by GPIO number
+    let mut spare_outputs: [Option<DynOutputPin>; 23] = Default::default();
+    spare_outputs[3] = Some(pins.gpio3.into_push_pull_output().into_dyn_pin());
+    spare_outputs[6] = Some(pins.gpio6.into_push_pull_output().into_dyn_pin());
+    spare_outputs[7] = Some(pins.gpio7.into_push_pull_output().into_dyn_pin());
+    spare_outputs[8] = Some(pins.gpio8.into_push_pull_output().into_dyn_pin());
+    spare_outputs[9] = Some(pins.gpio9.into_push_pull_output().into_dyn_pin());
+    spare_outputs[10] = Some(pins.gpio10.into_push_pull_output().into_dyn_pin());
+    spare_outputs[11] = Some(pins.gpio11.into_push_pull_output().into_dyn_pin());
+    spare_outputs[12] = Some(pins.gpio12.into_push_pull_output().into_dyn_pin());
+    spare_outputs[13] = Some(pins.gpio13.into_push_pull_output().into_dyn_pin());
+    spare_outputs[14] = Some(pins.gpio14.into_push_pull_output().into_dyn_pin());
+    spare_outputs[15] = Some(pins.gpio15.into_push_pull_output().into_dyn_pin());
+    spare_outputs[16] = Some(pins.gpio16.into_push_pull_output().into_dyn_pin());
+    spare_outputs[17] = Some(pins.gpio17.into_push_pull_output().into_dyn_pin());
+    spare_outputs[18] = Some(pins.gpio18.into_push_pull_output().into_dyn_pin());
+    spare_outputs[19] = Some(pins.gpio19.into_push_pull_output().into_dyn_pin());
+    spare_outputs[20] = Some(pins.gpio20.into_push_pull_output().into_dyn_pin());
+    spare_outputs[21] = Some(pins.gpio21.into_push_pull_output().into_dyn_pin());
+    spare_outputs[22] = Some(pins.gpio22.into_push_pull_output().into_dyn_pin());
+
+    // RS-485 transceiver for the multi-drop bus, DE is driven high only while replying
+    let bus_pins = (
+        pins.gpio0.into_function::<hal::gpio::FunctionUart>(),
+        pins.gpio1.into_function::<hal::gpio::FunctionUart>(),
+    );
+    let bus_uart = UartPeripheral::new(peripherals.UART0, bus_pins, &mut peripherals.RESETS)
+        .enable(
+            UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
+            clocks.peripheral_clock.freq(),
+        )
+        .unwrap();
+    let mut bus_driver_enable = pins.gpio2.into_push_pull_output();
+    bus_driver_enable.set_low().unwrap();
+
+    #[cfg(feature = "i2c-peripheral")]
+    let mut i2c = {
+        use hal::gpio::{FunctionI2C, Pin, PullUp};
+
+        let sda: Pin<_, FunctionI2C, PullUp> = pins.gpio4.reconfigure();
+        let scl: Pin<_, FunctionI2C, PullUp> = pins.gpio5.reconfigure();
+
+        hal::I2C::new_peripheral_event_iterator(
+            peripherals.I2C0,
+            sda,
+            scl,
+            &mut peripherals.RESETS,
+            I2C_ADDRESS,
+        )
+    };
 
     const STEP_DELAY_US: u32 = 900;
     const STEP_DELAY_TARGET_MS: u32 = 1000;
 
-    const STEPS_PER_FLAP: u32 = 58;
+    // The drums as built take 58 full steps a flap; step counts follow from the microsteps
+    const STEPPER: StepperConfig = StepperConfig {
+        full_steps_per_revolution: 58 * 55,
+        microsteps: 1,
+        gear_ratio: GearRatio::DIRECT,
+    };
+
+    const HOME_OFFSET: Offset = Offset::Flaps(5);
 
-    const HOME_OFFSET: u32 = STEPS_PER_FLAP * 5;
+    const BUS_ADDRESS: u8 = 1;
+
+    #[cfg(feature = "i2c-peripheral")]
+    const I2C_ADDRESS: u16 = 0x42;
 
     let mut target_idx = 0;
+    let mut command_line: Vec<u8, 64> = Vec::new();
+    let mut decoder = FrameDecoder::new();
+    let mut bus_node = BusNode::new(BUS_ADDRESS).unwrap();
+    let mut bus_receiver = BusReceiver::new();
 
     let sensor_calibration = SensorCalibration {
         trigger_value: 2200,
         untrigger_value: 2100,
     };
 
-    let mut BITS: [SplitFlapBitState; 4] = [
-        SplitFlapBitState::new(sensor_calibration, STEPS_PER_FLAP, HOME_OFFSET),
-        SplitFlapBitState::new(sensor_calibration, STEPS_PER_FLAP, HOME_OFFSET),
-        SplitFlapBitState::new(sensor_calibration, STEPS_PER_FLAP, HOME_OFFSET),
-        SplitFlapBitState::new(sensor_calibration, STEPS_PER_FLAP, HOME_OFFSET),
-    ];
+    // Used until a config has been saved to flash, with every sensor read from gpio27 as wired
+    let default_config = DeviceConfig {
+        step_pin: 18,
+        bits: [
+            BitConfig::new(sensor_calibration, STEPPER, HOME_OFFSET, 19, 1),
+            BitConfig::new(sensor_calibration, STEPPER, HOME_OFFSET, 20, 1),
+            BitConfig::new(sensor_calibration, STEPPER, HOME_OFFSET, 21, 1),
+            BitConfig::new(sensor_calibration, STEPPER, HOME_OFFSET, 22, 1),
+        ],
+    };
 
-    loop {
-        sensor_0 = adc.read(&mut sensor_pin_0).unwrap();
-        sensor_1 = adc.read(&mut sensor_pin_1).unwrap();
-        sensor_2 = adc.read(&mut sensor_pin_2).unwrap();
-        sensor_3 = adc.read(&mut sensor_pin_3).unwrap();
-
-        let s0_process = BITS[0].process(sensor_0 as u32);
-        let s1_process = BITS[1].process(sensor_1 as u32);
-        let s2_process = BITS[1].process(sensor_2 as u32);
-        let s3_process = BITS[1].process(sensor_3 as u32);
-
-        if s0_process {
-            s0en.set_high().unwrap();
-        } else {
-            s0en.set_low().unwrap();
+    let decoded = DeviceConfig::<4>::decode(flash::config_sector());
+    // A module that has never saved a config was just assembled, so check it over
+    let run_self_test = decoded == Err(ConfigError::Blank);
+
+    let mut config = match decoded {
+        Ok(config) if pins_available(&config, &spare_outputs) => config,
+        Ok(_) => {
+            info!("Saved config uses unavailable pins, using defaults");
+            default_config
+        }
+        Err(error) => {
+            info!(
+                "No usable saved config ({}), using defaults",
+                Debug2Format(&error)
+            );
+            default_config
         }
+    };
+
+    let mut step = spare_outputs[config.step_pin as usize].take().unwrap();
+    let enable_pins: [DynOutputPin; 4] = core::array::from_fn(|idx| {
+        spare_outputs[config.bits[idx].enable_pin as usize]
+            .take()
+            .unwrap()
+    });
+    let mut enables = EnableLines::new(enable_pins, peripherals.PWM, &mut peripherals.RESETS);
+    let mut idle_timers = [IdleTimer::new(); 4];
+
+    let mut device = Device::new(config.bit_states());
+
+    // State that changes too often to keep in the config sector
+    let mut state = KvStore::mount(flash::StateFlash).unwrap();
+
+    // Bits that restore their position skip the homing spin, and carry on showing the last message
+    if let Some(positions) = load_per_bit(&mut state, KEY_POSITIONS) {
+        let mut restored = false;
+
+        for (bit, steps) in device.bits_mut().iter_mut().zip(positions) {
+            if bit.restores_position() && steps != u32::MAX {
+                bit.restore(HomedSteps::new(steps));
+                restored = true;
+            }
+        }
+
+        let mut last_message = [b' '; 4];
+        if restored {
+            if let Ok(Some(4)) = state.get(KEY_LAST_MESSAGE, &mut last_message) {
+                device.set_content(Content::Text(last_message));
+            }
+        }
+    }
+
+    // Counters that show wear carry on from before the power cut
+    let revolutions = load_per_bit(&mut state, KEY_REVOLUTIONS);
+    let step_losses = load_per_bit(&mut state, KEY_STEP_LOSSES);
+
+    for (idx, bit) in device.bits_mut().iter_mut().enumerate() {
+        let mut counters = bit.counters();
 
-        if s1_process {
-            s1en.set_high().unwrap();
-        } else {
-            s1en.set_low().unwrap();
+        if let Some(revolutions) = revolutions {
+            counters.revolutions = revolutions[idx];
         }
 
-        if s2_process {
-            s2en.set_high().unwrap();
-        } else {
-            s2en.set_low().unwrap();
+        if let Some(step_losses) = step_losses {
+            counters.step_loss_corrections = step_losses[idx];
         }
 
-        if s3_process {
-            s3en.set_high().unwrap();
-        } else {
-            s3en.set_low().unwrap();
+        bit.set_counters(counters);
+    }
+
+    let mut saved_revolutions = device
+        .bits()
+        .each_ref()
+        .map(|bit| bit.counters().revolutions);
+    let mut saved_step_losses = device
+        .bits()
+        .each_ref()
+        .map(|bit| bit.counters().step_loss_corrections);
+
+    if run_self_test {
+        info!("No saved config, running self-test");
+        device.start_self_test();
+    }
+    let mut self_testing: [bool; 4] = core::array::from_fn(|idx| device.is_self_testing(idx));
+
+    let mut last_tick = timer.get_counter().ticks();
+
+    #[cfg(feature = "i2c-peripheral")]
+    let mut registers = RegisterMap::new(device.bits());
+
+    loop {
+        let now = timer.get_counter().ticks();
+        for bit in device.bits_mut().iter_mut() {
+            bit.record_time((now - last_tick) as u32);
+        }
+        last_tick = now;
+
+        let sensor_values: [u32; 4] =
+            core::array::from_fn(|idx| sensors.read(&mut adc, config.bits[idx].sensor_channel));
+
+        let transitioning = device.update_transition((now / 1000) as u32);
+        let stepping = device.process(sensor_values);
+
+        for (idx, testing) in self_testing.iter_mut().enumerate() {
+            if *testing && !device.is_self_testing(idx) {
+                if let Some(report) = device.self_test_report(idx) {
+                    let verdict = if report.passed() { "passed" } else { "FAILED" };
+                    info!(
+                        "Bit {} self-test {}: {}",
+                        idx,
+                        verdict,
+                        Debug2Format(&report)
+                    );
+                }
+            }
+
+            *testing = device.is_self_testing(idx);
         }
 
-        if !s0_process && !s1_process && !s2_process && !s3_process {
+        let now_us = timer.get_counter().ticks();
+        for (idx, &process) in stepping.iter().enumerate() {
+            let policy = device.bits()[idx].idle_policy();
+            enables.set(idx, idle_timers[idx].drive(policy, process, now_us));
+        }
+
+        if !transitioning && stepping.iter().all(|&process| !process) {
             //If we've stopped stepping, we can delay briefly and then advance to the next character
             //to be displayed
 
             delay.delay_ms(STEP_DELAY_TARGET_MS);
 
-            target_idx = (target_idx + 1) % TARGETS.len();
-            let targets = TARGETS[target_idx];
+            let now = rtc.now().ok().map(|now| from_rtc_date_time(&now));
 
-            for idx in 0..4 {
-                BITS[idx].set_target_character(targets[idx] as u8);
-            }
+            let now_ms = (timer.get_counter().ticks() / 1000) as u32;
+
+            let targets = match device.next_targets(now.as_ref(), now_ms) {
+                Some(targets) => targets,
+                None => {
+                    target_idx = (target_idx + 1) % TARGETS.len();
+                    TARGETS[target_idx]
+                }
+            };
+
+            device.show(targets);
 
             info!("New targets: {}", targets);
+
+            // Scrolling text changes too often to be worth the flash wear
+            if !matches!(device.content(), Content::Demo | Content::Marquee) {
+                let _ = state.set(KEY_LAST_MESSAGE, &targets);
+            }
+
+            // Bits about to move are saved as unknown, so a power cut part way is caught
+            if device.bits().iter().any(|bit| bit.restores_position()) {
+                let positions: [u32; 4] = core::array::from_fn(|idx| {
+                    let bit = &device.bits()[idx];
+
+                    bit.settled_position()
+                        .map(HomedSteps::get)
+                        .filter(|_| bit.restores_position() && !device.in_transition(idx))
+                        .unwrap_or(u32::MAX)
+                });
+
+                save_per_bit(&mut state, KEY_POSITIONS, positions);
+            }
+
+            let counters = device.bits().each_ref().map(|bit| bit.counters());
+
+            // A reset count wraps round to a large difference, so it is saved straight away
+            let revolutions = counters.map(|counters| counters.revolutions);
+            if revolutions
+                .iter()
+                .zip(saved_revolutions)
+                .any(|(&count, saved)| count.wrapping_sub(saved) >= REVOLUTIONS_PER_SAVE)
+            {
+                save_per_bit(&mut state, KEY_REVOLUTIONS, revolutions);
+                saved_revolutions = revolutions;
+            }
+
+            // Step losses are rare and each one is worth keeping
+            let step_losses = counters.map(|counters| counters.step_loss_corrections);
+            if step_losses != saved_step_losses {
+                save_per_bit(&mut state, KEY_STEP_LOSSES, step_losses);
+                saved_step_losses = step_losses;
+            }
+        }
+
+        #[cfg(feature = "i2c-peripheral")]
+        registers.update_faults(device.bits());
+
+        // The step line is shared, so bits holding still let go while it pulses
+        let holding: [EnableDrive; 4] = core::array::from_fn(|idx| enables.drive(idx));
+        for (idx, &process) in stepping.iter().enumerate() {
+            if !process {
+                enables.set(idx, EnableDrive::Off);
+            }
         }
 
         delay.delay_us(STEP_DELAY_US);
@@ -215,10 +588,54 @@ fn main() -> ! {
         delay.delay_us(STEP_DELAY_US);
         step.set_low().unwrap();
 
-        // info!("ADC: {}", reading);
+        for (idx, &drive) in holding.iter().enumerate() {
+            enables.set(idx, drive);
+        }
 
         // let _ = serial.write(b"Loop\r\n");
 
+        #[cfg(feature = "i2c-peripheral")]
+        while let Some(event) = i2c.next() {
+            use hal::i2c::peripheral::I2CEvent;
+
+            match event {
+                I2CEvent::Start | I2CEvent::Restart => registers.begin(),
+                I2CEvent::TransferWrite => {
+                    let mut buf = [0u8; 16];
+                    let count = i2c.read(&mut buf);
+                    registers.write(&buf[..count], &mut device);
+                }
+                I2CEvent::TransferRead => {
+                    // One byte per request, so the register address never runs ahead of what
+                    // the controller has clocked out
+                    let mut buf = [0u8; 1];
+                    registers.read(device.bits(), &mut buf);
+                    i2c.write(&buf);
+                }
+                I2CEvent::Stop => {}
+            }
+        }
+
+        // Frames from the bus controller
+        let mut bus_buf = [0u8; 32];
+
+        if let Ok(count) = bus_uart.read_raw(&mut bus_buf) {
+            for &byte in bus_buf.iter().take(count) {
+                if let Some(Ok(frame)) = bus_receiver.push(byte) {
+                    let mut reply = [0u8; MAX_ENCODED_LEN];
+
+                    if let Ok(len) = bus_node.handle(&frame, &mut device, &mut reply) {
+                        if len > 0 {
+                            bus_driver_enable.set_high().unwrap();
+                            bus_uart.write_full_blocking(&reply[..len]);
+                            while bus_uart.uart_is_busy() {}
+                            bus_driver_enable.set_low().unwrap();
+                        }
+                    }
+                }
+            }
+        }
+
         // Check for new data
         if usb_dev.poll(&mut [&mut serial]) {
             let mut buf = [0u8; 64];
@@ -231,10 +648,59 @@ fn main() -> ! {
                     // Do nothing
                 }
                 Ok(count) => {
-                    // Convert to upper case
-                    buf.iter_mut().take(count).for_each(|b| {
-                        b.make_ascii_uppercase();
-                    });
+                    for &byte in buf.iter().take(count) {
+                        match decoder.push(byte) {
+                            Received::Pending => {}
+                            Received::Byte(b) => {
+                                if b != b'\r' && b != b'\n' {
+                                    if command_line.push(b.to_ascii_uppercase()).is_err() {
+                                        // Too long to be a command, discard it
+                                        command_line.clear();
+                                    }
+                                    continue;
+                                }
+
+                                match parse_command(&command_line) {
+                                    Ok(Command::Text(text)) => {
+                                        let mut targets = [b' '; 4];
+                                        text_targets(text, &mut targets);
+                                        device.set_content(Content::Text(targets));
+                                    }
+                                    Ok(Command::SetTime(date_time)) => {
+                                        let _ = rtc.set_datetime(to_rtc_date_time(&date_time));
+                                    }
+                                    Ok(Command::Show(mode)) => {
+                                        device.set_content(Content::Timed(mode));
+                                    }
+                                    Err(_) => {}
+                                }
+
+                                command_line.clear();
+                            }
+                            Received::Frame(frame) => {
+                                let reply = device.handle(&frame);
+                                write_frame(&mut serial, frame.seq, &reply);
+
+                                if device.take_save_request() {
+                                    config.capture(device.bits());
+
+                                    let mut sector = [0xFF; flash::SECTOR_SIZE];
+                                    if config.encode(&mut sector).is_ok() {
+                                        flash::write_config_sector(&sector);
+                                        info!("Config saved");
+                                    }
+                                }
+                            }
+                            Received::Corrupt { seq, .. } => {
+                                let seq = seq.unwrap_or(0);
+                                let reply = Message::Nak {
+                                    seq,
+                                    reason: NakReason::Corrupt,
+                                };
+                                write_frame(&mut serial, seq, &reply);
+                            }
+                        }
+                    }
                     // // Send back to the host
                     // let mut wr_ptr = &buf[..count];
                     // while !wr_ptr.is_empty() {
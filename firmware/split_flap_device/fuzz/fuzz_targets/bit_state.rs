@@ -0,0 +1,86 @@
+//! Random sensor readings and target changes thrown at a single bit.
+//!
+//! Checks that nothing panics, that flap positions stay on the drum, and that a bit only calls
+//! itself settled once it has reached its target.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use split_flap_device::split_flap_bit_state::{
+    BitState, SensorCalibration, SplitFlapBitState, CHARACTER_SET,
+};
+use split_flap_device::steps::{FlapIndex, HomedSteps, Revolution, Steps};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    steps_per_flap: u8,
+    home_offset: u16,
+    trigger_value: u16,
+    untrigger_value: u16,
+    ops: Vec<Op>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// The sensor reads `reading` for `repeat` passes of the loop.
+    Sense {
+        reading: u16,
+        repeat: u8,
+    },
+    TargetFlap(u8),
+    TargetCharacter(u8),
+    Trim {
+        flap: u8,
+        trim: i16,
+    },
+    Restore(u32),
+    Rehome,
+}
+
+fuzz_target!(|input: Input| {
+    let calibration = SensorCalibration {
+        trigger_value: input.trigger_value as u32,
+        untrigger_value: input.untrigger_value as u32,
+    };
+    let steps_per_flap = (input.steps_per_flap as u32).max(1);
+    let revolution = Revolution::new(Steps::new(steps_per_flap)).unwrap();
+    let home_offset = HomedSteps::new(input.home_offset as u32);
+    let mut bit = SplitFlapBitState::new(calibration, revolution, home_offset);
+
+    for op in input.ops {
+        match op {
+            Op::Sense { reading, repeat } => {
+                for _ in 0..repeat {
+                    let stepped = bit.process(reading as u32);
+                    check(&bit);
+
+                    if !stepped {
+                        assert_eq!(bit.bit_state(), BitState::SETTLED);
+                        assert!(bit.settled_position().is_some());
+                    }
+                }
+            }
+            Op::TargetFlap(flap) => bit.set_target_flap(FlapIndex::wrapping(flap as usize)),
+            Op::TargetCharacter(character) => bit.set_target_character(character),
+            Op::Trim { flap, trim } => {
+                // The device refuses trims of half a flap or more
+                let flap = FlapIndex::wrapping(flap as usize);
+                if (trim as i32).abs() < (steps_per_flap / 2) as i32 {
+                    bit.set_flap_trim(flap, trim);
+                }
+            }
+            Op::Restore(steps_since_home) => bit.restore(HomedSteps::new(steps_since_home)),
+            Op::Rehome => bit.rehome(),
+        }
+    }
+});
+
+fn check(bit: &SplitFlapBitState) {
+    assert!(bit.current_flap().get() < CHARACTER_SET.len());
+    assert!(bit.target_flap().get() < CHARACTER_SET.len());
+
+    if bit.is_settled() {
+        assert_eq!(bit.current_flap(), bit.target_flap());
+    }
+}
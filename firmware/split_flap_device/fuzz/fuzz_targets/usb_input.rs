@@ -0,0 +1,165 @@
+//! Arbitrary bytes from the USB serial port, handled the way the firmware loop handles them:
+//! text commands outside of frames, binary frames answered by the device.
+//!
+//! Checks that nothing panics, that decoded frames encode back to the same frame and that every
+//! reply fits in a frame.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use split_flap_device::device::{text_targets, Content, Device};
+use split_flap_device::protocol::{
+    crc16, decode_frame, encode_frame, FrameDecoder, Message, NakReason, Received, MAX_ENCODED_LEN,
+};
+use split_flap_device::serial_command::{parse_command, Command};
+use split_flap_device::split_flap_bit_state::{SensorCalibration, SplitFlapBitState};
+use split_flap_device::steps::{HomedSteps, Revolution, Steps};
+
+const BITS: usize = 4;
+const STEPS_PER_FLAP: u32 = 58;
+const HOME_OFFSET: u32 = STEPS_PER_FLAP * 5;
+const COMMAND_LINE_LEN: usize = 64;
+/// Loop passes run after the input, to act on whatever it asked for.
+const PASSES: u32 = 200;
+
+#[derive(Arbitrary, Debug)]
+enum Chunk {
+    /// Bytes as they arrive, framed or not.
+    Raw(Vec<u8>),
+    /// A frame around arbitrary contents with a good CRC, which the fuzzer would rarely find.
+    Frame { seq: u8, message: Vec<u8> },
+}
+
+struct Port {
+    device: Device<BITS>,
+    decoder: FrameDecoder,
+    command_line: [u8; COMMAND_LINE_LEN],
+    command_len: usize,
+    out: [u8; MAX_ENCODED_LEN],
+}
+
+impl Port {
+    fn new() -> Port {
+        let calibration = SensorCalibration {
+            trigger_value: 2200,
+            untrigger_value: 2100,
+        };
+
+        let revolution = Revolution::new(Steps::new(STEPS_PER_FLAP)).unwrap();
+
+        Port {
+            device: Device::new(core::array::from_fn(|_| {
+                SplitFlapBitState::new(calibration, revolution, HomedSteps::new(HOME_OFFSET))
+            })),
+            decoder: FrameDecoder::new(),
+            command_line: [0; COMMAND_LINE_LEN],
+            command_len: 0,
+            out: [0; MAX_ENCODED_LEN],
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.decoder.push(byte) {
+            Received::Pending => {}
+            Received::Byte(b) => {
+                if b != b'\r' && b != b'\n' {
+                    if self.command_len == COMMAND_LINE_LEN {
+                        // Too long to be a command, discard it
+                        self.command_len = 0;
+                    } else {
+                        self.command_line[self.command_len] = b.to_ascii_uppercase();
+                        self.command_len += 1;
+                    }
+                    return;
+                }
+
+                match parse_command(&self.command_line[..self.command_len]) {
+                    Ok(Command::Text(text)) => {
+                        let mut targets = [b' '; BITS];
+                        text_targets(text, &mut targets);
+                        self.device.set_content(Content::Text(targets));
+                    }
+                    Ok(Command::SetTime(_)) => {}
+                    Ok(Command::Show(mode)) => self.device.set_content(Content::Timed(mode)),
+                    Err(_) => {}
+                }
+
+                self.command_len = 0;
+            }
+            Received::Frame(frame) => {
+                let len = encode_frame(frame.seq, &frame.message, &mut self.out)
+                    .expect("a decoded frame encodes again");
+                assert_eq!(decode_frame(&mut self.out[1..len - 1]), Ok(frame));
+
+                let reply = self.device.handle(&frame);
+                encode_frame(frame.seq, &reply, &mut self.out).expect("replies fit in a frame");
+            }
+            Received::Corrupt { seq, .. } => {
+                let seq = seq.unwrap_or(0);
+                let reply = Message::Nak {
+                    seq,
+                    reason: NakReason::Corrupt,
+                };
+                encode_frame(seq, &reply, &mut self.out).expect("NAKs fit in a frame");
+            }
+        }
+    }
+
+    /// Runs the loop with the sensors flickering, so the bits home and move.
+    fn run(&mut self) {
+        for now_ms in 0..PASSES {
+            let transitioning = self.device.update_transition(now_ms);
+            let stepping = self.device.process([now_ms % 2 * 4000; BITS]);
+
+            if !transitioning && !stepping.contains(&true) {
+                if let Some(targets) = self.device.next_targets(None, now_ms) {
+                    self.device.show(targets);
+                }
+            }
+        }
+    }
+}
+
+fuzz_target!(|chunks: Vec<Chunk>| {
+    let mut port = Port::new();
+
+    for chunk in chunks {
+        match chunk {
+            Chunk::Raw(bytes) => bytes.into_iter().for_each(|byte| port.receive(byte)),
+            Chunk::Frame { seq, message } => {
+                let mut body = vec![seq];
+                body.extend_from_slice(&message);
+                body.extend_from_slice(&crc16(&body).to_le_bytes());
+
+                port.receive(0);
+                cobs_encode(&body)
+                    .into_iter()
+                    .for_each(|byte| port.receive(byte));
+                port.receive(0);
+            }
+        }
+    }
+
+    port.run();
+});
+
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0];
+    let mut code_idx = 0;
+
+    for &byte in data {
+        if byte != 0 {
+            out.push(byte);
+        }
+
+        if byte == 0 || out.len() - code_idx == 0xFF {
+            out[code_idx] = (out.len() - code_idx) as u8;
+            code_idx = out.len();
+            out.push(0);
+        }
+    }
+
+    out[code_idx] = (out.len() - code_idx) as u8;
+    out
+}
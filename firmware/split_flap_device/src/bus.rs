@@ -0,0 +1,518 @@
+//! Multi-drop bus for chaining controller boards over RS-485.
+//!
+//! Frames use the same COBS and CRC-16 framing as the host protocol, with a destination and
+//! source address in place of the sequence number.  Only the controller talks unprompted; a node
+//! transmits only when a `Poll` is addressed to it, and the controller waits for that reply (or a
+//! timeout) before sending anything else, so two transmitters are never active at once.
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::Device;
+use crate::protocol::{decode_checked, encode_checked, Delimited, FrameDecoder, ProtocolError};
+use crate::split_flap_bit_state::{BitState, SplitFlapBitState};
+
+pub const CONTROLLER_ADDRESS: u8 = 0x00;
+pub const BROADCAST_ADDRESS: u8 = 0xFF;
+pub const MAX_BITS_PER_NODE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub homed: bool,
+    pub settled: bool,
+    /// Targets are waiting for a `Latch`.
+    pub staged: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BusMessage<'a> {
+    /// Target characters for the node's bits, held until the next `Latch`.
+    Stage(&'a [u8]),
+    /// Show the staged targets.  Broadcast so every board starts flipping together.
+    Latch,
+    Poll,
+    Status(NodeStatus),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BusFrame<'a> {
+    pub destination: u8,
+    pub source: u8,
+    pub message: BusMessage<'a>,
+}
+
+impl BusFrame<'_> {
+    /// Encodes the frame, with delimiters, into `out`.  Returns the number of bytes to send.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, ProtocolError> {
+        encode_checked(&[self.destination, self.source], &self.message, out)
+    }
+
+    fn is_for(&self, address: u8) -> bool {
+        self.destination == address || self.destination == BROADCAST_ADDRESS
+    }
+}
+
+/// Decodes a COBS encoded bus frame, without delimiters, in place.
+pub fn decode_bus_frame(encoded: &mut [u8]) -> Result<BusFrame<'_>, ProtocolError> {
+    let ([destination, source], message) =
+        decode_checked::<2, BusMessage>(encoded).map_err(|(_, error)| error)?;
+
+    Ok(BusFrame {
+        destination,
+        source,
+        message,
+    })
+}
+
+/// Collects bytes from the bus UART into frames.  Line noise between frames is dropped.
+#[derive(Default)]
+pub struct BusReceiver {
+    decoder: FrameDecoder,
+}
+
+impl BusReceiver {
+    pub fn new() -> BusReceiver {
+        BusReceiver {
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Result<BusFrame<'_>, ProtocolError>> {
+        match self.decoder.push_delimited(byte) {
+            Delimited::Pending | Delimited::Byte(_) => None,
+            Delimited::Overflow => Some(Err(ProtocolError::FrameTooLong)),
+            Delimited::Frame(encoded) => Some(decode_bus_frame(encoded)),
+        }
+    }
+}
+
+/// A module board listening on the bus.
+pub struct BusNode {
+    address: u8,
+    staged: [u8; MAX_BITS_PER_NODE],
+    staged_len: Option<usize>,
+}
+
+impl BusNode {
+    /// `None` for the controller or broadcast address, which a node can't listen on.
+    pub fn new(address: u8) -> Option<BusNode> {
+        if address == CONTROLLER_ADDRESS || address == BROADCAST_ADDRESS {
+            return None;
+        }
+
+        Some(BusNode {
+            address,
+            staged: [b' '; MAX_BITS_PER_NODE],
+            staged_len: None,
+        })
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Acts on a frame seen on the bus.  Returns the number of bytes written to `reply`, which is
+    /// zero whenever the node must stay silent.  Latched targets become the device's content, so
+    /// they stay up until the controller sends others.
+    pub fn handle<const BITS: usize>(
+        &mut self,
+        frame: &BusFrame,
+        device: &mut Device<BITS>,
+        reply: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        // Replies from other nodes share the wire but are only meant for the controller
+        if frame.source != CONTROLLER_ADDRESS || !frame.is_for(self.address) {
+            return Ok(0);
+        }
+
+        match frame.message {
+            BusMessage::Stage(targets) => {
+                let len = targets.len().min(MAX_BITS_PER_NODE);
+                self.staged[..len].copy_from_slice(&targets[..len]);
+                self.staged[len..].fill(b' ');
+                self.staged_len = Some(len);
+
+                Ok(0)
+            }
+            BusMessage::Latch => {
+                if self.staged_len.take().is_some() {
+                    let targets =
+                        core::array::from_fn(|idx| self.staged.get(idx).copied().unwrap_or(b' '));
+                    device.show_text(targets);
+                }
+
+                Ok(0)
+            }
+            // A broadcast poll would have every node answer at once
+            BusMessage::Poll if frame.destination == self.address => BusFrame {
+                destination: CONTROLLER_ADDRESS,
+                source: self.address,
+                message: BusMessage::Status(self.status(device.bits())),
+            }
+            .encode(reply),
+            BusMessage::Poll | BusMessage::Status(_) => Ok(0),
+        }
+    }
+
+    fn status(&self, bits: &[SplitFlapBitState]) -> NodeStatus {
+        NodeStatus {
+            homed: bits
+                .iter()
+                .all(|bit| bit.bit_state() != BitState::UNINITIALIZED),
+            settled: bits.iter().all(|bit| bit.is_settled()),
+            staged: self.staged_len.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BusError {
+    /// Still waiting for the reply to a poll.
+    Busy,
+    /// Polls must be addressed to a single node.
+    BroadcastPoll,
+    Protocol(ProtocolError),
+}
+
+/// The board that drives the bus, deciding who may transmit.
+pub struct BusController {
+    reply_timeout_ms: u32,
+    awaiting: Option<(u8, u32)>,
+}
+
+impl BusController {
+    pub fn new(reply_timeout_ms: u32) -> BusController {
+        BusController {
+            reply_timeout_ms,
+            awaiting: None,
+        }
+    }
+
+    /// True while a polled node may still be replying.
+    pub fn is_busy(&mut self, now_ms: u32) -> bool {
+        if let Some((_, sent_ms)) = self.awaiting {
+            if now_ms.wrapping_sub(sent_ms) >= self.reply_timeout_ms {
+                self.awaiting = None;
+            }
+        }
+
+        self.awaiting.is_some()
+    }
+
+    /// Encodes a frame for the bus into `out`, returning the number of bytes to send.
+    pub fn send(
+        &mut self,
+        destination: u8,
+        message: &BusMessage,
+        now_ms: u32,
+        out: &mut [u8],
+    ) -> Result<usize, BusError> {
+        if self.is_busy(now_ms) {
+            return Err(BusError::Busy);
+        }
+
+        let is_poll = matches!(message, BusMessage::Poll);
+
+        if is_poll && destination == BROADCAST_ADDRESS {
+            return Err(BusError::BroadcastPoll);
+        }
+
+        let len = BusFrame {
+            destination,
+            source: CONTROLLER_ADDRESS,
+            message: *message,
+        }
+        .encode(out)
+        .map_err(BusError::Protocol)?;
+
+        if is_poll {
+            self.awaiting = Some((destination, now_ms));
+        }
+
+        Ok(len)
+    }
+
+    /// Accepts the reply from the polled node, freeing the bus.
+    pub fn receive(&mut self, frame: &BusFrame) -> Option<(u8, NodeStatus)> {
+        let (polled, _) = self.awaiting?;
+
+        match frame.message {
+            BusMessage::Status(status)
+                if frame.destination == CONTROLLER_ADDRESS && frame.source == polled =>
+            {
+                self.awaiting = None;
+                Some((polled, status))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{
+        decode_bus_frame, BusController, BusError, BusFrame, BusMessage, BusNode, BusReceiver,
+        NodeStatus, BROADCAST_ADDRESS, CONTROLLER_ADDRESS,
+    };
+    use crate::device::{Content, Device};
+    use crate::protocol::MAX_ENCODED_LEN;
+    use crate::split_flap_bit_state::{SensorCalibration, SplitFlapBitState};
+    use crate::steps::{HomedSteps, Revolution, Steps};
+
+    struct SimulatedNode {
+        node: BusNode,
+        receiver: BusReceiver,
+        device: Device<4>,
+    }
+
+    impl SimulatedNode {
+        fn new(address: u8) -> SimulatedNode {
+            let calibration = SensorCalibration {
+                trigger_value: 2000,
+                untrigger_value: 1800,
+            };
+            let revolution = Revolution::new(Steps::new(58)).unwrap();
+
+            SimulatedNode {
+                node: BusNode::new(address).unwrap(),
+                receiver: BusReceiver::new(),
+                device: Device::new(core::array::from_fn(|_| {
+                    SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+                })),
+            }
+        }
+
+        /// Puts every bit on the first flap, so transitions can send them on.
+        fn home(&mut self) {
+            self.device.process([2100; 4]);
+        }
+
+        /// Where the bits are headed once the firmware loop has moved any transition on.
+        fn target_flaps(&mut self) -> [usize; 4] {
+            self.device.update_transition(0);
+
+            core::array::from_fn(|idx| self.device.bits()[idx].target_flap().get())
+        }
+    }
+
+    /// Nodes and a controller sharing one half-duplex wire.
+    struct VirtualBus {
+        controller: BusController,
+        controller_receiver: BusReceiver,
+        nodes: Vec<SimulatedNode>,
+        now_ms: u32,
+    }
+
+    impl VirtualBus {
+        fn new(addresses: &[u8]) -> VirtualBus {
+            VirtualBus {
+                controller: BusController::new(50),
+                controller_receiver: BusReceiver::new(),
+                nodes: addresses.iter().map(|&a| SimulatedNode::new(a)).collect(),
+                now_ms: 0,
+            }
+        }
+
+        /// Sends a frame from the controller and lets every node react.  Panics if more than one
+        /// node transmits in response, since that would collide on the wire.
+        fn transmit(&mut self, destination: u8, message: BusMessage) -> Option<(u8, NodeStatus)> {
+            let mut out = [0u8; MAX_ENCODED_LEN];
+            let len = self
+                .controller
+                .send(destination, &message, self.now_ms, &mut out)
+                .unwrap();
+
+            let mut replies = Vec::new();
+
+            for sim in self.nodes.iter_mut() {
+                for &byte in &out[..len] {
+                    if let Some(Ok(frame)) = sim.receiver.push(byte) {
+                        let mut reply = [0u8; MAX_ENCODED_LEN];
+                        let reply_len = sim
+                            .node
+                            .handle(&frame, &mut sim.device, &mut reply)
+                            .unwrap();
+
+                        if reply_len > 0 {
+                            replies.push(reply[..reply_len].to_vec());
+                        }
+                    }
+                }
+            }
+
+            assert!(
+                replies.len() <= 1,
+                "{} nodes transmitted at once",
+                replies.len()
+            );
+
+            let reply = replies.pop()?;
+            let mut status = None;
+
+            for &byte in &reply {
+                if let Some(Ok(frame)) = self.controller_receiver.push(byte) {
+                    status = self.controller.receive(&frame);
+                }
+            }
+
+            // Other nodes hear the reply too and must ignore it
+            for sim in self.nodes.iter_mut() {
+                for &byte in &reply {
+                    if let Some(Ok(frame)) = sim.receiver.push(byte) {
+                        let mut ignored = [0u8; MAX_ENCODED_LEN];
+                        assert_eq!(
+                            sim.node.handle(&frame, &mut sim.device, &mut ignored),
+                            Ok(0)
+                        );
+                    }
+                }
+            }
+
+            status
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = BusFrame {
+            destination: 3,
+            source: CONTROLLER_ADDRESS,
+            message: BusMessage::Stage(b"RAIL"),
+        };
+        let mut out = [0u8; MAX_ENCODED_LEN];
+
+        let len = frame.encode(&mut out).unwrap();
+
+        assert_eq!(decode_bus_frame(&mut out[1..len - 1]), Ok(frame));
+    }
+
+    #[test]
+    fn nodes_cannot_take_reserved_addresses() {
+        assert!(BusNode::new(CONTROLLER_ADDRESS).is_none());
+        assert!(BusNode::new(BROADCAST_ADDRESS).is_none());
+        assert_eq!(BusNode::new(1).map(|node| node.address()), Some(1));
+    }
+
+    #[test]
+    fn staged_targets_wait_for_latch() {
+        let mut bus = VirtualBus::new(&[1]);
+        bus.nodes.iter_mut().for_each(SimulatedNode::home);
+
+        bus.transmit(1, BusMessage::Stage(b"HI"));
+        assert_eq!(bus.nodes[0].target_flaps(), [0, 0, 0, 0]);
+
+        bus.transmit(BROADCAST_ADDRESS, BusMessage::Latch);
+        assert_eq!(bus.nodes[0].target_flaps(), [8, 9, 0, 0]);
+        // Kept as the content, so the firmware loop doesn't move on to something else
+        assert_eq!(bus.nodes[0].device.content(), &Content::Text(*b"HI  "));
+    }
+
+    #[test]
+    fn broadcast_latch_updates_every_node_together() {
+        let mut bus = VirtualBus::new(&[1, 2, 3]);
+        bus.nodes.iter_mut().for_each(SimulatedNode::home);
+
+        bus.transmit(1, BusMessage::Stage(b"ABCD"));
+        bus.transmit(2, BusMessage::Stage(b"EFGH"));
+        bus.transmit(3, BusMessage::Stage(b"IJKL"));
+
+        assert!(bus
+            .nodes
+            .iter_mut()
+            .all(|sim| sim.target_flaps() == [0, 0, 0, 0]));
+
+        bus.transmit(BROADCAST_ADDRESS, BusMessage::Latch);
+
+        assert_eq!(bus.nodes[0].target_flaps(), [1, 2, 3, 4]);
+        assert_eq!(bus.nodes[1].target_flaps(), [5, 6, 7, 8]);
+        assert_eq!(bus.nodes[2].target_flaps(), [9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn nodes_ignore_frames_for_other_addresses() {
+        let mut bus = VirtualBus::new(&[1, 2]);
+        bus.nodes.iter_mut().for_each(SimulatedNode::home);
+
+        bus.transmit(2, BusMessage::Stage(b"ZZZZ"));
+        bus.transmit(1, BusMessage::Latch);
+        bus.transmit(2, BusMessage::Latch);
+
+        assert_eq!(bus.nodes[0].target_flaps(), [0, 0, 0, 0]);
+        assert_eq!(bus.nodes[1].target_flaps(), [26, 26, 26, 26]);
+    }
+
+    #[test]
+    fn polls_are_answered_by_one_node() {
+        let mut bus = VirtualBus::new(&[1, 2, 3]);
+
+        bus.transmit(2, BusMessage::Stage(b"A"));
+
+        for address in [1, 2, 3] {
+            let (source, status) = bus.transmit(address, BusMessage::Poll).unwrap();
+
+            assert_eq!(source, address);
+            assert!(!status.homed);
+            assert_eq!(status.staged, address == 2);
+        }
+    }
+
+    #[test]
+    fn broadcast_poll_is_refused() {
+        let mut controller = BusController::new(50);
+        let mut out = [0u8; MAX_ENCODED_LEN];
+
+        assert_eq!(
+            controller.send(BROADCAST_ADDRESS, &BusMessage::Poll, 0, &mut out),
+            Err(BusError::BroadcastPoll)
+        );
+    }
+
+    #[test]
+    fn node_does_not_answer_broadcast_poll() {
+        let mut sim = SimulatedNode::new(4);
+        let frame = BusFrame {
+            destination: BROADCAST_ADDRESS,
+            source: CONTROLLER_ADDRESS,
+            message: BusMessage::Poll,
+        };
+        let mut reply = [0u8; MAX_ENCODED_LEN];
+
+        assert_eq!(sim.node.handle(&frame, &mut sim.device, &mut reply), Ok(0));
+    }
+
+    #[test]
+    fn controller_waits_for_reply_or_timeout() {
+        let mut controller = BusController::new(50);
+        let mut out = [0u8; MAX_ENCODED_LEN];
+
+        controller
+            .send(7, &BusMessage::Poll, 100, &mut out)
+            .unwrap();
+
+        assert_eq!(
+            controller.send(BROADCAST_ADDRESS, &BusMessage::Latch, 120, &mut out),
+            Err(BusError::Busy)
+        );
+
+        let reply = BusFrame {
+            destination: CONTROLLER_ADDRESS,
+            source: 7,
+            message: BusMessage::Status(NodeStatus {
+                homed: true,
+                settled: true,
+                staged: false,
+            }),
+        };
+        assert!(controller.receive(&reply).is_some());
+        assert!(!controller.is_busy(120));
+
+        controller
+            .send(8, &BusMessage::Poll, 200, &mut out)
+            .unwrap();
+        assert!(controller.is_busy(249));
+        assert!(!controller.is_busy(250));
+    }
+}
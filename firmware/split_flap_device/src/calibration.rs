@@ -0,0 +1,103 @@
+use crate::split_flap_bit_state::SensorCalibration;
+use crate::steps::Steps;
+
+/// Smallest difference between the brightest and darkest readings that can be told apart from
+/// noise.
+pub const MIN_SENSOR_SPAN: u32 = 200;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CalibrationError {
+    /// The readings barely changed over a revolution, so the sensor or magnet is missing.
+    NoSignal { min: u32, max: u32 },
+}
+
+/// Measures the home sensor over a revolution of the drum and picks thresholds from the range
+/// seen.  The bit must step once after each sample.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CalibrationSweep {
+    steps_remaining: u32,
+    min: u32,
+    max: u32,
+}
+
+impl CalibrationSweep {
+    pub fn new(revolution: Steps) -> CalibrationSweep {
+        CalibrationSweep {
+            steps_remaining: revolution.get(),
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn steps_remaining(&self) -> u32 {
+        self.steps_remaining
+    }
+
+    /// Records a reading.  Returns `None` until the revolution is complete.
+    pub fn sample(
+        &mut self,
+        sensor_value: u32,
+    ) -> Option<Result<SensorCalibration, CalibrationError>> {
+        self.min = self.min.min(sensor_value);
+        self.max = self.max.max(sensor_value);
+        self.steps_remaining = self.steps_remaining.saturating_sub(1);
+
+        if self.steps_remaining > 0 {
+            return None;
+        }
+
+        let span = self.max - self.min;
+
+        if span < MIN_SENSOR_SPAN {
+            return Some(Err(CalibrationError::NoSignal {
+                min: self.min,
+                max: self.max,
+            }));
+        }
+
+        // Trigger well above the background and release halfway, for plenty of hysteresis
+        Some(Ok(SensorCalibration {
+            trigger_value: self.min + span * 3 / 4,
+            untrigger_value: self.min + span / 2,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CalibrationError, CalibrationSweep};
+    use crate::split_flap_bit_state::SensorCalibration;
+    use crate::steps::Steps;
+
+    #[test]
+    fn thresholds_sit_between_extremes() {
+        let mut sweep = CalibrationSweep::new(Steps::new(4));
+
+        assert_eq!(sweep.sample(500), None);
+        assert_eq!(sweep.sample(3300), None);
+        assert_eq!(sweep.sample(520), None);
+
+        assert_eq!(
+            sweep.sample(510),
+            Some(Ok(SensorCalibration {
+                trigger_value: 2600,
+                untrigger_value: 1900
+            }))
+        );
+    }
+
+    #[test]
+    fn flat_readings_are_rejected() {
+        let mut sweep = CalibrationSweep::new(Steps::new(2));
+
+        sweep.sample(1000);
+
+        assert_eq!(
+            sweep.sample(1100),
+            Some(Err(CalibrationError::NoSignal {
+                min: 1000,
+                max: 1100
+            }))
+        );
+    }
+}
@@ -0,0 +1,405 @@
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Day of the week with 0 as Sunday, matching the RP2040 RTC.
+    pub fn day_of_week(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        (self.days_since_epoch() + 4).rem_euclid(7) as u8
+    }
+
+    pub fn seconds_since_epoch(&self) -> i64 {
+        self.days_since_epoch() * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    fn days_since_epoch(&self) -> i64 {
+        // Civil calendar to day number, counting years from March so the leap day comes last
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// Parses `YYYY-MM-DD HH:MM[:SS]`, also accepting `T` between the date and time.
+    pub fn parse(text: &[u8]) -> Option<DateTime> {
+        if text.len() != 16 && text.len() != 19 {
+            return None;
+        }
+
+        let separators_valid = text[4] == b'-'
+            && text[7] == b'-'
+            && (text[10] == b' ' || text[10] == b'T')
+            && text[13] == b':'
+            && (text.len() == 16 || text[16] == b':');
+
+        if !separators_valid {
+            return None;
+        }
+
+        let date_time = DateTime {
+            year: parse_number(&text[0..4])?,
+            month: parse_number(&text[5..7])? as u8,
+            day: parse_number(&text[8..10])? as u8,
+            hour: parse_number(&text[11..13])? as u8,
+            minute: parse_number(&text[14..16])? as u8,
+            second: if text.len() == 19 {
+                parse_number(&text[17..19])? as u8
+            } else {
+                0
+            },
+        };
+
+        if date_time.is_valid() {
+            Some(date_time)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<u16> {
+    digits.iter().try_fold(0u16, |value, &digit| {
+        if digit.is_ascii_digit() {
+            Some(value * 10 + (digit - b'0') as u16)
+        } else {
+            None
+        }
+    })
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HourFormat {
+    TwentyFourHour,
+    /// 12 hour time, followed by AM or PM when there is room.
+    TwelveHour,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DateFormat {
+    YearMonthDay,
+    DayMonth,
+    MonthDay,
+}
+
+/// Content generated from the current time rather than sent by the host.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisplayMode {
+    Clock(HourFormat),
+    Date(DateFormat),
+    Countdown(DateTime),
+}
+
+impl DisplayMode {
+    /// Writes the text for `now` into `out`, returning the number of characters written.
+    ///
+    /// Separators and suffixes are dropped when the full form doesn't fit, so a four bit
+    /// display shows `1234` rather than `12:3`.
+    pub fn render(&self, now: &DateTime, out: &mut [u8]) -> usize {
+        let mut text = TextBuffer::new();
+        self.write(now, true, &mut text);
+
+        if text.len > out.len() {
+            text = TextBuffer::new();
+            self.write(now, false, &mut text);
+        }
+
+        let len = text.len.min(out.len());
+        out[..len].copy_from_slice(&text.bytes[..len]);
+
+        len
+    }
+
+    fn write(&self, now: &DateTime, full: bool, text: &mut TextBuffer) {
+        match self {
+            DisplayMode::Clock(HourFormat::TwentyFourHour) => {
+                text.push_two_digits(now.hour as u32);
+                text.push_if(full, b':');
+                text.push_two_digits(now.minute as u32);
+            }
+            DisplayMode::Clock(HourFormat::TwelveHour) => {
+                let hour = match now.hour % 12 {
+                    0 => 12,
+                    hour => hour,
+                };
+
+                text.push_number(hour as u32);
+                text.push_if(full, b':');
+                text.push_two_digits(now.minute as u32);
+
+                if full {
+                    text.push(if now.hour < 12 { b'A' } else { b'P' });
+                    text.push(b'M');
+                }
+            }
+            DisplayMode::Date(DateFormat::YearMonthDay) => {
+                text.push_number(now.year as u32);
+                text.push_if(full, b'-');
+                text.push_two_digits(now.month as u32);
+                text.push_if(full, b'-');
+                text.push_two_digits(now.day as u32);
+            }
+            DisplayMode::Date(DateFormat::DayMonth) => {
+                text.push_two_digits(now.day as u32);
+                text.push_if(full, b'/');
+                text.push_two_digits(now.month as u32);
+            }
+            DisplayMode::Date(DateFormat::MonthDay) => {
+                text.push_two_digits(now.month as u32);
+                text.push_if(full, b'/');
+                text.push_two_digits(now.day as u32);
+            }
+            DisplayMode::Countdown(target) => {
+                let remaining = (target.seconds_since_epoch() - now.seconds_since_epoch()).max(0);
+
+                let days = (remaining / SECONDS_PER_DAY) as u32;
+                let hours = (remaining % SECONDS_PER_DAY / 3600) as u32;
+                let minutes = (remaining % 3600 / 60) as u32;
+                let seconds = (remaining % 60) as u32;
+
+                if days > 0 {
+                    text.push_number(days);
+                    text.push(b'D');
+                    text.push_if(full, b' ');
+                    text.push_two_digits(hours);
+                    text.push_if(full, b':');
+                    text.push_two_digits(minutes);
+                } else if hours > 0 {
+                    text.push_number(hours);
+                    text.push_if(full, b':');
+                    text.push_two_digits(minutes);
+                    text.push_if(full, b':');
+                    text.push_two_digits(seconds);
+                } else {
+                    text.push_number(minutes);
+                    text.push_if(full, b':');
+                    text.push_two_digits(seconds);
+                }
+            }
+        }
+    }
+}
+
+struct TextBuffer {
+    bytes: [u8; 24],
+    len: usize,
+}
+
+impl TextBuffer {
+    fn new() -> TextBuffer {
+        TextBuffer {
+            bytes: [b' '; 24],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, character: u8) {
+        if self.len < self.bytes.len() {
+            self.bytes[self.len] = character;
+            self.len += 1;
+        }
+    }
+
+    fn push_if(&mut self, condition: bool, character: u8) {
+        if condition {
+            self.push(character);
+        }
+    }
+
+    fn push_two_digits(&mut self, value: u32) {
+        self.push(b'0' + (value / 10 % 10) as u8);
+        self.push(b'0' + (value % 10) as u8);
+    }
+
+    fn push_number(&mut self, value: u32) {
+        let mut divisor = 1;
+
+        while value / divisor >= 10 {
+            divisor *= 10;
+        }
+
+        while divisor > 0 {
+            self.push(b'0' + (value / divisor % 10) as u8);
+            divisor /= 10;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DateFormat, DateTime, DisplayMode, HourFormat};
+
+    fn date_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    fn render<const N: usize>(mode: DisplayMode, now: &DateTime) -> ([u8; N], usize) {
+        let mut out = [b' '; N];
+        let len = mode.render(now, &mut out);
+
+        (out, len)
+    }
+
+    #[test]
+    fn parse_accepts_date_and_time() {
+        assert_eq!(
+            DateTime::parse(b"2024-02-29 13:05:09"),
+            Some(date_time(2024, 2, 29, 13, 5, 9))
+        );
+        assert_eq!(
+            DateTime::parse(b"2024-03-07T08:15"),
+            Some(date_time(2024, 3, 7, 8, 15, 0))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_dates() {
+        assert_eq!(DateTime::parse(b"2023-02-29 00:00:00"), None);
+        assert_eq!(DateTime::parse(b"2024-13-01 00:00:00"), None);
+        assert_eq!(DateTime::parse(b"2024-01-01 24:00:00"), None);
+        assert_eq!(DateTime::parse(b"2024/01/01 00:00:00"), None);
+        assert_eq!(DateTime::parse(b"2024-01-01"), None);
+    }
+
+    #[test]
+    fn day_of_week_starts_on_sunday() {
+        assert_eq!(date_time(1970, 1, 1, 0, 0, 0).day_of_week(), 4);
+        assert_eq!(date_time(2024, 3, 10, 0, 0, 0).day_of_week(), 0);
+        assert_eq!(date_time(2000, 2, 29, 0, 0, 0).day_of_week(), 2);
+    }
+
+    #[test]
+    fn seconds_since_epoch_matches_unix_time() {
+        assert_eq!(date_time(1970, 1, 1, 0, 0, 0).seconds_since_epoch(), 0);
+        assert_eq!(
+            date_time(2024, 3, 7, 13, 45, 30).seconds_since_epoch(),
+            1_709_819_130
+        );
+    }
+
+    #[test]
+    fn clock_uses_colon_when_it_fits() {
+        let now = date_time(2024, 3, 7, 9, 5, 0);
+
+        let (out, len) = render::<5>(DisplayMode::Clock(HourFormat::TwentyFourHour), &now);
+
+        assert_eq!(&out, b"09:05");
+        assert_eq!(len, 5);
+    }
+
+    #[test]
+    fn clock_drops_colon_on_four_bits() {
+        let now = date_time(2024, 3, 7, 21, 5, 0);
+
+        let (out, _) = render::<4>(DisplayMode::Clock(HourFormat::TwentyFourHour), &now);
+
+        assert_eq!(&out, b"2105");
+    }
+
+    #[test]
+    fn twelve_hour_clock_adds_meridiem() {
+        let (out, len) = render::<8>(
+            DisplayMode::Clock(HourFormat::TwelveHour),
+            &date_time(2024, 3, 7, 0, 30, 0),
+        );
+        assert_eq!(&out[..len], b"12:30AM");
+
+        let (out, len) = render::<8>(
+            DisplayMode::Clock(HourFormat::TwelveHour),
+            &date_time(2024, 3, 7, 13, 30, 0),
+        );
+        assert_eq!(&out[..len], b"1:30PM");
+    }
+
+    #[test]
+    fn date_formats() {
+        let now = date_time(2024, 3, 7, 0, 0, 0);
+
+        let (out, _) = render::<10>(DisplayMode::Date(DateFormat::YearMonthDay), &now);
+        assert_eq!(&out, b"2024-03-07");
+
+        let (out, _) = render::<5>(DisplayMode::Date(DateFormat::DayMonth), &now);
+        assert_eq!(&out, b"07/03");
+
+        let (out, _) = render::<4>(DisplayMode::Date(DateFormat::MonthDay), &now);
+        assert_eq!(&out, b"0307");
+    }
+
+    #[test]
+    fn countdown_shows_days_then_hours_then_minutes() {
+        let target = date_time(2025, 1, 1, 0, 0, 0);
+
+        let (out, len) = render::<16>(
+            DisplayMode::Countdown(target),
+            &date_time(2024, 12, 29, 21, 30, 0),
+        );
+        assert_eq!(&out[..len], b"2D 02:30");
+
+        let (out, len) = render::<16>(
+            DisplayMode::Countdown(target),
+            &date_time(2024, 12, 31, 21, 30, 15),
+        );
+        assert_eq!(&out[..len], b"2:29:45");
+
+        let (out, len) = render::<16>(
+            DisplayMode::Countdown(target),
+            &date_time(2024, 12, 31, 23, 58, 1),
+        );
+        assert_eq!(&out[..len], b"1:59");
+    }
+
+    #[test]
+    fn countdown_stops_at_zero() {
+        let (out, len) = render::<4>(
+            DisplayMode::Countdown(date_time(2024, 1, 1, 0, 0, 0)),
+            &date_time(2024, 6, 1, 0, 0, 0),
+        );
+
+        assert_eq!(&out[..len], b"0:00");
+    }
+}
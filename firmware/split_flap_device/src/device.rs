@@ -0,0 +1,1211 @@
+//! The device end of the host protocol, independent of the board it runs on.
+
+use crate::calibration::CalibrationSweep;
+use crate::clock::{DateTime, DisplayMode};
+use crate::glyphs::{expand_glyphs, DEFAULT_GLYPHS};
+use crate::idle::IdlePolicy;
+use crate::marquee::{Marquee, ScrollConfig};
+use crate::normalize::{normalize, NormalizeOptions};
+use crate::protocol::{BitStatus, ConfigKey, Frame, Message, NakReason};
+use crate::self_test::{SelfTest, SelfTestReport};
+use crate::split_flap_bit_state::{SplitFlapBitState, CHARACTER_SET};
+use crate::steps::FlapIndex;
+use crate::trace::{TraceBuffer, TraceSample, TRACE_CAPACITY};
+use crate::transition::{Transition, TransitionConfig};
+
+/// What the bits show once they have settled on the previous targets.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Content<const BITS: usize> {
+    /// Left to the board, e.g. cycling through built in messages.
+    Demo,
+    Text([u8; BITS]),
+    Timed(DisplayMode),
+    /// Cycles through the device's playlist.
+    Playlist,
+    /// Scrolls text too long for the bits across them.
+    Marquee,
+}
+
+impl<const BITS: usize> Content<BITS> {
+    /// The next targets for the bits, or `None` for `Demo`, `Playlist` and `Marquee`, which need
+    /// more state.
+    /// Timed content is blank until the time is known.
+    pub fn targets(&self, now: Option<&DateTime>) -> Option<[u8; BITS]> {
+        let mut targets = [b' '; BITS];
+
+        match self {
+            Content::Demo | Content::Playlist | Content::Marquee => return None,
+            Content::Text(text) => targets = *text,
+            Content::Timed(mode) => {
+                if let Some(now) = now {
+                    mode.render(now, &mut targets);
+                }
+            }
+        }
+
+        Some(targets)
+    }
+}
+
+/// Resolves glyph names and maps the text onto the flaps available, padding `targets` with
+/// blanks.  Unknown glyph names are shown as typed.  Returns how many flaps the text took up.
+pub fn text_targets(text: &[u8], targets: &mut [u8]) -> usize {
+    let mut expanded = [0u8; 64];
+
+    targets.fill(b' ');
+
+    let Ok(text) = core::str::from_utf8(text) else {
+        return 0;
+    };
+
+    let text = match expand_glyphs(text, &DEFAULT_GLYPHS, &mut expanded) {
+        Ok(len) => core::str::from_utf8(&expanded[..len]).unwrap_or(text),
+        Err(_) => text,
+    };
+
+    normalize(text, &NormalizeOptions::default(), targets).len
+}
+
+pub const MAX_MARQUEE_LEN: usize = 64;
+
+/// The flaps of the text scrolled by `Content::Marquee`, cut short at `MAX_MARQUEE_LEN`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MarqueeText {
+    flaps: [u8; MAX_MARQUEE_LEN],
+    len: usize,
+}
+
+impl MarqueeText {
+    pub fn new(text: &[u8]) -> MarqueeText {
+        let mut flaps = [b' '; MAX_MARQUEE_LEN];
+        let len = text_targets(text, &mut flaps);
+
+        MarqueeText { flaps, len }
+    }
+}
+
+impl AsRef<[u8]> for MarqueeText {
+    fn as_ref(&self) -> &[u8] {
+        &self.flaps[..self.len]
+    }
+}
+
+pub const MAX_PLAYLIST_ENTRIES: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PlaylistEntry<const BITS: usize> {
+    pub targets: [u8; BITS],
+    pub dwell_seconds: u16,
+}
+
+/// Messages shown in turn, each for at least its dwell time.
+pub struct Playlist<const BITS: usize> {
+    entries: [PlaylistEntry<BITS>; MAX_PLAYLIST_ENTRIES],
+    len: usize,
+    current: usize,
+    /// When the current entry was shown, once playback has started.
+    shown_at: Option<i64>,
+}
+
+impl<const BITS: usize> Default for Playlist<BITS> {
+    fn default() -> Self {
+        Playlist {
+            entries: [PlaylistEntry {
+                targets: [b' '; BITS],
+                dwell_seconds: 0,
+            }; MAX_PLAYLIST_ENTRIES],
+            len: 0,
+            current: 0,
+            shown_at: None,
+        }
+    }
+}
+
+impl<const BITS: usize> Playlist<BITS> {
+    pub fn entries(&self) -> &[PlaylistEntry<BITS>] {
+        &self.entries[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.restart();
+    }
+
+    /// Returns the entry back if the playlist is full.
+    pub fn push(&mut self, entry: PlaylistEntry<BITS>) -> Result<(), PlaylistEntry<BITS>> {
+        if self.len == MAX_PLAYLIST_ENTRIES {
+            return Err(entry);
+        }
+
+        self.entries[self.len] = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn restart(&mut self) {
+        self.current = 0;
+        self.shown_at = None;
+    }
+
+    /// The entry to show, moving on once the current one has been up long enough.  Without the
+    /// time every call moves on.
+    pub fn next(&mut self, now_seconds: Option<i64>) -> Option<[u8; BITS]> {
+        if self.len == 0 {
+            return None;
+        }
+
+        if let Some(shown_at) = self.shown_at {
+            let dwell = self.entries[self.current].dwell_seconds as i64;
+
+            if now_seconds.is_none_or(|now| now - shown_at >= dwell) {
+                self.current = (self.current + 1) % self.len;
+                self.shown_at = Some(now_seconds.unwrap_or(0));
+            }
+        } else {
+            self.shown_at = Some(now_seconds.unwrap_or(0));
+        }
+
+        Some(self.entries[self.current].targets)
+    }
+}
+
+pub struct Device<const BITS: usize> {
+    bits: [SplitFlapBitState; BITS],
+    sweeps: [Option<CalibrationSweep>; BITS],
+    self_tests: [Option<SelfTest>; BITS],
+    self_test_reports: [Option<SelfTestReport>; BITS],
+    content: Content<BITS>,
+    playlist: Playlist<BITS>,
+    marquee: Marquee<MarqueeText>,
+    save_requested: bool,
+    /// The bit whose readings are being recorded into `trace`.
+    traced_bit: Option<usize>,
+    trace: TraceBuffer<TRACE_CAPACITY>,
+    transition_config: TransitionConfig,
+    /// Moves on for each transition, so random effects differ from one message to the next.
+    transition_seed: u32,
+    transition: Option<Transition<BITS>>,
+    /// The targets last passed to `show`.
+    shown: Option<[u8; BITS]>,
+    /// Fingerprint of the last request handled and the reply sent to it, answered again if the
+    /// host resends the request after losing the reply.
+    last_reply: Option<(u8, u16, Message<'static>)>,
+}
+
+impl<const BITS: usize> Device<BITS> {
+    pub fn new(bits: [SplitFlapBitState; BITS]) -> Device<BITS> {
+        Device {
+            bits,
+            sweeps: [None; BITS],
+            self_tests: [None; BITS],
+            self_test_reports: [None; BITS],
+            content: Content::Demo,
+            playlist: Playlist::default(),
+            marquee: Marquee::new(MarqueeText::new(b""), BITS, ScrollConfig::default()),
+            save_requested: false,
+            traced_bit: None,
+            trace: TraceBuffer::new(),
+            transition_config: TransitionConfig::default(),
+            transition_seed: 0,
+            transition: None,
+            shown: None,
+            last_reply: None,
+        }
+    }
+
+    pub fn bits(&self) -> &[SplitFlapBitState; BITS] {
+        &self.bits
+    }
+
+    pub fn bits_mut(&mut self) -> &mut [SplitFlapBitState; BITS] {
+        &mut self.bits
+    }
+
+    pub fn content(&self) -> &Content<BITS> {
+        &self.content
+    }
+
+    pub fn set_content(&mut self, content: Content<BITS>) {
+        self.content = content;
+    }
+
+    pub fn playlist(&self) -> &Playlist<BITS> {
+        &self.playlist
+    }
+
+    pub fn marquee(&self) -> &Marquee<MarqueeText> {
+        &self.marquee
+    }
+
+    /// What to show now that the bits have settled, or `None` for `Demo`.  Scrolling text moves
+    /// on by `now_ms`, a millisecond count that may wrap.
+    pub fn next_targets(&mut self, now: Option<&DateTime>, now_ms: u32) -> Option<[u8; BITS]> {
+        match self.content {
+            Content::Playlist => {
+                let targets = self.playlist.next(now.map(|now| now.seconds_since_epoch()));
+                Some(targets.unwrap_or([b' '; BITS]))
+            }
+            Content::Marquee => {
+                self.marquee.advance(now_ms);
+
+                let mut targets = [b' '; BITS];
+                for (target, character) in targets.iter_mut().zip(self.marquee.window()) {
+                    *target = character;
+                }
+
+                Some(targets)
+            }
+            content => content.targets(now),
+        }
+    }
+
+    pub fn transition_config(&self) -> &TransitionConfig {
+        &self.transition_config
+    }
+
+    /// Sets the effects used by `show`, restarting the sequence of seeds from the config's.
+    pub fn set_transition_config(&mut self, config: TransitionConfig) {
+        self.transition_config = config;
+        self.transition_seed = config.seed;
+    }
+
+    /// Starts moving the bits to the `targets` characters, in place of any transition still
+    /// running.  The configured effects are only used when the targets have changed, so showing
+    /// the same message again just keeps the bits on it.
+    pub fn show(&mut self, targets: [u8; BITS]) {
+        let config = if self.shown == Some(targets) {
+            TransitionConfig::default()
+        } else {
+            self.transition_seed = self.transition_seed.wrapping_add(1);
+            self.transition_config
+        };
+
+        self.transition = Some(Transition::new(
+            &config,
+            self.transition_seed,
+            &self.bits,
+            &targets,
+        ));
+        self.shown = Some(targets);
+    }
+
+    /// Shows `targets` straight away and keeps them up, for controllers that decide themselves
+    /// when the display changes.
+    pub fn show_text(&mut self, targets: [u8; BITS]) {
+        self.content = Content::Text(targets);
+        self.show(targets);
+    }
+
+    /// Whether `bit` has further to go in the transition.
+    pub fn in_transition(&self, bit: usize) -> bool {
+        self.transition
+            .as_ref()
+            .is_some_and(|transition| transition.moves(bit, &self.bits[bit]))
+    }
+
+    /// Moves the transition on, returning whether it is still running.  Call this before each
+    /// `process`.
+    pub fn update_transition(&mut self, now_ms: u32) -> bool {
+        let running = self
+            .transition
+            .as_mut()
+            .is_some_and(|transition| transition.update(now_ms, &mut self.bits));
+
+        if !running {
+            self.transition = None;
+        }
+
+        running
+    }
+
+    /// Whether the host has asked for the settings to be saved since the last call.
+    pub fn take_save_request(&mut self) -> bool {
+        core::mem::take(&mut self.save_requested)
+    }
+
+    pub fn is_calibrating(&self, bit: usize) -> bool {
+        self.sweeps[bit].is_some()
+    }
+
+    /// Spins every bit through a self-test, after which each homes again.  Cancels any
+    /// calibration.
+    pub fn start_self_test(&mut self) {
+        for (idx, bit) in self.bits.iter().enumerate() {
+            self.sweeps[idx] = None;
+            self.self_tests[idx] = Some(SelfTest::new(bit.sensor_calibration(), bit.revolution()));
+            self.self_test_reports[idx] = None;
+        }
+    }
+
+    pub fn is_self_testing(&self, bit: usize) -> bool {
+        self.self_tests[bit].is_some()
+    }
+
+    /// The outcome of the last self-test of `bit`, `None` while it runs or if it never has.
+    pub fn self_test_report(&self, bit: usize) -> Option<SelfTestReport> {
+        self.self_test_reports[bit]
+    }
+
+    /// Takes a sensor reading for every bit, returning which bits need to step.
+    pub fn process(&mut self, sensor_values: [u32; BITS]) -> [bool; BITS] {
+        let mut steps = [false; BITS];
+
+        for (idx, &sensor_value) in sensor_values.iter().enumerate() {
+            let bit = &mut self.bits[idx];
+
+            if let Some(sweep) = &mut self.sweeps[idx] {
+                let Some(result) = sweep.sample(sensor_value) else {
+                    steps[idx] = true;
+                    continue;
+                };
+
+                // A failed sweep keeps the old values
+                if let Ok(calibration) = result {
+                    bit.set_sensor_calibration(calibration);
+                }
+
+                self.sweeps[idx] = None;
+                bit.rehome();
+            }
+
+            if let Some(test) = &mut self.self_tests[idx] {
+                let Some(report) = test.sample(sensor_value) else {
+                    steps[idx] = true;
+                    continue;
+                };
+
+                self.self_test_reports[idx] = Some(report);
+                self.self_tests[idx] = None;
+                bit.rehome();
+            }
+
+            let target_flap = bit.target_flap();
+            steps[idx] = bit.process(sensor_value);
+
+            if self.traced_bit == Some(idx) {
+                let sample = TraceSample::of(bit, sensor_value, target_flap, steps[idx]);
+                self.trace.push(sample);
+            }
+        }
+
+        steps
+    }
+
+    /// Carries out a request from the host, returning the reply to send back.  A request sent
+    /// again with the same sequence number is only answered, not carried out twice.
+    pub fn handle(&mut self, frame: &Frame) -> Message<'static> {
+        let fingerprint = frame.fingerprint();
+
+        if let Some((seq, last, reply)) = self.last_reply {
+            if seq == frame.seq && last == fingerprint {
+                return reply;
+            }
+        }
+
+        let reply = self.carry_out(frame);
+        self.last_reply = Some((frame.seq, fingerprint, reply));
+
+        reply
+    }
+
+    fn carry_out(&mut self, frame: &Frame) -> Message<'static> {
+        let seq = frame.seq;
+        let nak = |reason| Message::Nak { seq, reason };
+
+        match frame.message {
+            Message::SetText(text) => {
+                let mut targets = [b' '; BITS];
+                text_targets(text.as_bytes(), &mut targets);
+
+                self.content = Content::Text(targets);
+                Message::Ack { seq }
+            }
+            Message::SetScrollingText { text, config } => {
+                let text = MarqueeText::new(text.as_bytes());
+
+                self.marquee = Marquee::new(text, BITS, config);
+                self.content = Content::Marquee;
+                Message::Ack { seq }
+            }
+            Message::SetRawPositions(positions) => {
+                if positions.len() != BITS
+                    || positions.iter().any(|&p| p as usize >= CHARACTER_SET.len())
+                {
+                    return nak(NakReason::InvalidValue);
+                }
+
+                let mut targets = [b' '; BITS];
+                for ((target, &position), bit) in targets.iter_mut().zip(positions).zip(&self.bits)
+                {
+                    *target = bit.character_set()[position as usize];
+                }
+
+                self.content = Content::Text(targets);
+                Message::Ack { seq }
+            }
+            Message::QueryStatus { bit } => match self.bits.get(bit as usize) {
+                Some(state) => Message::Status(BitStatus {
+                    bit,
+                    state: state.bit_state(),
+                    flap: state.current_flap().get() as u8,
+                    target_flap: state.target_flap().get() as u8,
+                    calibrating: self.is_calibrating(bit as usize),
+                }),
+                None => nak(NakReason::UnknownBit),
+            },
+            Message::GetConfig(key) => {
+                let Some(state) = self.bits.get(key.bit() as usize) else {
+                    return nak(NakReason::UnknownBit);
+                };
+
+                let value = match key {
+                    ConfigKey::StepsPerFlap { .. } => state.steps_per_flap().get(),
+                    ConfigKey::StepsPerRevolution { .. } => state.revolution().steps().get(),
+                    ConfigKey::HomeOffset { .. } => state.offset_steps_to_first_position().get(),
+                    ConfigKey::TriggerValue { .. } => state.sensor_calibration().trigger_value,
+                    ConfigKey::UntriggerValue { .. } => state.sensor_calibration().untrigger_value,
+                    ConfigKey::FlapTrim { flap, .. } => match FlapIndex::new(flap as usize) {
+                        Some(flap) => state.flap_trim(flap) as i32 as u32,
+                        None => return nak(NakReason::InvalidValue),
+                    },
+                    ConfigKey::RestorePosition { .. } => state.restores_position() as u32,
+                    ConfigKey::IdlePolicy { .. } => state.idle_policy().to_config(),
+                };
+
+                Message::Config(key, value)
+            }
+            Message::SetConfig(key, value) => {
+                let Some(state) = self.bits.get_mut(key.bit() as usize) else {
+                    return nak(NakReason::UnknownBit);
+                };
+
+                let mut calibration = state.sensor_calibration();
+
+                match key {
+                    ConfigKey::TriggerValue { .. } => calibration.trigger_value = value,
+                    ConfigKey::UntriggerValue { .. } => calibration.untrigger_value = value,
+                    ConfigKey::FlapTrim { flap, .. } => {
+                        let Some(flap) = FlapIndex::new(flap as usize) else {
+                            return nak(NakReason::InvalidValue);
+                        };
+                        let Ok(trim) = i16::try_from(value as i32) else {
+                            return nak(NakReason::InvalidValue);
+                        };
+                        if trim.unsigned_abs() as u32 >= state.revolution().trim_limit().get() {
+                            return nak(NakReason::InvalidValue);
+                        }
+
+                        state.set_flap_trim(flap, trim);
+                        return Message::Ack { seq };
+                    }
+                    ConfigKey::RestorePosition { .. } => {
+                        if value > 1 {
+                            return nak(NakReason::InvalidValue);
+                        }
+
+                        state.set_restores_position(value == 1);
+                        return Message::Ack { seq };
+                    }
+                    ConfigKey::IdlePolicy { .. } => {
+                        let Some(policy) = IdlePolicy::from_config(value) else {
+                            return nak(NakReason::InvalidValue);
+                        };
+
+                        state.set_idle_policy(policy);
+                        return Message::Ack { seq };
+                    }
+                    // Step counts come from the stepper, set in the saved config
+                    ConfigKey::StepsPerFlap { .. }
+                    | ConfigKey::HomeOffset { .. }
+                    | ConfigKey::StepsPerRevolution { .. } => return nak(NakReason::Unsupported),
+                }
+
+                state.set_sensor_calibration(calibration);
+                Message::Ack { seq }
+            }
+            Message::Home => {
+                for bit in self.bits.iter_mut() {
+                    bit.rehome();
+                }
+
+                Message::Ack { seq }
+            }
+            Message::Calibrate { bit } => {
+                let Some(state) = self.bits.get(bit as usize) else {
+                    return nak(NakReason::UnknownBit);
+                };
+
+                self.sweeps[bit as usize] = Some(CalibrationSweep::new(state.revolution().steps()));
+                self.self_tests[bit as usize] = None;
+
+                Message::Ack { seq }
+            }
+            Message::ClearPlaylist => {
+                self.playlist.clear();
+                Message::Ack { seq }
+            }
+            Message::AddPlaylistEntry {
+                text,
+                dwell_seconds,
+            } => {
+                let mut targets = [b' '; BITS];
+                text_targets(text.as_bytes(), &mut targets);
+
+                match self.playlist.push(PlaylistEntry {
+                    targets,
+                    dwell_seconds,
+                }) {
+                    Ok(()) => Message::Ack { seq },
+                    Err(_) => nak(NakReason::InvalidValue),
+                }
+            }
+            Message::StartPlaylist => {
+                if self.playlist.entries().is_empty() {
+                    return nak(NakReason::InvalidValue);
+                }
+
+                self.playlist.restart();
+                self.content = Content::Playlist;
+                Message::Ack { seq }
+            }
+            Message::SaveConfig => {
+                self.save_requested = true;
+                Message::Ack { seq }
+            }
+            Message::QueryCounters { bit } => match self.bits.get(bit as usize) {
+                Some(state) => Message::Counters {
+                    bit,
+                    counters: state.counters(),
+                },
+                None => nak(NakReason::UnknownBit),
+            },
+            Message::ResetCounters { bit } => match self.bits.get_mut(bit as usize) {
+                Some(state) => {
+                    state.reset_counters();
+                    Message::Ack { seq }
+                }
+                None => nak(NakReason::UnknownBit),
+            },
+            Message::StartTrace { bit } => match self.bits.get_mut(bit as usize) {
+                Some(state) => {
+                    // Starting from home lets a replay begin from a freshly made bit
+                    state.rehome();
+                    self.trace.clear();
+                    self.traced_bit = Some(bit as usize);
+                    Message::Ack { seq }
+                }
+                None => nak(NakReason::UnknownBit),
+            },
+            Message::StopTrace => {
+                self.traced_bit = None;
+                Message::Ack { seq }
+            }
+            Message::ReadTrace => Message::Trace(self.trace.take_chunk()),
+            Message::RunSelfTest => {
+                self.start_self_test();
+                Message::Ack { seq }
+            }
+            Message::QuerySelfTest { bit } => match self.bits.get(bit as usize) {
+                Some(_) => Message::SelfTestResult {
+                    bit,
+                    report: self.self_test_report(bit as usize),
+                },
+                None => nak(NakReason::UnknownBit),
+            },
+            Message::SetTransition(config) => {
+                if !config.is_valid() {
+                    return nak(NakReason::InvalidValue);
+                }
+
+                self.set_transition_config(config);
+                Message::Ack { seq }
+            }
+            // Only ever sent by the device
+            Message::Ack { .. }
+            | Message::Nak { .. }
+            | Message::Status(_)
+            | Message::Config(..)
+            | Message::Counters { .. }
+            | Message::Trace(_)
+            | Message::SelfTestResult { .. } => nak(NakReason::Unsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{text_targets, Content, Device};
+    use crate::clock::DateTime;
+    use crate::idle::IdlePolicy;
+    use crate::marquee::{ScrollConfig, ScrollWrap};
+    use crate::protocol::{BitStatus, ConfigKey, Frame, Message, NakReason};
+    use crate::split_flap_bit_state::{
+        BitState, SensorCalibration, SensorState, SplitFlapBitState,
+    };
+    use crate::steps::{HomedSteps, Revolution, Steps};
+    use crate::transition::{Effect, TransitionConfig};
+
+    const STEPS_PER_FLAP: u32 = 2;
+    const REVOLUTION: u32 = STEPS_PER_FLAP * 55;
+
+    fn new_device() -> Device<4> {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+
+        let revolution = Revolution::new(Steps::new(STEPS_PER_FLAP)).unwrap();
+
+        Device::new(core::array::from_fn(|_| {
+            SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+        }))
+    }
+
+    fn handle(device: &mut Device<4>, message: Message) -> Message<'static> {
+        device.handle(&Frame { seq: 9, message })
+    }
+
+    #[test]
+    fn text_is_normalized_into_content() {
+        let mut device = new_device();
+
+        assert_eq!(
+            handle(&mut device, Message::SetText("hi {HEART}")),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(device.content(), &Content::Text(*b"HI \x01"));
+
+        let mut targets = [0u8; 3];
+        text_targets(b"\xFF", &mut targets);
+        assert_eq!(&targets, b"   ");
+    }
+
+    #[test]
+    fn scrolling_text_moves_on_once_settled() {
+        let mut device = new_device();
+
+        let config = ScrollConfig {
+            characters_per_frame: 2,
+            frame_ms: 100,
+            start_pause_ms: 500,
+            end_pause_ms: 1_000,
+            wrap: ScrollWrap::Stop,
+        };
+
+        assert_eq!(
+            handle(
+                &mut device,
+                Message::SetScrollingText {
+                    text: "gate {HEART}2",
+                    config
+                }
+            ),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(device.content(), &Content::Marquee);
+
+        assert_eq!(device.next_targets(None, 0), Some(*b"GATE"));
+        assert_eq!(device.next_targets(None, 499), Some(*b"GATE"));
+        assert_eq!(device.next_targets(None, 500), Some(*b"TE \x01"));
+        assert_eq!(device.next_targets(None, 550), Some(*b"TE \x01"));
+        assert_eq!(device.next_targets(None, 650), Some(*b"E \x012"));
+    }
+
+    #[test]
+    fn raw_positions_must_cover_every_bit() {
+        let mut device = new_device();
+
+        assert_eq!(
+            handle(&mut device, Message::SetRawPositions(&[1, 2, 3])),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetRawPositions(&[1, 2, 3, 4])),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(device.content().targets(None), Some(*b"ABCD"));
+    }
+
+    #[test]
+    fn config_reads_back_and_rejects_step_counts() {
+        let mut device = new_device();
+        let key = ConfigKey::TriggerValue { bit: 3 };
+
+        handle(&mut device, Message::SetConfig(key, 2500));
+
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, 2500)
+        );
+        let key = ConfigKey::StepsPerRevolution { bit: 2 };
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, REVOLUTION)
+        );
+        assert_eq!(
+            handle(
+                &mut device,
+                Message::SetConfig(ConfigKey::StepsPerFlap { bit: 0 }, 10)
+            ),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::Unsupported
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::QueryStatus { bit: 4 }),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::UnknownBit
+            }
+        );
+    }
+
+    #[test]
+    fn home_returns_bits_to_uninitialized() {
+        let mut device = new_device();
+
+        device.process([2100; 4]);
+        assert!(device.bits().iter().all(|bit| bit.is_settled()));
+
+        handle(&mut device, Message::Home);
+
+        assert!(device
+            .bits()
+            .iter()
+            .all(|bit| bit.bit_state() == BitState::UNINITIALIZED));
+    }
+
+    #[test]
+    fn calibration_sweeps_a_revolution_then_rehomes() {
+        let mut device = new_device();
+
+        handle(&mut device, Message::Calibrate { bit: 1 });
+
+        // Home sensor bright on the first step of every revolution
+        for step in 0..REVOLUTION {
+            let value = if step == 0 { 3500 } else { 300 };
+            assert!(device.process([0, value, 0, 0])[1]);
+        }
+
+        assert!(!device.is_calibrating(1));
+        assert_eq!(
+            device.bits()[1].sensor_calibration(),
+            SensorCalibration {
+                trigger_value: 2700,
+                untrigger_value: 1900
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::QueryStatus { bit: 1 }),
+            Message::Status(BitStatus {
+                bit: 1,
+                state: BitState::UNINITIALIZED,
+                flap: 0,
+                target_flap: 0,
+                calibrating: false
+            })
+        );
+    }
+
+    #[test]
+    fn flap_trims_are_limited_to_half_a_flap() {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let revolution = Revolution::new(Steps::new(8)).unwrap();
+        let mut device: Device<4> = Device::new(core::array::from_fn(|_| {
+            SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+        }));
+        let key = ConfigKey::FlapTrim { bit: 0, flap: 3 };
+
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, -3i32 as u32)),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 4)),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, -3i32 as u32)
+        );
+    }
+
+    #[test]
+    fn flaps_of_one_step_only_take_no_trim() {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let revolution = Revolution::new(Steps::new(1)).unwrap();
+        let mut device: Device<4> = Device::new(core::array::from_fn(|_| {
+            SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+        }));
+        let key = ConfigKey::FlapTrim { bit: 0, flap: 3 };
+
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 0)),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 1)),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+    }
+
+    #[test]
+    fn flap_trims_that_cannot_be_stored_are_refused() {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        // So many steps that a trim under half a flap can still be too big to store
+        let revolution = Revolution::new(Steps::new(140_000)).unwrap();
+        let mut device: Device<4> = Device::new(core::array::from_fn(|_| {
+            SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+        }));
+        let key = ConfigKey::FlapTrim { bit: 0, flap: 3 };
+
+        for value in [40_000, i32::MIN as u32, -32_769i32 as u32] {
+            assert_eq!(
+                handle(&mut device, Message::SetConfig(key, value)),
+                Message::Nak {
+                    seq: 9,
+                    reason: NakReason::InvalidValue
+                }
+            );
+        }
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, -32_768i32 as u32)),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, -32_768i32 as u32)
+        );
+    }
+
+    #[test]
+    fn restore_position_is_on_or_off() {
+        let mut device = new_device();
+        let key = ConfigKey::RestorePosition { bit: 2 };
+
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 1)),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 2)),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, 1)
+        );
+        assert!(device.bits()[2].restores_position());
+    }
+
+    #[test]
+    fn idle_policy_is_packed_into_config_values() {
+        let mut device = new_device();
+        let key = ConfigKey::IdlePolicy { bit: 1 };
+        let policy = IdlePolicy::HoldFor { ms: 500 };
+
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, policy.to_config())),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetConfig(key, 9 << 24)),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::GetConfig(key)),
+            Message::Config(key, policy.to_config())
+        );
+        assert_eq!(device.bits()[1].idle_policy(), policy);
+    }
+
+    #[test]
+    fn counters_can_be_read_and_reset() {
+        let mut device = new_device();
+        device.process([100; 4]);
+
+        let Message::Counters { bit: 1, counters } =
+            handle(&mut device, Message::QueryCounters { bit: 1 })
+        else {
+            panic!("Counters not returned");
+        };
+        assert_eq!(counters.steps, 1);
+
+        assert_eq!(
+            handle(&mut device, Message::ResetCounters { bit: 1 }),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(device.bits()[1].counters().steps, 0);
+        assert_eq!(
+            handle(&mut device, Message::QueryCounters { bit: 4 }),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::UnknownBit
+            }
+        );
+    }
+
+    #[test]
+    fn trace_records_only_the_traced_bit() {
+        let mut device = new_device();
+
+        assert_eq!(
+            handle(&mut device, Message::StartTrace { bit: 2 }),
+            Message::Ack { seq: 9 }
+        );
+        device.process([100, 100, 3000, 100]);
+        device.process([100; 4]);
+        assert_eq!(
+            handle(&mut device, Message::StopTrace),
+            Message::Ack { seq: 9 }
+        );
+        device.process([100; 4]);
+
+        let Message::Trace(chunk) = handle(&mut device, Message::ReadTrace) else {
+            panic!("Trace not returned");
+        };
+        assert_eq!(chunk.samples().len(), 2);
+        assert_eq!(chunk.samples()[0].reading, 3000);
+        assert_eq!(chunk.samples()[0].sensor, SensorState::Triggered);
+
+        let next = Frame {
+            seq: 10,
+            message: Message::ReadTrace,
+        };
+        let Message::Trace(chunk) = device.handle(&next) else {
+            panic!("Trace not returned");
+        };
+        assert!(chunk.samples().is_empty());
+        assert_eq!(
+            handle(&mut device, Message::StartTrace { bit: 4 }),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::UnknownBit
+            }
+        );
+    }
+
+    #[test]
+    fn resent_request_gets_the_same_reply() {
+        let mut device = new_device();
+
+        handle(&mut device, Message::StartTrace { bit: 0 });
+        device.process([3000, 100, 100, 100]);
+
+        let read = |seq| Frame {
+            seq,
+            message: Message::ReadTrace,
+        };
+        let Message::Trace(first) = device.handle(&read(1)) else {
+            panic!("Trace not returned");
+        };
+        assert_eq!(first.samples().len(), 1);
+
+        // The reply was lost, so the host sends the same frame again
+        assert_eq!(device.handle(&read(1)), Message::Trace(first));
+
+        let Message::Trace(next) = device.handle(&read(2)) else {
+            panic!("Trace not returned");
+        };
+        assert!(next.samples().is_empty());
+
+        // Only a matching message counts as resent
+        assert_eq!(
+            device.handle(&Frame {
+                seq: 2,
+                message: Message::StartTrace { bit: 4 },
+            }),
+            Message::Nak {
+                seq: 2,
+                reason: NakReason::UnknownBit
+            }
+        );
+    }
+
+    #[test]
+    fn self_test_reports_each_bit_then_homes() {
+        let mut device = new_device();
+
+        assert_eq!(
+            handle(&mut device, Message::RunSelfTest),
+            Message::Ack { seq: 9 }
+        );
+        assert_eq!(
+            handle(&mut device, Message::QuerySelfTest { bit: 0 }),
+            Message::SelfTestResult {
+                bit: 0,
+                report: None
+            }
+        );
+
+        // Bit 3 has no magnet
+        for step in 0..=2 * REVOLUTION {
+            let magnet = if step % REVOLUTION == 7 { 3000 } else { 100 };
+
+            assert!(device.is_self_testing(0));
+            assert_eq!(device.process([magnet, magnet, magnet, 100]), [true; 4]);
+        }
+
+        let next = Frame {
+            seq: 10,
+            message: Message::QuerySelfTest { bit: 0 },
+        };
+        let Message::SelfTestResult {
+            bit: 0,
+            report: Some(report),
+        } = device.handle(&next)
+        else {
+            panic!("Self-test not finished");
+        };
+        assert!(report.passed());
+        assert!(!device.self_test_report(3).unwrap().passed());
+        assert!(!device.is_self_testing(0));
+        assert_eq!(device.bits()[0].bit_state(), BitState::UNINITIALIZED);
+        assert_eq!(
+            handle(&mut device, Message::QuerySelfTest { bit: 4 }),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::UnknownBit
+            }
+        );
+    }
+
+    #[test]
+    fn playlist_cycles_after_dwell() {
+        let mut device = new_device();
+        let at = |second| DateTime {
+            year: 2025,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second,
+        };
+
+        assert_eq!(
+            handle(&mut device, Message::StartPlaylist),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+
+        for (text, dwell_seconds) in [("ONE", 10), ("TWO", 5)] {
+            handle(
+                &mut device,
+                Message::AddPlaylistEntry {
+                    text,
+                    dwell_seconds,
+                },
+            );
+        }
+        handle(&mut device, Message::StartPlaylist);
+
+        assert_eq!(device.next_targets(Some(&at(0)), 0), Some(*b"ONE "));
+        assert_eq!(device.next_targets(Some(&at(9)), 0), Some(*b"ONE "));
+        assert_eq!(device.next_targets(Some(&at(10)), 0), Some(*b"TWO "));
+        assert_eq!(device.next_targets(Some(&at(15)), 0), Some(*b"ONE "));
+        assert_eq!(device.next_targets(None, 0), Some(*b"TWO "));
+    }
+
+    #[test]
+    fn save_config_is_requested_once() {
+        let mut device = new_device();
+
+        assert!(!device.take_save_request());
+        assert_eq!(
+            handle(&mut device, Message::SaveConfig),
+            Message::Ack { seq: 9 }
+        );
+        assert!(device.take_save_request());
+        assert!(!device.take_save_request());
+    }
+
+    #[test]
+    fn messages_are_shown_with_the_transition() {
+        let mut device = new_device();
+        let still = TransitionConfig::new(
+            &[Effect::Cascade {
+                every: 0,
+                dwell_ms: 0,
+            }],
+            0,
+        );
+        let wave = TransitionConfig::new(&[Effect::Wave { delay_ms: 10 }, Effect::Spin], 0);
+
+        assert_eq!(
+            handle(&mut device, Message::SetTransition(still.unwrap())),
+            Message::Nak {
+                seq: 9,
+                reason: NakReason::InvalidValue
+            }
+        );
+        assert_eq!(
+            handle(&mut device, Message::SetTransition(wave.unwrap())),
+            Message::Ack { seq: 9 }
+        );
+
+        device.process([3000; 4]);
+        device.show(*b"BBBB");
+        assert!(device.in_transition(3));
+
+        let mut first_steps = [None; 4];
+        let mut positions = [0u32; 4];
+        let mut now_ms = 0;
+
+        // The spin passes the magnet, which has to be seen to keep count
+        while device.update_transition(now_ms) {
+            let readings = positions.map(|position| match position % REVOLUTION {
+                0 => 3000,
+                _ => 100,
+            });
+            let stepped = device.process(readings);
+
+            for ((first, position), stepped) in
+                first_steps.iter_mut().zip(&mut positions).zip(stepped)
+            {
+                if stepped && first.is_none() {
+                    *first = Some(now_ms);
+                }
+                *position += stepped as u32;
+            }
+
+            now_ms += 1;
+            assert!(now_ms < 1000);
+        }
+
+        assert_eq!(first_steps, [Some(0), Some(10), Some(20), Some(30)]);
+        assert!(device.bits().iter().all(|bit| bit.target_flap().get() == 2));
+        assert!(!device.update_transition(now_ms));
+        assert!(!device.in_transition(3));
+
+        // Showing the same message again doesn't spin the bits
+        device.show(*b"BBBB");
+        assert!(!device.in_transition(3));
+    }
+}
@@ -0,0 +1,508 @@
+//! Settings that differ between modules, kept in a reserved flash sector so that one firmware
+//! build suits every module.
+//!
+//! The sector starts with a header, followed by the settings.  Everything is little endian.
+//!
+//! | Offset | Size              | Contents                                         |
+//! |--------|-------------------|--------------------------------------------------|
+//! | 0      | 4                 | `CONFIG_MAGIC`                                   |
+//! | 4      | 2                 | `CONFIG_VERSION`                                 |
+//! | 6      | 2                 | Length of the settings after the header          |
+//! | 8      | 2                 | CRC-16 of the settings                           |
+//! | 10     | 2                 | Reserved                                         |
+//! | 12     | 1                 | Number of bits                                   |
+//! | 13     | 1                 | Step pin                                         |
+//! | 14     | 2                 | Reserved                                         |
+//! | 16     | `BIT_RECORD_LEN`  | Each bit in turn, see `BitConfig`                |
+
+use crate::idle::IdlePolicy;
+use crate::protocol::crc16;
+use crate::split_flap_bit_state::{SensorCalibration, SplitFlapBitState, CHARACTER_SET};
+use crate::steps::{Angle, FlapIndex, GearRatio, Offset, Revolution, StepperConfig};
+
+pub const CONFIG_MAGIC: [u8; 4] = *b"SFDC";
+pub const CONFIG_VERSION: u16 = 1;
+
+/// Flap trims are kept in fractions of a flap this small, so they hold when the steps change.
+pub const TRIM_UNITS_PER_FLAP: i32 = 10_000;
+
+const HEADER_LEN: usize = 12;
+const GLOBAL_LEN: usize = 4;
+/// The motor's full steps per revolution, the home offset in millidegrees, trigger, untrigger,
+/// enable pin, sensor channel, whether to restore the position, a reserved byte, the character
+/// set, two bytes for each flap trim, the idle policy packed by `IdlePolicy::to_config`, then the
+/// microsteps and the gear ratio's motor and drum turns.
+pub const BIT_RECORD_LEN: usize = 4 * 4 + 4 + 3 * CHARACTER_SET.len() + 4 * 4;
+
+/// Bytes needed to store the config of a device with `bits` bits.
+pub const fn encoded_len(bits: usize) -> usize {
+    HEADER_LEN + GLOBAL_LEN + bits * BIT_RECORD_LEN
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigError {
+    /// The sector has been erased and never written.
+    Blank,
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadCrc,
+    /// Written for a device with a different number of bits.
+    BitCount {
+        expected: usize,
+        found: usize,
+    },
+    /// The settings for `bit` can't be used, such as a trim of more than half a flap.
+    InvalidBit(usize),
+    BufferTooSmall,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BitConfig {
+    pub sensor_calibration: SensorCalibration,
+    pub stepper: StepperConfig,
+    /// From home to the first flap, as an angle so that it holds whatever the microsteps.
+    pub home_offset: Angle,
+    /// GPIO driving the motor driver's enable input.
+    pub enable_pin: u8,
+    /// ADC channel the home sensor is wired to.
+    pub sensor_channel: u8,
+    /// Restore the position saved before a power cut rather than homing.
+    pub restore_position: bool,
+    pub idle_policy: IdlePolicy,
+    pub character_set: [u8; CHARACTER_SET.len()],
+    /// In `TRIM_UNITS_PER_FLAP`, worked out in steps once the bit is set up.
+    pub flap_trims: [i16; CHARACTER_SET.len()],
+}
+
+impl BitConfig {
+    /// A bit with the standard character set and no trims.  A `stepper` that can't make a
+    /// revolution leaves the offset at home, and the config is refused when loaded.
+    pub fn new(
+        sensor_calibration: SensorCalibration,
+        stepper: StepperConfig,
+        home_offset: Offset,
+        enable_pin: u8,
+        sensor_channel: u8,
+    ) -> BitConfig {
+        let home_offset = stepper.revolution().map_or(Angle::default(), |revolution| {
+            revolution.angle(home_offset.position(revolution))
+        });
+
+        BitConfig {
+            sensor_calibration,
+            stepper,
+            home_offset,
+            enable_pin,
+            sensor_channel,
+            restore_position: false,
+            idle_policy: IdlePolicy::Release,
+            character_set: CHARACTER_SET,
+            flap_trims: [0; CHARACTER_SET.len()],
+        }
+    }
+
+    /// `None` for a stepper that can't make a revolution.
+    pub fn revolution(&self) -> Option<Revolution> {
+        self.stepper.revolution()
+    }
+
+    /// A bit set up with these settings, yet to find home.  Panics if there is no `revolution`,
+    /// which `DeviceConfig::decode` never lets through.
+    pub fn bit_state(&self) -> SplitFlapBitState {
+        let mut state = SplitFlapBitState::with_stepper(
+            self.sensor_calibration,
+            self.stepper,
+            Offset::Angle(self.home_offset),
+        )
+        .expect("the stepper makes a revolution");
+        let steps_per_flap = state.steps_per_flap().get() as i64;
+
+        state.set_character_set(self.character_set);
+        state.set_restores_position(self.restore_position);
+        state.set_idle_policy(self.idle_policy);
+        for (flap, &trim) in FlapIndex::all().zip(&self.flap_trims) {
+            let steps = rescale(trim as i64, steps_per_flap, TRIM_UNITS_PER_FLAP as i64);
+            state.set_flap_trim(flap, steps.clamp(i16::MIN as i64, i16::MAX as i64) as i16);
+        }
+
+        state
+    }
+
+    /// Takes on the settings that can be changed while running.
+    pub fn capture(&mut self, state: &SplitFlapBitState) {
+        self.sensor_calibration = state.sensor_calibration();
+        self.character_set = *state.character_set();
+        self.restore_position = state.restores_position();
+        self.idle_policy = state.idle_policy();
+
+        let steps_per_flap = state.steps_per_flap().get() as i64;
+
+        for (flap, trim) in FlapIndex::all().zip(&mut self.flap_trims) {
+            *trim = trim_units(state.flap_trim(flap) as i64, steps_per_flap);
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let Some(revolution) = self.revolution() else {
+            return false;
+        };
+        let steps_per_flap = revolution.steps_per_flap().get() as i64;
+        let trim_limit = revolution.trim_limit().get() as i64;
+
+        self.flap_trims.iter().all(|&trim| {
+            rescale(trim as i64, steps_per_flap, TRIM_UNITS_PER_FLAP as i64).abs() < trim_limit
+        })
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        let mut writer = Writer { out, pos: 0 };
+
+        writer.put(&self.stepper.full_steps_per_revolution.to_le_bytes());
+        writer.put(&self.home_offset.millidegrees().to_le_bytes());
+        writer.put(&self.sensor_calibration.trigger_value.to_le_bytes());
+        writer.put(&self.sensor_calibration.untrigger_value.to_le_bytes());
+        writer.put(&[
+            self.enable_pin,
+            self.sensor_channel,
+            self.restore_position as u8,
+            0,
+        ]);
+        writer.put(&self.character_set);
+        for trim in self.flap_trims {
+            writer.put(&trim.to_le_bytes());
+        }
+        writer.put(&self.idle_policy.to_config().to_le_bytes());
+        writer.put(&self.stepper.microsteps.to_le_bytes());
+        writer.put(&self.stepper.gear_ratio.motor_turns.to_le_bytes());
+        writer.put(&self.stepper.gear_ratio.drum_turns.to_le_bytes());
+    }
+
+    /// Reads a record written by `encode`, `None` if it holds an unknown idle policy.
+    fn decode(data: &[u8]) -> Option<BitConfig> {
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+        };
+        let sets = 20;
+        let trims = sets + CHARACTER_SET.len();
+        let rest = trims + 2 * CHARACTER_SET.len();
+
+        Some(BitConfig {
+            stepper: StepperConfig {
+                full_steps_per_revolution: u32_at(0),
+                microsteps: u32_at(rest + 4),
+                gear_ratio: GearRatio {
+                    motor_turns: u32_at(rest + 8),
+                    drum_turns: u32_at(rest + 12),
+                },
+            },
+            home_offset: Angle::from_millidegrees(u32_at(4)),
+            sensor_calibration: SensorCalibration {
+                trigger_value: u32_at(8),
+                untrigger_value: u32_at(12),
+            },
+            enable_pin: data[16],
+            sensor_channel: data[17],
+            restore_position: data[18] != 0,
+            idle_policy: IdlePolicy::from_config(u32_at(rest))?,
+            character_set: core::array::from_fn(|flap| data[sets + flap]),
+            flap_trims: core::array::from_fn(|flap| {
+                let pos = trims + 2 * flap;
+                i16::from_le_bytes([data[pos], data[pos + 1]])
+            }),
+        })
+    }
+}
+
+/// `steps` of a flap of `steps_per_flap`, in `TRIM_UNITS_PER_FLAP`.
+fn trim_units(steps: i64, steps_per_flap: i64) -> i16 {
+    let units = rescale(steps, TRIM_UNITS_PER_FLAP as i64, steps_per_flap.max(1));
+
+    units.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+
+/// `value * to / from`, rounded to the nearest.
+fn rescale(value: i64, to: i64, from: i64) -> i64 {
+    let scaled = value * to;
+
+    (scaled + scaled.signum() * from / 2) / from
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DeviceConfig<const BITS: usize> {
+    /// GPIO pulsed to step every motor at once.
+    pub step_pin: u8,
+    pub bits: [BitConfig; BITS],
+}
+
+impl<const BITS: usize> DeviceConfig<BITS> {
+    pub fn bit_states(&self) -> [SplitFlapBitState; BITS] {
+        core::array::from_fn(|idx| self.bits[idx].bit_state())
+    }
+
+    /// Takes on the runtime settings of `bits`, ready to be saved.
+    pub fn capture(&mut self, bits: &[SplitFlapBitState; BITS]) {
+        for (config, state) in self.bits.iter_mut().zip(bits.iter()) {
+            config.capture(state);
+        }
+    }
+
+    /// Writes the header and settings, returning the length used.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, ConfigError> {
+        let len = encoded_len(BITS);
+
+        if out.len() < len {
+            return Err(ConfigError::BufferTooSmall);
+        }
+
+        let (header, body) = out[..len].split_at_mut(HEADER_LEN);
+
+        body[..GLOBAL_LEN].copy_from_slice(&[BITS as u8, self.step_pin, 0, 0]);
+        for (bit, record) in self
+            .bits
+            .iter()
+            .zip(body[GLOBAL_LEN..].chunks_exact_mut(BIT_RECORD_LEN))
+        {
+            bit.encode(record);
+        }
+
+        let mut writer = Writer {
+            out: header,
+            pos: 0,
+        };
+        writer.put(&CONFIG_MAGIC);
+        writer.put(&CONFIG_VERSION.to_le_bytes());
+        writer.put(&(body.len() as u16).to_le_bytes());
+        writer.put(&crc16(body).to_le_bytes());
+        writer.put(&[0, 0]);
+
+        Ok(len)
+    }
+
+    /// Reads settings written by `encode`.  Anything after them is ignored, so the whole flash
+    /// sector can be passed in.
+    pub fn decode(data: &[u8]) -> Result<DeviceConfig<BITS>, ConfigError> {
+        if data.len() < HEADER_LEN {
+            return Err(ConfigError::Truncated);
+        }
+
+        if data[..HEADER_LEN].iter().all(|&byte| byte == 0xFF) {
+            return Err(ConfigError::Blank);
+        }
+
+        if data[..4] != CONFIG_MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+
+        let body_len = u16::from_le_bytes([data[6], data[7]]) as usize;
+        let crc = u16::from_le_bytes([data[8], data[9]]);
+        let body = data
+            .get(HEADER_LEN..HEADER_LEN + body_len)
+            .ok_or(ConfigError::Truncated)?;
+
+        if crc16(body) != crc {
+            return Err(ConfigError::BadCrc);
+        }
+
+        let found = *body.first().ok_or(ConfigError::Truncated)? as usize;
+        if found != BITS {
+            return Err(ConfigError::BitCount {
+                expected: BITS,
+                found,
+            });
+        }
+
+        if body_len != GLOBAL_LEN + BITS * BIT_RECORD_LEN {
+            return Err(ConfigError::Truncated);
+        }
+
+        let mut records = body[GLOBAL_LEN..].chunks_exact(BIT_RECORD_LEN);
+        let mut bits = [None; BITS];
+
+        for (idx, bit) in bits.iter_mut().enumerate() {
+            *bit = records
+                .next()
+                .and_then(BitConfig::decode)
+                .filter(BitConfig::is_valid);
+
+            if bit.is_none() {
+                return Err(ConfigError::InvalidBit(idx));
+            }
+        }
+
+        Ok(DeviceConfig {
+            step_pin: body[1],
+            bits: bits.map(Option::unwrap),
+        })
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.out[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encoded_len, BitConfig, ConfigError, DeviceConfig};
+    use crate::idle::IdlePolicy;
+    use crate::split_flap_bit_state::{BitState, SensorCalibration};
+    use crate::steps::{FlapIndex, GearRatio, HomedSteps, Offset, StepperConfig, Steps};
+
+    const STEPPER: StepperConfig = StepperConfig {
+        full_steps_per_revolution: 200,
+        microsteps: 16,
+        gear_ratio: GearRatio::DIRECT,
+    };
+
+    fn config() -> DeviceConfig<2> {
+        let calibration = SensorCalibration {
+            trigger_value: 2200,
+            untrigger_value: 2100,
+        };
+
+        DeviceConfig {
+            step_pin: 18,
+            bits: [
+                BitConfig::new(calibration, STEPPER, Offset::Flaps(5), 19, 0),
+                BitConfig::new(calibration, STEPPER, Offset::Flaps(5), 20, 1),
+            ],
+        }
+    }
+
+    #[test]
+    fn encode_then_decode_round_trips() {
+        let mut original = config();
+        original.bits[1].flap_trims[3] = -5;
+        original.bits[1].character_set.swap(1, 2);
+        original.bits[0].restore_position = true;
+        original.bits[1].idle_policy = IdlePolicy::ReducedHold { duty_percent: 25 };
+
+        let mut sector = [0xFF; 4096];
+        let len = original.encode(&mut sector).unwrap();
+
+        assert_eq!(len, encoded_len(2));
+        assert_eq!(DeviceConfig::<2>::decode(&sector), Ok(original));
+    }
+
+    #[test]
+    fn blank_and_corrupt_sectors_are_rejected() {
+        let mut sector = [0xFF; 4096];
+        assert_eq!(DeviceConfig::<2>::decode(&sector), Err(ConfigError::Blank));
+
+        config().encode(&mut sector).unwrap();
+        sector[40] ^= 1;
+        assert_eq!(DeviceConfig::<2>::decode(&sector), Err(ConfigError::BadCrc));
+
+        sector[0] = b'X';
+        assert_eq!(
+            DeviceConfig::<2>::decode(&sector),
+            Err(ConfigError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn other_versions_and_sizes_are_rejected() {
+        let mut sector = [0xFF; 4096];
+        config().encode(&mut sector).unwrap();
+
+        assert_eq!(
+            DeviceConfig::<3>::decode(&sector),
+            Err(ConfigError::BitCount {
+                expected: 3,
+                found: 2
+            })
+        );
+
+        sector[4] = 2;
+        assert_eq!(
+            DeviceConfig::<2>::decode(&sector),
+            Err(ConfigError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            DeviceConfig::<2>::decode(&sector[..8]),
+            Err(ConfigError::Truncated)
+        );
+    }
+
+    #[test]
+    fn unusable_settings_are_rejected() {
+        let mut invalid = config();
+        // Half a flap
+        invalid.bits[1].flap_trims[0] = 5_000;
+
+        let mut sector = [0xFF; 4096];
+        invalid.encode(&mut sector).unwrap();
+
+        assert_eq!(
+            DeviceConfig::<2>::decode(&sector),
+            Err(ConfigError::InvalidBit(1))
+        );
+
+        // Three drum turns for one of the motor don't come out in whole steps
+        let mut invalid = config();
+        invalid.bits[0].stepper.gear_ratio.drum_turns = 3;
+        invalid.encode(&mut sector).unwrap();
+
+        assert_eq!(
+            DeviceConfig::<2>::decode(&sector),
+            Err(ConfigError::InvalidBit(0))
+        );
+    }
+
+    #[test]
+    fn offsets_are_worked_out_from_the_stepper() {
+        let mut config = config();
+        config.bits[0].flap_trims[1] = -2_500;
+        let bits = config.bit_states();
+
+        assert_eq!(
+            bits[0].offset_steps_to_first_position(),
+            HomedSteps::new(290)
+        );
+        assert_eq!(bits[0].flap_trim(FlapIndex::wrapping(1)), -15);
+        assert_eq!(bits[0].revolution().steps(), Steps::new(3200));
+        assert_eq!(bits[1].stepper(), STEPPER);
+
+        // Half the microsteps, half the steps
+        config.bits[0].stepper.microsteps = 8;
+        let bits = config.bit_states();
+
+        assert_eq!(
+            bits[0].offset_steps_to_first_position(),
+            HomedSteps::new(145)
+        );
+        assert_eq!(bits[0].flap_trim(FlapIndex::wrapping(1)), -7);
+    }
+
+    #[test]
+    fn capture_keeps_runtime_changes() {
+        let mut config = config();
+        let mut bits = config.bit_states();
+
+        assert_eq!(bits[0].bit_state(), BitState::UNINITIALIZED);
+
+        bits[1].set_flap_trim(FlapIndex::wrapping(7), 3);
+        bits[1].set_sensor_calibration(SensorCalibration {
+            trigger_value: 2500,
+            untrigger_value: 1900,
+        });
+        config.capture(&bits);
+
+        assert_eq!(config.bits[1].flap_trims[7], 517);
+        assert_eq!(config.bits[1].sensor_calibration.trigger_value, 2500);
+        assert_eq!(config.bit_states()[1].flap_trim(FlapIndex::wrapping(7)), 3);
+    }
+}
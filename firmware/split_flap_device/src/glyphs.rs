@@ -0,0 +1,209 @@
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Glyph<'a> {
+    pub name: &'a str,
+    pub code: u8,
+}
+
+/// Names for the custom artwork flaps of a character set.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GlyphTable<'a> {
+    glyphs: &'a [Glyph<'a>],
+}
+
+/// Names for the 0x01..0x0B flaps of the standard drum.
+pub const DEFAULT_GLYPHS: GlyphTable<'static> = GlyphTable::new(&[
+    Glyph::new("HEART", 0x01),
+    Glyph::new("ARROW_UP", 0x02),
+    Glyph::new("ARROW_DOWN", 0x03),
+    Glyph::new("ARROW_LEFT", 0x04),
+    Glyph::new("ARROW_RIGHT", 0x05),
+    Glyph::new("TRAIN", 0x06),
+    Glyph::new("BUS", 0x07),
+    Glyph::new("PLANE", 0x08),
+    Glyph::new("STAR", 0x09),
+    Glyph::new("SUN", 0x0A),
+    Glyph::new("CLOUD", 0x0B),
+]);
+
+impl<'a> Glyph<'a> {
+    pub const fn new(name: &'a str, code: u8) -> Glyph<'a> {
+        Glyph { name, code }
+    }
+}
+
+impl<'a> GlyphTable<'a> {
+    pub const fn new(glyphs: &'a [Glyph<'a>]) -> GlyphTable<'a> {
+        GlyphTable { glyphs }
+    }
+
+    pub fn glyphs(&self) -> &'a [Glyph<'a>] {
+        self.glyphs
+    }
+
+    /// Names are matched without regard to case.
+    pub fn code(&self, name: &str) -> Option<u8> {
+        self.glyphs
+            .iter()
+            .find(|glyph| glyph.name.eq_ignore_ascii_case(name))
+            .map(|glyph| glyph.code)
+    }
+
+    pub fn name(&self, code: u8) -> Option<&'a str> {
+        self.glyphs
+            .iter()
+            .find(|glyph| glyph.code == code)
+            .map(|glyph| glyph.name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlyphError {
+    UnknownGlyph { offset: usize },
+    UnterminatedEscape { offset: usize },
+    BufferTooSmall,
+}
+
+/// Replaces `{NAME}` escapes in `text` with glyph codes, writing the result to `out`.
+///
+/// `{{` and `}}` produce literal braces.  Control characters such as tabs and line breaks would
+/// read as glyph codes, so they are written as spaces.  Everything else is copied unchanged, so
+/// the output is still valid UTF-8 and can be passed on to normalization.  Returns the number of
+/// bytes written.
+pub fn expand_glyphs(text: &str, table: &GlyphTable, out: &mut [u8]) -> Result<usize, GlyphError> {
+    let mut len = 0;
+    let mut rest = text;
+
+    while let Some(brace) = rest.find(['{', '}']) {
+        let offset = text.len() - rest.len() + brace;
+        let (literal, escape) = rest.split_at(brace);
+
+        len = push_text(out, len, literal)?;
+
+        if escape.starts_with("{{") || escape.starts_with("}}") {
+            len = push(out, len, &escape.as_bytes()[..1])?;
+            rest = &escape[2..];
+            continue;
+        }
+
+        if escape.starts_with('}') {
+            return Err(GlyphError::UnterminatedEscape { offset });
+        }
+
+        let Some(end) = escape.find('}') else {
+            return Err(GlyphError::UnterminatedEscape { offset });
+        };
+
+        let code = table
+            .code(&escape[1..end])
+            .ok_or(GlyphError::UnknownGlyph { offset })?;
+
+        len = push(out, len, &[code])?;
+        rest = &escape[end + 1..];
+    }
+
+    push_text(out, len, rest)
+}
+
+fn push_text(out: &mut [u8], len: usize, text: &str) -> Result<usize, GlyphError> {
+    let end = push(out, len, text.as_bytes())?;
+
+    for byte in &mut out[len..end] {
+        if byte.is_ascii_control() {
+            *byte = b' ';
+        }
+    }
+
+    Ok(end)
+}
+
+fn push(out: &mut [u8], len: usize, bytes: &[u8]) -> Result<usize, GlyphError> {
+    let end = len + bytes.len();
+
+    out.get_mut(len..end)
+        .ok_or(GlyphError::BufferTooSmall)?
+        .copy_from_slice(bytes);
+
+    Ok(end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_glyphs, Glyph, GlyphError, GlyphTable, DEFAULT_GLYPHS};
+
+    fn expanded<const N: usize>(
+        text: &str,
+        table: &GlyphTable,
+    ) -> Result<([u8; N], usize), GlyphError> {
+        let mut out = [0u8; N];
+        let len = expand_glyphs(text, table, &mut out)?;
+
+        Ok((out, len))
+    }
+
+    #[test]
+    fn default_table_names_every_custom_flap() {
+        for code in 0x01..=0x0B {
+            assert!(DEFAULT_GLYPHS.name(code).is_some(), "No name for {}", code);
+        }
+
+        assert_eq!(DEFAULT_GLYPHS.code("train"), Some(0x06));
+    }
+
+    #[test]
+    fn escapes_are_replaced_with_codes() {
+        let (out, len) = expanded::<16>("I {HEART} NY {arrow_up}", &DEFAULT_GLYPHS).unwrap();
+
+        assert_eq!(&out[..len], b"I \x01 NY \x02");
+    }
+
+    #[test]
+    fn control_characters_are_not_taken_for_glyphs() {
+        let (out, len) = expanded::<16>("A\tB\r\nC{STAR}", &DEFAULT_GLYPHS).unwrap();
+
+        assert_eq!(&out[..len], b"A B  C\x09");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let (out, len) = expanded::<8>("{{A}}", &DEFAULT_GLYPHS).unwrap();
+
+        assert_eq!(&out[..len], b"{A}");
+    }
+
+    #[test]
+    fn custom_tables_are_used() {
+        let glyphs = [Glyph::new("LOGO", 0x0B)];
+        let table = GlyphTable::new(&glyphs);
+
+        let (out, len) = expanded::<8>("{LOGO}", &table).unwrap();
+        assert_eq!(&out[..len], &[0x0B]);
+
+        assert_eq!(
+            expanded::<8>("{HEART}", &table),
+            Err(GlyphError::UnknownGlyph { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        assert_eq!(
+            expanded::<8>("AB{HEART", &DEFAULT_GLYPHS),
+            Err(GlyphError::UnterminatedEscape { offset: 2 })
+        );
+        assert_eq!(
+            expanded::<8>("A}", &DEFAULT_GLYPHS),
+            Err(GlyphError::UnterminatedEscape { offset: 1 })
+        );
+        assert_eq!(
+            expanded::<2>("ABC", &DEFAULT_GLYPHS),
+            Err(GlyphError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn non_ascii_text_is_copied_unchanged() {
+        let (out, len) = expanded::<8>("É{SUN}", &DEFAULT_GLYPHS).unwrap();
+
+        assert_eq!(core::str::from_utf8(&out[..len]), Ok("É\x0A"));
+    }
+}
@@ -0,0 +1,205 @@
+//! What a bit's motor driver does once the bit stops moving.  Some drivers need hold current to
+//! keep the drum from slipping, others overheat if held.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest `HoldFor`, as the config value keeps the time in 24 bits.
+pub const MAX_HOLD_MS: u32 = (1 << 24) - 1;
+
+const KIND_SHIFT: u32 = 24;
+const PARAM_MASK: u32 = (1 << KIND_SHIFT) - 1;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum IdlePolicy {
+    /// De-energise as soon as the bit settles.
+    #[default]
+    Release,
+    /// Hold at full current for `ms` after settling, then de-energise.
+    HoldFor { ms: u32 },
+    /// Hold at full current until the bit next moves.
+    Hold,
+    /// Hold until the bit next moves, switching the enable line on for `duty_percent` of the
+    /// time to reduce the current.
+    ReducedHold { duty_percent: u8 },
+}
+
+impl IdlePolicy {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            IdlePolicy::HoldFor { ms } => ms <= MAX_HOLD_MS,
+            IdlePolicy::ReducedHold { duty_percent } => (1..100).contains(&duty_percent),
+            IdlePolicy::Release | IdlePolicy::Hold => true,
+        }
+    }
+
+    /// Packs the policy into a config value, the kind in the top byte and its parameter below.
+    pub fn to_config(self) -> u32 {
+        let (kind, param) = match self {
+            IdlePolicy::Release => (0, 0),
+            IdlePolicy::HoldFor { ms } => (1, ms.min(MAX_HOLD_MS)),
+            IdlePolicy::Hold => (2, 0),
+            IdlePolicy::ReducedHold { duty_percent } => (3, duty_percent as u32),
+        };
+
+        (kind << KIND_SHIFT) | param
+    }
+
+    /// `None` for values `to_config` can't produce.
+    pub fn from_config(value: u32) -> Option<IdlePolicy> {
+        let param = value & PARAM_MASK;
+
+        let policy = match value >> KIND_SHIFT {
+            0 => IdlePolicy::Release,
+            1 => IdlePolicy::HoldFor { ms: param },
+            2 => IdlePolicy::Hold,
+            3 => IdlePolicy::ReducedHold {
+                duty_percent: param.min(u8::MAX as u32) as u8,
+            },
+            _ => return None,
+        };
+
+        (policy.is_valid() && policy.to_config() == value).then_some(policy)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EnableDrive {
+    Off,
+    On,
+    /// Switched on for `duty_percent` of each PWM period.
+    Pwm {
+        duty_percent: u8,
+    },
+}
+
+/// Times how long a bit has been idle, to apply its `IdlePolicy`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct IdleTimer {
+    idle_since_us: Option<u64>,
+}
+
+impl IdleTimer {
+    pub fn new() -> IdleTimer {
+        IdleTimer::default()
+    }
+
+    /// How to drive the enable line of a bit that is or isn't `stepping` at `now_us`, read from a
+    /// microsecond clock that never wraps.
+    pub fn drive(&mut self, policy: IdlePolicy, stepping: bool, now_us: u64) -> EnableDrive {
+        if stepping {
+            self.idle_since_us = None;
+            return EnableDrive::On;
+        }
+
+        let idle_us = now_us.saturating_sub(*self.idle_since_us.get_or_insert(now_us));
+
+        match policy {
+            IdlePolicy::Release => EnableDrive::Off,
+            IdlePolicy::HoldFor { ms } if idle_us < ms as u64 * 1000 => EnableDrive::On,
+            IdlePolicy::HoldFor { .. } => EnableDrive::Off,
+            IdlePolicy::Hold => EnableDrive::On,
+            IdlePolicy::ReducedHold { duty_percent } => EnableDrive::Pwm { duty_percent },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EnableDrive, IdlePolicy, IdleTimer, MAX_HOLD_MS};
+
+    /// Stands in for the board's microsecond timer.
+    struct FakeClock {
+        now_us: u64,
+    }
+
+    impl FakeClock {
+        fn advance_ms(&mut self, ms: u64) -> u64 {
+            self.now_us += ms * 1000;
+            self.now_us
+        }
+    }
+
+    #[test]
+    fn moving_bits_are_always_driven() {
+        let mut timer = IdleTimer::new();
+
+        for policy in [IdlePolicy::Release, IdlePolicy::Hold] {
+            assert_eq!(timer.drive(policy, true, 5), EnableDrive::On);
+        }
+    }
+
+    #[test]
+    fn release_drops_the_enable_at_once() {
+        let mut timer = IdleTimer::new();
+
+        assert_eq!(timer.drive(IdlePolicy::Release, false, 0), EnableDrive::Off);
+    }
+
+    #[test]
+    fn hold_for_counts_time_not_calls() {
+        let mut clock = FakeClock { now_us: 1_000_000 };
+        let mut timer = IdleTimer::new();
+        let policy = IdlePolicy::HoldFor { ms: 250 };
+
+        timer.drive(policy, true, clock.now_us);
+        assert_eq!(timer.drive(policy, false, clock.now_us), EnableDrive::On);
+
+        // However often it is asked, it holds until 250ms have passed
+        for _ in 0..1000 {
+            assert_eq!(timer.drive(policy, false, clock.now_us), EnableDrive::On);
+        }
+        assert_eq!(
+            timer.drive(policy, false, clock.advance_ms(249)),
+            EnableDrive::On
+        );
+        assert_eq!(
+            timer.drive(policy, false, clock.advance_ms(1)),
+            EnableDrive::Off
+        );
+
+        // Moving again restarts the hold
+        timer.drive(policy, true, clock.advance_ms(10));
+        assert_eq!(
+            timer.drive(policy, false, clock.advance_ms(100)),
+            EnableDrive::On
+        );
+        assert_eq!(
+            timer.drive(policy, false, clock.advance_ms(300)),
+            EnableDrive::Off
+        );
+    }
+
+    #[test]
+    fn holds_last_until_the_next_move() {
+        let mut clock = FakeClock { now_us: 0 };
+        let mut timer = IdleTimer::new();
+        let reduced = IdlePolicy::ReducedHold { duty_percent: 30 };
+
+        timer.drive(IdlePolicy::Hold, false, clock.now_us);
+        assert_eq!(
+            timer.drive(IdlePolicy::Hold, false, clock.advance_ms(3_600_000)),
+            EnableDrive::On
+        );
+        assert_eq!(
+            timer.drive(reduced, false, clock.advance_ms(1)),
+            EnableDrive::Pwm { duty_percent: 30 }
+        );
+    }
+
+    #[test]
+    fn config_values_round_trip() {
+        for policy in [
+            IdlePolicy::Release,
+            IdlePolicy::HoldFor { ms: 0 },
+            IdlePolicy::HoldFor { ms: MAX_HOLD_MS },
+            IdlePolicy::Hold,
+            IdlePolicy::ReducedHold { duty_percent: 40 },
+        ] {
+            assert_eq!(IdlePolicy::from_config(policy.to_config()), Some(policy));
+        }
+
+        assert_eq!(IdlePolicy::from_config(4 << 24), None);
+        assert_eq!(IdlePolicy::from_config((3 << 24) | 100), None);
+        assert_eq!(IdlePolicy::from_config((2 << 24) | 1), None);
+    }
+}
@@ -0,0 +1,521 @@
+//! A small key-value store for state that changes often, such as the last message shown.
+//!
+//! Values are appended to a log in one sector at a time, so flash is only erased once a sector
+//! fills up.  The latest value of every key is then copied to the next sector, going round all
+//! of them in turn to spread the wear.
+//!
+//! Each sector starts with `SECTOR_MAGIC` and a sequence number, the highest being the sector in
+//! use.  The header of a new sector is written after its values, so a power cut while changing
+//! sectors leaves the old one in use.  Records are a CRC-16 of the rest of the record, the key,
+//! the length of the value, then the value.  A record with a bad CRC was cut short by a power
+//! cut and is ignored, along with anything after it in the sector.
+
+use crate::protocol::crc16;
+
+pub const SECTOR_MAGIC: [u8; 4] = *b"SFKV";
+pub const MAX_VALUE_LEN: usize = 128;
+
+/// Targets of the content shown before the last power cut.
+pub const KEY_LAST_MESSAGE: u8 = 0;
+/// Per-bit count of missed home positions, as `u32`s.
+pub const KEY_STEP_LOSSES: u8 = 1;
+/// Per-bit count of revolutions, as `u32`s.
+pub const KEY_REVOLUTIONS: u8 = 2;
+/// Per-bit settled steps since home, as `u32`s, with `u32::MAX` for bits that weren't at rest.
+pub const KEY_POSITIONS: u8 = 3;
+
+const SECTOR_HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 4;
+
+/// Flash that erases to `0xFF` a sector at a time, and whose writes can only clear bits.
+pub trait NorFlash {
+    type Error;
+
+    /// Size of the sectors that `erase` works on.
+    const ERASE_SIZE: usize;
+
+    /// Total size, a multiple of `ERASE_SIZE`.
+    fn capacity(&self) -> usize;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erases from `from` up to `to`, both on sector boundaries.
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+
+    /// Writes `bytes` at any offset into erased flash.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KvError<E> {
+    Flash(E),
+    /// Needs at least two sectors, so that one can be erased while the other holds the values.
+    TooFewSectors,
+    ValueTooLong,
+    BufferTooSmall,
+    /// The latest value of every key doesn't fit in one sector.
+    Full,
+}
+
+impl<E> From<E> for KvError<E> {
+    fn from(error: E) -> Self {
+        KvError::Flash(error)
+    }
+}
+
+/// Where the latest value for a key is stored.
+#[derive(Clone, Copy)]
+struct Location {
+    offset: u32,
+    len: usize,
+}
+
+pub struct KvStore<F: NorFlash> {
+    flash: F,
+    sector_count: usize,
+    /// The sector in use and its sequence number, or `None` while the flash is blank.
+    active: Option<(usize, u32)>,
+    /// Offset within the active sector of the next record.
+    write_offset: usize,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Finds the values already in `flash`.  Blank flash gives an empty store.
+    pub fn mount(mut flash: F) -> Result<KvStore<F>, KvError<F::Error>> {
+        let sector_count = flash.capacity() / F::ERASE_SIZE;
+
+        if sector_count < 2 {
+            return Err(KvError::TooFewSectors);
+        }
+
+        let mut active = None;
+
+        for sector in 0..sector_count {
+            let mut header = [0u8; SECTOR_HEADER_LEN];
+            flash.read((sector * F::ERASE_SIZE) as u32, &mut header)?;
+
+            if header[..4] != SECTOR_MAGIC {
+                continue;
+            }
+
+            let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+            if active.is_none_or(|(_, latest)| sequence > latest) {
+                active = Some((sector, sequence));
+            }
+        }
+
+        let mut store = KvStore {
+            flash,
+            sector_count,
+            active,
+            write_offset: SECTOR_HEADER_LEN,
+        };
+
+        if let Some((sector, _)) = active {
+            store.write_offset = store.scan(sector, |_, _| {})?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+
+    /// Copies the value of `key` into `out`, returning its length, or `None` if it has never been
+    /// set.
+    pub fn get(&mut self, key: u8, out: &mut [u8]) -> Result<Option<usize>, KvError<F::Error>> {
+        let Some(location) = self.find(key)? else {
+            return Ok(None);
+        };
+
+        let out = out.get_mut(..location.len).ok_or(KvError::BufferTooSmall)?;
+        self.flash.read(location.offset, out)?;
+
+        Ok(Some(location.len))
+    }
+
+    /// Stores `value` for `key`.  Once this returns, the value survives a power cut.  Setting
+    /// the value already stored doesn't write anything.
+    pub fn set(&mut self, key: u8, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLong);
+        }
+
+        let mut current = [0u8; MAX_VALUE_LEN];
+        if let Some(len) = self.get(key, &mut current)? {
+            if current[..len] == *value {
+                return Ok(());
+            }
+        }
+
+        match self.active {
+            Some((sector, _))
+                if self.write_offset + RECORD_HEADER_LEN + value.len() <= F::ERASE_SIZE =>
+            {
+                match self.write_record(sector, self.write_offset, key, value) {
+                    Ok(offset) => {
+                        self.write_offset = offset;
+                        Ok(())
+                    }
+                    Err(error) => {
+                        // The record may be half written, so start afresh in the next sector
+                        self.write_offset = F::ERASE_SIZE;
+                        Err(error)
+                    }
+                }
+            }
+            _ => self.move_to_next_sector(key, value),
+        }
+    }
+
+    /// Starts the next sector with the latest value of every key, plus `key` set to `value`.
+    fn move_to_next_sector(&mut self, key: u8, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        let (next, sequence) = match self.active {
+            Some((sector, sequence)) => ((sector + 1) % self.sector_count, sequence + 1),
+            None => (0, 0),
+        };
+
+        let start = (next * F::ERASE_SIZE) as u32;
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)?;
+
+        let mut offset = SECTOR_HEADER_LEN;
+
+        if let Some((sector, _)) = self.active {
+            let mut live = [false; 256];
+            self.scan(sector, |key, _| live[key as usize] = true)?;
+            live[key as usize] = false;
+
+            for other in (0..=255u8).filter(|&other| live[other as usize]) {
+                let mut other_value = [0u8; MAX_VALUE_LEN];
+                let len = self.get(other, &mut other_value)?.unwrap_or(0);
+
+                if offset + RECORD_HEADER_LEN + len > F::ERASE_SIZE {
+                    return Err(KvError::Full);
+                }
+
+                offset = self.write_record(next, offset, other, &other_value[..len])?;
+            }
+        }
+
+        if offset + RECORD_HEADER_LEN + value.len() > F::ERASE_SIZE {
+            return Err(KvError::Full);
+        }
+
+        offset = self.write_record(next, offset, key, value)?;
+
+        // The magic goes last, so a sector is never used with half its sequence number
+        self.flash.write(start + 4, &sequence.to_le_bytes())?;
+        self.flash.write(start, &SECTOR_MAGIC)?;
+
+        self.active = Some((next, sequence));
+        self.write_offset = offset;
+
+        Ok(())
+    }
+
+    fn find(&mut self, key: u8) -> Result<Option<Location>, KvError<F::Error>> {
+        let Some((sector, _)) = self.active else {
+            return Ok(None);
+        };
+
+        let mut found = None;
+        self.scan(sector, |record_key, location| {
+            if record_key == key {
+                found = Some(location);
+            }
+        })?;
+
+        Ok(found)
+    }
+
+    /// Calls `visit` for every complete record in `sector`, oldest first, returning where the
+    /// next record can be written.
+    fn scan(
+        &mut self,
+        sector: usize,
+        mut visit: impl FnMut(u8, Location),
+    ) -> Result<usize, KvError<F::Error>> {
+        let base = sector * F::ERASE_SIZE;
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut record = [0u8; RECORD_HEADER_LEN + MAX_VALUE_LEN];
+
+        while offset + RECORD_HEADER_LEN <= F::ERASE_SIZE {
+            let header = &mut record[..RECORD_HEADER_LEN];
+            self.flash.read((base + offset) as u32, header)?;
+
+            if header.iter().all(|&byte| byte == 0xFF) {
+                return Ok(offset);
+            }
+
+            let crc = u16::from_le_bytes([header[0], header[1]]);
+            let key = header[2];
+            let len = header[3] as usize;
+            let end = offset + RECORD_HEADER_LEN + len;
+
+            if len > MAX_VALUE_LEN || end > F::ERASE_SIZE {
+                break;
+            }
+
+            let value = &mut record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+            self.flash
+                .read((base + offset + RECORD_HEADER_LEN) as u32, value)?;
+
+            if crc16(&record[2..RECORD_HEADER_LEN + len]) != crc {
+                break;
+            }
+
+            visit(
+                key,
+                Location {
+                    offset: (base + offset + RECORD_HEADER_LEN) as u32,
+                    len,
+                },
+            );
+            offset = end;
+        }
+
+        // Nothing more can be written after a torn record, so the next value starts a new sector
+        Ok(F::ERASE_SIZE)
+    }
+
+    fn write_record(
+        &mut self,
+        sector: usize,
+        offset: usize,
+        key: u8,
+        value: &[u8],
+    ) -> Result<usize, KvError<F::Error>> {
+        let len = RECORD_HEADER_LEN + value.len();
+        let mut record = [0u8; RECORD_HEADER_LEN + MAX_VALUE_LEN];
+
+        record[2] = key;
+        record[3] = value.len() as u8;
+        record[RECORD_HEADER_LEN..len].copy_from_slice(value);
+
+        let crc = crc16(&record[2..len]);
+        record[..2].copy_from_slice(&crc.to_le_bytes());
+
+        self.flash
+            .write((sector * F::ERASE_SIZE + offset) as u32, &record[..len])?;
+
+        Ok(offset + len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KvError, KvStore, NorFlash, MAX_VALUE_LEN};
+
+    const SECTOR_SIZE: usize = 128;
+    const SECTORS: usize = 4;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    struct PowerCut;
+
+    /// Flash in RAM that can lose power part way through an erase or write.
+    #[derive(Clone)]
+    struct RamFlash {
+        data: [u8; SECTOR_SIZE * SECTORS],
+        erase_counts: [u32; SECTORS],
+        /// Bytes that can be erased or written before the power is cut.
+        budget: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> RamFlash {
+            RamFlash {
+                data: [0xFF; SECTOR_SIZE * SECTORS],
+                erase_counts: [0; SECTORS],
+                budget: None,
+            }
+        }
+
+        fn spend(&mut self) -> Result<(), PowerCut> {
+            match &mut self.budget {
+                Some(0) => Err(PowerCut),
+                Some(budget) => {
+                    *budget -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        type Error = PowerCut;
+
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            assert_eq!(from as usize % SECTOR_SIZE, 0);
+            assert_eq!(to as usize % SECTOR_SIZE, 0);
+
+            for sector in from as usize / SECTOR_SIZE..to as usize / SECTOR_SIZE {
+                self.erase_counts[sector] += 1;
+            }
+
+            // Erased from the end, so a cut leaves the sector header in place
+            for idx in (from as usize..to as usize).rev() {
+                self.spend()?;
+                self.data[idx] = 0xFF;
+            }
+
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            for (idx, &byte) in (offset as usize..).zip(bytes) {
+                self.spend()?;
+                self.data[idx] &= byte;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn get(store: &mut KvStore<RamFlash>, key: u8) -> Option<[u8; 4]> {
+        let mut value = [0u8; 4];
+        store.get(key, &mut value).unwrap().map(|len| {
+            assert_eq!(len, 4);
+            value
+        })
+    }
+
+    fn remount(store: KvStore<RamFlash>) -> KvStore<RamFlash> {
+        let mut flash = store.into_flash();
+        flash.budget = None;
+
+        KvStore::mount(flash).unwrap()
+    }
+
+    #[test]
+    fn values_survive_remount() {
+        let mut store = KvStore::mount(RamFlash::new()).unwrap();
+
+        assert_eq!(get(&mut store, 1), None);
+
+        store.set(1, b"ABCD").unwrap();
+        store.set(2, b"WXYZ").unwrap();
+        store.set(1, b"EFGH").unwrap();
+
+        let mut store = remount(store);
+
+        assert_eq!(get(&mut store, 1), Some(*b"EFGH"));
+        assert_eq!(get(&mut store, 2), Some(*b"WXYZ"));
+    }
+
+    #[test]
+    fn unchanged_values_are_not_rewritten() {
+        let mut store = KvStore::mount(RamFlash::new()).unwrap();
+        store.set(1, b"ABCD").unwrap();
+
+        let before = store.flash.data;
+        store.set(1, b"ABCD").unwrap();
+
+        assert_eq!(store.flash.data, before);
+    }
+
+    #[test]
+    fn sectors_wear_evenly() {
+        let mut store = KvStore::mount(RamFlash::new()).unwrap();
+
+        for count in 0..1000u32 {
+            store.set((count % 3) as u8, &count.to_le_bytes()).unwrap();
+        }
+
+        let mut store = remount(store);
+
+        assert_eq!(get(&mut store, 0), Some(999u32.to_le_bytes()));
+        assert_eq!(get(&mut store, 1), Some(997u32.to_le_bytes()));
+        assert_eq!(get(&mut store, 2), Some(998u32.to_le_bytes()));
+
+        let counts = store.flash.erase_counts;
+        let most = counts.iter().max().unwrap();
+        let least = counts.iter().min().unwrap();
+        assert!(*least > 10);
+        assert!(most - least <= 1, "{:?}", counts);
+    }
+
+    #[test]
+    fn power_cut_keeps_old_or_new_value() {
+        // Fill most of the first sector, so that the interrupted sets cover writing within a
+        // sector as well as moving to the next one
+        let mut filled = KvStore::mount(RamFlash::new()).unwrap();
+        for count in 0..13u32 {
+            filled.set((count % 2) as u8, &count.to_le_bytes()).unwrap();
+        }
+
+        for budget in 0..SECTOR_SIZE * 2 {
+            let mut store = KvStore::mount(filled.flash.clone()).unwrap();
+            store.flash.budget = Some(budget);
+
+            let results = [
+                store.set(0, b"NEW0"),
+                store.set(1, b"NEW1"),
+                store.set(0, b"END0"),
+            ];
+            let completed = results.iter().take_while(|result| result.is_ok()).count();
+
+            let mut store = remount(store);
+            let expected: [[&[u8; 4]; 2]; 4] = [
+                [&12u32.to_le_bytes(), &11u32.to_le_bytes()],
+                [b"NEW0", &11u32.to_le_bytes()],
+                [b"NEW0", b"NEW1"],
+                [b"END0", b"NEW1"],
+            ];
+
+            // The interrupted set may or may not have taken effect, everything before it must
+            let values = [get(&mut store, 0).unwrap(), get(&mut store, 1).unwrap()];
+            let before = expected[completed].map(|value| *value);
+            let after = expected[(completed + 1).min(3)].map(|value| *value);
+
+            assert!(
+                values == before || values == after,
+                "budget {}: {:?}",
+                budget,
+                values
+            );
+
+            // And the store can still be written
+            store.set(1, b"MORE").unwrap();
+            assert_eq!(get(&mut remount(store), 1), Some(*b"MORE"));
+        }
+    }
+
+    #[test]
+    fn oversized_values_are_rejected() {
+        let mut store = KvStore::mount(RamFlash::new()).unwrap();
+
+        assert_eq!(
+            store.set(1, &[0; MAX_VALUE_LEN + 1]),
+            Err(KvError::ValueTooLong)
+        );
+        assert_eq!(store.get(1, &mut [0; 4]), Ok(None),);
+
+        store.set(1, b"ABCD").unwrap();
+        assert_eq!(store.get(1, &mut [0; 2]), Err(KvError::BufferTooSmall));
+    }
+
+    #[test]
+    fn values_that_fill_a_sector_are_rejected() {
+        let mut store = KvStore::mount(RamFlash::new()).unwrap();
+
+        store.set(1, &[1; 80]).unwrap();
+
+        assert_eq!(store.set(2, &[2; 80]), Err(KvError::Full));
+        assert_eq!(store.get(1, &mut [0; 80]), Ok(Some(80)));
+    }
+}
@@ -1,4 +1,22 @@
 #![no_std]
 
+pub mod bus;
+pub mod calibration;
+pub mod clock;
+pub mod device;
+pub mod device_config;
+pub mod glyphs;
+pub mod idle;
+pub mod kv_store;
+pub mod marquee;
+pub mod normalize;
+pub mod protocol;
+pub mod registers;
+pub mod self_test;
+pub mod serial_command;
 pub mod split_flap_bit_state;
-
+pub mod steps;
+pub mod text_layout;
+pub mod trace;
+pub mod transition;
+pub mod travel;
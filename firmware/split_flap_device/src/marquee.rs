@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+
+use crate::split_flap_bit_state::SplitFlapBitState;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ScrollWrap {
+    /// Scroll until the end of the message is showing, then stay there.
+    Stop,
+    /// Jump back to the start of the message after the end pause.
+    Restart,
+    /// Treat the message as a loop, with `gap` blank flaps between repetitions.
+    Continuous { gap: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ScrollConfig {
+    /// Number of characters the message moves per frame.
+    pub characters_per_frame: usize,
+    /// Minimum time a frame is shown once every bit has settled.
+    pub frame_ms: u32,
+    pub start_pause_ms: u32,
+    pub end_pause_ms: u32,
+    pub wrap: ScrollWrap,
+}
+
+impl Default for ScrollConfig {
+    fn default() -> Self {
+        ScrollConfig {
+            characters_per_frame: 1,
+            frame_ms: 500,
+            start_pause_ms: 2000,
+            end_pause_ms: 2000,
+            wrap: ScrollWrap::Restart,
+        }
+    }
+}
+
+/// Scrolls a message that is longer than the display across a row of bits.
+///
+/// A new frame is only produced once every bit has settled on the previous one.
+pub struct Marquee<M> {
+    message: M,
+    width: usize,
+    config: ScrollConfig,
+    offset: usize,
+    settled_since_ms: Option<u32>,
+}
+
+impl<M: AsRef<[u8]>> Marquee<M> {
+    pub fn new(message: M, width: usize, config: ScrollConfig) -> Marquee<M> {
+        Marquee {
+            message,
+            width,
+            config,
+            offset: 0,
+            settled_since_ms: None,
+        }
+    }
+
+    pub fn message(&self) -> &M {
+        &self.message
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Messages that fit on the display are shown without scrolling.
+    pub fn scrolls(&self) -> bool {
+        self.len() > self.width
+    }
+
+    /// The characters currently in view, padded with blanks.
+    pub fn window(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.width).map(move |idx| self.character_at(self.offset + idx))
+    }
+
+    fn character_at(&self, position: usize) -> u8 {
+        let position = match self.config.wrap {
+            ScrollWrap::Continuous { gap } if self.scrolls() => position % (self.len() + gap),
+            _ => position,
+        };
+
+        self.message.as_ref().get(position).copied().unwrap_or(b' ')
+    }
+
+    fn len(&self) -> usize {
+        self.message.as_ref().len()
+    }
+
+    fn last_offset(&self) -> usize {
+        self.len().saturating_sub(self.width)
+    }
+
+    fn at_end(&self) -> bool {
+        match self.config.wrap {
+            ScrollWrap::Continuous { .. } => false,
+            _ => self.offset >= self.last_offset(),
+        }
+    }
+
+    fn dwell_ms(&self) -> u32 {
+        if self.offset == 0 {
+            self.config.start_pause_ms
+        } else if self.at_end() {
+            self.config.end_pause_ms
+        } else {
+            self.config.frame_ms
+        }
+    }
+
+    /// Moves to the next frame if the current one has been settled for long enough.
+    ///
+    /// Should only be called while every bit is settled.  Returns true if the window moved.
+    pub fn advance(&mut self, now_ms: u32) -> bool {
+        if !self.scrolls() {
+            return false;
+        }
+
+        let settled_since_ms = *self.settled_since_ms.get_or_insert(now_ms);
+
+        if now_ms.wrapping_sub(settled_since_ms) < self.dwell_ms() {
+            return false;
+        }
+
+        let step = self.config.characters_per_frame.max(1);
+
+        let next_offset = match self.config.wrap {
+            ScrollWrap::Stop if self.at_end() => return false,
+            ScrollWrap::Restart if self.at_end() => 0,
+            ScrollWrap::Stop | ScrollWrap::Restart => (self.offset + step).min(self.last_offset()),
+            ScrollWrap::Continuous { gap } => (self.offset + step) % (self.len() + gap),
+        };
+
+        self.offset = next_offset;
+        self.settled_since_ms = None;
+
+        true
+    }
+
+    /// Sets the target character of each bit to the current window.
+    pub fn apply(&self, bits: &mut [SplitFlapBitState]) {
+        for (bit, character) in bits.iter_mut().zip(self.window()) {
+            bit.set_target_character(character);
+        }
+    }
+
+    /// Advances and applies the next frame once all bits have settled.  Returns true if the
+    /// targets changed.
+    pub fn update(&mut self, now_ms: u32, bits: &mut [SplitFlapBitState]) -> bool {
+        if !bits.iter().all(|bit| bit.is_settled()) {
+            self.settled_since_ms = None;
+            return false;
+        }
+
+        if self.advance(now_ms) {
+            self.apply(bits);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Marquee, ScrollConfig, ScrollWrap};
+    use crate::split_flap_bit_state::{SensorCalibration, SplitFlapBitState};
+    use crate::steps::{HomedSteps, Revolution, Steps};
+
+    fn config(wrap: ScrollWrap) -> ScrollConfig {
+        ScrollConfig {
+            characters_per_frame: 1,
+            frame_ms: 100,
+            start_pause_ms: 1000,
+            end_pause_ms: 500,
+            wrap,
+        }
+    }
+
+    fn window<const N: usize>(marquee: &Marquee<impl AsRef<[u8]>>) -> [u8; N] {
+        let mut result = [0u8; N];
+
+        for (slot, character) in result.iter_mut().zip(marquee.window()) {
+            *slot = character;
+        }
+
+        result
+    }
+
+    fn revolution() -> Revolution {
+        Revolution::new(Steps::new(58)).unwrap()
+    }
+
+    fn homed_bit() -> SplitFlapBitState {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut bit = SplitFlapBitState::new(calibration, revolution(), HomedSteps::HOME);
+
+        bit.process(2100);
+
+        bit
+    }
+
+    #[test]
+    fn short_message_does_not_scroll() {
+        let mut marquee = Marquee::new(b"HI", 4, config(ScrollWrap::Restart));
+
+        assert!(!marquee.advance(0));
+        assert!(!marquee.advance(10_000));
+        assert_eq!(&window::<4>(&marquee), b"HI  ");
+    }
+
+    #[test]
+    fn first_frame_is_held_for_start_pause() {
+        let mut marquee = Marquee::new(b"ABCDEF", 4, config(ScrollWrap::Restart));
+
+        assert!(!marquee.advance(0));
+        assert!(!marquee.advance(999));
+        assert!(marquee.advance(1000));
+        assert_eq!(&window::<4>(&marquee), b"BCDE");
+    }
+
+    #[test]
+    fn frames_advance_by_characters_per_frame() {
+        let mut marquee = Marquee::new(
+            b"ABCDEFGHIJ",
+            4,
+            ScrollConfig {
+                characters_per_frame: 4,
+                ..config(ScrollWrap::Stop)
+            },
+        );
+
+        marquee.advance(0);
+        marquee.advance(1000);
+        assert_eq!(&window::<4>(&marquee), b"EFGH");
+
+        marquee.advance(1000);
+        marquee.advance(1100);
+        assert_eq!(&window::<4>(&marquee), b"GHIJ");
+    }
+
+    #[test]
+    fn stop_stays_at_end_of_message() {
+        let mut marquee = Marquee::new(b"ABCDE", 4, config(ScrollWrap::Stop));
+
+        marquee.advance(0);
+        marquee.advance(1000);
+        assert_eq!(marquee.offset(), 1);
+
+        marquee.advance(1000);
+        assert!(!marquee.advance(100_000));
+        assert_eq!(&window::<4>(&marquee), b"BCDE");
+    }
+
+    #[test]
+    fn restart_returns_to_start_after_end_pause() {
+        let mut marquee = Marquee::new(b"ABCDE", 4, config(ScrollWrap::Restart));
+
+        marquee.advance(0);
+        marquee.advance(1000);
+
+        assert!(!marquee.advance(1000));
+        assert!(!marquee.advance(1499));
+        assert!(marquee.advance(1500));
+        assert_eq!(&window::<4>(&marquee), b"ABCD");
+    }
+
+    #[test]
+    fn continuous_wraps_with_gap() {
+        let mut marquee = Marquee::new(
+            b"ABCDE",
+            4,
+            ScrollConfig {
+                characters_per_frame: 3,
+                ..config(ScrollWrap::Continuous { gap: 1 })
+            },
+        );
+
+        marquee.advance(0);
+        marquee.advance(1000);
+        assert_eq!(&window::<4>(&marquee), b"DE A");
+
+        marquee.advance(1000);
+        marquee.advance(1100);
+        assert_eq!(marquee.offset(), 0);
+        assert_eq!(&window::<4>(&marquee), b"ABCD");
+    }
+
+    #[test]
+    fn update_waits_for_all_bits_to_settle() {
+        let mut bits = [
+            homed_bit(),
+            SplitFlapBitState::new(
+                SensorCalibration {
+                    trigger_value: 2000,
+                    untrigger_value: 1800,
+                },
+                revolution(),
+                HomedSteps::HOME,
+            ),
+        ];
+        let mut marquee = Marquee::new(b"ABC", 2, config(ScrollWrap::Restart));
+
+        assert!(!marquee.update(0, &mut bits));
+        assert!(!marquee.update(5000, &mut bits));
+
+        bits[1].process(2100);
+
+        assert!(!marquee.update(5000, &mut bits));
+        assert!(marquee.update(6000, &mut bits));
+    }
+
+    #[test]
+    fn update_sets_bit_targets_to_window() {
+        let mut bits = [homed_bit(), homed_bit()];
+        let mut marquee = Marquee::new(b"ABC", 2, config(ScrollWrap::Restart));
+
+        marquee.update(0, &mut bits);
+        marquee.update(1000, &mut bits);
+
+        assert!(bits[0].process(100), "First bit is not seeking B");
+        assert!(bits[1].process(100), "Second bit is not seeking C");
+    }
+}
@@ -0,0 +1,262 @@
+use crate::split_flap_bit_state::CHARACTER_SET;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NormalizeOptions {
+    /// Flap used for characters with no sensible equivalent, or `None` to drop them.
+    pub replacement: Option<u8>,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions {
+            replacement: Some(b' '),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SubstitutionKind {
+    /// Replaced with the nearest available flaps, e.g. `É` with `E` or `ß` with `SS`.
+    Transliterated,
+    /// No equivalent, so the configured replacement was used.
+    Unmappable,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Substitution {
+    /// Byte offset of the character in the input text.
+    pub offset: usize,
+    pub original: char,
+    pub kind: SubstitutionKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NormalizeSummary {
+    pub len: usize,
+    pub substitutions: usize,
+    /// The output buffer filled before the end of the text.
+    pub truncated: bool,
+}
+
+/// Converts UTF-8 text into flap codes, writing them to `out`.
+///
+/// Lowercase letters are folded to uppercase without being reported as substitutions.  Line
+/// breaks are passed through for the layout engine.  Glyph codes are kept too, and tabs share a
+/// byte with one, so typed text should go through `expand_glyphs` first.
+pub fn normalize(text: &str, options: &NormalizeOptions, out: &mut [u8]) -> NormalizeSummary {
+    normalize_with(text, options, out, |_| {})
+}
+
+/// As [`normalize`], calling `on_substitution` for every character that could not be shown as is.
+pub fn normalize_with<F>(
+    text: &str,
+    options: &NormalizeOptions,
+    out: &mut [u8],
+    mut on_substitution: F,
+) -> NormalizeSummary
+where
+    F: FnMut(Substitution),
+{
+    let mut summary = NormalizeSummary::default();
+
+    for (offset, original) in text.char_indices() {
+        let mut single = [0u8; 1];
+
+        let (flaps, kind): (&[u8], Option<SubstitutionKind>) = match fold(original) {
+            Some(code) => {
+                single[0] = code;
+                (&single, None)
+            }
+            None => match transliterate(original) {
+                Some(flaps) => (flaps, Some(SubstitutionKind::Transliterated)),
+                None => match options.replacement {
+                    Some(code) => {
+                        single[0] = code;
+                        (&single, Some(SubstitutionKind::Unmappable))
+                    }
+                    None => (&[], Some(SubstitutionKind::Unmappable)),
+                },
+            },
+        };
+
+        if summary.len + flaps.len() > out.len() {
+            summary.truncated = true;
+            break;
+        }
+
+        out[summary.len..summary.len + flaps.len()].copy_from_slice(flaps);
+        summary.len += flaps.len();
+
+        if let Some(kind) = kind {
+            summary.substitutions += 1;
+            on_substitution(Substitution {
+                offset,
+                original,
+                kind,
+            });
+        }
+    }
+
+    summary
+}
+
+fn fold(c: char) -> Option<u8> {
+    if !c.is_ascii() {
+        return None;
+    }
+
+    let code = c.to_ascii_uppercase() as u8;
+
+    if CHARACTER_SET.contains(&code) || code == b'\n' || code == b'\r' {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+fn transliterate(c: char) -> Option<&'static [u8]> {
+    let flaps: &[u8] = match c {
+        'À'..='Å' | 'à'..='å' | 'Ā'..='ą' => b"A",
+        'Æ' | 'æ' => b"AE",
+        'Ç' | 'ç' | 'Ć'..='č' => b"C",
+        'Ð' | 'ð' | 'Ď'..='đ' => b"D",
+        'È'..='Ë' | 'è'..='ë' | 'Ē'..='ě' => b"E",
+        'Ĝ'..='ģ' => b"G",
+        'Ĥ'..='ħ' => b"H",
+        'Ì'..='Ï' | 'ì'..='ï' | 'Ĩ'..='ı' => b"I",
+        'Ĳ' | 'ĳ' => b"IJ",
+        'Ĵ' | 'ĵ' => b"J",
+        'Ķ'..='ĸ' => b"K",
+        'Ĺ'..='ł' => b"L",
+        'Ñ' | 'ñ' | 'Ń'..='ŋ' => b"N",
+        'Ò'..='Ö' | 'ò'..='ö' | 'Ø' | 'ø' | 'Ō'..='ő' => b"O",
+        'Œ' | 'œ' => b"OE",
+        'Ŕ'..='ř' => b"R",
+        'Ś'..='š' | 'ſ' => b"S",
+        'ß' => b"SS",
+        'Ţ'..='ŧ' => b"T",
+        'Þ' | 'þ' => b"TH",
+        'Ù'..='Ü' | 'ù'..='ü' | 'Ũ'..='ų' => b"U",
+        'Ŵ' | 'ŵ' => b"W",
+        'Ý' | 'ý' | 'ÿ' | 'Ŷ'..='Ÿ' => b"Y",
+        'Ź'..='ž' => b"Z",
+        ',' | '!' | '¡' | '·' => b".",
+        ';' => b":",
+        '–' | '—' | '‐' | '‑' | '−' | '~' => b"-",
+        '…' => b"...",
+        '\\' | '|' | '÷' => b"/",
+        '\u{a0}' => b" ",
+        // Quotes have no flap and read fine when left out
+        '\'' | '"' | '`' | '´' | '‘' | '’' | '‚' | '“' | '”' | '„' => b"",
+        _ => return None,
+    };
+
+    Some(flaps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize, normalize_with, NormalizeOptions, Substitution, SubstitutionKind};
+
+    fn normalized<const N: usize>(text: &str, options: &NormalizeOptions) -> ([u8; N], usize) {
+        let mut out = [0u8; N];
+        let summary = normalize(text, options, &mut out);
+
+        (out, summary.len)
+    }
+
+    #[test]
+    fn lowercase_is_folded_without_substitutions() {
+        let mut out = [0u8; 5];
+
+        let summary = normalize("hello", &NormalizeOptions::default(), &mut out);
+
+        assert_eq!(&out, b"HELLO");
+        assert_eq!(summary.substitutions, 0);
+    }
+
+    #[test]
+    fn accented_letters_are_transliterated() {
+        let (out, len) = normalized::<16>("Éüñ Straße", &NormalizeOptions::default());
+
+        assert_eq!(&out[..len], b"EUN STRASSE");
+    }
+
+    #[test]
+    fn punctuation_maps_to_nearest_flap() {
+        let (out, len) = normalized::<32>("Don't stop, it's 9–5…", &NormalizeOptions::default());
+
+        assert_eq!(&out[..len], b"DONT STOP. ITS 9-5...");
+    }
+
+    #[test]
+    fn unmappable_characters_use_replacement() {
+        let options = NormalizeOptions {
+            replacement: Some(b'%'),
+        };
+
+        let (out, len) = normalized::<8>("A?B☃", &options);
+
+        assert_eq!(&out[..len], b"A%B%");
+    }
+
+    #[test]
+    fn unmappable_characters_can_be_dropped() {
+        let options = NormalizeOptions { replacement: None };
+
+        let (out, len) = normalized::<8>("(A)", &options);
+
+        assert_eq!(&out[..len], b"A");
+    }
+
+    #[test]
+    fn substitutions_are_reported_with_offsets() {
+        let mut out = [0u8; 8];
+        let mut reported = [None; 4];
+        let mut count = 0;
+
+        normalize_with("ÀB?", &NormalizeOptions::default(), &mut out, |s| {
+            reported[count] = Some(s);
+            count += 1;
+        });
+
+        assert_eq!(count, 2);
+        assert_eq!(
+            reported[0],
+            Some(Substitution {
+                offset: 0,
+                original: 'À',
+                kind: SubstitutionKind::Transliterated
+            })
+        );
+        assert_eq!(
+            reported[1],
+            Some(Substitution {
+                offset: 3,
+                original: '?',
+                kind: SubstitutionKind::Unmappable
+            })
+        );
+    }
+
+    #[test]
+    fn line_breaks_and_glyph_codes_pass_through() {
+        let (out, len) = normalized::<4>("a\n\u{1}", &NormalizeOptions::default());
+
+        assert_eq!(&out[..len], &[b'A', b'\n', 0x01]);
+    }
+
+    #[test]
+    fn output_is_truncated_at_whole_characters() {
+        let mut out = [0u8; 3];
+
+        let summary = normalize("ASS ß", &NormalizeOptions::default(), &mut out);
+        assert_eq!(&out[..summary.len], b"ASS");
+        assert!(summary.truncated);
+
+        let mut out = [0u8; 5];
+        let summary = normalize("ASS ß", &NormalizeOptions::default(), &mut out);
+        assert_eq!(&out[..summary.len], b"ASS ");
+        assert!(summary.truncated);
+    }
+}
@@ -0,0 +1,689 @@
+//! Binary protocol between a host and the device over the USB serial port.
+//!
+//! Each frame is `seq | postcard message | CRC-16 (little endian)`, COBS encoded and sent between
+//! two `0x00` delimiters.  The receiver answers every frame with an `Ack`, a `Nak` or the reply
+//! to a query, carrying the same sequence number.  Bytes outside a frame are handed back to the
+//! caller, so the line based commands keep working on the same port.
+
+use serde::{Deserialize, Serialize};
+
+use crate::marquee::ScrollConfig;
+use crate::self_test::SelfTestReport;
+use crate::split_flap_bit_state::{BitCounters, BitState};
+use crate::trace::TraceChunk;
+use crate::transition::TransitionConfig;
+
+/// Largest frame before COBS encoding: sequence number, message and CRC.
+pub const MAX_FRAME_LEN: usize = 256;
+/// Largest frame after COBS encoding, including both delimiters.
+pub const MAX_ENCODED_LEN: usize = MAX_FRAME_LEN + MAX_FRAME_LEN / 254 + 1 + 2;
+
+const CRC_LEN: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProtocolError {
+    BufferTooSmall,
+    FrameTooLong,
+    Cobs,
+    Crc,
+    Malformed,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum NakReason {
+    Corrupt,
+    Malformed,
+    UnknownBit,
+    InvalidValue,
+    Unsupported,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ConfigKey {
+    StepsPerFlap {
+        bit: u8,
+    },
+    HomeOffset {
+        bit: u8,
+    },
+    TriggerValue {
+        bit: u8,
+    },
+    UntriggerValue {
+        bit: u8,
+    },
+    /// Signed steps added where `flap` stops, sent as the bits of an `i32`.
+    FlapTrim {
+        bit: u8,
+        flap: u8,
+    },
+    /// 1 to restore the position saved before a power cut rather than homing, 0 to always home.
+    RestorePosition {
+        bit: u8,
+    },
+    /// What the motor driver does once the bit settles, packed by `IdlePolicy::to_config`.
+    IdlePolicy {
+        bit: u8,
+    },
+    /// Steps in a turn of the drum, which needn't divide evenly between the flaps as
+    /// `StepsPerFlap` does.
+    StepsPerRevolution {
+        bit: u8,
+    },
+}
+
+impl ConfigKey {
+    pub fn bit(&self) -> u8 {
+        match *self {
+            ConfigKey::StepsPerFlap { bit }
+            | ConfigKey::HomeOffset { bit }
+            | ConfigKey::TriggerValue { bit }
+            | ConfigKey::UntriggerValue { bit }
+            | ConfigKey::FlapTrim { bit, .. }
+            | ConfigKey::RestorePosition { bit }
+            | ConfigKey::IdlePolicy { bit }
+            | ConfigKey::StepsPerRevolution { bit } => bit,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BitStatus {
+    pub bit: u8,
+    pub state: BitState,
+    /// Indexes into `CHARACTER_SET`.
+    pub flap: u8,
+    pub target_flap: u8,
+    /// A calibration sweep is running, after which the bit homes again.
+    pub calibrating: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Message<'a> {
+    Ack {
+        seq: u8,
+    },
+    Nak {
+        seq: u8,
+        reason: NakReason,
+    },
+    SetText(&'a str),
+    /// One `CHARACTER_SET` index per bit.
+    SetRawPositions(&'a [u8]),
+    QueryStatus {
+        bit: u8,
+    },
+    Status(BitStatus),
+    GetConfig(ConfigKey),
+    SetConfig(ConfigKey, u32),
+    Config(ConfigKey, u32),
+    /// Find home again on every bit.
+    Home,
+    /// Measure the sensor over a revolution and set the trigger values from it.
+    Calibrate {
+        bit: u8,
+    },
+    ClearPlaylist,
+    /// Appends text to the playlist, shown for at least `dwell_seconds`.
+    AddPlaylistEntry {
+        text: &'a str,
+        dwell_seconds: u16,
+    },
+    /// Cycles through the playlist until other content is set.
+    StartPlaylist,
+    /// Writes the current settings to flash, to be used from the next boot.
+    SaveConfig,
+    /// Asks for the running totals of `bit`, answered with `Counters`.
+    QueryCounters {
+        bit: u8,
+    },
+    Counters {
+        bit: u8,
+        counters: BitCounters,
+    },
+    ResetCounters {
+        bit: u8,
+    },
+    /// Homes `bit` and records every reading it takes from then on, replacing any earlier trace.
+    StartTrace {
+        bit: u8,
+    },
+    StopTrace,
+    /// Asks for the oldest recorded samples, answered with `Trace`.  Samples are removed once
+    /// sent, so the host polls this to stream the trace.
+    ReadTrace,
+    Trace(TraceChunk),
+    /// Spins every bit through a self-test, then homes it.
+    RunSelfTest,
+    /// Asks for the outcome of the last self-test of `bit`, answered with `SelfTestResult`.
+    QuerySelfTest {
+        bit: u8,
+    },
+    /// `None` while the test is running or if it never has.
+    SelfTestResult {
+        bit: u8,
+        report: Option<SelfTestReport>,
+    },
+    /// Effects used for every message shown from then on.
+    SetTransition(TransitionConfig),
+    /// Shows text, scrolling it across the bits with `config` if it is longer than the display.
+    SetScrollingText {
+        text: &'a str,
+        config: ScrollConfig,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame<'a> {
+    pub seq: u8,
+    pub message: Message<'a>,
+}
+
+impl Frame<'_> {
+    /// CRC of the sequence number and message, telling a resent frame from a new one that
+    /// happens to reuse the sequence number.
+    pub fn fingerprint(&self) -> u16 {
+        let mut body = [0u8; MAX_FRAME_LEN];
+        body[0] = self.seq;
+
+        match postcard::to_slice(&self.message, &mut body[1..]) {
+            Ok(payload) => {
+                let len = 1 + payload.len();
+                crc16(&body[..len])
+            }
+            Err(_) => crc16(&[self.seq]),
+        }
+    }
+}
+
+/// Encodes a frame, with delimiters, into `out`.  Returns the number of bytes to send.
+pub fn encode_frame(seq: u8, message: &Message, out: &mut [u8]) -> Result<usize, ProtocolError> {
+    encode_checked(&[seq], message, out)
+}
+
+/// Decodes a COBS encoded frame, without delimiters, in place.
+pub fn decode_frame(encoded: &mut [u8]) -> Result<Frame<'_>, ProtocolError> {
+    decode(encoded).map_err(|(_, error)| error)
+}
+
+fn decode(encoded: &mut [u8]) -> Result<Frame<'_>, (Option<u8>, ProtocolError)> {
+    let (header, message) = decode_checked::<1, Message>(encoded)?;
+
+    Ok(Frame {
+        seq: header[0],
+        message,
+    })
+}
+
+/// Serializes `header | message | CRC` and COBS encodes it between delimiters.
+pub(crate) fn encode_checked<T: Serialize>(
+    header: &[u8],
+    message: &T,
+    out: &mut [u8],
+) -> Result<usize, ProtocolError> {
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    frame[..header.len()].copy_from_slice(header);
+
+    let payload_len =
+        postcard::to_slice(message, &mut frame[header.len()..MAX_FRAME_LEN - CRC_LEN])
+            .map_err(|_| ProtocolError::FrameTooLong)?
+            .len();
+    let body_len = header.len() + payload_len;
+    let frame_len = body_len + CRC_LEN;
+
+    let crc = crc16(&frame[..body_len]);
+    frame[body_len..frame_len].copy_from_slice(&crc.to_le_bytes());
+
+    if out.len() < 2 {
+        return Err(ProtocolError::BufferTooSmall);
+    }
+
+    let out_len = out.len();
+    let encoded_len = cobs_encode(&frame[..frame_len], &mut out[1..out_len - 1])?;
+
+    out[0] = 0;
+    out[encoded_len + 1] = 0;
+
+    Ok(encoded_len + 2)
+}
+
+/// Reverses `encode_checked` in place.  Errors carry the first header byte when the frame was
+/// long enough to have one, so a NAK can be addressed even though the CRC failed.
+pub(crate) fn decode_checked<'a, const HEADER_LEN: usize, T: Deserialize<'a>>(
+    encoded: &'a mut [u8],
+) -> Result<([u8; HEADER_LEN], T), (Option<u8>, ProtocolError)> {
+    let len = cobs_decode_in_place(encoded).map_err(|error| (None, error))?;
+    let frame = &encoded[..len];
+
+    if frame.len() < HEADER_LEN + CRC_LEN {
+        return Err((None, ProtocolError::Malformed));
+    }
+
+    let mut header = [0u8; HEADER_LEN];
+    header.copy_from_slice(&frame[..HEADER_LEN]);
+
+    let hint = header.first().copied();
+    let (body, crc) = frame.split_at(frame.len() - CRC_LEN);
+
+    if crc16(body).to_le_bytes() != crc {
+        return Err((hint, ProtocolError::Crc));
+    }
+
+    let message =
+        postcard::from_bytes(&body[HEADER_LEN..]).map_err(|_| (hint, ProtocolError::Malformed))?;
+
+    Ok((header, message))
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Received<'a> {
+    /// Nothing to act on yet.
+    Pending,
+    /// A byte that arrived outside of a frame.
+    Byte(u8),
+    Frame(Frame<'a>),
+    Corrupt {
+        seq: Option<u8>,
+        error: ProtocolError,
+    },
+}
+
+/// Collects bytes from the serial port into frames.
+pub struct FrameDecoder {
+    buffer: [u8; MAX_ENCODED_LEN],
+    len: usize,
+    in_frame: bool,
+    overflowed: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: [0; MAX_ENCODED_LEN],
+            len: 0,
+            in_frame: false,
+            overflowed: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Received<'_> {
+        match self.push_delimited(byte) {
+            Delimited::Pending => Received::Pending,
+            Delimited::Byte(byte) => Received::Byte(byte),
+            Delimited::Overflow => Received::Corrupt {
+                seq: None,
+                error: ProtocolError::FrameTooLong,
+            },
+            Delimited::Frame(encoded) => match decode(encoded) {
+                Ok(frame) => Received::Frame(frame),
+                Err((seq, error)) => Received::Corrupt { seq, error },
+            },
+        }
+    }
+
+    /// Collects the encoded bytes of a frame without decoding them.
+    pub(crate) fn push_delimited(&mut self, byte: u8) -> Delimited<'_> {
+        if byte == 0 {
+            // A delimiter with nothing before it starts a frame.  Treating repeated delimiters
+            // this way also resynchronizes after a lost byte.
+            if self.len == 0 && !self.overflowed {
+                self.in_frame = true;
+                return Delimited::Pending;
+            }
+
+            let len = self.len;
+            let overflowed = self.overflowed;
+
+            self.len = 0;
+            self.in_frame = false;
+            self.overflowed = false;
+
+            if overflowed {
+                return Delimited::Overflow;
+            }
+
+            return Delimited::Frame(&mut self.buffer[..len]);
+        }
+
+        if !self.in_frame {
+            return Delimited::Byte(byte);
+        }
+
+        if self.len == self.buffer.len() {
+            self.overflowed = true;
+        } else {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+
+        Delimited::Pending
+    }
+}
+
+pub(crate) enum Delimited<'a> {
+    Pending,
+    Byte(u8),
+    Frame(&'a mut [u8]),
+    Overflow,
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> Result<usize, ProtocolError> {
+    if out.len() < data.len() + data.len() / 254 + 1 {
+        return Err(ProtocolError::BufferTooSmall);
+    }
+
+    let mut code_idx = 0;
+    let mut write = 1;
+    let mut code = 1u8;
+
+    for &byte in data {
+        if byte != 0 {
+            out[write] = byte;
+            write += 1;
+            code += 1;
+        }
+
+        if byte == 0 || code == 0xFF {
+            out[code_idx] = code;
+            code_idx = write;
+            write += 1;
+            code = 1;
+        }
+    }
+
+    out[code_idx] = code;
+
+    Ok(write)
+}
+
+fn cobs_decode_in_place(buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < buffer.len() {
+        let code = buffer[read] as usize;
+
+        if code == 0 {
+            return Err(ProtocolError::Cobs);
+        }
+
+        let start = read + 1;
+        let end = start + code - 1;
+
+        if end > buffer.len() {
+            return Err(ProtocolError::Cobs);
+        }
+
+        buffer.copy_within(start..end, write);
+        write += code - 1;
+        read = end;
+
+        if code != 0xFF && read < buffer.len() {
+            buffer[write] = 0;
+            write += 1;
+        }
+    }
+
+    Ok(write)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        cobs_decode_in_place, cobs_encode, crc16, decode_frame, encode_frame, BitStatus, ConfigKey,
+        Frame, FrameDecoder, Message, NakReason, ProtocolError, Received, MAX_ENCODED_LEN,
+    };
+    use crate::marquee::{ScrollConfig, ScrollWrap};
+    use crate::split_flap_bit_state::{BitCounters, BitState, SensorState};
+    use crate::trace::{TraceChunk, TraceSample, TRACE_CHUNK};
+
+    fn round_trip(data: &[u8]) {
+        let mut encoded = [0u8; 600];
+        let len = cobs_encode(data, &mut encoded).unwrap();
+
+        assert!(!encoded[..len].contains(&0), "Encoded data contains a zero");
+
+        let decoded_len = cobs_decode_in_place(&mut encoded[..len]).unwrap();
+        assert_eq!(&encoded[..decoded_len], data);
+    }
+
+    fn encoded(seq: u8, message: &Message) -> ([u8; MAX_ENCODED_LEN], usize) {
+        let mut out = [0u8; MAX_ENCODED_LEN];
+        let len = encode_frame(seq, message, &mut out).unwrap();
+
+        (out, len)
+    }
+
+    #[test]
+    fn crc_matches_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn cobs_round_trips() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0, 1, 0]);
+        round_trip(b"NO ZEROS");
+
+        let mut long = [7u8; 520];
+        long[300] = 0;
+        round_trip(&long);
+        round_trip(&long[..254]);
+    }
+
+    #[test]
+    fn cobs_rejects_truncated_block() {
+        let mut encoded = [5, 1, 2];
+
+        assert_eq!(cobs_decode_in_place(&mut encoded), Err(ProtocolError::Cobs));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let messages = [
+            Message::Ack { seq: 3 },
+            Message::Nak {
+                seq: 4,
+                reason: NakReason::Corrupt,
+            },
+            Message::SetText("HELLO"),
+            Message::SetRawPositions(&[0, 1, 54, 0]),
+            Message::QueryStatus { bit: 2 },
+            Message::Status(BitStatus {
+                bit: 2,
+                state: BitState::SETTLED,
+                flap: 8,
+                target_flap: 8,
+                calibrating: false,
+            }),
+            Message::SetConfig(ConfigKey::TriggerValue { bit: 1 }, 2200),
+            Message::Home,
+            Message::Calibrate { bit: 3 },
+            Message::GetConfig(ConfigKey::FlapTrim { bit: 1, flap: 40 }),
+            Message::AddPlaylistEntry {
+                text: "NEXT",
+                dwell_seconds: 30,
+            },
+            Message::SaveConfig,
+            Message::Counters {
+                bit: 1,
+                counters: BitCounters {
+                    steps: 1 << 40,
+                    revolutions: 12,
+                    ..BitCounters::default()
+                },
+            },
+            Message::StartTrace { bit: 0 },
+            Message::SelfTestResult {
+                bit: 3,
+                report: None,
+            },
+            Message::SetScrollingText {
+                text: "DEPARTURES",
+                config: ScrollConfig {
+                    wrap: ScrollWrap::Continuous { gap: 3 },
+                    ..ScrollConfig::default()
+                },
+            },
+            // The largest chunk still fits a frame
+            Message::Trace(TraceChunk {
+                dropped: u32::MAX,
+                len: TRACE_CHUNK as u8,
+                samples: [TraceSample {
+                    step: u32::MAX,
+                    reading: u32::MAX,
+                    sensor: SensorState::Triggered,
+                    state: BitState::SEEKING,
+                    target_flap: 54,
+                    stepped: true,
+                }; TRACE_CHUNK],
+            }),
+        ];
+
+        for (seq, message) in messages.iter().enumerate() {
+            let (mut out, len) = encoded(seq as u8, message);
+
+            assert_eq!(out[0], 0);
+            assert_eq!(out[len - 1], 0);
+
+            let frame = decode_frame(&mut out[1..len - 1]).unwrap();
+            assert_eq!(
+                frame,
+                Frame {
+                    seq: seq as u8,
+                    message: *message
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_fails_crc() {
+        let (mut out, len) = encoded(9, &Message::SetText("HELLO"));
+        out[4] ^= 0x01;
+
+        assert_eq!(decode_frame(&mut out[1..len - 1]), Err(ProtocolError::Crc));
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let text = core::str::from_utf8(&[b'A'; 300]).unwrap();
+        let mut out = [0u8; MAX_ENCODED_LEN];
+
+        assert_eq!(
+            encode_frame(0, &Message::SetText(text), &mut out),
+            Err(ProtocolError::FrameTooLong)
+        );
+    }
+
+    #[test]
+    fn decoder_passes_through_bytes_outside_frames() {
+        let mut decoder = FrameDecoder::new();
+
+        assert_eq!(decoder.push(b'T'), Received::Byte(b'T'));
+        assert_eq!(decoder.push(b'\n'), Received::Byte(b'\n'));
+    }
+
+    #[test]
+    fn decoder_assembles_frames() {
+        let (out, len) = encoded(7, &Message::QueryStatus { bit: 1 });
+        let mut decoder = FrameDecoder::new();
+
+        for &byte in &out[..len - 1] {
+            assert_eq!(decoder.push(byte), Received::Pending);
+        }
+
+        assert_eq!(
+            decoder.push(0),
+            Received::Frame(Frame {
+                seq: 7,
+                message: Message::QueryStatus { bit: 1 }
+            })
+        );
+    }
+
+    #[test]
+    fn decoder_reports_sequence_of_corrupt_frame() {
+        let (mut out, len) = encoded(7, &Message::SetText("HI"));
+        out[len - 2] ^= 0x10;
+        let mut decoder = FrameDecoder::new();
+
+        for &byte in &out[..len - 1] {
+            decoder.push(byte);
+        }
+
+        assert_eq!(
+            decoder.push(0),
+            Received::Corrupt {
+                seq: Some(7),
+                error: ProtocolError::Crc
+            }
+        );
+    }
+
+    #[test]
+    fn decoder_resynchronizes_after_lost_delimiter() {
+        let (out, len) = encoded(1, &Message::Ack { seq: 0 });
+        let mut decoder = FrameDecoder::new();
+
+        // The leading delimiter of the first frame was lost, so its body arrives as loose bytes
+        // and its trailing delimiter looks like the start of a frame.
+        for &byte in &out[1..len] {
+            decoder.push(byte);
+        }
+
+        let mut frames = 0;
+        for &byte in &out[..len] {
+            if let Received::Frame(frame) = decoder.push(byte) {
+                assert_eq!(
+                    frame,
+                    Frame {
+                        seq: 1,
+                        message: Message::Ack { seq: 0 }
+                    }
+                );
+                frames += 1;
+            }
+        }
+
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn decoder_reports_overflow() {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.push(0);
+        for _ in 0..MAX_ENCODED_LEN + 1 {
+            decoder.push(1);
+        }
+
+        assert_eq!(
+            decoder.push(0),
+            Received::Corrupt {
+                seq: None,
+                error: ProtocolError::FrameTooLong
+            }
+        );
+    }
+}
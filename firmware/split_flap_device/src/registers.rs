@@ -0,0 +1,391 @@
+//! Register map for driving a module board as an I2C peripheral.
+//!
+//! A write transaction starts with the register address, followed by data written to consecutive
+//! registers.  Reads continue from the last address written.  Targets and calibration are staged
+//! and only take effect when `REG_COMMIT` is written, so a controller can update every bit at once.
+//!
+//! | Address              | Access | Contents                                                  |
+//! |----------------------|--------|-----------------------------------------------------------|
+//! | `0x00`               | R      | `DEVICE_ID`                                               |
+//! | `0x01`               | R      | `REGISTER_MAP_VERSION`                                    |
+//! | `0x02`               | R      | Number of bits                                            |
+//! | `0x03`               | W      | Commit, any value applies the staged registers            |
+//! | `0x10 + bit`         | RW     | Staged target character                                   |
+//! | `0x20 + bit`         | R      | `BitState`, 0 uninitialized, 1 seeking, 2 settled         |
+//! | `0x30 + bit`         | R      | Character currently showing                               |
+//! | `0x40 + bit`         | RW     | Fault flags, write 1 to clear                             |
+//! | `0x50 + 4 * bit`     | RW     | Staged trigger then untrigger value, `u16` little endian  |
+
+use crate::device::Device;
+use crate::split_flap_bit_state::{BitState, SensorCalibration, SplitFlapBitState};
+
+pub const DEVICE_ID: u8 = 0x5F;
+pub const REGISTER_MAP_VERSION: u8 = 1;
+
+pub const REG_DEVICE_ID: u8 = 0x00;
+pub const REG_VERSION: u8 = 0x01;
+pub const REG_BIT_COUNT: u8 = 0x02;
+pub const REG_COMMIT: u8 = 0x03;
+pub const REG_TARGET: u8 = 0x10;
+pub const REG_STATE: u8 = 0x20;
+pub const REG_CURRENT: u8 = 0x30;
+pub const REG_FAULTS: u8 = 0x40;
+pub const REG_CALIBRATION: u8 = 0x50;
+
+/// Room for 16 bits in each block of registers.
+pub const MAX_BITS: usize = 16;
+
+/// The bit did not find its home position within two revolutions.
+pub const FAULT_HOMING_TIMEOUT: u8 = 1 << 0;
+
+pub struct RegisterMap<const BITS: usize> {
+    address: u8,
+    /// The next byte written sets `address` rather than a register.
+    expecting_address: bool,
+    targets: [u8; BITS],
+    calibration: [[u8; 4]; BITS],
+    faults: [u8; BITS],
+    /// Each bit's homing timeout count when last seen by `update_faults`.
+    homing_timeouts: [u32; BITS],
+}
+
+impl<const BITS: usize> RegisterMap<BITS> {
+    /// Stages the current targets and calibration of `bits`.
+    pub fn new(bits: &[SplitFlapBitState; BITS]) -> RegisterMap<BITS> {
+        assert!(BITS <= MAX_BITS);
+
+        RegisterMap {
+            address: 0,
+            expecting_address: true,
+            targets: core::array::from_fn(|idx| {
+                bits[idx].character_set()[bits[idx].target_flap().get()]
+            }),
+            calibration: core::array::from_fn(|idx| {
+                encode_calibration(bits[idx].sensor_calibration())
+            }),
+            faults: [0; BITS],
+            homing_timeouts: core::array::from_fn(|idx| {
+                bits[idx].counters().faults.homing_timeouts
+            }),
+        }
+    }
+
+    /// Call on every start or repeated start condition.
+    pub fn begin(&mut self) {
+        self.expecting_address = true;
+    }
+
+    /// Handles bytes sent by the controller, which may be split over several calls.  Committed
+    /// targets become the device's content, so they stay up until the controller sends others.
+    pub fn write(&mut self, data: &[u8], device: &mut Device<BITS>) {
+        for &byte in data {
+            if self.expecting_address {
+                self.address = byte;
+                self.expecting_address = false;
+                continue;
+            }
+
+            self.write_register(self.address, byte, device);
+            self.address = self.address.wrapping_add(1);
+        }
+    }
+
+    /// Fills `out` with consecutive registers for the controller to read.
+    pub fn read(&mut self, bits: &[SplitFlapBitState; BITS], out: &mut [u8]) {
+        for byte in out.iter_mut() {
+            *byte = self.read_register(self.address, bits);
+            self.address = self.address.wrapping_add(1);
+        }
+    }
+
+    pub fn raise_fault(&mut self, bit: usize, flags: u8) {
+        self.faults[bit] |= flags;
+    }
+
+    /// Raises the faults the bits have counted since the last call.
+    pub fn update_faults(&mut self, bits: &[SplitFlapBitState; BITS]) {
+        for (idx, bit) in bits.iter().enumerate() {
+            let homing_timeouts = bit.counters().faults.homing_timeouts;
+
+            if homing_timeouts != self.homing_timeouts[idx] {
+                self.homing_timeouts[idx] = homing_timeouts;
+                self.raise_fault(idx, FAULT_HOMING_TIMEOUT);
+            }
+        }
+    }
+
+    pub fn faults(&self, bit: usize) -> u8 {
+        self.faults[bit]
+    }
+
+    // Splits an address into the base of its block and the offset within it, for registers that
+    // belong to one of the bits
+    fn block(address: u8) -> Option<(u8, usize)> {
+        let (base, width) = match address {
+            REG_TARGET..=0x1F => (REG_TARGET, 1),
+            REG_STATE..=0x2F => (REG_STATE, 1),
+            REG_CURRENT..=0x3F => (REG_CURRENT, 1),
+            REG_FAULTS..=0x4F => (REG_FAULTS, 1),
+            REG_CALIBRATION..=0x8F => (REG_CALIBRATION, 4),
+            _ => return None,
+        };
+        let offset = (address - base) as usize;
+
+        (offset < BITS * width).then_some((base, offset))
+    }
+
+    fn read_register(&self, address: u8, bits: &[SplitFlapBitState; BITS]) -> u8 {
+        match Self::block(address) {
+            Some((REG_TARGET, bit)) => self.targets[bit],
+            Some((REG_STATE, bit)) => match bits[bit].bit_state() {
+                BitState::UNINITIALIZED => 0,
+                BitState::SEEKING => 1,
+                BitState::SETTLED => 2,
+            },
+            Some((REG_CURRENT, bit)) => match bits[bit].bit_state() {
+                BitState::UNINITIALIZED => b' ',
+                _ => bits[bit].character_set()[bits[bit].current_flap().get()],
+            },
+            Some((REG_FAULTS, bit)) => self.faults[bit],
+            Some((REG_CALIBRATION, offset)) => self.calibration[offset / 4][offset % 4],
+            _ => match address {
+                REG_DEVICE_ID => DEVICE_ID,
+                REG_VERSION => REGISTER_MAP_VERSION,
+                REG_BIT_COUNT => BITS as u8,
+                _ => 0,
+            },
+        }
+    }
+
+    // Writes to read-only or unused registers are ignored
+    fn write_register(&mut self, address: u8, value: u8, device: &mut Device<BITS>) {
+        match Self::block(address) {
+            Some((REG_TARGET, bit)) => self.targets[bit] = value,
+            Some((REG_FAULTS, bit)) => self.faults[bit] &= !value,
+            Some((REG_CALIBRATION, offset)) => self.calibration[offset / 4][offset % 4] = value,
+            _ => {
+                if address == REG_COMMIT {
+                    self.commit(device);
+                }
+            }
+        }
+    }
+
+    fn commit(&self, device: &mut Device<BITS>) {
+        for (idx, bit) in device.bits_mut().iter_mut().enumerate() {
+            bit.set_sensor_calibration(decode_calibration(self.calibration[idx]));
+        }
+
+        device.show_text(self.targets);
+    }
+}
+
+fn encode_calibration(calibration: SensorCalibration) -> [u8; 4] {
+    let trigger = calibration.trigger_value.min(u16::MAX as u32) as u16;
+    let untrigger = calibration.untrigger_value.min(u16::MAX as u32) as u16;
+    let [t0, t1] = trigger.to_le_bytes();
+    let [u0, u1] = untrigger.to_le_bytes();
+
+    [t0, t1, u0, u1]
+}
+
+fn decode_calibration(bytes: [u8; 4]) -> SensorCalibration {
+    SensorCalibration {
+        trigger_value: u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+        untrigger_value: u16::from_le_bytes([bytes[2], bytes[3]]) as u32,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        RegisterMap, DEVICE_ID, FAULT_HOMING_TIMEOUT, REG_BIT_COUNT, REG_CALIBRATION, REG_COMMIT,
+        REG_CURRENT, REG_DEVICE_ID, REG_FAULTS, REG_STATE, REG_TARGET,
+    };
+    use crate::device::{Content, Device};
+    use crate::split_flap_bit_state::{SensorCalibration, SplitFlapBitState};
+    use crate::steps::{HomedSteps, Revolution, Steps};
+
+    fn new_device() -> Device<4> {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+
+        let revolution = Revolution::new(Steps::new(58)).unwrap();
+
+        Device::new(core::array::from_fn(|_| {
+            SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+        }))
+    }
+
+    fn read<const N: usize>(
+        map: &mut RegisterMap<4>,
+        device: &mut Device<4>,
+        address: u8,
+    ) -> [u8; N] {
+        let mut out = [0u8; N];
+
+        map.begin();
+        map.write(&[address], device);
+        map.begin();
+        map.read(device.bits(), &mut out);
+
+        out
+    }
+
+    fn write(map: &mut RegisterMap<4>, device: &mut Device<4>, data: &[u8]) {
+        map.begin();
+        map.write(data, device);
+    }
+
+    #[test]
+    fn identification_registers_are_readable() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        assert_eq!(
+            read::<3>(&mut map, &mut device, REG_DEVICE_ID),
+            [DEVICE_ID, 1, 4]
+        );
+        assert_eq!(read::<1>(&mut map, &mut device, REG_BIT_COUNT), [4]);
+    }
+
+    #[test]
+    fn targets_apply_only_on_commit() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+        device.process([2100; 4]);
+
+        write(&mut map, &mut device, &[REG_TARGET, b'A', b'B', b'C', b'D']);
+
+        assert_eq!(read::<4>(&mut map, &mut device, REG_TARGET), *b"ABCD");
+        assert!(device.bits().iter().all(|bit| bit.target_flap().get() == 0));
+
+        write(&mut map, &mut device, &[REG_COMMIT, 1]);
+        device.update_transition(0);
+
+        assert_eq!(
+            device.bits().each_ref().map(|bit| bit.target_flap().get()),
+            [1, 2, 3, 4]
+        );
+        // Kept as the content, so the firmware loop doesn't move on to something else
+        assert_eq!(device.content(), &Content::Text(*b"ABCD"));
+    }
+
+    #[test]
+    fn writes_can_be_split_across_calls() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        map.begin();
+        map.write(&[REG_TARGET + 2], &mut device);
+        map.write(b"X", &mut device);
+        map.write(b"Y", &mut device);
+
+        assert_eq!(read::<4>(&mut map, &mut device, REG_TARGET), *b"  XY");
+    }
+
+    #[test]
+    fn calibration_is_little_endian_per_bit() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        assert_eq!(
+            read::<4>(&mut map, &mut device, REG_CALIBRATION + 4),
+            [0xD0, 0x07, 0x08, 0x07]
+        );
+
+        write(
+            &mut map,
+            &mut device,
+            &[REG_CALIBRATION + 8, 0x98, 0x08, 0x34, 0x08],
+        );
+        write(&mut map, &mut device, &[REG_COMMIT, 1]);
+
+        assert_eq!(
+            device.bits()[2].sensor_calibration(),
+            SensorCalibration {
+                trigger_value: 2200,
+                untrigger_value: 2100
+            }
+        );
+        assert_eq!(device.bits()[1].sensor_calibration().trigger_value, 2000);
+    }
+
+    #[test]
+    fn state_and_current_character_follow_bits() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        assert_eq!(read::<4>(&mut map, &mut device, REG_STATE), [0, 0, 0, 0]);
+        assert_eq!(read::<4>(&mut map, &mut device, REG_CURRENT), *b"    ");
+
+        device.bits_mut()[0].process(2100);
+        device.bits_mut()[0].process(2100);
+
+        assert_eq!(read::<2>(&mut map, &mut device, REG_STATE), [2, 0]);
+        assert_eq!(read::<1>(&mut map, &mut device, REG_CURRENT), [b' ']);
+    }
+
+    #[test]
+    fn faults_are_cleared_by_writing_ones() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        map.raise_fault(3, FAULT_HOMING_TIMEOUT | 0x80);
+        assert_eq!(read::<1>(&mut map, &mut device, REG_FAULTS + 3), [0x81]);
+
+        write(
+            &mut map,
+            &mut device,
+            &[REG_FAULTS + 3, FAULT_HOMING_TIMEOUT],
+        );
+        assert_eq!(map.faults(3), 0x80);
+    }
+
+    #[test]
+    fn homing_timeouts_raise_a_fault_once() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        // Two revolutions without seeing the magnet
+        for _ in 0..2 * 58 * 55 {
+            device.bits_mut()[1].process(0);
+        }
+        map.update_faults(device.bits());
+
+        assert_eq!(map.faults(0), 0);
+        assert_eq!(map.faults(1), FAULT_HOMING_TIMEOUT);
+
+        write(
+            &mut map,
+            &mut device,
+            &[REG_FAULTS + 1, FAULT_HOMING_TIMEOUT],
+        );
+        map.update_faults(device.bits());
+        assert_eq!(map.faults(1), 0);
+
+        // Homing again starts the count afresh
+        device.bits_mut()[1].process(2100);
+        device.bits_mut()[1].rehome();
+        device.bits_mut()[1].process(0);
+        map.update_faults(device.bits());
+        assert_eq!(map.faults(1), 0);
+    }
+
+    #[test]
+    fn unused_registers_read_zero_and_ignore_writes() {
+        let mut device = new_device();
+        let mut map = RegisterMap::new(device.bits());
+
+        write(
+            &mut map,
+            &mut device,
+            &[REG_TARGET + 4, b'Z', REG_DEVICE_ID],
+        );
+
+        assert_eq!(read::<2>(&mut map, &mut device, REG_TARGET + 4), [0, 0]);
+        assert_eq!(read::<1>(&mut map, &mut device, REG_DEVICE_ID), [DEVICE_ID]);
+    }
+}
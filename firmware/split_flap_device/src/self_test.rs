@@ -0,0 +1,262 @@
+//! Checks a newly assembled bit: that the motor turns, the sensor sees the magnet, and a
+//! revolution is as long as expected.
+
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::MIN_SENSOR_SPAN;
+use crate::split_flap_bit_state::SensorCalibration;
+use crate::steps::Revolution;
+
+const REVOLUTIONS: u32 = 2;
+const FAILURE_KINDS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SelfTestFailure {
+    /// The readings barely changed, so the motor isn't turning or the sensor or magnet is missing.
+    NoSignal,
+    /// The darkest reading was above the untrigger value, so the sensor would never release.
+    NeverReleased,
+    /// The brightest reading was below the trigger value, so home would never be found.
+    NeverTriggered,
+    /// The sensor should see the magnet once a revolution, so twice in the test.
+    HomeTriggers,
+    /// The magnet came round more than half a flap away from where a revolution should end,
+    /// from missed steps or the wrong steps per flap.
+    RevolutionLength,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SelfTestReport {
+    pub min: u32,
+    pub max: u32,
+    pub home_triggers: u8,
+    /// Steps between the first two home triggers.
+    pub revolution_steps: Option<u32>,
+    failures: [Option<SelfTestFailure>; FAILURE_KINDS],
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = SelfTestFailure> + '_ {
+        self.failures.iter().flatten().copied()
+    }
+}
+
+/// Spins a bit for two revolutions, watching the home sensor.  The bit must step once after
+/// each sample.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SelfTest {
+    calibration: SensorCalibration,
+    revolution: Revolution,
+    steps: u32,
+    min: u32,
+    max: u32,
+    /// `None` until the first reading, which can't be an edge as the drum may start on the magnet.
+    triggered: Option<bool>,
+    home_triggers: u8,
+    first_trigger: Option<u32>,
+    revolution_steps: Option<u32>,
+}
+
+impl SelfTest {
+    pub fn new(calibration: SensorCalibration, revolution: Revolution) -> SelfTest {
+        SelfTest {
+            calibration,
+            revolution,
+            steps: 0,
+            min: u32::MAX,
+            max: 0,
+            triggered: None,
+            home_triggers: 0,
+            first_trigger: None,
+            revolution_steps: None,
+        }
+    }
+
+    fn revolution(&self) -> u32 {
+        self.revolution.steps().get()
+    }
+
+    /// Readings still to take.  The first only sets the starting sensor state, so one more is
+    /// taken than there are steps in the revolutions, and the magnet is crossed exactly twice
+    /// wherever the drum starts.
+    pub fn steps_remaining(&self) -> u32 {
+        REVOLUTIONS
+            .saturating_mul(self.revolution())
+            .saturating_add(1)
+            .saturating_sub(self.steps)
+    }
+
+    /// Records a reading.  Returns `None` until both revolutions are complete.
+    pub fn sample(&mut self, sensor_value: u32) -> Option<SelfTestReport> {
+        self.min = self.min.min(sensor_value);
+        self.max = self.max.max(sensor_value);
+
+        let triggered = match self.triggered {
+            Some(false) if sensor_value > self.calibration.trigger_value => {
+                self.count_trigger();
+                true
+            }
+            Some(true) if sensor_value < self.calibration.untrigger_value => false,
+            Some(triggered) => triggered,
+            None => sensor_value > self.calibration.untrigger_value,
+        };
+        self.triggered = Some(triggered);
+        self.steps += 1;
+
+        (self.steps_remaining() == 0).then(|| self.report())
+    }
+
+    fn count_trigger(&mut self) {
+        self.home_triggers = self.home_triggers.saturating_add(1);
+
+        match self.first_trigger {
+            None => self.first_trigger = Some(self.steps),
+            Some(first) if self.revolution_steps.is_none() => {
+                self.revolution_steps = Some(self.steps - first)
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn report(&self) -> SelfTestReport {
+        let revolution = self.revolution();
+        let length_ok = self.revolution_steps.is_some_and(|steps| {
+            steps.abs_diff(revolution) <= self.revolution.steps_per_flap().get() / 2
+        });
+
+        let checks = [
+            (
+                self.max - self.min < MIN_SENSOR_SPAN,
+                SelfTestFailure::NoSignal,
+            ),
+            (
+                self.min >= self.calibration.untrigger_value,
+                SelfTestFailure::NeverReleased,
+            ),
+            (
+                self.max <= self.calibration.trigger_value,
+                SelfTestFailure::NeverTriggered,
+            ),
+            (
+                self.home_triggers != REVOLUTIONS as u8,
+                SelfTestFailure::HomeTriggers,
+            ),
+            (!length_ok, SelfTestFailure::RevolutionLength),
+        ];
+
+        SelfTestReport {
+            min: self.min,
+            max: self.max,
+            home_triggers: self.home_triggers,
+            revolution_steps: self.revolution_steps,
+            failures: checks.map(|(failed, failure)| failed.then_some(failure)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{SelfTest, SelfTestFailure, SelfTestReport};
+    use crate::split_flap_bit_state::SensorCalibration;
+    use crate::steps::{Revolution, Steps};
+
+    const STEPS_PER_FLAP: u32 = 2;
+    const REVOLUTION: u32 = STEPS_PER_FLAP * 55;
+
+    fn new_test() -> SelfTest {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+
+        SelfTest::new(
+            calibration,
+            Revolution::new(Steps::new(STEPS_PER_FLAP)).unwrap(),
+        )
+    }
+
+    // Runs the test on a drum starting at `start`, whose sensor reads `reading(position)`
+    fn run(start: u32, revolution: u32, reading: impl Fn(u32) -> u32) -> SelfTestReport {
+        let mut test = new_test();
+        let mut position = start;
+
+        loop {
+            if let Some(report) = test.sample(reading(position % revolution)) {
+                assert_eq!(test.steps_remaining(), 0);
+                return report;
+            }
+
+            position += 1;
+        }
+    }
+
+    fn magnet(position: u32) -> u32 {
+        if position < 3 {
+            3000
+        } else {
+            500
+        }
+    }
+
+    fn failures(report: &SelfTestReport) -> Vec<SelfTestFailure> {
+        report.failures().collect()
+    }
+
+    #[test]
+    fn healthy_bit_passes_from_anywhere_on_the_drum() {
+        for start in [0, 1, 2, 3, 50, REVOLUTION - 1] {
+            let report = run(start, REVOLUTION, magnet);
+
+            assert!(report.passed(), "Failed from {}: {:?}", start, report);
+            assert_eq!(report.home_triggers, 2);
+            assert_eq!(report.revolution_steps, Some(REVOLUTION));
+            assert_eq!((report.min, report.max), (500, 3000));
+        }
+    }
+
+    #[test]
+    fn stalled_motor_has_no_signal() {
+        let report = run(0, REVOLUTION, |_| 600);
+
+        assert_eq!(
+            failures(&report),
+            [
+                SelfTestFailure::NoSignal,
+                SelfTestFailure::NeverTriggered,
+                SelfTestFailure::HomeTriggers,
+                SelfTestFailure::RevolutionLength,
+            ]
+        );
+    }
+
+    #[test]
+    fn short_revolution_is_reported() {
+        // Skipping steps makes the drum come round early
+        let report = run(10, REVOLUTION - 8, magnet);
+
+        assert_eq!(report.home_triggers, 2);
+        assert_eq!(report.revolution_steps, Some(REVOLUTION - 8));
+        assert_eq!(failures(&report), [SelfTestFailure::RevolutionLength]);
+    }
+
+    #[test]
+    fn dim_sensor_never_triggers() {
+        let report = run(
+            0,
+            REVOLUTION,
+            |position| if position < 3 { 1900 } else { 500 },
+        );
+
+        assert!(failures(&report).contains(&SelfTestFailure::NeverTriggered));
+        assert!(!failures(&report).contains(&SelfTestFailure::NeverReleased));
+        assert_eq!(report.home_triggers, 0);
+    }
+}
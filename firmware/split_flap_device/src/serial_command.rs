@@ -0,0 +1,175 @@
+use crate::clock::{DateFormat, DateTime, DisplayMode, HourFormat};
+
+/// A line of text sent to the device over the USB serial port.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command<'a> {
+    /// `TEXT <message>`
+    Text(&'a [u8]),
+    /// `TIME <YYYY-MM-DD HH:MM:SS>`
+    SetTime(DateTime),
+    /// `CLOCK [12|24]`, `DATE [YMD|DM|MD]` or `COUNTDOWN <YYYY-MM-DD HH:MM:SS>`
+    Show(DisplayMode),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CommandError {
+    Empty,
+    UnknownCommand,
+    InvalidArgument,
+}
+
+pub fn parse_command(line: &[u8]) -> Result<Command<'_>, CommandError> {
+    let line = trim(line);
+
+    if line.is_empty() {
+        return Err(CommandError::Empty);
+    }
+
+    let (name, argument) = match line.iter().position(|&c| c == b' ') {
+        Some(idx) => (&line[..idx], trim(&line[idx + 1..])),
+        None => (line, &line[line.len()..]),
+    };
+
+    if name.eq_ignore_ascii_case(b"TEXT") {
+        Ok(Command::Text(argument))
+    } else if name.eq_ignore_ascii_case(b"TIME") {
+        Ok(Command::SetTime(parse_date_time(argument)?))
+    } else if name.eq_ignore_ascii_case(b"CLOCK") {
+        let format = match argument {
+            b"" | b"24" => HourFormat::TwentyFourHour,
+            b"12" => HourFormat::TwelveHour,
+            _ => return Err(CommandError::InvalidArgument),
+        };
+
+        Ok(Command::Show(DisplayMode::Clock(format)))
+    } else if name.eq_ignore_ascii_case(b"DATE") {
+        let format = if argument.is_empty() || argument.eq_ignore_ascii_case(b"YMD") {
+            DateFormat::YearMonthDay
+        } else if argument.eq_ignore_ascii_case(b"DM") {
+            DateFormat::DayMonth
+        } else if argument.eq_ignore_ascii_case(b"MD") {
+            DateFormat::MonthDay
+        } else {
+            return Err(CommandError::InvalidArgument);
+        };
+
+        Ok(Command::Show(DisplayMode::Date(format)))
+    } else if name.eq_ignore_ascii_case(b"COUNTDOWN") {
+        Ok(Command::Show(DisplayMode::Countdown(parse_date_time(
+            argument,
+        )?)))
+    } else {
+        Err(CommandError::UnknownCommand)
+    }
+}
+
+fn parse_date_time(argument: &[u8]) -> Result<DateTime, CommandError> {
+    let mut text = [0u8; 19];
+
+    if argument.len() > text.len() {
+        return Err(CommandError::InvalidArgument);
+    }
+
+    // Input may have been upper-cased on the way in, so accept `t` and `T` alike
+    let text = &mut text[..argument.len()];
+    text.copy_from_slice(argument);
+    text.make_ascii_uppercase();
+
+    DateTime::parse(text).ok_or(CommandError::InvalidArgument)
+}
+
+fn trim(line: &[u8]) -> &[u8] {
+    let is_padding = |c: &u8| c.is_ascii_whitespace();
+
+    let start = line
+        .iter()
+        .position(|c| !is_padding(c))
+        .unwrap_or(line.len());
+    let end = line
+        .iter()
+        .rposition(|c| !is_padding(c))
+        .map_or(start, |idx| idx + 1);
+
+    &line[start..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_command, Command, CommandError};
+    use crate::clock::{DateFormat, DateTime, DisplayMode, HourFormat};
+
+    fn new_year() -> DateTime {
+        DateTime {
+            year: 2025,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+
+    #[test]
+    fn text_keeps_message() {
+        assert_eq!(
+            parse_command(b"TEXT HELLO WORLD\r\n"),
+            Ok(Command::Text(b"HELLO WORLD"))
+        );
+    }
+
+    #[test]
+    fn time_sets_date_time() {
+        assert_eq!(
+            parse_command(b"time 2025-01-01t00:00:00"),
+            Ok(Command::SetTime(new_year()))
+        );
+    }
+
+    #[test]
+    fn clock_defaults_to_24_hour() {
+        assert_eq!(
+            parse_command(b"CLOCK"),
+            Ok(Command::Show(DisplayMode::Clock(
+                HourFormat::TwentyFourHour
+            )))
+        );
+        assert_eq!(
+            parse_command(b"CLOCK 12"),
+            Ok(Command::Show(DisplayMode::Clock(HourFormat::TwelveHour)))
+        );
+    }
+
+    #[test]
+    fn date_accepts_formats() {
+        assert_eq!(
+            parse_command(b"DATE DM"),
+            Ok(Command::Show(DisplayMode::Date(DateFormat::DayMonth)))
+        );
+        assert_eq!(
+            parse_command(b"DATE"),
+            Ok(Command::Show(DisplayMode::Date(DateFormat::YearMonthDay)))
+        );
+    }
+
+    #[test]
+    fn countdown_parses_target() {
+        assert_eq!(
+            parse_command(b"COUNTDOWN 2025-01-01 00:00:00"),
+            Ok(Command::Show(DisplayMode::Countdown(new_year())))
+        );
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        assert_eq!(parse_command(b"  \r\n"), Err(CommandError::Empty));
+        assert_eq!(parse_command(b"REBOOT"), Err(CommandError::UnknownCommand));
+        assert_eq!(
+            parse_command(b"CLOCK 13"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(
+            parse_command(b"TIME TOMORROW"),
+            Err(CommandError::InvalidArgument)
+        );
+    }
+}
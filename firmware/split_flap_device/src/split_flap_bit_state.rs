@@ -1,106 +1,58 @@
-// use std::convert::From;
-use core::cmp::PartialEq;
-use core::ops::{self, Add, Mul, Rem};
+use serde::{Deserialize, Serialize};
 
-const CHARACTER_SET: [u8; 55] = [
+use crate::idle::IdlePolicy;
+use crate::steps::{FlapIndex, HomedSteps, Offset, Revolution, StepperConfig, Steps};
+
+pub const CHARACTER_SET: [u8; 55] = [
     b' ', b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', b'M', b'N', b'O',
     b'P', b'Q', b'R', b'S', b'T', b'U', b'V', b'W', b'X', b'Y', b'Z', b'0', b'1', b'2', b'3', b'4',
     b'5', b'6', b'7', b'8', b'9', b':', b'-', b'_', b'.', b'%', b'@', b'/', 0x01, 0x02, 0x03, 0x04,
     0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
 ];
 
-#[derive(Clone, Copy)]
-struct Steps {
-    steps: u32,
-}
-
-impl Mul<u32> for Steps {
-    type Output = Self;
-    fn mul(self, rhs: u32) -> Self::Output {
-        Steps {
-            steps: self.steps * rhs,
-        }
-    }
-}
-
-impl Mul<usize> for Steps {
-    type Output = Self;
-    fn mul(self, rhs: usize) -> Self::Output {
-        self.mul(rhs as u32)
-    }
-}
-
-#[derive(Clone, Copy)]
-struct HomedSteps {
-    homed_steps: u32,
-}
-
-impl Add<Steps> for HomedSteps {
-    type Output = Self;
-    fn add(self, rhs: Steps) -> Self::Output {
-        HomedSteps {
-            homed_steps: self.homed_steps + rhs.steps,
-        }
-    }
-}
-
-impl Rem<Steps> for HomedSteps {
-    type Output = Self;
-    fn rem(self, rhs: Steps) -> Self::Output {
-        HomedSteps {
-            homed_steps: self.homed_steps % rhs.steps,
-        }
-    }
-}
-
-
-impl PartialEq<HomedSteps> for HomedSteps {
-    fn eq(&self, other: &HomedSteps) -> bool {
-        self.homed_steps == other.homed_steps
-    }
-}
-
-impl HomedSteps {
-    fn from_offset(homing_offset: u32) -> HomedSteps {
-        HomedSteps {
-            homed_steps: homing_offset,
-        }
-    }
-
-    fn from(steps: Steps, homed_steps_offset: HomedSteps) -> HomedSteps {
-        HomedSteps {
-            homed_steps: steps.steps + homed_steps_offset.homed_steps,
-        }
-    }
-
-    fn empty() -> HomedSteps {
-        HomedSteps { homed_steps: 0 }
-    }
-
-    fn clear(&mut self) {
-        self.homed_steps = 0;
-    }
-
-    fn inc(&mut self) {
-        self.homed_steps += 1;
-    }
-}
-
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub enum BitState {
     UNINITIALIZED,
     SEEKING,
     SETTLED,
 }
 
-#[derive(Clone, Copy)]
+/// Running totals kept by a bit, showing how hard it has been working.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct BitCounters {
+    pub steps: u64,
+    pub revolutions: u32,
+    /// Times the sensor saw the magnet come round.
+    pub home_detections: u32,
+    /// Times the bit forgot its position and had to find home again.
+    pub rehomes: u32,
+    /// Times the sensor found home away from where the step count expected it.
+    pub step_loss_corrections: u32,
+    pub faults: FaultCounters,
+    /// Times the bit came to rest after moving.
+    pub characters_displayed: u32,
+    /// Microseconds spent in each `BitState`, as passed to `record_time`.
+    pub uninitialized_us: u64,
+    pub seeking_us: u64,
+    pub settled_us: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct FaultCounters {
+    /// Two revolutions passed while homing without the sensor seeing the magnet.
+    pub homing_timeouts: u32,
+    /// The position restored on power up turned out to be wrong.
+    pub restore_mismatches: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct SensorCalibration {
     pub trigger_value: u32,
     pub untrigger_value: u32,
 }
 
-#[derive(PartialEq)]
-enum SensorState {
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SensorState {
     Triggered,
     Untriggered,
 }
@@ -108,60 +60,301 @@ enum SensorState {
 pub struct SplitFlapBitState {
     sensor_calibration: SensorCalibration,
     bit_state: BitState,
-    steps_per_flap: Steps,
+    stepper: StepperConfig,
+    // Worked out from `stepper`
+    revolution: Revolution,
     offset_steps_to_first_position: HomedSteps,
     steps_since_home: HomedSteps,
     target_steps: HomedSteps,
     sensor_state: SensorState,
+    // Per flap corrections for drums whose flaps are not evenly spaced
+    flap_trims: [i16; CHARACTER_SET.len()],
+    // What is printed on each flap, for drums that differ from `CHARACTER_SET`
+    character_set: [u8; CHARACTER_SET.len()],
+    // Whether to restore the position saved before a power cut rather than homing
+    restores_position: bool,
+    // The position came from `restore` and the sensor has yet to confirm it
+    unverified: bool,
+    idle_policy: IdlePolicy,
+    counters: BitCounters,
+    // Steps towards the next whole revolution in `counters`
+    revolution_steps: u32,
+    // Steps taken while uninitialized, to spot a sensor that never triggers
+    homing_steps: u32,
 }
 
 impl SplitFlapBitState {
+    /// A bit on a drum known only by its steps, as though turned directly by the motor.
     pub fn new(
         sensor_calibration: SensorCalibration,
-        steps_per_flap: u32,
-        offset_steps_to_first_position: u32,
+        revolution: Revolution,
+        offset_steps_to_first_position: HomedSteps,
     ) -> SplitFlapBitState {
-        SplitFlapBitState {
-            sensor_calibration: sensor_calibration,
+        let stepper = StepperConfig::direct(revolution);
+
+        SplitFlapBitState::with_stepper(
+            sensor_calibration,
+            stepper,
+            Offset::Steps(offset_steps_to_first_position),
+        )
+        .expect("a direct drive makes the revolution it came from")
+    }
+
+    /// A bit driven by `stepper`, with step counts worked out from it.  `None` if the stepper
+    /// doesn't turn the drum in a whole number of steps, as checked by `StepperConfig::revolution`.
+    pub fn with_stepper(
+        sensor_calibration: SensorCalibration,
+        stepper: StepperConfig,
+        offset_to_first_position: Offset,
+    ) -> Option<SplitFlapBitState> {
+        let revolution = stepper.revolution()?;
+
+        Some(SplitFlapBitState {
+            sensor_calibration,
             bit_state: BitState::UNINITIALIZED,
-            steps_per_flap: Steps {
-                steps: steps_per_flap,
-            },
-            offset_steps_to_first_position: HomedSteps::from_offset(offset_steps_to_first_position),
-            steps_since_home: HomedSteps::empty(),
-            target_steps: HomedSteps::empty(),
+            stepper,
+            revolution,
+            offset_steps_to_first_position: offset_to_first_position.position(revolution),
+            steps_since_home: HomedSteps::HOME,
+            target_steps: HomedSteps::HOME,
             sensor_state: SensorState::Untriggered,
-        }
+            flap_trims: [0; CHARACTER_SET.len()],
+            character_set: CHARACTER_SET,
+            restores_position: false,
+            unverified: false,
+            idle_policy: IdlePolicy::Release,
+            counters: BitCounters::default(),
+            revolution_steps: 0,
+            homing_steps: 0,
+        })
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.bit_state == BitState::SETTLED
+    }
+
+    pub fn bit_state(&self) -> BitState {
+        self.bit_state
+    }
+
+    pub fn sensor_state(&self) -> SensorState {
+        self.sensor_state
+    }
+
+    /// Only meaningful once the bit has homed.
+    pub fn steps_since_home(&self) -> HomedSteps {
+        self.steps_since_home
     }
 
-    pub fn set_homed(&mut self, is_homed: bool) {
+    pub fn sensor_calibration(&self) -> SensorCalibration {
+        self.sensor_calibration
+    }
+
+    pub fn set_sensor_calibration(&mut self, sensor_calibration: SensorCalibration) {
+        self.sensor_calibration = sensor_calibration;
+    }
+
+    pub fn stepper(&self) -> StepperConfig {
+        self.stepper
+    }
+
+    pub fn revolution(&self) -> Revolution {
+        self.revolution
+    }
+
+    pub fn steps_per_flap(&self) -> Steps {
+        self.revolution.steps_per_flap()
+    }
+
+    pub fn offset_steps_to_first_position(&self) -> HomedSteps {
+        self.offset_steps_to_first_position
+    }
+
+    pub fn set_homed(&mut self, _is_homed: bool) {
         // self.steps_since_home = 0;
     }
 
-    fn lookup_target_character_position(&self, target_character_code: u8) -> u32 {
-        let position = CHARACTER_SET
+    /// Forgets the home position so the bit finds it again.  Once homed it returns to the first
+    /// flap, like a bit that has just powered up.
+    pub fn rehome(&mut self) {
+        self.bit_state = BitState::UNINITIALIZED;
+        self.sensor_state = SensorState::Untriggered;
+        self.unverified = false;
+        self.homing_steps = 0;
+        self.counters.rehomes += 1;
+    }
+
+    pub fn counters(&self) -> BitCounters {
+        self.counters
+    }
+
+    /// Carries on from counters saved before a power cut.
+    pub fn set_counters(&mut self, counters: BitCounters) {
+        self.counters = counters;
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.counters = BitCounters::default();
+    }
+
+    /// Adds `elapsed_us` to the time spent in the current state.
+    pub fn record_time(&mut self, elapsed_us: u32) {
+        let total = match self.bit_state {
+            BitState::UNINITIALIZED => &mut self.counters.uninitialized_us,
+            BitState::SEEKING => &mut self.counters.seeking_us,
+            BitState::SETTLED => &mut self.counters.settled_us,
+        };
+
+        *total += elapsed_us as u64;
+    }
+
+    pub fn restores_position(&self) -> bool {
+        self.restores_position
+    }
+
+    /// Whether the firmware should save this bit's position and `restore` it on power up.
+    pub fn set_restores_position(&mut self, restores_position: bool) {
+        self.restores_position = restores_position;
+    }
+
+    pub fn idle_policy(&self) -> IdlePolicy {
+        self.idle_policy
+    }
+
+    /// What the firmware does with the motor driver once the bit settles.
+    pub fn set_idle_policy(&mut self, idle_policy: IdlePolicy) {
+        self.idle_policy = idle_policy;
+    }
+
+    /// Steps past home where the bit has come to rest, to be saved for `restore`.  `None` while
+    /// the bit is homing or moving.
+    pub fn settled_position(&self) -> Option<HomedSteps> {
+        let at_rest =
+            self.bit_state != BitState::UNINITIALIZED && self.steps_since_home == self.target_steps;
+
+        at_rest.then_some(self.steps_since_home)
+    }
+
+    /// Assumes the bit is where it was saved by `settled_position`, instead of spinning to find
+    /// home.  The position is checked when the sensor next passes home, and if it was wrong the
+    /// bit homes there as though it had just powered up.
+    pub fn restore(&mut self, steps_since_home: HomedSteps) {
+        self.steps_since_home = self.revolution.wrap(steps_since_home);
+        self.target_steps = self.steps_since_home;
+        self.bit_state = BitState::SETTLED;
+        // The drum may have stopped over the magnet, so only a fresh crossing counts as home
+        self.sensor_state = SensorState::Triggered;
+        self.unverified = true;
+    }
+
+    pub fn character_set(&self) -> &[u8; CHARACTER_SET.len()] {
+        &self.character_set
+    }
+
+    /// Replaces the characters printed on the drum, keeping the flap it is seeking.
+    pub fn set_character_set(&mut self, character_set: [u8; CHARACTER_SET.len()]) {
+        self.character_set = character_set;
+    }
+
+    fn lookup_target_character_position(&self, target_character_code: u8) -> FlapIndex {
+        self.character_set
             .iter()
             .position(|&c| c == target_character_code)
-            .unwrap_or(0);
-
-        position as u32
+            .map_or(FlapIndex::FIRST, FlapIndex::wrapping)
     }
 
     fn lookup_target_character_steps(&self, target_character_code: u8) -> HomedSteps {
         let target_position = self.lookup_target_character_position(target_character_code);
 
-       (self.offset_steps_to_first_position + (self.steps_per_flap * target_position)) % (self.steps_per_flap * CHARACTER_SET.len())
+        self.flap_steps(target_position)
+    }
+
+    fn flap_steps(&self, flap: FlapIndex) -> HomedSteps {
+        let untrimmed = self.revolution.advance(
+            self.offset_steps_to_first_position,
+            self.revolution.flap_steps(flap),
+        );
+        let trim =
+            (self.flap_trims[flap.get()] as i64).rem_euclid(self.revolution.steps().get() as i64);
+
+        self.revolution.advance(untrimmed, Steps::new(trim as u32))
+    }
+
+    // The flap showing at the given step count
+    fn steps_flap(&self, homed_steps: HomedSteps) -> FlapIndex {
+        let steps_past_first = self
+            .revolution
+            .distance(self.offset_steps_to_first_position, homed_steps);
+
+        let flap = self.revolution.flap_at(steps_past_first);
+        let next = flap.forward(1);
+
+        // A negative trim stops the next flap slightly early
+        let next_start = self.revolution.flap_end(flap).get();
+        match next_start.checked_add_signed(self.flap_trims[next.get()] as i32) {
+            Some(next_stop) if steps_past_first.get() >= next_stop => next,
+            _ => flap,
+        }
+    }
+
+    pub fn flap_trim(&self, flap: FlapIndex) -> i16 {
+        self.flap_trims[flap.get()]
+    }
+
+    /// Moves where `flap` stops by `trim` steps.  Trims must stay under
+    /// `Revolution::trim_limit`.
+    pub fn set_flap_trim(&mut self, flap: FlapIndex, trim: i16) {
+        let target_flap = self.target_flap();
+
+        self.flap_trims[flap.get()] = trim;
+
+        if self.bit_state != BitState::UNINITIALIZED {
+            self.set_target_flap(target_flap);
+        }
+    }
+
+    /// The flap showing `character`, or the first flap if none does.
+    pub fn flap_of(&self, character: u8) -> FlapIndex {
+        self.lookup_target_character_position(character)
     }
 
     pub fn set_target_character(&mut self, target_character: u8) {
         self.target_steps = self.lookup_target_character_steps(target_character);
     }
 
+    pub fn set_target_flap(&mut self, flap: FlapIndex) {
+        self.target_steps = self.flap_steps(flap);
+    }
+
+    pub fn target_flap(&self) -> FlapIndex {
+        self.steps_flap(self.target_steps)
+    }
+
+    /// Only meaningful once the bit has homed.
+    pub fn current_flap(&self) -> FlapIndex {
+        self.steps_flap(self.steps_since_home)
+    }
+
     fn process_sensor(&mut self, sensor_value: u32) {
         if sensor_value > self.sensor_calibration.trigger_value {
             if self.sensor_state == SensorState::Untriggered {
                 self.sensor_state = SensorState::Triggered;
-                self.steps_since_home.clear();
+                self.counters.home_detections += 1;
+
+                if self.unverified {
+                    self.unverified = false;
+
+                    if !self.near_home() {
+                        self.bit_state = BitState::UNINITIALIZED;
+                        self.homing_steps = 0;
+                        self.counters.rehomes += 1;
+                        self.counters.faults.restore_mismatches += 1;
+                    }
+                } else if self.bit_state != BitState::UNINITIALIZED && !self.near_home() {
+                    self.counters.step_loss_corrections += 1;
+                }
+
+                self.steps_since_home = HomedSteps::HOME;
 
                 if self.bit_state == BitState::UNINITIALIZED {
                     //First homing of the bit.  Set us up to seek to the home position.
@@ -174,29 +367,73 @@ impl SplitFlapBitState {
         }
     }
 
+    // Whether the step count agrees with the sensor being at home, to within half a flap
+    fn near_home(&self) -> bool {
+        let revolution = self.revolution.steps().get();
+        let past_home = self.revolution.wrap(self.steps_since_home).get();
+
+        past_home.min(revolution - past_home) <= self.steps_per_flap().get() / 2
+    }
+
     pub fn process(&mut self, sensor_value: u32) -> bool {
         self.process_sensor(sensor_value);
 
         if self.bit_state == BitState::UNINITIALIZED {
+            self.homing_steps = self.homing_steps.saturating_add(1);
+
+            if self.revolution.steps().checked_mul(2) == Some(Steps::new(self.homing_steps)) {
+                self.counters.faults.homing_timeouts += 1;
+            }
+
+            self.count_step();
             return true;
         }
 
-        let needs_step = !(self.steps_since_home == self.target_steps);
+        let needs_step = self.steps_since_home != self.target_steps;
 
         if needs_step {
             self.bit_state = BitState::SEEKING;
             //Assume the step will be taken
-            self.steps_since_home.inc();
+            self.steps_since_home = self
+                .revolution
+                .advance(self.steps_since_home, Steps::new(1));
+            self.count_step();
         } else {
+            if self.bit_state == BitState::SEEKING {
+                self.counters.characters_displayed += 1;
+            }
+
             self.bit_state = BitState::SETTLED;
         }
 
         needs_step
     }
+
+    fn count_step(&mut self) {
+        self.counters.steps += 1;
+        self.revolution_steps += 1;
+
+        if self.revolution_steps >= self.revolution.steps().get() {
+            self.revolution_steps = 0;
+            self.counters.revolutions += 1;
+        }
+    }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::split_flap_bit_state::{BitState, HomedSteps};
+    use crate::split_flap_bit_state::BitState;
+    use crate::steps::{
+        FlapIndex, GearRatio, HomedSteps, Offset, Revolution, StepperConfig, Steps,
+    };
+
+    fn revolution(steps_per_flap: u32) -> Revolution {
+        Revolution::new(Steps::new(steps_per_flap)).unwrap()
+    }
+
+    fn flap(index: usize) -> FlapIndex {
+        FlapIndex::new(index).unwrap()
+    }
 
     #[test]
     fn new_starts_uninitialized() {
@@ -204,7 +441,8 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let result = super::SplitFlapBitState::new(calibration, 58, 58 * 6);
+        let result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(58 * 6));
 
         assert_eq!(result.bit_state as u32, BitState::UNINITIALIZED as u32)
     }
@@ -215,10 +453,11 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let result = super::SplitFlapBitState::new(calibration, 58, 58 * 6);
+        let result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(58 * 6));
 
         assert!(
-            result.steps_since_home == HomedSteps::empty(),
+            result.steps_since_home == HomedSteps::HOME,
             "steps_since_home is not empty"
         )
     }
@@ -229,10 +468,11 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let result = super::SplitFlapBitState::new(calibration, 58, 58 * 6);
+        let result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(58 * 6));
 
         assert!(
-            result.target_steps == HomedSteps::empty(),
+            result.target_steps == HomedSteps::HOME,
             "target_steps is not empty"
         )
     }
@@ -243,14 +483,15 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 58 * 6);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(58 * 6));
 
         let sensor_value: u32 = 2100;
 
         result.process(sensor_value);
 
         assert_eq!(result.bit_state as u32, BitState::SEEKING as u32);
-        assert_eq!(result.target_steps.homed_steps, 58 * 6);
+        assert_eq!(result.target_steps.get(), 58 * 6);
     }
 
     #[test]
@@ -259,14 +500,15 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 58 * 6);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(58 * 6));
 
         let sensor_value: u32 = 2100;
 
         result.process(sensor_value);
         result.process(sensor_value);
 
-        assert_eq!(result.steps_since_home.homed_steps, 2);
+        assert_eq!(result.steps_since_home.get(), 2);
     }
 
     #[test]
@@ -275,7 +517,8 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
         let sensor_value: u32 = 2100;
 
@@ -298,15 +541,16 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
         let sensor_value: u32 = 2100;
 
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
         assert!(
             result.bit_state == BitState::SETTLED,
             "Target reached, bit state not settled"
@@ -319,19 +563,20 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
         let sensor_value: u32 = 2100;
 
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
-        result.set_target_character('A' as u8);
+        result.set_target_character(b'A');
 
-        assert_eq!(result.target_steps.homed_steps, 3 + 58);
+        assert_eq!(result.target_steps.get(), 3 + 58);
     }
 
     #[test]
@@ -340,19 +585,20 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
         let sensor_value: u32 = 2100;
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
         let sensor_value: u32 = 100;
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
-        result.set_target_character('A' as u8);
+        result.set_target_character(b'A');
 
         let process = result.process(sensor_value);
 
@@ -365,25 +611,26 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
         let sensor_value: u32 = 2100;
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
         let sensor_value: u32 = 100;
-        let process = result.process(sensor_value);
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
+        result.process(sensor_value);
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
-        result.set_target_character('V' as u8);
-        result.set_target_character('H' as u8);
+        result.set_target_character(b'V');
+        result.set_target_character(b'H');
 
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
         assert_eq!(
-            result.target_steps.homed_steps,
+            result.target_steps.get(),
             (8 * 58) + 3,
             "Target steps is not as expected"
         );
@@ -395,17 +642,18 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 6 * 58 );
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(6 * 58));
 
         let sensor_value: u32 = 2100;
 
         //Leads to home position found
-        let process = result.process(sensor_value);
+        result.process(sensor_value);
 
-        result.set_target_character(0x0A as u8);
+        result.set_target_character(0x0A);
 
         assert_eq!(
-            result.target_steps.homed_steps,
+            result.target_steps.get(),
             (4 * 58),
             "Target steps is not as expected"
         );
@@ -417,36 +665,319 @@ mod test {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let result = super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
-        let position = result.lookup_target_character_position(' ' as u8);
+        let position = result.lookup_target_character_position(b' ');
 
-        assert_eq!(position, 0);
+        assert_eq!(position, flap(0));
     }
 
     #[test]
+    #[allow(non_snake_case)]
     fn A_returns_position_1() {
         let calibration = super::SensorCalibration {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let result = super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
 
-        let position = result.lookup_target_character_position('A' as u8);
+        let position = result.lookup_target_character_position(b'A');
 
-        assert_eq!(position, 1);
+        assert_eq!(position, flap(1));
     }
 
     #[test]
+    #[allow(non_snake_case)]
     fn H_returns_position_8() {
         let calibration = super::SensorCalibration {
             trigger_value: 2000,
             untrigger_value: 1800,
         };
-        let mut result = super::SplitFlapBitState::new(calibration, 58, 3);
+        let result = super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(3));
+
+        let position = result.lookup_target_character_position(b'H');
+
+        assert_eq!(position, flap(8));
+    }
+
+    #[test]
+    fn setting_target_flap_matches_setting_character() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(6 * 58));
+
+        result.set_target_flap(flap(8));
+
+        assert_eq!(result.target_steps.get(), (14 * 58));
+        assert_eq!(result.target_flap(), flap(8));
+    }
+
+    #[test]
+    fn current_flap_follows_steps_since_home() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.process(2100);
+        assert_eq!(result.current_flap(), flap(53));
+
+        for _ in 0..4 {
+            result.process(100);
+        }
+
+        assert_eq!(result.current_flap(), flap(0));
+        assert!(result.is_settled(), "Bit did not settle on first flap");
+    }
+
+    #[test]
+    fn rehome_seeks_home_again() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.process(2100);
+        for _ in 0..4 {
+            result.process(100);
+        }
+        assert!(result.is_settled());
+
+        result.rehome();
+
+        assert!(
+            result.process(100),
+            "Bit did not step while looking for home"
+        );
+        assert_eq!(result.bit_state(), BitState::UNINITIALIZED);
+
+        result.process(2100);
+        assert_eq!(result.bit_state(), BitState::SEEKING);
+    }
+
+    #[test]
+    fn flap_trim_moves_target_steps() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(6 * 58));
+
+        result.process(2100);
+        result.set_target_flap(flap(8));
+        result.set_flap_trim(flap(8), -3);
+
+        assert_eq!(result.target_steps.get(), (14 * 58) - 3);
+        assert_eq!(result.target_flap(), flap(8));
+        assert_eq!(result.flap_trim(flap(8)), -3);
+
+        result.set_flap_trim(flap(0), -3);
+        result.set_target_flap(flap(0));
+
+        // Flap 0 sits just before the first position, so the trim wraps into the offset
+        assert_eq!(result.target_steps.get(), (6 * 58) - 3);
+        assert_eq!(result.target_flap(), flap(0));
+    }
 
-        let position = result.lookup_target_character_position('H' as u8);
+    #[test]
+    fn microstepped_bit_works_out_its_steps() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let stepper = StepperConfig {
+            full_steps_per_revolution: 200,
+            microsteps: 16,
+            gear_ratio: GearRatio::DIRECT,
+        };
+        let mut result =
+            super::SplitFlapBitState::with_stepper(calibration, stepper, Offset::Flaps(5)).unwrap();
+
+        assert_eq!(result.stepper(), stepper);
+        assert_eq!(result.revolution().steps(), Steps::new(3200));
+        assert_eq!(result.offset_steps_to_first_position().get(), 290);
+
+        result.process(2100);
+        result.set_target_character(b'K');
+
+        // 3200 steps don't share evenly between 55 flaps, so flap 11 starts at 640 not 638
+        assert_eq!(result.target_steps.get(), 290 + 640);
+        assert_eq!(result.target_flap(), flap(11));
+
+        // A turn of the drum that ends part way through a step is refused
+        let geared = StepperConfig {
+            gear_ratio: GearRatio {
+                motor_turns: 1,
+                drum_turns: 3,
+            },
+            ..stepper
+        };
+        assert!(
+            super::SplitFlapBitState::with_stepper(calibration, geared, Offset::Flaps(5)).is_none()
+        );
+    }
+
+    #[test]
+    fn custom_character_set_changes_character_lookup() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(58), HomedSteps::new(0));
+        let mut character_set = super::CHARACTER_SET;
+        character_set.swap(1, 2);
+
+        result.set_character_set(character_set);
+        result.set_target_character(b'A');
+
+        assert_eq!(result.target_flap(), flap(2));
+        assert_eq!(result.character_set()[1], b'B');
+    }
+
+    #[test]
+    fn restored_bit_settles_without_moving() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.restore(HomedSteps::new(4 + 2 * 8));
+
+        // Starting over the magnet is not taken as passing home
+        assert!(!result.process(2100), "Restored bit stepped");
+        assert!(!result.process(100), "Restored bit stepped");
+
+        assert!(result.is_settled());
+        assert!(result.unverified);
+        assert_eq!(result.current_flap(), flap(8));
+        assert_eq!(result.settled_position(), Some(HomedSteps::new(4 + 2 * 8)));
+    }
+
+    #[test]
+    fn restored_position_is_confirmed_at_home() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.restore(HomedSteps::new(100));
+        result.set_target_flap(flap(2));
+
+        // Ten steps to the end of the revolution, where the sensor sees the magnet
+        for _ in 0..10 {
+            assert!(result.process(100));
+        }
+        assert!(result.process(2100));
+
+        assert!(!result.unverified);
+        assert_eq!(result.bit_state(), BitState::SEEKING);
+        assert_eq!(result.target_flap(), flap(2));
+    }
+
+    #[test]
+    fn wrong_restored_position_homes_again() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.restore(HomedSteps::new(100));
+        result.set_target_flap(flap(2));
+
+        // The magnet turns up five flaps early
+        result.process(100);
+        result.process(2100);
+
+        assert!(!result.unverified);
+        assert_eq!(result.bit_state(), BitState::SEEKING);
+        assert_eq!(result.target_flap(), flap(0));
+        assert_eq!(result.settled_position(), None);
+        assert_eq!(result.counters().faults.restore_mismatches, 1);
+    }
+
+    #[test]
+    fn counters_follow_a_revolution() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        result.process(2100);
+        while result.process(100) {}
+
+        let counters = result.counters();
+        assert_eq!(counters.steps, 4);
+        assert_eq!(counters.home_detections, 1);
+        assert_eq!(counters.characters_displayed, 1);
+
+        result.record_time(500);
+        assert_eq!(result.counters().settled_us, 500);
+
+        // Round past the magnet, which is where the step count expects it
+        result.set_target_flap(flap(1));
+        while result.process(100) {}
+        result.set_target_flap(flap(0));
+        while result.process(if result.steps_since_home == HomedSteps::HOME {
+            2100
+        } else {
+            100
+        }) {}
+
+        let counters = result.counters();
+        assert_eq!(counters.steps, 114);
+        assert_eq!(counters.revolutions, 1);
+        assert_eq!(counters.home_detections, 2);
+        assert_eq!(counters.step_loss_corrections, 0);
+        assert_eq!(counters.characters_displayed, 3);
+
+        result.reset_counters();
+        assert_eq!(result.counters(), super::BitCounters::default());
+    }
+
+    #[test]
+    fn counters_record_lost_steps_and_faults() {
+        let calibration = super::SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let mut result =
+            super::SplitFlapBitState::new(calibration, revolution(2), HomedSteps::new(4));
+
+        // Two revolutions without seeing the magnet
+        for _ in 0..300 {
+            result.process(100);
+        }
+        assert_eq!(result.counters().faults.homing_timeouts, 1);
+
+        result.process(2100);
+        while result.process(100) {}
+
+        // The magnet turns up well before a revolution has gone by
+        result.set_target_flap(flap(20));
+        for _ in 0..10 {
+            result.process(100);
+        }
+        result.process(2100);
+        assert_eq!(result.counters().step_loss_corrections, 1);
 
-        assert_eq!(position, 8);
+        result.rehome();
+        assert_eq!(result.counters().rehomes, 1);
     }
 }
@@ -0,0 +1,434 @@
+//! Step counts, positions on the drum, flaps and angles as separate types, so that one can't be
+//! passed for another.  A drum's `Revolution` converts between them, wrapping positions round the
+//! drum rather than letting them overflow.
+//!
+//! A `StepperConfig` describes the motor, driver and gearing, and works out the `Revolution`, so
+//! changing the microstep setting needs no step counts recomputing by hand.  A revolution needn't
+//! divide evenly between the flaps; each flap then starts on the step nearest before its share.
+
+use crate::split_flap_bit_state::CHARACTER_SET;
+
+const FLAPS: usize = CHARACTER_SET.len();
+const MILLIDEGREES_PER_TURN: u32 = 360_000;
+
+/// A number of motor steps, such as how far to move.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Steps(u32);
+
+impl Steps {
+    pub const ZERO: Steps = Steps(0);
+
+    pub const fn new(steps: u32) -> Steps {
+        Steps(steps)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Steps) -> Option<Steps> {
+        self.0.checked_add(rhs.0).map(Steps)
+    }
+
+    pub fn checked_sub(self, rhs: Steps) -> Option<Steps> {
+        self.0.checked_sub(rhs.0).map(Steps)
+    }
+
+    pub fn checked_mul(self, rhs: u32) -> Option<Steps> {
+        self.0.checked_mul(rhs).map(Steps)
+    }
+
+    pub fn abs_diff(self, other: Steps) -> Steps {
+        Steps(self.0.abs_diff(other.0))
+    }
+}
+
+/// A position on the drum, in steps past where the home sensor triggers.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct HomedSteps(u32);
+
+impl HomedSteps {
+    pub const HOME: HomedSteps = HomedSteps(0);
+
+    pub const fn new(steps_past_home: u32) -> HomedSteps {
+        HomedSteps(steps_past_home)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// A flap, by its index in `CHARACTER_SET`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct FlapIndex(u8);
+
+impl FlapIndex {
+    pub const FIRST: FlapIndex = FlapIndex(0);
+
+    /// `None` past the last flap.
+    pub fn new(index: usize) -> Option<FlapIndex> {
+        (index < FLAPS).then_some(FlapIndex(index as u8))
+    }
+
+    /// Counts on round the drum past the last flap.
+    pub fn wrapping(index: usize) -> FlapIndex {
+        FlapIndex((index % FLAPS) as u8)
+    }
+
+    pub fn all() -> impl Iterator<Item = FlapIndex> {
+        (0..FLAPS as u8).map(FlapIndex)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0 as usize
+    }
+
+    /// The flap `flaps` further on, going round the drum.
+    pub fn forward(self, flaps: usize) -> FlapIndex {
+        FlapIndex::wrapping(self.get() + flaps % FLAPS)
+    }
+
+    /// The flap `flaps` back, going round the drum.
+    pub fn back(self, flaps: usize) -> FlapIndex {
+        FlapIndex::wrapping(self.get() + FLAPS - flaps % FLAPS)
+    }
+
+    /// Flaps passed turning forward from this flap to `to`.
+    pub fn flaps_to(self, to: FlapIndex) -> usize {
+        (to.get() + FLAPS - self.get()) % FLAPS
+    }
+}
+
+/// How far round the drum has turned, to a thousandth of a degree.  Always less than a turn.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Angle(u32);
+
+impl Angle {
+    pub fn from_degrees(degrees: u32) -> Angle {
+        Angle(degrees % 360 * 1000)
+    }
+
+    pub fn from_millidegrees(millidegrees: u32) -> Angle {
+        Angle(millidegrees % MILLIDEGREES_PER_TURN)
+    }
+
+    pub fn millidegrees(self) -> u32 {
+        self.0
+    }
+}
+
+/// Turns of the motor for turns of the drum, 1:1 for a drum on the motor shaft.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GearRatio {
+    pub motor_turns: u32,
+    pub drum_turns: u32,
+}
+
+impl GearRatio {
+    pub const DIRECT: GearRatio = GearRatio {
+        motor_turns: 1,
+        drum_turns: 1,
+    };
+}
+
+/// How a drum is driven, from which its steps are worked out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StepperConfig {
+    /// Full steps in a turn of the motor shaft, 200 for a 1.8° motor.
+    pub full_steps_per_revolution: u32,
+    /// Steps the driver divides each full step into, 1 when not microstepping.
+    pub microsteps: u32,
+    pub gear_ratio: GearRatio,
+}
+
+impl StepperConfig {
+    /// A motor turning the drum directly a step at a time, for drums only known by their steps.
+    pub fn direct(revolution: Revolution) -> StepperConfig {
+        StepperConfig {
+            full_steps_per_revolution: revolution.steps().0,
+            microsteps: 1,
+            gear_ratio: GearRatio::DIRECT,
+        }
+    }
+
+    /// `None` unless a turn of the drum is a whole number of steps, at least one per flap, that
+    /// fits in a `u32`.
+    pub fn revolution(&self) -> Option<Revolution> {
+        let motor_steps = self.full_steps_per_revolution as u64 * self.microsteps as u64;
+        let geared = motor_steps * self.gear_ratio.motor_turns as u64;
+        let drum_turns = self.gear_ratio.drum_turns as u64;
+
+        if drum_turns == 0 || !geared.is_multiple_of(drum_turns) {
+            return None;
+        }
+
+        Revolution::of_steps(Steps(u32::try_from(geared / drum_turns).ok()?))
+    }
+}
+
+/// Where the first flap sits past home, in whichever units it was measured.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Offset {
+    Steps(HomedSteps),
+    Angle(Angle),
+    /// Whole flaps, as for a magnet lined up with a flap edge.
+    Flaps(u32),
+}
+
+impl Offset {
+    /// The offset in steps on a drum turning through `revolution`.
+    pub fn position(self, revolution: Revolution) -> HomedSteps {
+        match self {
+            Offset::Steps(steps) => revolution.wrap(steps),
+            Offset::Angle(angle) => revolution.position_at(angle),
+            Offset::Flaps(flaps) => {
+                let flap = FlapIndex::wrapping(flaps as usize);
+                HomedSteps(revolution.flap_steps(flap).0)
+            }
+        }
+    }
+}
+
+/// The steps in a turn of the drum, for converting between positions, flaps and angles.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Revolution {
+    steps: Steps,
+}
+
+impl Revolution {
+    /// `None` for no steps per flap, or so many that a revolution can't be counted in a `u32`.
+    pub fn new(steps_per_flap: Steps) -> Option<Revolution> {
+        Revolution::of_steps(steps_per_flap.checked_mul(FLAPS as u32)?)
+    }
+
+    /// `None` for fewer steps than there are flaps.
+    pub fn of_steps(steps: Steps) -> Option<Revolution> {
+        (steps.0 >= FLAPS as u32).then_some(Revolution { steps })
+    }
+
+    /// Rounded down where the flaps don't share the revolution evenly.
+    pub fn steps_per_flap(self) -> Steps {
+        Steps(self.steps.0 / FLAPS as u32)
+    }
+
+    /// Flap trims must stay under this many steps either way, or the bit would stop nearer a
+    /// neighbouring flap.  At least one, so an untrimmed flap is always allowed.
+    pub fn trim_limit(self) -> Steps {
+        Steps((self.steps_per_flap().0 / 2).max(1))
+    }
+
+    pub fn steps(self) -> Steps {
+        self.steps
+    }
+
+    /// `position` brought round to within a revolution of home.
+    pub fn wrap(self, position: HomedSteps) -> HomedSteps {
+        HomedSteps(position.0 % self.steps.0)
+    }
+
+    /// Where the drum is after turning `steps` on from `position`.
+    pub fn advance(self, position: HomedSteps, steps: Steps) -> HomedSteps {
+        let revolution = self.steps().0 as u64;
+
+        HomedSteps(((position.0 as u64 + steps.0 as u64) % revolution) as u32)
+    }
+
+    /// Steps turning forward from `from` to `to`.
+    pub fn distance(self, from: HomedSteps, to: HomedSteps) -> Steps {
+        let (from, to) = (self.wrap(from).0, self.wrap(to).0);
+
+        match to.checked_sub(from) {
+            Some(steps) => Steps(steps),
+            None => Steps(self.steps().0 - (from - to)),
+        }
+    }
+
+    /// Steps from the first flap to the start of `flap`.
+    pub fn flap_steps(self, flap: FlapIndex) -> Steps {
+        self.share(flap.0 as u64)
+    }
+
+    /// Steps from the first flap to the start of the one after `flap`, a whole revolution for
+    /// the last flap.
+    pub fn flap_end(self, flap: FlapIndex) -> Steps {
+        self.share(flap.0 as u64 + 1)
+    }
+
+    /// The flap that starts at or before `steps` past the first, going round the drum.
+    pub fn flap_at(self, steps: Steps) -> FlapIndex {
+        let past_first = self.wrap(HomedSteps(steps.0)).0 as u64;
+        let flaps = FLAPS as u64;
+
+        // The last flap whose share of the revolution starts by `past_first`
+        FlapIndex::wrapping((((past_first + 1) * flaps - 1) / self.steps.0 as u64) as usize)
+    }
+
+    // Steps taken by the first `flaps` flaps
+    fn share(self, flaps: u64) -> Steps {
+        Steps((self.steps.0 as u64 * flaps / FLAPS as u64) as u32)
+    }
+
+    pub fn angle(self, position: HomedSteps) -> Angle {
+        let millidegrees =
+            self.wrap(position).0 as u64 * MILLIDEGREES_PER_TURN as u64 / self.steps().0 as u64;
+
+        Angle(millidegrees as u32)
+    }
+
+    /// The position nearest `angle`.
+    pub fn position_at(self, angle: Angle) -> HomedSteps {
+        let turn = MILLIDEGREES_PER_TURN as u64;
+        let steps = (angle.0 as u64 * self.steps().0 as u64 + turn / 2) / turn;
+
+        self.wrap(HomedSteps(steps as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Angle, FlapIndex, GearRatio, HomedSteps, Offset, Revolution, StepperConfig, Steps,
+    };
+
+    fn revolution(steps_per_flap: u32) -> Revolution {
+        Revolution::new(Steps::new(steps_per_flap)).unwrap()
+    }
+
+    #[test]
+    fn revolutions_must_be_countable() {
+        assert_eq!(Revolution::new(Steps::ZERO), None);
+        assert_eq!(Revolution::new(Steps::new(u32::MAX / 55 + 1)), None);
+        assert_eq!(revolution(58).steps(), Steps::new(58 * 55));
+        assert_eq!(Revolution::of_steps(Steps::new(54)), None);
+    }
+
+    #[test]
+    fn trims_stay_under_half_a_flap() {
+        assert_eq!(revolution(58).trim_limit(), Steps::new(29));
+        assert_eq!(revolution(3).trim_limit(), Steps::new(1));
+        assert_eq!(revolution(1).trim_limit(), Steps::new(1));
+    }
+
+    #[test]
+    fn steppers_work_out_the_revolution() {
+        let mut stepper = StepperConfig {
+            full_steps_per_revolution: 200,
+            microsteps: 1,
+            gear_ratio: GearRatio::DIRECT,
+        };
+        assert_eq!(stepper.revolution().unwrap().steps(), Steps::new(200));
+
+        stepper.microsteps = 16;
+        assert_eq!(stepper.revolution().unwrap().steps(), Steps::new(3200));
+
+        stepper.gear_ratio = GearRatio {
+            motor_turns: 5,
+            drum_turns: 2,
+        };
+        assert_eq!(stepper.revolution().unwrap().steps(), Steps::new(8000));
+
+        // A part step per turn of the drum would creep round
+        stepper.gear_ratio.drum_turns = 3;
+        assert_eq!(stepper.revolution(), None);
+        stepper.gear_ratio.drum_turns = 0;
+        assert_eq!(stepper.revolution(), None);
+        stepper.microsteps = 0;
+        assert_eq!(stepper.revolution(), None);
+
+        assert_eq!(
+            StepperConfig::direct(revolution(4)).revolution(),
+            Some(revolution(4))
+        );
+    }
+
+    #[test]
+    fn positions_wrap_round_the_drum() {
+        let revolution = revolution(4);
+        let last = HomedSteps::new(219);
+
+        assert_eq!(revolution.advance(last, Steps::new(1)), HomedSteps::HOME);
+        assert_eq!(
+            revolution.advance(last, Steps::new(u32::MAX)),
+            HomedSteps::new(((219 + u32::MAX as u64) % 220) as u32)
+        );
+        assert_eq!(revolution.wrap(HomedSteps::new(445)), HomedSteps::new(5));
+        assert_eq!(
+            revolution.distance(HomedSteps::new(200), HomedSteps::new(10)),
+            Steps::new(30)
+        );
+    }
+
+    #[test]
+    fn flaps_stay_on_the_drum() {
+        assert_eq!(FlapIndex::new(54).map(FlapIndex::get), Some(54));
+        assert_eq!(FlapIndex::new(55), None);
+        assert_eq!(FlapIndex::wrapping(57).get(), 2);
+        assert_eq!(FlapIndex::FIRST.back(1).get(), 54);
+        assert_eq!(FlapIndex::wrapping(53).forward(3).get(), 1);
+        assert_eq!(FlapIndex::wrapping(3).flaps_to(FlapIndex::FIRST), 52);
+        assert_eq!(FlapIndex::all().count(), 55);
+    }
+
+    #[test]
+    fn flaps_steps_and_angles_convert() {
+        let revolution = revolution(8);
+        let flap = FlapIndex::wrapping(11);
+
+        assert_eq!(revolution.flap_steps(flap), Steps::new(88));
+        assert_eq!(revolution.flap_at(Steps::new(95)), flap);
+        assert_eq!(revolution.flap_at(Steps::new(440 + 88)), flap);
+
+        let quarter = Angle::from_degrees(90);
+        assert_eq!(revolution.position_at(quarter), HomedSteps::new(110));
+        assert_eq!(revolution.angle(HomedSteps::new(110)), quarter);
+        assert_eq!(Angle::from_degrees(450), quarter);
+        assert_eq!(
+            revolution.position_at(Angle::from_millidegrees(359_999)),
+            HomedSteps::HOME
+        );
+    }
+
+    #[test]
+    fn uneven_revolutions_share_out_the_flaps() {
+        let revolution = Revolution::of_steps(Steps::new(3200)).unwrap();
+
+        assert_eq!(revolution.steps_per_flap(), Steps::new(58));
+        assert_eq!(
+            revolution.flap_steps(FlapIndex::wrapping(1)),
+            Steps::new(58)
+        );
+        assert_eq!(
+            revolution.flap_steps(FlapIndex::wrapping(11)),
+            Steps::new(640)
+        );
+        assert_eq!(
+            revolution.flap_end(FlapIndex::FIRST.back(1)),
+            Steps::new(3200)
+        );
+
+        for flap in FlapIndex::all() {
+            let start = revolution.flap_steps(flap);
+            let end = revolution.flap_end(flap);
+
+            assert_eq!(revolution.flap_at(start), flap);
+            assert_eq!(revolution.flap_at(Steps::new(end.get() - 1)), flap);
+        }
+    }
+
+    #[test]
+    fn offsets_convert_from_any_unit() {
+        let revolution = revolution(8);
+
+        assert_eq!(
+            Offset::Steps(HomedSteps::new(445)).position(revolution),
+            HomedSteps::new(5)
+        );
+        assert_eq!(
+            Offset::Angle(Angle::from_degrees(90)).position(revolution),
+            HomedSteps::new(110)
+        );
+        assert_eq!(Offset::Flaps(5).position(revolution), HomedSteps::new(40));
+        assert_eq!(Offset::Flaps(56).position(revolution), HomedSteps::new(8));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::split_flap_bit_state::CHARACTER_SET;
+
+const ELLIPSIS: &[u8] = b"...";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BoardGeometry {
+    pub rows: usize,
+    pub columns: usize,
+}
+
+impl BoardGeometry {
+    pub fn new(rows: usize, columns: usize) -> BoardGeometry {
+        BoardGeometry { rows, columns }
+    }
+
+    pub fn bit_count(&self) -> usize {
+        self.rows * self.columns
+    }
+
+    /// Bits are numbered row-major, starting at the top left of the board.
+    pub fn bit_index(&self, row: usize, column: usize) -> usize {
+        row * self.columns + column
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Justification {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Overflow {
+    Truncate,
+    Ellipsis,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LayoutOptions {
+    pub justification: Justification,
+    pub overflow: Overflow,
+    pub word_wrap: bool,
+    /// Flap shown in place of any character that is not in the character set.
+    pub fallback: u8,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        LayoutOptions {
+            justification: Justification::Left,
+            overflow: Overflow::Truncate,
+            word_wrap: true,
+            fallback: b' ',
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayoutError {
+    BufferSizeMismatch { expected: usize, actual: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LayoutSummary {
+    pub rows_used: usize,
+    pub truncated: bool,
+    pub substitutions: usize,
+}
+
+/// Lays `text` out on the board, writing one target character per bit into `targets`.
+pub fn layout(
+    text: &str,
+    geometry: BoardGeometry,
+    options: &LayoutOptions,
+    targets: &mut [u8],
+) -> Result<LayoutSummary, LayoutError> {
+    layout_lines(text.split('\n'), geometry, options, targets)
+}
+
+/// As [`layout`], for text already split at its line breaks.  Needed once glyphs have been
+/// expanded, as some share a byte with the line break.
+pub fn layout_lines<'a, I>(
+    lines: I,
+    geometry: BoardGeometry,
+    options: &LayoutOptions,
+    targets: &mut [u8],
+) -> Result<LayoutSummary, LayoutError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    if targets.len() != geometry.bit_count() {
+        return Err(LayoutError::BufferSizeMismatch {
+            expected: geometry.bit_count(),
+            actual: targets.len(),
+        });
+    }
+
+    targets.fill(b' ');
+
+    let columns = geometry.columns;
+    let mut summary = LayoutSummary::default();
+    let mut lines = LineBreaker::new(lines.into_iter(), columns, options.word_wrap).peekable();
+
+    for row in 0..geometry.rows {
+        let Some(line) = lines.next() else {
+            break;
+        };
+
+        let line_width = line.chars().count();
+        let more_lines_than_rows = row + 1 == geometry.rows && lines.peek().is_some();
+        let truncated = more_lines_than_rows || line_width > columns;
+
+        let ellipsis_width = if truncated && options.overflow == Overflow::Ellipsis {
+            ELLIPSIS.len().min(columns)
+        } else {
+            0
+        };
+        let text_width = line_width.min(columns - ellipsis_width);
+        let width = text_width + ellipsis_width;
+
+        let start = match options.justification {
+            Justification::Left => 0,
+            Justification::Center => (columns - width) / 2,
+            Justification::Right => columns - width,
+        };
+
+        let row_targets =
+            &mut targets[geometry.bit_index(row, start)..geometry.bit_index(row, columns)];
+
+        for (target, c) in row_targets.iter_mut().zip(line.chars().take(text_width)) {
+            *target = match flap_code(c) {
+                Some(code) => code,
+                None => {
+                    summary.substitutions += 1;
+                    options.fallback
+                }
+            };
+        }
+
+        row_targets[text_width..width].copy_from_slice(&ELLIPSIS[..ellipsis_width]);
+
+        summary.rows_used = row + 1;
+        summary.truncated |= truncated;
+    }
+
+    Ok(summary)
+}
+
+fn flap_code(c: char) -> Option<u8> {
+    if !c.is_ascii() {
+        return None;
+    }
+
+    let code = c as u8;
+
+    if CHARACTER_SET.contains(&code) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// Splits paragraphs into display lines, optionally at word boundaries.
+struct LineBreaker<'a, I> {
+    paragraphs: I,
+    paragraph: Option<&'a str>,
+    columns: usize,
+    word_wrap: bool,
+}
+
+impl<'a, I: Iterator<Item = &'a str>> LineBreaker<'a, I> {
+    fn new(paragraphs: I, columns: usize, word_wrap: bool) -> LineBreaker<'a, I> {
+        LineBreaker {
+            paragraphs,
+            paragraph: None,
+            columns,
+            word_wrap,
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = &'a str>> Iterator for LineBreaker<'a, I> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let paragraph = match self.paragraph.take() {
+            Some(paragraph) => paragraph,
+            None => self.paragraphs.next()?.trim_end_matches('\r'),
+        };
+
+        if !self.word_wrap || self.columns == 0 {
+            return Some(paragraph);
+        }
+
+        let (line, rest) = wrap(paragraph, self.columns);
+
+        if !rest.is_empty() {
+            self.paragraph = Some(rest);
+        }
+
+        Some(line)
+    }
+}
+
+/// Returns the first line of `paragraph` that fits in `columns`, and whatever is left over.
+fn wrap(paragraph: &str, columns: usize) -> (&str, &str) {
+    let Some((limit, _)) = paragraph.char_indices().nth(columns) else {
+        return (paragraph, "");
+    };
+
+    let word_break = if paragraph[limit..].starts_with(' ') {
+        Some(limit)
+    } else {
+        paragraph[..limit].rfind(' ')
+    };
+
+    match word_break {
+        Some(index) if !paragraph[..index].trim_end_matches(' ').is_empty() => (
+            paragraph[..index].trim_end_matches(' '),
+            paragraph[index..].trim_start_matches(' '),
+        ),
+        // A single word longer than the row has to be split mid-word
+        _ => (
+            &paragraph[..limit],
+            paragraph[limit..].trim_start_matches(' '),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        layout, layout_lines, BoardGeometry, Justification, LayoutError, LayoutOptions,
+        LayoutSummary, Overflow,
+    };
+
+    fn layout_rows<const N: usize>(
+        text: &str,
+        geometry: BoardGeometry,
+        options: &LayoutOptions,
+    ) -> ([u8; N], LayoutSummary) {
+        let mut targets = [0u8; N];
+        let summary = layout(text, geometry, options, &mut targets).unwrap();
+
+        (targets, summary)
+    }
+
+    #[test]
+    fn short_text_is_left_justified_and_padded() {
+        let (targets, summary) =
+            layout_rows::<8>("HI", BoardGeometry::new(2, 4), &LayoutOptions::default());
+
+        assert_eq!(&targets, b"HI      ");
+        assert_eq!(summary.rows_used, 1);
+        assert!(!summary.truncated);
+    }
+
+    #[test]
+    fn words_wrap_onto_following_rows() {
+        let (targets, _) = layout_rows::<48>(
+            "NEXT TRAIN TO BROOKLYN BRIDGE",
+            BoardGeometry::new(3, 16),
+            &LayoutOptions::default(),
+        );
+
+        assert_eq!(&targets[..16], b"NEXT TRAIN TO   ");
+        assert_eq!(&targets[16..32], b"BROOKLYN BRIDGE ");
+        assert_eq!(&targets[32..], b"                ");
+    }
+
+    #[test]
+    fn word_longer_than_row_is_split() {
+        let (targets, _) = layout_rows::<8>(
+            "ABCDEFG",
+            BoardGeometry::new(2, 4),
+            &LayoutOptions::default(),
+        );
+
+        assert_eq!(&targets, b"ABCDEFG ");
+    }
+
+    #[test]
+    fn explicit_line_breaks_start_new_rows() {
+        let (targets, summary) = layout_rows::<12>(
+            "A\r\n\nB",
+            BoardGeometry::new(3, 4),
+            &LayoutOptions::default(),
+        );
+
+        assert_eq!(&targets, b"A       B   ");
+        assert_eq!(summary.rows_used, 3);
+    }
+
+    #[test]
+    fn split_lines_keep_glyphs_that_share_the_line_break_byte() {
+        let mut targets = [0u8; 8];
+
+        layout_lines(
+            ["A\x0A", "B"],
+            BoardGeometry::new(2, 4),
+            &LayoutOptions::default(),
+            &mut targets,
+        )
+        .unwrap();
+
+        assert_eq!(&targets, b"A\x0A  B   ");
+    }
+
+    #[test]
+    fn center_justification_centers_each_row() {
+        let options = LayoutOptions {
+            justification: Justification::Center,
+            ..LayoutOptions::default()
+        };
+
+        let (targets, _) = layout_rows::<12>("AB\nC", BoardGeometry::new(2, 6), &options);
+
+        assert_eq!(&targets, b"  AB    C   ");
+    }
+
+    #[test]
+    fn right_justification_aligns_to_last_column() {
+        let options = LayoutOptions {
+            justification: Justification::Right,
+            ..LayoutOptions::default()
+        };
+
+        let (targets, _) = layout_rows::<4>("12", BoardGeometry::new(1, 4), &options);
+
+        assert_eq!(&targets, b"  12");
+    }
+
+    #[test]
+    fn long_line_without_wrap_is_truncated() {
+        let options = LayoutOptions {
+            word_wrap: false,
+            ..LayoutOptions::default()
+        };
+
+        let (targets, summary) = layout_rows::<4>("ABC DEF", BoardGeometry::new(1, 4), &options);
+
+        assert_eq!(&targets, b"ABC ");
+        assert!(summary.truncated);
+    }
+
+    #[test]
+    fn long_line_with_ellipsis_ends_in_dots() {
+        let options = LayoutOptions {
+            word_wrap: false,
+            overflow: Overflow::Ellipsis,
+            ..LayoutOptions::default()
+        };
+
+        let (targets, _) = layout_rows::<6>("ABCDEFGH", BoardGeometry::new(1, 6), &options);
+
+        assert_eq!(&targets, b"ABC...");
+    }
+
+    #[test]
+    fn too_many_rows_puts_ellipsis_on_last_row() {
+        let options = LayoutOptions {
+            overflow: Overflow::Ellipsis,
+            ..LayoutOptions::default()
+        };
+
+        let (targets, summary) =
+            layout_rows::<12>("AB\nCD\nEF", BoardGeometry::new(2, 6), &options);
+
+        assert_eq!(&targets, b"AB    CD... ");
+        assert!(summary.truncated);
+    }
+
+    #[test]
+    fn unsupported_characters_use_fallback() {
+        let options = LayoutOptions {
+            fallback: b'-',
+            ..LayoutOptions::default()
+        };
+
+        let (targets, summary) = layout_rows::<4>("aÉ\u{1}!", BoardGeometry::new(1, 4), &options);
+
+        assert_eq!(&targets, &[b'-', b'-', 0x01, b'-']);
+        assert_eq!(summary.substitutions, 3);
+    }
+
+    #[test]
+    fn mismatched_buffer_is_rejected() {
+        let mut targets = [0u8; 4];
+
+        let result = layout(
+            "A",
+            BoardGeometry::new(3, 16),
+            &LayoutOptions::default(),
+            &mut targets,
+        );
+
+        assert_eq!(
+            result,
+            Err(LayoutError::BufferSizeMismatch {
+                expected: 48,
+                actual: 4
+            })
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Records what one bit read from its sensor and decided on each step, for debugging homing.
+//!
+//! The firmware keeps the latest samples in a ring buffer that the host drains over USB.  A
+//! recording can then be fed back through a fresh `SplitFlapBitState` with `replay`, which checks
+//! that it makes the same decisions, so traces taken in the field can become regression tests.
+
+use serde::{Deserialize, Serialize};
+
+use crate::split_flap_bit_state::{BitState, SensorState, SplitFlapBitState};
+use crate::steps::FlapIndex;
+
+/// Samples kept on the device between reads by the host.
+pub const TRACE_CAPACITY: usize = 512;
+/// Samples sent in each `Trace` message, few enough to fit a frame.
+pub const TRACE_CHUNK: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TraceSample {
+    /// `steps_since_home` once the reading was processed.  Stale while the bit is homing.
+    pub step: u32,
+    /// Raw ADC reading from the home sensor.
+    pub reading: u32,
+    pub sensor: SensorState,
+    pub state: BitState,
+    /// The flap the bit was seeking when the reading was taken.
+    pub target_flap: u8,
+    pub stepped: bool,
+}
+
+impl TraceSample {
+    const EMPTY: TraceSample = TraceSample {
+        step: 0,
+        reading: 0,
+        sensor: SensorState::Untriggered,
+        state: BitState::UNINITIALIZED,
+        target_flap: 0,
+        stepped: false,
+    };
+
+    /// Describes `bit` just after it processed `reading`.
+    pub fn of(
+        bit: &SplitFlapBitState,
+        reading: u32,
+        target_flap: FlapIndex,
+        stepped: bool,
+    ) -> Self {
+        TraceSample {
+            step: bit.steps_since_home().get(),
+            reading,
+            sensor: bit.sensor_state(),
+            state: bit.bit_state(),
+            target_flap: target_flap.get() as u8,
+            stepped,
+        }
+    }
+
+    // The step count only means something once the bit has homed
+    fn matches(&self, other: &TraceSample) -> bool {
+        let same_step = self.state == BitState::UNINITIALIZED || self.step == other.step;
+
+        same_step
+            && self.reading == other.reading
+            && self.sensor == other.sensor
+            && self.state == other.state
+            && self.target_flap == other.target_flap
+            && self.stepped == other.stepped
+    }
+}
+
+/// The latest `N` samples.  Once full, each new sample replaces the oldest.
+pub struct TraceBuffer<const N: usize> {
+    samples: [TraceSample; N],
+    start: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> TraceBuffer<N> {
+    pub fn new() -> TraceBuffer<N> {
+        TraceBuffer {
+            samples: [TraceSample::EMPTY; N],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+
+    pub fn push(&mut self, sample: TraceSample) {
+        if self.len == N {
+            self.start = (self.start + 1) % N;
+            self.dropped = self.dropped.saturating_add(1);
+        } else {
+            self.len += 1;
+        }
+
+        self.samples[(self.start + self.len - 1) % N] = sample;
+    }
+
+    /// Removes the oldest sample.
+    pub fn pop(&mut self) -> Option<TraceSample> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let sample = self.samples[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+
+        Some(sample)
+    }
+
+    /// Samples overwritten before they were read, since the last call.
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
+
+    /// Moves up to `TRACE_CHUNK` of the oldest samples into a chunk for the host.
+    pub fn take_chunk(&mut self) -> TraceChunk {
+        let mut chunk = TraceChunk {
+            dropped: self.take_dropped(),
+            len: 0,
+            samples: [TraceSample::EMPTY; TRACE_CHUNK],
+        };
+
+        while (chunk.len as usize) < TRACE_CHUNK {
+            let Some(sample) = self.pop() else {
+                break;
+            };
+
+            chunk.samples[chunk.len as usize] = sample;
+            chunk.len += 1;
+        }
+
+        chunk
+    }
+}
+
+impl<const N: usize> Default for TraceBuffer<N> {
+    fn default() -> Self {
+        TraceBuffer::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TraceChunk {
+    /// Samples lost because the host fell behind, just before the first in this chunk.
+    pub dropped: u32,
+    pub len: u8,
+    pub samples: [TraceSample; TRACE_CHUNK],
+}
+
+impl TraceChunk {
+    pub fn samples(&self) -> &[TraceSample] {
+        &self.samples[..(self.len as usize).min(TRACE_CHUNK)]
+    }
+}
+
+/// Where a replay first disagreed with the recording.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Divergence {
+    pub index: usize,
+    pub recorded: TraceSample,
+    pub replayed: TraceSample,
+}
+
+/// Feeds the recorded readings through `bit`, returning how many samples matched.  `bit` should
+/// be set up like the one recorded and just about to find home, as a capture starts that way.
+pub fn replay<'a>(
+    bit: &mut SplitFlapBitState,
+    samples: impl IntoIterator<Item = &'a TraceSample>,
+) -> Result<usize, Divergence> {
+    let mut count = 0;
+
+    for (index, recorded) in samples.into_iter().enumerate() {
+        let target_flap = FlapIndex::wrapping(recorded.target_flap as usize);
+        bit.set_target_flap(target_flap);
+        let stepped = bit.process(recorded.reading);
+        let replayed = TraceSample::of(bit, recorded.reading, target_flap, stepped);
+
+        if !recorded.matches(&replayed) {
+            return Err(Divergence {
+                index,
+                recorded: *recorded,
+                replayed,
+            });
+        }
+
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{replay, TraceBuffer, TraceSample, TRACE_CHUNK};
+    use crate::split_flap_bit_state::{BitState, SensorCalibration, SplitFlapBitState};
+    use crate::steps::{FlapIndex, HomedSteps, Revolution, Steps};
+
+    const STEPS_PER_FLAP: u32 = 2;
+    const REVOLUTION: u32 = STEPS_PER_FLAP * 55;
+
+    fn new_bit() -> SplitFlapBitState {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+
+        SplitFlapBitState::new(
+            calibration,
+            Revolution::new(Steps::new(STEPS_PER_FLAP)).unwrap(),
+            HomedSteps::new(4),
+        )
+    }
+
+    fn sample(reading: u32) -> TraceSample {
+        TraceSample {
+            reading,
+            ..TraceSample::EMPTY
+        }
+    }
+
+    // Simulates a drum starting `position` steps past the magnet, seeking `target_flap`
+    fn record(position: u32, target_flap: u8, count: usize) -> Vec<TraceSample> {
+        let mut bit = new_bit();
+        let mut position = position;
+        let mut samples = Vec::new();
+        let target_flap = FlapIndex::wrapping(target_flap as usize);
+
+        for _ in 0..count {
+            let reading = if position.is_multiple_of(REVOLUTION) {
+                3000
+            } else {
+                400
+            };
+
+            bit.set_target_flap(target_flap);
+            let stepped = bit.process(reading);
+            samples.push(TraceSample::of(&bit, reading, target_flap, stepped));
+
+            if stepped {
+                position += 1;
+            }
+        }
+
+        samples
+    }
+
+    #[test]
+    fn buffer_keeps_the_latest_samples() {
+        let mut buffer: TraceBuffer<3> = TraceBuffer::new();
+
+        for reading in 0..5 {
+            buffer.push(sample(reading));
+        }
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.take_dropped(), 2);
+        assert_eq!(buffer.take_dropped(), 0);
+        assert_eq!(buffer.pop().map(|s| s.reading), Some(2));
+        assert_eq!(buffer.pop().map(|s| s.reading), Some(3));
+        assert_eq!(buffer.pop().map(|s| s.reading), Some(4));
+        assert_eq!(buffer.pop(), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn chunks_drain_in_order() {
+        let mut buffer: TraceBuffer<64> = TraceBuffer::new();
+
+        for reading in 0..(TRACE_CHUNK as u32 + 3) {
+            buffer.push(sample(reading));
+        }
+
+        let first = buffer.take_chunk();
+        assert_eq!(first.samples().len(), TRACE_CHUNK);
+        assert_eq!(first.samples()[0].reading, 0);
+
+        let second = buffer.take_chunk();
+        assert_eq!(
+            second
+                .samples()
+                .iter()
+                .map(|s| s.reading)
+                .collect::<Vec<_>>(),
+            [12, 13, 14]
+        );
+        assert!(buffer.take_chunk().samples().is_empty());
+    }
+
+    #[test]
+    fn recording_replays_cleanly() {
+        let samples = record(37, 5, 3 * REVOLUTION as usize);
+
+        assert!(samples.iter().any(|s| s.state == BitState::SETTLED));
+        assert_eq!(replay(&mut new_bit(), &samples), Ok(samples.len()));
+    }
+
+    #[test]
+    fn replay_reports_where_a_decision_changed() {
+        let mut samples = record(37, 5, 3 * REVOLUTION as usize);
+        let homed = samples
+            .iter()
+            .position(|s| s.state == BitState::SEEKING)
+            .unwrap();
+
+        // As though an older firmware had missed the magnet on this reading
+        samples[homed].state = BitState::UNINITIALIZED;
+
+        let divergence = replay(&mut new_bit(), &samples).unwrap_err();
+        assert_eq!(divergence.index, homed);
+        assert_eq!(divergence.replayed.state, BitState::SEEKING);
+    }
+}
@@ -0,0 +1,501 @@
+//! Effects used when the display changes to a new message, for installations where the change
+//! is part of the show.
+//!
+//! Effects compose: `Wave` and `RandomOrder` add to when each column starts, while `Spin` and
+//! `Cascade` change the flaps a column passes through on the way to its target.  Anything random
+//! comes from the seed, so a transition can be replayed exactly.
+
+use serde::{Deserialize, Serialize};
+
+use crate::split_flap_bit_state::SplitFlapBitState;
+use crate::steps::FlapIndex;
+
+pub const MAX_EFFECTS: usize = 4;
+
+/// A waypoint for each spin, then the target.
+const MAX_WAYPOINTS: usize = MAX_EFFECTS + 1;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Effect {
+    /// Each column starts `delay_ms` after the one to its left.
+    Wave { delay_ms: u16 },
+    /// Columns start one at a time in a random order, `delay_ms` apart.
+    RandomOrder { delay_ms: u16 },
+    /// Goes once round the whole drum before landing.
+    Spin,
+    /// Rests for `dwell_ms` on every `every`th flap on the way.
+    Cascade { every: u8, dwell_ms: u16 },
+}
+
+/// The effects applied to each new message, in order.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct TransitionConfig {
+    effects: [Option<Effect>; MAX_EFFECTS],
+    pub seed: u32,
+}
+
+impl TransitionConfig {
+    /// `None` if there are more than `MAX_EFFECTS` effects.
+    pub fn new(effects: &[Effect], seed: u32) -> Option<TransitionConfig> {
+        if effects.len() > MAX_EFFECTS {
+            return None;
+        }
+
+        Some(TransitionConfig {
+            effects: core::array::from_fn(|idx| effects.get(idx).copied()),
+            seed,
+        })
+    }
+
+    pub fn effects(&self) -> impl Iterator<Item = Effect> + '_ {
+        self.effects.iter().flatten().copied()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.effects()
+            .all(|effect| !matches!(effect, Effect::Cascade { every: 0, .. }))
+    }
+}
+
+/// Xorshift, which is plenty for shuffling a few columns.
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Rng {
+        // Xorshift never leaves zero
+        Rng(seed.wrapping_mul(0x9E37_79B9) | 1)
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        ((self.next() as u64 * bound as u64) >> 32) as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Cascade {
+    every: usize,
+    dwell_ms: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Column {
+    delay_ms: u32,
+    waypoints: [FlapIndex; MAX_WAYPOINTS],
+    len: usize,
+    /// Index of the waypoint being headed for, `len` once the column has landed.
+    next: usize,
+    /// The flap the bit was last sent to.
+    stop: Option<FlapIndex>,
+    stopped_at_ms: Option<u32>,
+}
+
+impl Column {
+    fn new() -> Column {
+        Column {
+            delay_ms: 0,
+            waypoints: [FlapIndex::FIRST; MAX_WAYPOINTS],
+            len: 0,
+            next: 0,
+            stop: None,
+            stopped_at_ms: None,
+        }
+    }
+
+    fn push(&mut self, flap: FlapIndex) {
+        self.waypoints[self.len] = flap;
+        self.len += 1;
+    }
+
+    // Sends the bit on once it has reached its last stop, returning whether the column is still
+    // going
+    fn update(
+        &mut self,
+        elapsed_ms: u32,
+        now_ms: u32,
+        cascade: Option<Cascade>,
+        bit: &mut SplitFlapBitState,
+    ) -> bool {
+        if self.next == self.len {
+            return false;
+        }
+
+        // Also waits out homing, which would forget the flap it was sent to
+        if elapsed_ms < self.delay_ms || !bit.is_settled() {
+            return true;
+        }
+
+        let from = bit.current_flap();
+
+        if self.stop == Some(from) {
+            if from == self.waypoints[self.next] {
+                self.next += 1;
+
+                if self.next == self.len {
+                    return false;
+                }
+            } else if let Some(cascade) = cascade {
+                let stopped_at_ms = *self.stopped_at_ms.get_or_insert(now_ms);
+
+                if now_ms.wrapping_sub(stopped_at_ms) < cascade.dwell_ms {
+                    return true;
+                }
+            }
+        }
+
+        let waypoint = self.waypoints[self.next];
+        let stop = match cascade {
+            Some(cascade) if from.flaps_to(waypoint) > cascade.every => from.forward(cascade.every),
+            _ => waypoint,
+        };
+
+        bit.set_target_flap(stop);
+        self.stop = Some(stop);
+        self.stopped_at_ms = None;
+
+        true
+    }
+}
+
+/// Moves a row of bits to new targets with the configured effects.
+pub struct Transition<const BITS: usize> {
+    columns: [Column; BITS],
+    cascade: Option<Cascade>,
+    started_ms: Option<u32>,
+}
+
+impl<const BITS: usize> Transition<BITS> {
+    /// Plans the move from where the bits are now to the `targets` characters.  Bits that are
+    /// still homing only take on the timing effects.
+    pub fn new(
+        config: &TransitionConfig,
+        seed: u32,
+        bits: &[SplitFlapBitState; BITS],
+        targets: &[u8; BITS],
+    ) -> Transition<BITS> {
+        let mut columns: [Column; BITS] = [Column::new(); BITS];
+        let mut cascade = None;
+        let mut rng = Rng::new(seed);
+
+        for effect in config.effects() {
+            match effect {
+                Effect::Wave { delay_ms } => {
+                    for (idx, column) in columns.iter_mut().enumerate() {
+                        column.delay_ms += idx as u32 * delay_ms as u32;
+                    }
+                }
+                Effect::RandomOrder { delay_ms } => {
+                    let mut order: [usize; BITS] = core::array::from_fn(|idx| idx);
+
+                    for idx in (1..BITS).rev() {
+                        order.swap(idx, rng.below(idx + 1));
+                    }
+
+                    for (rank, &idx) in order.iter().enumerate() {
+                        columns[idx].delay_ms += rank as u32 * delay_ms as u32;
+                    }
+                }
+                Effect::Spin => {
+                    for (column, bit) in columns.iter_mut().zip(bits) {
+                        if !bit.is_settled() {
+                            continue;
+                        }
+
+                        // Stopping one short of where it started passes every other flap
+                        let from = match column.len {
+                            0 => bit.current_flap(),
+                            len => column.waypoints[len - 1],
+                        };
+                        column.push(from.back(1));
+                    }
+                }
+                Effect::Cascade { every, dwell_ms } => {
+                    cascade = Some(Cascade {
+                        every: (every as usize).max(1),
+                        dwell_ms: dwell_ms as u32,
+                    });
+                }
+            }
+        }
+
+        for ((column, bit), &target) in columns.iter_mut().zip(bits).zip(targets) {
+            column.push(bit.flap_of(target));
+        }
+
+        Transition {
+            columns,
+            cascade,
+            started_ms: None,
+        }
+    }
+
+    /// How long after the transition starts `column` begins to move.
+    pub fn delay_ms(&self, column: usize) -> u32 {
+        self.columns[column].delay_ms
+    }
+
+    /// Whether `column` has further to go, including bits waiting to start.
+    pub fn moves(&self, column: usize, bit: &SplitFlapBitState) -> bool {
+        let column = &self.columns[column];
+
+        match column.len - column.next {
+            0 => false,
+            1 => !bit.is_settled() || bit.current_flap() != column.waypoints[column.next],
+            _ => true,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.columns.iter().all(|column| column.next == column.len)
+    }
+
+    /// Sends each bit on to its next stop once it has arrived at the last.  Call this before each
+    /// `process` of the bits.  Returns false once every bit has landed on its target.
+    pub fn update(&mut self, now_ms: u32, bits: &mut [SplitFlapBitState; BITS]) -> bool {
+        let started_ms = *self.started_ms.get_or_insert(now_ms);
+        let elapsed_ms = now_ms.wrapping_sub(started_ms);
+        let mut running = false;
+
+        for (column, bit) in self.columns.iter_mut().zip(bits.iter_mut()) {
+            running |= column.update(elapsed_ms, now_ms, self.cascade, bit);
+        }
+
+        running
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Effect, Transition, TransitionConfig, MAX_EFFECTS};
+    use crate::split_flap_bit_state::{SensorCalibration, SplitFlapBitState, CHARACTER_SET};
+    use crate::steps::{FlapIndex, HomedSteps, Revolution, Steps};
+
+    const STEPS_PER_FLAP: u32 = 2;
+    const REVOLUTION: u32 = STEPS_PER_FLAP * CHARACTER_SET.len() as u32;
+
+    /// Bits on drums with the magnet at step 0, stepped once a millisecond.
+    struct Display<const N: usize> {
+        bits: [SplitFlapBitState; N],
+        positions: [u32; N],
+        now_ms: u32,
+    }
+
+    impl<const N: usize> Display<N> {
+        fn at(flaps: [usize; N]) -> Display<N> {
+            let calibration = SensorCalibration {
+                trigger_value: 2000,
+                untrigger_value: 1800,
+            };
+            let revolution = Revolution::new(Steps::new(STEPS_PER_FLAP)).unwrap();
+            let mut display = Display {
+                bits: core::array::from_fn(|_| {
+                    SplitFlapBitState::new(calibration, revolution, HomedSteps::HOME)
+                }),
+                positions: [0; N],
+                now_ms: 0,
+            };
+
+            // Homing forgets any target, so only set them once it's done
+            display.tick();
+            for (bit, flap) in display.bits.iter_mut().zip(flaps) {
+                bit.set_target_flap(FlapIndex::wrapping(flap));
+            }
+            while display.tick().contains(&true) {}
+
+            display
+        }
+
+        fn tick(&mut self) -> [bool; N] {
+            self.now_ms += 1;
+
+            core::array::from_fn(|idx| {
+                let reading = if self.positions[idx] % REVOLUTION < 2 {
+                    3000
+                } else {
+                    500
+                };
+                let stepped = self.bits[idx].process(reading);
+                self.positions[idx] += stepped as u32;
+                stepped
+            })
+        }
+
+        // Runs the transition to the end, passing each millisecond's steps to `watch`
+        fn run(&mut self, transition: &mut Transition<N>, mut watch: impl FnMut(u32, [bool; N])) {
+            while transition.update(self.now_ms, &mut self.bits) {
+                let steps = self.tick();
+                watch(self.now_ms, steps);
+
+                assert!(self.now_ms < 100_000, "Transition never finished");
+            }
+
+            assert!(transition.is_finished());
+        }
+
+        fn flaps(&self) -> [usize; N] {
+            self.bits.each_ref().map(|bit| bit.current_flap().get())
+        }
+    }
+
+    fn transition<const N: usize>(
+        effects: &[Effect],
+        seed: u32,
+        display: &Display<N>,
+        flaps: [usize; N],
+    ) -> Transition<N> {
+        let config = TransitionConfig::new(effects, seed).unwrap();
+
+        Transition::new(
+            &config,
+            seed,
+            &display.bits,
+            &flaps.map(|f| CHARACTER_SET[f]),
+        )
+    }
+
+    #[test]
+    fn without_effects_every_bit_goes_straight_there() {
+        let mut display = Display::at([0, 4, 9]);
+        let mut transition = transition(&[], 0, &display, [3, 4, 1]);
+        let mut steps = [0; 3];
+
+        display.run(&mut transition, |_, stepped| {
+            for (count, stepped) in steps.iter_mut().zip(stepped) {
+                *count += stepped as u32;
+            }
+        });
+
+        assert_eq!(display.flaps(), [3, 4, 1]);
+        assert_eq!(steps, [3, 0, 47].map(|flaps| flaps * STEPS_PER_FLAP));
+    }
+
+    #[test]
+    fn wave_starts_each_column_after_the_last() {
+        let mut display = Display::at([0; 4]);
+        let start_ms = display.now_ms;
+        let mut transition = transition(&[Effect::Wave { delay_ms: 40 }], 0, &display, [5; 4]);
+        let mut first_steps = [None; 4];
+
+        display.run(&mut transition, |now_ms, stepped| {
+            for (first, stepped) in first_steps.iter_mut().zip(stepped) {
+                if stepped && first.is_none() {
+                    *first = Some(now_ms - start_ms);
+                }
+            }
+        });
+
+        assert_eq!(first_steps, [Some(1), Some(41), Some(81), Some(121)]);
+        assert_eq!(display.flaps(), [5; 4]);
+    }
+
+    #[test]
+    fn random_order_depends_only_on_the_seed() {
+        let display = Display::at([0; 4]);
+        let random = [Effect::RandomOrder { delay_ms: 25 }];
+        let delays = |seed| {
+            let transition = transition(&random, seed, &display, [1; 4]);
+            [0, 1, 2, 3].map(|column| transition.delay_ms(column))
+        };
+
+        for seed in 0..20 {
+            let mut sorted = delays(seed);
+            sorted.sort();
+
+            assert_eq!(sorted, [0, 25, 50, 75]);
+            assert_eq!(delays(seed), delays(seed));
+        }
+
+        assert!((1..20).any(|seed| delays(seed) != delays(0)));
+    }
+
+    #[test]
+    fn spin_goes_round_the_drum_before_landing() {
+        let mut display = Display::at([10]);
+        let mut transition = transition(&[Effect::Spin], 0, &display, [12]);
+        let mut steps = 0;
+
+        display.run(&mut transition, |_, [stepped]| steps += stepped as u32);
+
+        // Round to the flap before 10, then on to 12
+        assert_eq!(steps, (54 + 3) * STEPS_PER_FLAP);
+        assert_eq!(display.flaps(), [12]);
+    }
+
+    #[test]
+    fn cascade_rests_on_the_flaps_between() {
+        let mut display = Display::at([0]);
+        let cascade = Effect::Cascade {
+            every: 5,
+            dwell_ms: 30,
+        };
+        let mut transition = transition(&[cascade], 0, &display, [12]);
+        let mut rests = [0; 3];
+        let mut rest_count = 0;
+        let mut last_step_ms = None;
+        let mut steps = 0;
+
+        display.run(&mut transition, |now_ms, [stepped]| {
+            if !stepped {
+                return;
+            }
+
+            if let Some(last_step_ms) = last_step_ms.filter(|&ms| now_ms - ms > 1) {
+                assert!(now_ms - last_step_ms > 30, "Rested too briefly");
+                rests[rest_count] = steps / STEPS_PER_FLAP;
+                rest_count += 1;
+            }
+
+            last_step_ms = Some(now_ms);
+            steps += 1;
+        });
+
+        assert_eq!(&rests[..rest_count], [5, 10]);
+        assert_eq!(display.flaps(), [12]);
+    }
+
+    #[test]
+    fn effects_compose() {
+        let mut display = Display::at([0, 20, 40]);
+        let effects = [
+            Effect::RandomOrder { delay_ms: 15 },
+            Effect::Spin,
+            Effect::Cascade {
+                every: 11,
+                dwell_ms: 5,
+            },
+            Effect::Wave { delay_ms: 50 },
+        ];
+        let mut transition = transition(&effects, 7, &display, [1, 2, 3]);
+        let mut steps = [0; 3];
+
+        assert!((0..3).all(|column| transition.delay_ms(column) >= column as u32 * 50));
+
+        display.run(&mut transition, |_, stepped| {
+            for (count, stepped) in steps.iter_mut().zip(stepped) {
+                *count += stepped as u32;
+            }
+        });
+
+        assert_eq!(display.flaps(), [1, 2, 3]);
+        assert!(steps.iter().all(|&steps| steps > REVOLUTION));
+    }
+
+    #[test]
+    fn configs_are_checked() {
+        let spins = [Effect::Spin; MAX_EFFECTS + 1];
+        let still = Effect::Cascade {
+            every: 0,
+            dwell_ms: 10,
+        };
+
+        assert_eq!(TransitionConfig::new(&spins, 0), None);
+        assert!(TransitionConfig::new(&spins[1..], 0).unwrap().is_valid());
+        assert!(!TransitionConfig::new(&[still], 0).unwrap().is_valid());
+    }
+}
@@ -0,0 +1,150 @@
+//! How far the drums turn between messages.  Bits only turn forward, so going back a flap costs
+//! nearly a whole revolution, and the order messages are shown in changes the total travel and
+//! so the wear.
+
+use crate::split_flap_bit_state::CHARACTER_SET;
+use crate::steps::FlapIndex;
+
+const BLANK: u8 = b' ';
+
+// Characters without a flap show the first, as with `SplitFlapBitState::flap_of`
+fn flap(character: u8) -> FlapIndex {
+    CHARACTER_SET
+        .iter()
+        .position(|&c| c == character)
+        .map_or(FlapIndex::FIRST, FlapIndex::wrapping)
+}
+
+/// Flaps a bit passes going from showing `from` to showing `to`.
+pub fn flap_travel(from: u8, to: u8) -> u32 {
+    flap(from).flaps_to(flap(to)) as u32
+}
+
+/// Flaps passed by all the bits going from the message `from` to `to`, one character per bit.
+/// Bits past the end of either message are taken to show a blank.
+pub fn travel(from: &[u8], to: &[u8]) -> u32 {
+    let character = |message: &[u8], idx| message.get(idx).copied().unwrap_or(BLANK);
+
+    (0..from.len().max(to.len()))
+        .map(|idx| flap_travel(character(from, idx), character(to, idx)))
+        .sum()
+}
+
+/// Whether blanking the display between `from` and `to` adds no travel, because every bit passes
+/// the blank flap on the way anyway.  A blank can never save travel.
+pub fn blank_is_free(from: &[u8], to: &[u8]) -> bool {
+    travel(from, &[]) + travel(&[], to) == travel(from, to)
+}
+
+/// Travel for one pass through `messages` in `order`, back round to the first.
+pub fn cycle_travel(messages: &[&[u8]], order: &[usize]) -> u32 {
+    (0..order.len())
+        .map(|idx| {
+            let next = order[(idx + 1) % order.len()];
+            travel(messages[order[idx]], messages[next])
+        })
+        .sum()
+}
+
+/// Reorders `order`, which indexes `messages`, to cut `cycle_travel`.  The first message stays
+/// first.  Moves single messages to wherever they save the most until none can be moved for a
+/// saving, so the result is never worse than the order given, but may not be the best possible.
+pub fn plan_order(messages: &[&[u8]], order: &mut [usize]) {
+    let mut best = cycle_travel(messages, order);
+    let mut improved = true;
+
+    while improved {
+        improved = false;
+
+        for from in 1..order.len() {
+            for to in 1..order.len() {
+                if from == to {
+                    continue;
+                }
+
+                move_entry(order, from, to);
+                let cost = cycle_travel(messages, order);
+
+                if cost < best {
+                    best = cost;
+                    improved = true;
+                } else {
+                    move_entry(order, to, from);
+                }
+            }
+        }
+    }
+}
+
+fn move_entry(order: &mut [usize], from: usize, to: usize) {
+    if from < to {
+        order[from..=to].rotate_left(1);
+    } else {
+        order[to..=from].rotate_right(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{blank_is_free, cycle_travel, flap_travel, plan_order, travel};
+
+    #[test]
+    fn bits_only_turn_forward() {
+        assert_eq!(flap_travel(b'A', b'C'), 2);
+        assert_eq!(flap_travel(b'C', b'A'), 53);
+        assert_eq!(flap_travel(b'Q', b'Q'), 0);
+        // Unknown characters show the blank flap
+        assert_eq!(flap_travel(b'~', b'A'), 1);
+    }
+
+    #[test]
+    fn travel_covers_every_bit() {
+        assert_eq!(travel(b"AB", b"BA"), 1 + 54);
+        // The longer message's extra bits come from or go to blank
+        assert_eq!(travel(b"A", b"AB"), 2);
+        assert_eq!(travel(b"AB", b""), 54 + 53);
+    }
+
+    #[test]
+    fn blank_is_free_only_when_every_bit_wraps() {
+        assert!(blank_is_free(b"ZY", b"AB"));
+        assert!(blank_is_free(b"Z ", b"A "));
+        assert!(!blank_is_free(b"ZA", b"AB"));
+    }
+
+    #[test]
+    fn planning_puts_messages_in_flap_order() {
+        let messages: [&[u8]; 4] = [b"A", b"D", b"C", b"B"];
+        let mut order = [0, 1, 2, 3];
+
+        assert_eq!(cycle_travel(&messages, &order), 3 + 54 + 54 + 54);
+        plan_order(&messages, &mut order);
+
+        assert_eq!(order, [0, 3, 2, 1]);
+        assert_eq!(cycle_travel(&messages, &order), 55);
+    }
+
+    #[test]
+    fn planning_never_makes_travel_worse() {
+        let messages: [&[u8]; 6] = [b"PLAT", b"FORM", b"9:45", b"LATE", b"    ", b"ZONE"];
+
+        for first in 0..messages.len() {
+            let mut order: Vec<usize> = (0..messages.len()).collect();
+            order.swap(0, first);
+            let before = cycle_travel(&messages, &order);
+
+            plan_order(&messages, &mut order);
+
+            assert_eq!(order[0], first);
+            assert!(cycle_travel(&messages, &order) <= before);
+
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, [0, 1, 2, 3, 4, 5]);
+        }
+    }
+}
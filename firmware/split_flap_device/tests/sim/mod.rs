@@ -0,0 +1,282 @@
+//! A display simulated step by step: a drum per bit, turned by the device's decisions the way the
+//! firmware loop turns the motors, with host messages scripted against a simulated clock.
+//! Nothing depends on real time, so any run can be repeated exactly.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use split_flap_device::device::{Content, Device};
+use split_flap_device::protocol::{Frame, Message};
+use split_flap_device::split_flap_bit_state::{
+    SensorCalibration, SplitFlapBitState, CHARACTER_SET,
+};
+use split_flap_device::steps::{HomedSteps, Revolution, Steps};
+
+pub const BITS: usize = 4;
+
+const FLAPS: u32 = CHARACTER_SET.len() as u32;
+const CALIBRATION: SensorCalibration = SensorCalibration {
+    trigger_value: 2000,
+    untrigger_value: 1800,
+};
+const MAGNET_READING: u32 = 3000;
+const BACKGROUND_READING: u32 = 500;
+/// Idle milliseconds after which a display with nothing left to do counts as settled.
+const QUIET_MS: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Drum {
+    pub steps_per_flap: u32,
+    /// Steps from the magnet to the first flap.
+    pub home_offset: u32,
+    /// Steps past the leading edge of the magnet.
+    pub position: u32,
+    /// Steps for which the sensor sees the magnet, none for a missing magnet.
+    pub magnet_width: u32,
+    /// Every `n`th step is lost, like a motor slipping under load.
+    pub misses_every: Option<u32>,
+    pulses: u32,
+}
+
+impl Drum {
+    pub fn new(steps_per_flap: u32, home_offset: u32, position: u32) -> Drum {
+        Drum {
+            steps_per_flap,
+            home_offset,
+            position: position % (steps_per_flap * FLAPS),
+            magnet_width: (steps_per_flap / 2).max(1),
+            misses_every: None,
+            pulses: 0,
+        }
+    }
+
+    pub fn revolution(&self) -> u32 {
+        self.steps_per_flap * FLAPS
+    }
+
+    fn reading(&self) -> u32 {
+        if self.position < self.magnet_width {
+            MAGNET_READING
+        } else {
+            BACKGROUND_READING
+        }
+    }
+
+    fn step(&mut self) {
+        self.pulses += 1;
+
+        if self
+            .misses_every
+            .is_some_and(|every| self.pulses.is_multiple_of(every))
+        {
+            return;
+        }
+
+        self.position = (self.position + 1) % self.revolution();
+    }
+
+    /// The character facing the viewer, going by where the drum really is.
+    pub fn showing(&self) -> u8 {
+        let past_first = (self.position + self.revolution() - self.home_offset % self.revolution())
+            % self.revolution();
+        let flap = (past_first + self.steps_per_flap / 2) / self.steps_per_flap % FLAPS;
+
+        CHARACTER_SET[flap as usize]
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Outcome {
+    /// What the drums show, one character per bit.
+    pub shown: String,
+    /// Steps each bit asked for, whether or not its drum turned.
+    pub steps: [u64; BITS],
+    /// Homing timeouts, restore mismatches and step loss corrections, over every bit.
+    pub faults: u32,
+    pub elapsed_ms: u32,
+    /// False if the time limit ran out first.
+    pub settled: bool,
+}
+
+pub struct Sim {
+    pub device: Device<BITS>,
+    pub drums: [Drum; BITS],
+    /// Replies to the scripted messages, in order.
+    pub replies: Vec<Message<'static>>,
+    /// Messages still to send and when, latest first.
+    script: Vec<(u32, Message<'static>)>,
+    now_ms: u32,
+    steps: [u64; BITS],
+}
+
+impl Sim {
+    /// Powers up a display whose drums were left as given, with nothing to show but blanks.
+    pub fn new(drums: [Drum; BITS]) -> Sim {
+        let mut device = Device::new(drums.map(|drum| {
+            let revolution = Revolution::new(Steps::new(drum.steps_per_flap)).unwrap();
+            SplitFlapBitState::new(CALIBRATION, revolution, HomedSteps::new(drum.home_offset))
+        }));
+        device.set_content(Content::Text([b' '; BITS]));
+
+        Sim {
+            device,
+            drums,
+            replies: Vec::new(),
+            script: Vec::new(),
+            now_ms: 0,
+            steps: [0; BITS],
+        }
+    }
+
+    /// Drums alike but for where they start.
+    pub fn with_positions(steps_per_flap: u32, home_offset: u32, positions: [u32; BITS]) -> Sim {
+        Sim::new(positions.map(|position| Drum::new(steps_per_flap, home_offset, position)))
+    }
+
+    /// Sends `message` once the clock reaches `at_ms`.
+    pub fn at(&mut self, at_ms: u32, message: Message<'static>) -> &mut Sim {
+        self.script.push((at_ms, message));
+        self.script.sort_by_key(|&(at_ms, _)| u32::MAX - at_ms);
+        self
+    }
+
+    /// Sends `message` straight away.
+    pub fn send(&mut self, message: Message<'static>) -> &mut Sim {
+        self.at(self.now_ms, message)
+    }
+
+    /// Sends text straight away, for text that isn't known until the test runs.
+    pub fn show(&mut self, text: &str) -> &mut Sim {
+        let reply = self.device.handle(&Frame {
+            seq: 0,
+            message: Message::SetText(text),
+        });
+        self.replies.push(reply);
+        self
+    }
+
+    /// One pass of the firmware loop, a millisecond long.  Returns whether every bit is at rest
+    /// with nowhere further to go.
+    fn tick(&mut self) -> bool {
+        while let Some(&(at_ms, message)) = self.script.last() {
+            if at_ms > self.now_ms {
+                break;
+            }
+
+            self.script.pop();
+            let reply = self.device.handle(&Frame { seq: 0, message });
+            self.replies.push(reply);
+        }
+
+        let transitioning = self.device.update_transition(self.now_ms);
+        let stepping = self.device.process(self.drums.map(|drum| drum.reading()));
+
+        for ((drum, steps), stepped) in self.drums.iter_mut().zip(&mut self.steps).zip(stepping) {
+            if stepped {
+                drum.step();
+                *steps += 1;
+            }
+        }
+
+        let still = !stepping.contains(&true);
+        if !transitioning && still {
+            if let Some(targets) = self.device.next_targets(None, self.now_ms) {
+                self.device.show(targets);
+            }
+        }
+
+        self.now_ms += 1;
+        still && !(0..BITS).any(|bit| self.device.in_transition(bit))
+    }
+
+    /// Runs until the script is done and the display has come to rest, or for at most
+    /// `limit_ms`.  Steps are counted from the previous run.
+    pub fn run(&mut self, limit_ms: u32) -> Outcome {
+        let started_ms = self.now_ms;
+        let mut quiet_ms = 0;
+        self.steps = [0; BITS];
+
+        while quiet_ms < QUIET_MS && self.now_ms - started_ms < limit_ms {
+            let at_rest = self.tick();
+
+            quiet_ms = match at_rest && self.script.is_empty() {
+                true => quiet_ms + 1,
+                false => 0,
+            };
+        }
+
+        let faults = self
+            .device
+            .bits()
+            .iter()
+            .map(|bit| {
+                let counters = bit.counters();
+                counters.faults.homing_timeouts
+                    + counters.faults.restore_mismatches
+                    + counters.step_loss_corrections
+            })
+            .sum();
+
+        Outcome {
+            shown: self
+                .drums
+                .iter()
+                .map(|drum| drum.showing() as char)
+                .collect(),
+            steps: self.steps,
+            faults,
+            elapsed_ms: self.now_ms - started_ms,
+            settled: quiet_ms == QUIET_MS,
+        }
+    }
+}
+
+/// Splitmix64, to generate cases for property tests.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `range`, which must not be empty.
+    pub fn between(&mut self, range: std::ops::RangeInclusive<u32>) -> u32 {
+        let span = (range.end() - range.start()) as u64 + 1;
+        range.start() + (self.next() % span) as u32
+    }
+
+    pub fn pick<T: Copy>(&mut self, choices: &[T]) -> T {
+        choices[self.between(0..=choices.len() as u32 - 1) as usize]
+    }
+
+    /// Text of characters the drums have, so it shows exactly as generated.
+    pub fn text(&mut self) -> String {
+        let printable: Vec<u8> = CHARACTER_SET
+            .iter()
+            .copied()
+            .filter(|c| c.is_ascii_graphic() || *c == b' ')
+            .collect();
+
+        (0..BITS).map(|_| self.pick(&printable) as char).collect()
+    }
+}
+
+/// Checks `property` against `cases` generated cases, naming the seed of the first that fails
+/// so it can be run alone.
+pub fn check(cases: u64, property: impl Fn(&mut Rng)) {
+    for seed in 0..cases {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| property(&mut Rng::new(seed))));
+
+        if result.is_err() {
+            panic!("Property failed for seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! The device driven step by step against simulated drums, from power up to the text showing.
+
+mod sim;
+
+use split_flap_device::protocol::Message;
+use split_flap_device::transition::{Effect, TransitionConfig};
+
+use sim::{check, Drum, Sim, BITS};
+
+const STEPS_PER_FLAP: u32 = 8;
+const HOME_OFFSET: u32 = 3;
+const LIMIT_MS: u32 = 10_000;
+
+#[test]
+fn text_shows_from_wherever_the_drums_stopped() {
+    for positions in [[0, 0, 0, 0], [7, 100, 250, 439], [3, 4, 5, 6]] {
+        let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, positions);
+        sim.send(Message::SetText("ab12"));
+
+        let outcome = sim.run(LIMIT_MS);
+
+        assert!(outcome.settled, "{:?}", positions);
+        assert_eq!(outcome.shown, "AB12");
+        assert_eq!(outcome.faults, 0);
+    }
+}
+
+#[test]
+fn changing_one_letter_only_turns_that_bit() {
+    let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, [10, 20, 30, 40]);
+    sim.send(Message::SetText("GATE"));
+    assert_eq!(sim.run(LIMIT_MS).shown, "GATE");
+
+    sim.send(Message::SetText("GATS"));
+    let outcome = sim.run(LIMIT_MS);
+
+    assert_eq!(outcome.shown, "GATS");
+    assert_eq!(outcome.steps[..3], [0, 0, 0]);
+    assert_eq!(outcome.steps[3], 14 * STEPS_PER_FLAP as u64);
+}
+
+#[test]
+fn messages_arrive_on_schedule() {
+    let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, [0; BITS]);
+    sim.at(0, Message::SetText("ONE"))
+        .at(2_000, Message::SetText("TWO"))
+        .at(4_000, Message::SetText("SIX"));
+
+    let outcome = sim.run(LIMIT_MS);
+
+    assert!(outcome.elapsed_ms > 4_000);
+    assert_eq!(outcome.shown, "SIX ");
+    assert!(sim
+        .replies
+        .iter()
+        .all(|reply| matches!(reply, Message::Ack { .. })));
+}
+
+#[test]
+fn slipping_is_corrected_at_home() {
+    let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, [0; BITS]);
+    sim.send(Message::SetText("ZZZZ"));
+    sim.run(LIMIT_MS);
+
+    for drum in &mut sim.drums {
+        drum.misses_every = Some(20);
+    }
+
+    // Going round past the magnet shows how far the drums fell behind
+    sim.send(Message::SetText("YYYY"));
+    let outcome = sim.run(LIMIT_MS);
+
+    assert!(outcome.settled);
+    assert_eq!(outcome.faults, BITS as u32);
+}
+
+#[test]
+fn a_missing_magnet_times_out_homing() {
+    let mut drums = [Drum::new(STEPS_PER_FLAP, HOME_OFFSET, 0); BITS];
+    drums[2].magnet_width = 0;
+
+    let mut sim = Sim::new(drums);
+    sim.send(Message::SetText("ABCD"));
+    let outcome = sim.run(LIMIT_MS);
+
+    assert!(!outcome.settled);
+    assert_eq!(outcome.faults, 1);
+    assert_eq!(outcome.steps[2], LIMIT_MS as u64);
+}
+
+#[test]
+fn rehoming_keeps_the_text() {
+    let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, [5, 50, 150, 400]);
+    sim.send(Message::SetText("HOME"));
+    sim.run(LIMIT_MS);
+
+    sim.send(Message::Home);
+    let outcome = sim.run(LIMIT_MS);
+
+    assert_eq!(outcome.shown, "HOME");
+    assert_eq!(outcome.faults, 0);
+    assert!(outcome.steps.iter().all(|&steps| steps > 0));
+}
+
+#[test]
+fn transitions_land_on_the_text() {
+    let config = TransitionConfig::new(&[Effect::Wave { delay_ms: 40 }, Effect::Spin], 7).unwrap();
+
+    let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, [0; BITS]);
+    sim.send(Message::SetText("FROM"));
+    sim.run(LIMIT_MS);
+
+    sim.send(Message::SetTransition(config))
+        .send(Message::SetText("TO"));
+    let outcome = sim.run(LIMIT_MS);
+
+    assert_eq!(outcome.shown, "TO  ");
+    assert_eq!(outcome.faults, 0);
+    // Spinning takes every bit that changes round at least once
+    let revolution = sim.drums[0].revolution() as u64;
+    assert!(outcome.steps.iter().all(|&steps| steps > revolution));
+}
+
+#[test]
+fn any_text_shows_within_two_revolutions_from_any_start() {
+    check(200, |rng| {
+        let steps_per_flap = rng.between(2..=8);
+        let drums = [(); BITS].map(|_| {
+            let mut drum = Drum::new(
+                steps_per_flap,
+                rng.between(0..=steps_per_flap - 1),
+                rng.between(0..=steps_per_flap * 55 - 1),
+            );
+            drum.magnet_width = rng.between(1..=(steps_per_flap / 2).max(1));
+            drum
+        });
+        let text = rng.text();
+
+        let mut sim = Sim::new(drums);
+        sim.show(&text);
+        let outcome = sim.run(LIMIT_MS);
+
+        assert!(outcome.settled);
+        assert_eq!(outcome.shown, text);
+        assert_eq!(outcome.faults, 0);
+        for (steps, drum) in outcome.steps.iter().zip(&sim.drums) {
+            assert!(*steps <= 2 * drum.revolution() as u64);
+        }
+    });
+}
+
+#[test]
+fn later_text_turns_each_bit_less_than_a_revolution() {
+    check(200, |rng| {
+        let positions = [(); BITS].map(|_| rng.between(0..=439));
+        let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, positions);
+        let first = rng.text();
+        let second = rng.text();
+
+        sim.show(&first);
+        sim.run(LIMIT_MS);
+        sim.show(&second);
+        let outcome = sim.run(LIMIT_MS);
+
+        assert_eq!(outcome.shown, second);
+        assert_eq!(outcome.faults, 0);
+        for ((steps, before), after) in outcome.steps.iter().zip(first.bytes()).zip(second.bytes())
+        {
+            assert!(*steps < sim.drums[0].revolution() as u64);
+            assert_eq!(*steps == 0, before == after);
+        }
+    });
+}
+
+#[test]
+fn any_transition_lands_on_the_text() {
+    let effects = [
+        Effect::Wave { delay_ms: 30 },
+        Effect::RandomOrder { delay_ms: 50 },
+        Effect::Spin,
+        Effect::Cascade {
+            every: 4,
+            dwell_ms: 20,
+        },
+    ];
+
+    check(100, |rng| {
+        let chosen: Vec<Effect> = (0..rng.between(1..=3))
+            .map(|_| rng.pick(&effects))
+            .collect();
+        let config = TransitionConfig::new(&chosen, rng.next() as u32).unwrap();
+        let positions = [(); BITS].map(|_| rng.between(0..=439));
+        let text = rng.text();
+
+        let mut sim = Sim::with_positions(STEPS_PER_FLAP, HOME_OFFSET, positions);
+        sim.send(Message::SetTransition(config));
+        sim.show(&text);
+        let outcome = sim.run(LIMIT_MS);
+
+        assert!(outcome.settled, "{:?}", chosen);
+        assert_eq!(outcome.shown, text);
+        assert_eq!(outcome.faults, 0);
+    });
+}
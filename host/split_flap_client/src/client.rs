@@ -0,0 +1,348 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serialport::SerialPort;
+use split_flap_device::idle::IdlePolicy;
+use split_flap_device::marquee::ScrollConfig;
+use split_flap_device::protocol::{
+    encode_frame, BitStatus, ConfigKey, FrameDecoder, Message, NakReason, Received, MAX_ENCODED_LEN,
+};
+use split_flap_device::self_test::SelfTestReport;
+use split_flap_device::split_flap_bit_state::{BitCounters, SensorCalibration};
+use split_flap_device::trace::TraceChunk;
+use split_flap_device::transition::TransitionConfig;
+
+use crate::error::Error;
+
+pub const BAUD_RATE: u32 = 115_200;
+/// How long to wait for each reply before sending the request again.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+pub const DEFAULT_RETRIES: u32 = 2;
+
+// Short enough that the client notices its own deadlines promptly
+const PORT_READ_TIMEOUT: Duration = Duration::from_millis(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+enum Reply {
+    Ack,
+    Status(BitStatus),
+    Config(ConfigKey, u32),
+    Counters(u8, BitCounters),
+    Trace(TraceChunk),
+    SelfTest(u8, Option<SelfTestReport>),
+}
+
+/// Talks to one display.  Requests are retried when no reply arrives in time.
+pub struct Client<P> {
+    port: P,
+    decoder: FrameDecoder,
+    seq: u8,
+    timeout: Duration,
+    retries: u32,
+}
+
+/// Opens a serial port such as `/dev/ttyACM0` with the settings the device expects.
+pub fn open_port(path: &str) -> Result<Box<dyn SerialPort>, Error> {
+    Ok(serialport::new(path, BAUD_RATE)
+        .timeout(PORT_READ_TIMEOUT)
+        .open()?)
+}
+
+impl Client<Box<dyn SerialPort>> {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Ok(Client::new(open_port(path)?))
+    }
+}
+
+impl<P: Read + Write> Client<P> {
+    pub fn new(port: P) -> Client<P> {
+        Client {
+            port,
+            decoder: FrameDecoder::new(),
+            seq: first_seq(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Shows text, which the device normalizes to the flaps it has.
+    pub fn show(&mut self, text: &str) -> Result<(), Error> {
+        self.expect_ack(&Message::SetText(text))
+    }
+
+    /// Shows text, scrolling it across the display with `config` if it doesn't fit.
+    pub fn scroll(&mut self, text: &str, config: ScrollConfig) -> Result<(), Error> {
+        self.expect_ack(&Message::SetScrollingText { text, config })
+    }
+
+    /// Shows one `CHARACTER_SET` index per bit.
+    pub fn show_positions(&mut self, positions: &[u8]) -> Result<(), Error> {
+        self.expect_ack(&Message::SetRawPositions(positions))
+    }
+
+    pub fn bit_status(&mut self, bit: u8) -> Result<BitStatus, Error> {
+        match self.request(&Message::QueryStatus { bit })? {
+            Reply::Status(status) => Ok(status),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    /// The status of every bit, in order.
+    pub fn status(&mut self) -> Result<Vec<BitStatus>, Error> {
+        let mut statuses = Vec::new();
+
+        for bit in 0..=u8::MAX {
+            match self.bit_status(bit) {
+                Ok(status) => statuses.push(status),
+                Err(Error::Nak(NakReason::UnknownBit)) => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(statuses)
+    }
+
+    pub fn home_all(&mut self) -> Result<(), Error> {
+        self.expect_ack(&Message::Home)
+    }
+
+    /// Sweeps `bit` through a revolution to measure its sensor, waiting up to `timeout` for the
+    /// sweep to finish.  Returns the thresholds the device settled on.
+    pub fn calibrate(&mut self, bit: u8, timeout: Duration) -> Result<SensorCalibration, Error> {
+        self.expect_ack(&Message::Calibrate { bit })?;
+
+        let deadline = Instant::now() + timeout;
+
+        while self.bit_status(bit)?.calibrating {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(SensorCalibration {
+            trigger_value: self.get_config(ConfigKey::TriggerValue { bit })?,
+            untrigger_value: self.get_config(ConfigKey::UntriggerValue { bit })?,
+        })
+    }
+
+    /// Spins every bit through a self-test, waiting up to `timeout` for them all to finish.
+    /// Returns a report per bit.
+    pub fn self_test(&mut self, timeout: Duration) -> Result<Vec<(u8, SelfTestReport)>, Error> {
+        let bits: Vec<u8> = self.status()?.iter().map(|status| status.bit).collect();
+        self.expect_ack(&Message::RunSelfTest)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut reports = Vec::new();
+
+        for bit in bits {
+            loop {
+                if let Some(report) = self.self_test_report(bit)? {
+                    reports.push((bit, report));
+                    break;
+                }
+
+                if Instant::now() >= deadline {
+                    return Err(Error::Timeout);
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// The outcome of the last self-test of `bit`, `None` while one is running.
+    pub fn self_test_report(&mut self, bit: u8) -> Result<Option<SelfTestReport>, Error> {
+        match self.request(&Message::QuerySelfTest { bit })? {
+            Reply::SelfTest(reply_bit, report) if reply_bit == bit => Ok(report),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn get_config(&mut self, key: ConfigKey) -> Result<u32, Error> {
+        match self.request(&Message::GetConfig(key))? {
+            Reply::Config(reply_key, value) if reply_key == key => Ok(value),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn set_config(&mut self, key: ConfigKey, value: u32) -> Result<(), Error> {
+        self.expect_ack(&Message::SetConfig(key, value))
+    }
+
+    /// Steps added to where `flap` of `bit` is shown, to correct for an unevenly printed drum.
+    pub fn flap_trim(&mut self, bit: u8, flap: u8) -> Result<i32, Error> {
+        Ok(self.get_config(ConfigKey::FlapTrim { bit, flap })? as i32)
+    }
+
+    pub fn set_flap_trim(&mut self, bit: u8, flap: u8, trim: i32) -> Result<(), Error> {
+        self.set_config(ConfigKey::FlapTrim { bit, flap }, trim as u32)
+    }
+
+    /// What `bit` does with its motor once it stops moving.
+    pub fn idle_policy(&mut self, bit: u8) -> Result<IdlePolicy, Error> {
+        IdlePolicy::from_config(self.get_config(ConfigKey::IdlePolicy { bit })?)
+            .ok_or(Error::UnexpectedReply)
+    }
+
+    pub fn set_idle_policy(&mut self, bit: u8, policy: IdlePolicy) -> Result<(), Error> {
+        self.set_config(ConfigKey::IdlePolicy { bit }, policy.to_config())
+    }
+
+    /// Running totals of how hard `bit` has been working, since it was last reset.
+    pub fn counters(&mut self, bit: u8) -> Result<BitCounters, Error> {
+        match self.request(&Message::QueryCounters { bit })? {
+            Reply::Counters(reply_bit, counters) if reply_bit == bit => Ok(counters),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn reset_counters(&mut self, bit: u8) -> Result<(), Error> {
+        self.expect_ack(&Message::ResetCounters { bit })
+    }
+
+    /// Homes `bit` and starts recording its sensor readings, to be collected with `read_trace`.
+    pub fn start_trace(&mut self, bit: u8) -> Result<(), Error> {
+        self.expect_ack(&Message::StartTrace { bit })
+    }
+
+    pub fn stop_trace(&mut self) -> Result<(), Error> {
+        self.expect_ack(&Message::StopTrace)
+    }
+
+    /// Takes the oldest samples the device has recorded.  A request retried after a lost reply
+    /// gets the same samples again.
+    pub fn read_trace(&mut self) -> Result<TraceChunk, Error> {
+        match self.request(&Message::ReadTrace)? {
+            Reply::Trace(chunk) => Ok(chunk),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    /// Sets the effects the device uses whenever the display changes.
+    pub fn set_transition(&mut self, config: TransitionConfig) -> Result<(), Error> {
+        self.expect_ack(&Message::SetTransition(config))
+    }
+
+    /// Stores the calibration, trims and character sets in flash, to be used after a restart.
+    pub fn save_config(&mut self) -> Result<(), Error> {
+        self.expect_ack(&Message::SaveConfig)
+    }
+
+    pub fn clear_playlist(&mut self) -> Result<(), Error> {
+        self.expect_ack(&Message::ClearPlaylist)
+    }
+
+    /// Replaces the playlist with `entries` of text and dwell seconds, and starts playing it.
+    pub fn push_playlist(&mut self, entries: &[(&str, u16)]) -> Result<(), Error> {
+        self.clear_playlist()?;
+
+        for &(text, dwell_seconds) in entries {
+            self.expect_ack(&Message::AddPlaylistEntry {
+                text,
+                dwell_seconds,
+            })?;
+        }
+
+        self.expect_ack(&Message::StartPlaylist)
+    }
+
+    fn expect_ack(&mut self, message: &Message) -> Result<(), Error> {
+        match self.request(message)? {
+            Reply::Ack => Ok(()),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    fn request(&mut self, message: &Message) -> Result<Reply, Error> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut encoded = [0u8; MAX_ENCODED_LEN];
+        let len = encode_frame(seq, message, &mut encoded).map_err(Error::Protocol)?;
+
+        // A retry keeps the sequence number, so the device answers it without acting twice
+        for _ in 0..=self.retries {
+            self.port.write_all(&encoded[..len])?;
+            self.port.flush()?;
+
+            if let Some(reply) = self.read_reply(seq)? {
+                return reply;
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    /// Waits for the reply to `seq`.  `None` means the request should be sent again.
+    fn read_reply(&mut self, seq: u8) -> Result<Option<Result<Reply, Error>>, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0u8; 64];
+
+        while Instant::now() < deadline {
+            let count = match self.port.read(&mut buf) {
+                Ok(0) => return Err(Error::Disconnected),
+                Ok(count) => count,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            for &byte in &buf[..count] {
+                let reply = match self.decoder.push(byte) {
+                    // Replies to earlier requests that arrived late, or telemetry
+                    Received::Frame(frame) if frame.seq != seq => continue,
+                    Received::Frame(frame) => match frame.message {
+                        Message::Ack { .. } => Ok(Reply::Ack),
+                        Message::Nak {
+                            reason: NakReason::Corrupt,
+                            ..
+                        } => return Ok(None),
+                        Message::Nak { reason, .. } => Err(Error::Nak(reason)),
+                        Message::Status(status) => Ok(Reply::Status(status)),
+                        Message::Config(key, value) => Ok(Reply::Config(key, value)),
+                        Message::Counters { bit, counters } => Ok(Reply::Counters(bit, counters)),
+                        Message::Trace(chunk) => Ok(Reply::Trace(chunk)),
+                        Message::SelfTestResult { bit, report } => Ok(Reply::SelfTest(bit, report)),
+                        _ => Err(Error::UnexpectedReply),
+                    },
+                    Received::Corrupt {
+                        seq: Some(corrupt_seq),
+                        ..
+                    } if corrupt_seq == seq => return Ok(None),
+                    _ => continue,
+                };
+
+                return Ok(Some(reply));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Starts each client somewhere else, so its first request isn't taken for a resend of the last
+/// one an earlier client made.
+fn first_seq() -> u8 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_micros() as u8)
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
@@ -0,0 +1,21 @@
+use std::io;
+
+use split_flap_device::protocol::{NakReason, ProtocolError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("serial port: {0}")]
+    Serial(#[from] serialport::Error),
+    #[error("i/o: {0}")]
+    Io(#[from] io::Error),
+    #[error("no reply from device")]
+    Timeout,
+    #[error("device rejected request: {0:?}")]
+    Nak(NakReason),
+    #[error("protocol: {0:?}")]
+    Protocol(ProtocolError),
+    #[error("unexpected reply from device")]
+    UnexpectedReply,
+    #[error("device disconnected")]
+    Disconnected,
+}
@@ -0,0 +1,254 @@
+//! A display simulated in process, for testing host software without hardware.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use split_flap_device::clock::DateTime;
+use split_flap_device::device::{Content, Device};
+use split_flap_device::protocol::{
+    encode_frame, FrameDecoder, Message, NakReason, Received, MAX_ENCODED_LEN,
+};
+use split_flap_device::split_flap_bit_state::{
+    SensorCalibration, SplitFlapBitState, CHARACTER_SET,
+};
+use split_flap_device::steps::{HomedSteps, Revolution, Steps};
+
+pub const FAKE_BITS: usize = 4;
+/// Far fewer than a real drum, so the simulation settles quickly.
+pub const FAKE_STEPS_PER_FLAP: u32 = 4;
+
+const REVOLUTION: u32 = FAKE_STEPS_PER_FLAP * CHARACTER_SET.len() as u32;
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+/// One end of an in-memory byte stream, standing in for a serial port.
+pub struct PipeEnd {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+/// Two connected ends.  Reads time out like a serial port and return 0 once the other end is
+/// dropped.
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+
+    (
+        PipeEnd {
+            tx: a_tx,
+            rx: a_rx,
+            pending: VecDeque::new(),
+        },
+        PipeEnd {
+            tx: b_tx,
+            rx: b_rx,
+            pending: VecDeque::new(),
+        },
+    )
+}
+
+impl PipeEnd {
+    // Non-blocking, for the simulation loop
+    fn try_fill(&mut self) -> Result<(), TryRecvError> {
+        loop {
+            let chunk = self.rx.try_recv()?;
+            self.pending.extend(chunk);
+        }
+    }
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(READ_TIMEOUT) {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.pending.len());
+        for (byte, pending) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *byte = pending;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the device logic against simulated drums in a background thread.  Stopped when dropped.
+pub struct FakeDevice {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeDevice {
+    /// Starts the simulation, returning the host end of its serial link.
+    pub fn spawn() -> (FakeDevice, PipeEnd) {
+        let (host, port) = pipe();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || simulate(port, &stop))
+        };
+
+        (
+            FakeDevice {
+                stop,
+                thread: Some(thread),
+            },
+            host,
+        )
+    }
+}
+
+impl Drop for FakeDevice {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A fake device together with the host end of its link, stopped when dropped.  Stands in for a
+/// serial port wherever one is expected.
+pub struct FakePort {
+    _device: FakeDevice,
+    port: PipeEnd,
+}
+
+impl FakePort {
+    pub fn spawn() -> FakePort {
+        let (device, port) = FakeDevice::spawn();
+
+        FakePort {
+            _device: device,
+            port,
+        }
+    }
+}
+
+impl Read for FakePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for FakePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+/// Home sensor reading for a drum at `position` steps, bright just past the magnet.
+fn sensor_value(position: u32) -> u32 {
+    if position < FAKE_STEPS_PER_FLAP / 2 {
+        3000
+    } else {
+        400
+    }
+}
+
+/// The simulated clock starts at midnight on 2025-01-01 when the device is spawned.
+fn clock(started: Instant) -> DateTime {
+    let elapsed = started.elapsed().as_secs();
+
+    DateTime {
+        year: 2025,
+        month: 1,
+        day: 1 + (elapsed / 86_400).min(30) as u8,
+        hour: (elapsed / 3600 % 24) as u8,
+        minute: (elapsed / 60 % 60) as u8,
+        second: (elapsed % 60) as u8,
+    }
+}
+
+fn simulate(mut port: PipeEnd, stop: &AtomicBool) {
+    let calibration = SensorCalibration {
+        trigger_value: 2200,
+        untrigger_value: 2100,
+    };
+    let mut device: Device<FAKE_BITS> = Device::new(core::array::from_fn(|_| {
+        SplitFlapBitState::new(
+            calibration,
+            Revolution::new(Steps::new(FAKE_STEPS_PER_FLAP)).unwrap(),
+            HomedSteps::new(FAKE_STEPS_PER_FLAP * 5),
+        )
+    }));
+    device.set_content(Content::Text([b' '; FAKE_BITS]));
+
+    // Drums power up wherever they were left
+    let mut positions: [u32; FAKE_BITS] =
+        core::array::from_fn(|idx| (idx as u32 * 37) % REVOLUTION);
+    let mut decoder = FrameDecoder::new();
+    let started = Instant::now();
+
+    while !stop.load(Ordering::Relaxed) {
+        if port.try_fill() == Err(TryRecvError::Disconnected) && port.pending.is_empty() {
+            return;
+        }
+
+        while let Some(byte) = port.pending.pop_front() {
+            let (seq, reply) = match decoder.push(byte) {
+                Received::Frame(frame) => (frame.seq, device.handle(&frame)),
+                Received::Corrupt { seq, .. } => {
+                    let seq = seq.unwrap_or(0);
+                    let reason = NakReason::Corrupt;
+                    (seq, Message::Nak { seq, reason })
+                }
+                Received::Pending | Received::Byte(_) => continue,
+            };
+
+            let mut encoded = [0u8; MAX_ENCODED_LEN];
+            if let Ok(len) = encode_frame(seq, &reply, &mut encoded) {
+                let _ = port.write(&encoded[..len]);
+            }
+        }
+
+        let transitioning = device.update_transition(started.elapsed().as_millis() as u32);
+        let steps = device.process(positions.map(sensor_value));
+
+        for (position, &step) in positions.iter_mut().zip(steps.iter()) {
+            if step {
+                *position = (*position + 1) % REVOLUTION;
+            }
+        }
+
+        if !transitioning && steps.iter().all(|&step| !step) {
+            let now_ms = started.elapsed().as_millis() as u32;
+
+            if let Some(targets) = device.next_targets(Some(&clock(started)), now_ms) {
+                device.show(targets);
+            }
+
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! Host side client for split-flap displays attached over USB serial.
+
+pub mod client;
+pub mod error;
+pub mod fake;
+pub mod trace;
@@ -0,0 +1,249 @@
+//! Sensor traces recorded from a device, saved as CSV and replayed against the bit logic.
+//!
+//! A file starts with the settings the bit was recorded with, then a row per reading:
+//!
+//! ```text
+//! # steps_per_revolution=220 home_offset=20 trigger=2200 untrigger=2100
+//! step,reading,sensor,state,target_flap,stepped
+//! 0,400,untriggered,uninitialized,0,1
+//! ```
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use split_flap_device::protocol::ConfigKey;
+use split_flap_device::split_flap_bit_state::{
+    BitState, SensorCalibration, SensorState, SplitFlapBitState,
+};
+use split_flap_device::steps::{HomedSteps, Revolution, Steps};
+use split_flap_device::trace::{self, Divergence, TraceSample};
+
+use crate::client::Client;
+use crate::error::Error;
+
+const HEADER: &str = "step,reading,sensor,state,target_flap,stepped";
+// Lets the device record a few more samples between empty reads
+const EMPTY_READ_SLEEP: Duration = Duration::from_millis(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TraceError {
+    #[error("line {line}: {reason}")]
+    Line { line: usize, reason: String },
+    #[error("no settings line")]
+    MissingSettings,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceFile {
+    pub revolution: Revolution,
+    pub home_offset: HomedSteps,
+    pub calibration: SensorCalibration,
+    pub samples: Vec<TraceSample>,
+}
+
+impl TraceFile {
+    /// Homes `bit` and records its first `count` readings.  Also returns how many samples the
+    /// device dropped because they weren't read quickly enough, which spoils a replay.
+    pub fn record<P: Read + Write>(
+        client: &mut Client<P>,
+        bit: u8,
+        count: usize,
+    ) -> Result<(TraceFile, u32), Error> {
+        let steps = client.get_config(ConfigKey::StepsPerRevolution { bit })?;
+        let revolution = Revolution::of_steps(Steps::new(steps)).ok_or(Error::UnexpectedReply)?;
+        let home_offset = HomedSteps::new(client.get_config(ConfigKey::HomeOffset { bit })?);
+        let calibration = SensorCalibration {
+            trigger_value: client.get_config(ConfigKey::TriggerValue { bit })?,
+            untrigger_value: client.get_config(ConfigKey::UntriggerValue { bit })?,
+        };
+
+        let mut samples = Vec::with_capacity(count);
+        let mut dropped = 0u32;
+
+        client.start_trace(bit)?;
+
+        while samples.len() < count {
+            let chunk = client.read_trace()?;
+            dropped = dropped.saturating_add(chunk.dropped);
+
+            if chunk.samples().is_empty() {
+                thread::sleep(EMPTY_READ_SLEEP);
+            }
+
+            samples.extend_from_slice(chunk.samples());
+        }
+
+        client.stop_trace()?;
+        samples.truncate(count);
+
+        let file = TraceFile {
+            revolution,
+            home_offset,
+            calibration,
+            samples,
+        };
+
+        Ok((file, dropped))
+    }
+
+    /// Feeds the readings through a bit set up like the recorded one, returning how many
+    /// samples it agreed with.
+    pub fn replay(&self) -> Result<usize, Divergence> {
+        let mut bit = SplitFlapBitState::new(self.calibration, self.revolution, self.home_offset);
+
+        trace::replay(&mut bit, &self.samples)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# steps_per_revolution={} home_offset={} trigger={} untrigger={}",
+            self.revolution.steps().get(),
+            self.home_offset.get(),
+            self.calibration.trigger_value,
+            self.calibration.untrigger_value
+        );
+        let _ = writeln!(out, "{}", HEADER);
+
+        for sample in &self.samples {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                sample.step,
+                sample.reading,
+                sensor_name(sample.sensor),
+                state_name(sample.state),
+                sample.target_flap,
+                sample.stepped as u8
+            );
+        }
+
+        out
+    }
+
+    pub fn parse(text: &str) -> Result<TraceFile, TraceError> {
+        let mut settings = None;
+        let mut samples = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_error = |reason: &str| TraceError::Line {
+                line: idx + 1,
+                reason: reason.to_owned(),
+            };
+            let line = line.trim();
+
+            if let Some(comment) = line.strip_prefix('#') {
+                if comment.contains('=') {
+                    settings = Some(parse_settings(comment).map_err(line_error)?);
+                }
+                continue;
+            }
+
+            if line.is_empty() || line == HEADER {
+                continue;
+            }
+
+            samples.push(parse_sample(line).map_err(line_error)?);
+        }
+
+        let (revolution, home_offset, calibration) = settings.ok_or(TraceError::MissingSettings)?;
+
+        Ok(TraceFile {
+            revolution,
+            home_offset,
+            calibration,
+            samples,
+        })
+    }
+}
+
+fn parse_settings(line: &str) -> Result<(Revolution, HomedSteps, SensorCalibration), &'static str> {
+    let mut revolution = None;
+    let mut values = [None; 3];
+
+    for pair in line.split_whitespace() {
+        let (name, value) = pair.split_once('=').ok_or("expected name=value")?;
+        let value = value.parse().map_err(|_| "setting is not a number")?;
+
+        let slot = match name {
+            "steps_per_revolution" => {
+                let steps = Revolution::of_steps(Steps::new(value));
+                revolution = Some(steps.ok_or("steps_per_revolution is out of range")?);
+                continue;
+            }
+            // Written before revolutions needn't share evenly between the flaps
+            "steps_per_flap" => {
+                let steps = Revolution::new(Steps::new(value));
+                revolution = Some(steps.ok_or("steps_per_flap is out of range")?);
+                continue;
+            }
+            "home_offset" => 0,
+            "trigger" => 1,
+            "untrigger" => 2,
+            _ => return Err("unknown setting"),
+        };
+        values[slot] = Some(value);
+    }
+
+    match (revolution, values) {
+        (Some(revolution), [Some(home_offset), Some(trigger_value), Some(untrigger_value)]) => {
+            Ok((
+                revolution,
+                HomedSteps::new(home_offset),
+                SensorCalibration {
+                    trigger_value,
+                    untrigger_value,
+                },
+            ))
+        }
+        _ => Err("missing a setting"),
+    }
+}
+
+fn parse_sample(line: &str) -> Result<TraceSample, &'static str> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [step, reading, sensor, state, target_flap, stepped] = fields[..] else {
+        return Err("expected 6 fields");
+    };
+
+    Ok(TraceSample {
+        step: step.parse().map_err(|_| "bad step")?,
+        reading: reading.parse().map_err(|_| "bad reading")?,
+        sensor: match sensor {
+            "triggered" => SensorState::Triggered,
+            "untriggered" => SensorState::Untriggered,
+            _ => return Err("bad sensor state"),
+        },
+        state: match state {
+            "uninitialized" => BitState::UNINITIALIZED,
+            "seeking" => BitState::SEEKING,
+            "settled" => BitState::SETTLED,
+            _ => return Err("bad bit state"),
+        },
+        target_flap: target_flap.parse().map_err(|_| "bad target flap")?,
+        stepped: match stepped {
+            "0" => false,
+            "1" => true,
+            _ => return Err("bad stepped flag"),
+        },
+    })
+}
+
+fn sensor_name(sensor: SensorState) -> &'static str {
+    match sensor {
+        SensorState::Triggered => "triggered",
+        SensorState::Untriggered => "untriggered",
+    }
+}
+
+fn state_name(state: BitState) -> &'static str {
+    match state {
+        BitState::UNINITIALIZED => "uninitialized",
+        BitState::SEEKING => "seeking",
+        BitState::SETTLED => "settled",
+    }
+}
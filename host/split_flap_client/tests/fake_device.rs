@@ -0,0 +1,257 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use split_flap_client::client::Client;
+use split_flap_client::error::Error;
+use split_flap_client::fake::{pipe, FakeDevice, PipeEnd, FAKE_BITS};
+use split_flap_device::device::text_targets;
+use split_flap_device::marquee::ScrollConfig;
+use split_flap_device::protocol::{BitStatus, ConfigKey, NakReason};
+use split_flap_device::split_flap_bit_state::{BitState, SensorCalibration, CHARACTER_SET};
+use split_flap_device::transition::{Effect, TransitionConfig};
+
+fn connect() -> (FakeDevice, Client<PipeEnd>) {
+    let (device, port) = FakeDevice::spawn();
+
+    (device, Client::new(port))
+}
+
+/// Polls until every bit has settled showing `text`.
+fn wait_for_text(client: &mut Client<PipeEnd>, text: &str) -> Vec<BitStatus> {
+    let mut expected = [0u8; FAKE_BITS];
+    text_targets(text.as_bytes(), &mut expected);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        let statuses = client.status().unwrap();
+        let showing = statuses.iter().all(|status| {
+            status.state == BitState::SETTLED
+                && CHARACTER_SET[status.flap as usize] == expected[status.bit as usize]
+        });
+
+        if showing {
+            return statuses;
+        }
+
+        assert!(Instant::now() < deadline, "Display never showed {:?}", text);
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn show_moves_every_bit() {
+    let (_device, mut client) = connect();
+
+    client.show("rail").unwrap();
+    let statuses = wait_for_text(&mut client, "RAIL");
+
+    assert_eq!(statuses.len(), FAKE_BITS);
+    assert_eq!(
+        statuses.iter().map(|s| s.target_flap).collect::<Vec<_>>(),
+        [18, 1, 9, 12]
+    );
+}
+
+#[test]
+fn scrolling_text_starts_at_the_beginning() {
+    let (_device, mut client) = connect();
+
+    client
+        .scroll("departures", ScrollConfig::default())
+        .unwrap();
+    wait_for_text(&mut client, "DEPA");
+}
+
+#[test]
+fn transitions_land_on_the_text() {
+    let (_device, mut client) = connect();
+    let effects = [
+        Effect::Wave { delay_ms: 5 },
+        Effect::Spin,
+        Effect::Cascade {
+            every: 20,
+            dwell_ms: 2,
+        },
+    ];
+    let still = Effect::Cascade {
+        every: 0,
+        dwell_ms: 0,
+    };
+
+    assert!(matches!(
+        client.set_transition(TransitionConfig::new(&[still], 0).unwrap()),
+        Err(Error::Nak(NakReason::InvalidValue))
+    ));
+
+    client
+        .set_transition(TransitionConfig::new(&effects, 3).unwrap())
+        .unwrap();
+    client.show("rail").unwrap();
+    let landed = wait_for_text(&mut client, "RAIL");
+
+    // The same text is shown again each time the bits settle, which mustn't spin them again
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(client.status().unwrap(), landed);
+    }
+}
+
+#[test]
+fn unknown_bit_is_reported() {
+    let (_device, mut client) = connect();
+
+    assert!(matches!(
+        client.bit_status(FAKE_BITS as u8),
+        Err(Error::Nak(NakReason::UnknownBit))
+    ));
+}
+
+#[test]
+fn config_round_trips() {
+    let (_device, mut client) = connect();
+    let key = ConfigKey::UntriggerValue { bit: 2 };
+
+    client.set_config(key, 1234).unwrap();
+
+    assert_eq!(client.get_config(key).unwrap(), 1234);
+    assert!(matches!(
+        client.set_config(ConfigKey::StepsPerFlap { bit: 0 }, 10),
+        Err(Error::Nak(NakReason::Unsupported))
+    ));
+}
+
+#[test]
+fn home_all_finds_home_again() {
+    let (_device, mut client) = connect();
+
+    client.show("AB").unwrap();
+    wait_for_text(&mut client, "AB");
+
+    client.home_all().unwrap();
+
+    // Homing brings every bit back to the first flap before the text is shown again
+    assert!(client
+        .status()
+        .unwrap()
+        .iter()
+        .any(|status| status.state != BitState::SETTLED || status.flap == 0));
+    wait_for_text(&mut client, "AB");
+}
+
+#[test]
+fn calibrate_measures_sensor() {
+    let (_device, mut client) = connect();
+
+    let calibration = client.calibrate(1, Duration::from_secs(5)).unwrap();
+
+    // The simulated sensor reads 400 away from home and 3000 over the magnet
+    assert_eq!(
+        calibration,
+        SensorCalibration {
+            trigger_value: 2350,
+            untrigger_value: 1700
+        }
+    );
+    assert_eq!(
+        client
+            .get_config(ConfigKey::TriggerValue { bit: 0 })
+            .unwrap(),
+        2200
+    );
+
+    client.show("OK").unwrap();
+    wait_for_text(&mut client, "OK");
+}
+
+#[test]
+fn silent_device_times_out() {
+    let (port, _device_end) = pipe();
+    let mut client = Client::new(port);
+    client.set_timeout(Duration::from_millis(30));
+    client.set_retries(1);
+
+    let started = Instant::now();
+
+    assert!(matches!(client.home_all(), Err(Error::Timeout)));
+    assert!(started.elapsed() >= Duration::from_millis(60));
+}
+
+#[test]
+fn dropped_device_is_reported() {
+    let (port, device_end) = pipe();
+    let mut client = Client::new(port);
+
+    drop(device_end);
+
+    assert!(matches!(
+        client.show("HI"),
+        Err(Error::Io(_)) | Err(Error::Disconnected)
+    ));
+}
+
+#[test]
+fn flap_trim_round_trips() {
+    let (_device, mut client) = connect();
+
+    client.set_flap_trim(3, 10, -1).unwrap();
+
+    assert_eq!(client.flap_trim(3, 10).unwrap(), -1);
+    assert_eq!(client.flap_trim(2, 10).unwrap(), 0);
+    assert!(matches!(
+        client.set_flap_trim(3, 10, 2),
+        Err(Error::Nak(NakReason::InvalidValue))
+    ));
+}
+
+#[test]
+fn playlist_shows_first_entry() {
+    let (_device, mut client) = connect();
+
+    client.push_playlist(&[("BUS", 60), ("TRAM", 60)]).unwrap();
+    wait_for_text(&mut client, "BUS");
+
+    assert!(matches!(
+        client.push_playlist(&[]),
+        Err(Error::Nak(NakReason::InvalidValue))
+    ));
+}
+
+#[test]
+fn counters_follow_the_display_and_reset() {
+    let (_device, mut client) = connect();
+
+    client.show("zone").unwrap();
+    wait_for_text(&mut client, "ZONE");
+
+    let counters = client.counters(0).unwrap();
+    assert!(counters.steps > 0);
+    assert!(counters.home_detections > 0);
+    assert!(counters.characters_displayed > 0);
+
+    client.reset_counters(0).unwrap();
+    assert_eq!(client.counters(0).unwrap().steps, 0);
+    assert!(client.counters(1).unwrap().steps > 0);
+
+    assert!(matches!(
+        client.counters(FAKE_BITS as u8),
+        Err(Error::Nak(NakReason::UnknownBit))
+    ));
+}
+
+#[test]
+fn self_test_passes_every_bit() {
+    let (_device, mut client) = connect();
+
+    let reports = client.self_test(Duration::from_secs(5)).unwrap();
+
+    assert_eq!(reports.len(), FAKE_BITS);
+    for (bit, report) in reports {
+        assert!(report.passed(), "Bit {} failed: {:?}", bit, report);
+        assert_eq!(report.home_triggers, 2);
+    }
+
+    // Each bit homes again afterwards
+    client.show("OK").unwrap();
+    wait_for_text(&mut client, "OK");
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::Path;
+
+use split_flap_client::client::Client;
+use split_flap_client::fake::{FakeDevice, FAKE_STEPS_PER_FLAP};
+use split_flap_client::trace::TraceFile;
+use split_flap_device::split_flap_bit_state::{BitState, CHARACTER_SET};
+
+// Enough to home from anywhere on the drum, then show a character
+const SAMPLES: usize = 3 * FAKE_STEPS_PER_FLAP as usize * CHARACTER_SET.len();
+
+fn record() -> TraceFile {
+    let (_device, port) = FakeDevice::spawn();
+    let mut client = Client::new(port);
+
+    client.show("WXYZ").unwrap();
+    let (file, dropped) = TraceFile::record(&mut client, 1, SAMPLES).unwrap();
+    assert_eq!(dropped, 0);
+
+    file
+}
+
+#[test]
+fn fake_device_trace_replays() {
+    let file = record();
+
+    assert_eq!(file.samples.len(), SAMPLES);
+    assert_eq!(file.samples[0].state, BitState::UNINITIALIZED);
+    assert_eq!(file.samples.last().unwrap().state, BitState::SETTLED);
+    assert_eq!(file.replay(), Ok(SAMPLES));
+}
+
+#[test]
+fn trace_survives_csv() {
+    let file = record();
+
+    assert_eq!(TraceFile::parse(&file.to_csv()).unwrap(), file);
+}
+
+#[test]
+fn tampered_trace_diverges() {
+    let mut file = record();
+    let homed = file
+        .samples
+        .iter()
+        .position(|sample| sample.state != BitState::UNINITIALIZED)
+        .unwrap();
+
+    // The magnet reading is missed, so the bit carries on homing
+    file.samples[homed].reading = 0;
+
+    assert_eq!(file.replay().unwrap_err().index, homed);
+}
+
+/// Recordings kept because they once showed a bug, each of which must still replay cleanly.
+#[test]
+fn recorded_traces_replay() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/traces");
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let file = TraceFile::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        if let Err(divergence) = file.replay() {
+            panic!("{}: {:?}", path.display(), divergence);
+        }
+    }
+}
+
+#[test]
+fn malformed_rows_name_their_line() {
+    let text = "# steps_per_flap=4 home_offset=20 trigger=2200 untrigger=2100\n\
+                step,reading,sensor,state,target_flap,stepped\n\
+                0,400,sideways,uninitialized,0,1\n";
+
+    assert_eq!(
+        TraceFile::parse(text).unwrap_err().to_string(),
+        "line 3: bad sensor state"
+    );
+}
@@ -0,0 +1,187 @@
+//! Per-bit settings saved to and restored from TOML files, for copying a tuned display's
+//! calibration to a replacement or keeping it under version control.
+
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+use split_flap_client::client::Client;
+use split_flap_device::idle::IdlePolicy;
+use split_flap_device::protocol::ConfigKey;
+use split_flap_device::split_flap_bit_state::CHARACTER_SET;
+
+use crate::error::Error;
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ConfigFile {
+    pub bits: Vec<BitConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BitConfig {
+    pub bit: u8,
+    /// Fixed in firmware, only checked when loading.
+    pub steps_per_flap: u32,
+    /// Fixed in firmware, only checked when loading.
+    pub home_offset: u32,
+    pub trigger_value: u32,
+    pub untrigger_value: u32,
+    /// Skip homing on power up by restoring the position saved before it.
+    #[serde(default)]
+    pub restore_position: bool,
+    /// What the motor driver does once the bit stops moving.
+    #[serde(default)]
+    pub idle_policy: IdlePolicy,
+    /// One per flap, in `CHARACTER_SET` order.
+    pub flap_trims: Vec<i32>,
+}
+
+/// Reads every setting of every bit.
+pub fn dump<P: Read + Write>(client: &mut Client<P>) -> Result<ConfigFile, Error> {
+    let mut bits = Vec::new();
+
+    for status in client.status()? {
+        let bit = status.bit;
+        let flap_trims = (0..CHARACTER_SET.len() as u8)
+            .map(|flap| client.flap_trim(bit, flap))
+            .collect::<Result<_, _>>()?;
+
+        bits.push(BitConfig {
+            bit,
+            steps_per_flap: client.get_config(ConfigKey::StepsPerFlap { bit })?,
+            home_offset: client.get_config(ConfigKey::HomeOffset { bit })?,
+            trigger_value: client.get_config(ConfigKey::TriggerValue { bit })?,
+            untrigger_value: client.get_config(ConfigKey::UntriggerValue { bit })?,
+            restore_position: client.get_config(ConfigKey::RestorePosition { bit })? != 0,
+            idle_policy: client.idle_policy(bit)?,
+            flap_trims,
+        });
+    }
+
+    Ok(ConfigFile { bits })
+}
+
+/// Applies `config` to the device.  Everything is checked before anything is written, so a file
+/// from a differently built display changes nothing.
+pub fn load<P: Read + Write>(client: &mut Client<P>, config: &ConfigFile) -> Result<(), Error> {
+    let device_bits = client.status()?.len();
+
+    if config.bits.len() != device_bits {
+        return Err(Error::BitCount {
+            file: config.bits.len(),
+            device: device_bits,
+        });
+    }
+
+    for bit_config in &config.bits {
+        let bit = bit_config.bit;
+
+        if !bit_config.idle_policy.is_valid() {
+            return Err(Error::IdlePolicy {
+                bit,
+                policy: bit_config.idle_policy,
+            });
+        }
+
+        let fixed = [
+            (
+                "steps_per_flap",
+                ConfigKey::StepsPerFlap { bit },
+                bit_config.steps_per_flap,
+            ),
+            (
+                "home_offset",
+                ConfigKey::HomeOffset { bit },
+                bit_config.home_offset,
+            ),
+        ];
+
+        for (setting, key, file) in fixed {
+            let device = client.get_config(key)?;
+
+            if device != file {
+                return Err(Error::FixedSetting {
+                    bit,
+                    setting,
+                    device,
+                    file,
+                });
+            }
+        }
+    }
+
+    for bit_config in &config.bits {
+        let bit = bit_config.bit;
+
+        client.set_config(ConfigKey::TriggerValue { bit }, bit_config.trigger_value)?;
+        client.set_config(
+            ConfigKey::UntriggerValue { bit },
+            bit_config.untrigger_value,
+        )?;
+        client.set_config(
+            ConfigKey::RestorePosition { bit },
+            bit_config.restore_position as u32,
+        )?;
+        client.set_idle_policy(bit, bit_config.idle_policy)?;
+
+        for (flap, &trim) in bit_config.flap_trims.iter().enumerate() {
+            client.set_flap_trim(bit, flap as u8, trim)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_client::client::Client;
+    use split_flap_client::fake::{FakeDevice, FAKE_BITS, FAKE_STEPS_PER_FLAP};
+    use split_flap_device::idle::IdlePolicy;
+
+    use super::{dump, load, ConfigFile};
+    use crate::error::Error;
+
+    #[test]
+    fn dump_survives_toml_and_load() {
+        let (_device, port) = FakeDevice::spawn();
+        let mut client = Client::new(port);
+
+        let mut config = dump(&mut client).unwrap();
+        assert_eq!(config.bits.len(), FAKE_BITS);
+        assert_eq!(config.bits[1].steps_per_flap, FAKE_STEPS_PER_FLAP);
+
+        config.bits[1].trigger_value = 2500;
+        config.bits[1].flap_trims[7] = -1;
+        config.bits[2].restore_position = true;
+        config.bits[0].idle_policy = IdlePolicy::HoldFor { ms: 500 };
+        config.bits[3].idle_policy = IdlePolicy::ReducedHold { duty_percent: 30 };
+
+        let text = toml::to_string(&config).unwrap();
+        let parsed: ConfigFile = toml::from_str(&text).unwrap();
+        load(&mut client, &parsed).unwrap();
+
+        assert_eq!(dump(&mut client).unwrap(), config);
+    }
+
+    #[test]
+    fn fixed_settings_must_match() {
+        let (_device, port) = FakeDevice::spawn();
+        let mut client = Client::new(port);
+
+        let mut config = dump(&mut client).unwrap();
+        config.bits[2].trigger_value = 9999;
+        config.bits[3].steps_per_flap += 1;
+        config.bits[1].idle_policy = IdlePolicy::ReducedHold { duty_percent: 100 };
+
+        assert!(matches!(
+            load(&mut client, &config),
+            Err(Error::IdlePolicy { bit: 1, .. })
+        ));
+
+        config.bits[1].idle_policy = IdlePolicy::Hold;
+        assert!(matches!(
+            load(&mut client, &config),
+            Err(Error::FixedSetting { bit: 3, .. })
+        ));
+        assert_ne!(dump(&mut client).unwrap().bits[2].trigger_value, 9999);
+    }
+}
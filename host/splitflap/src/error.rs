@@ -0,0 +1,47 @@
+use std::io;
+use std::path::PathBuf;
+
+use split_flap_client::trace::TraceError;
+use split_flap_device::idle::IdlePolicy;
+use split_flap_device::trace::TraceSample;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Client(#[from] split_flap_client::error::Error),
+    #[error("{path}: {source}")]
+    File { path: PathBuf, source: io::Error },
+    #[error("{path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{path}: {source}")]
+    Trace { path: PathBuf, source: TraceError },
+    #[error("{path}: sample {index} was {recorded:?} when recorded, {replayed:?} when replayed")]
+    Diverged {
+        path: PathBuf,
+        index: usize,
+        recorded: TraceSample,
+        replayed: TraceSample,
+    },
+    #[error("writing config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("bit {bit}: {setting} is {device} on the device, reflash to change it to {file}")]
+    FixedSetting {
+        bit: u8,
+        setting: &'static str,
+        device: u32,
+        file: u32,
+    },
+    #[error("bit {bit}: {policy:?} is not a valid idle policy")]
+    IdlePolicy { bit: u8, policy: IdlePolicy },
+    #[error("{0} bits failed the self-test")]
+    SelfTestFailed(usize),
+    #[error("{0} effects given but the device takes at most {1}")]
+    TooManyEffects(usize, usize),
+    #[error("no flap shows {0:?}")]
+    UnknownFlap(char),
+    #[error("config has {file} bits but the device has {device}")]
+    BitCount { file: usize, device: usize },
+}
@@ -0,0 +1,373 @@
+//! Operates a split-flap display over USB serial.
+
+mod config;
+mod error;
+mod playlist;
+mod scroll;
+mod self_test;
+mod transition;
+mod watch;
+mod wear;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use split_flap_client::client::Client;
+use split_flap_client::trace::TraceFile;
+use split_flap_device::marquee::{ScrollConfig, ScrollWrap};
+use split_flap_device::split_flap_bit_state::CHARACTER_SET;
+use split_flap_device::transition::{Effect, TransitionConfig, MAX_EFFECTS};
+
+use crate::config::ConfigFile;
+use crate::error::Error;
+use crate::playlist::PlaylistFile;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Serial port the display is attached to.
+    #[arg(long, env = "SPLITFLAP_PORT", default_value = "/dev/ttyACM0")]
+    port: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show text, words are joined with spaces.
+    Show {
+        #[arg(required = true)]
+        text: Vec<String>,
+        /// Scroll text that is longer than the display.
+        #[arg(long)]
+        scroll: bool,
+        /// Characters the text moves at a time.
+        #[arg(long, default_value_t = 1, requires = "scroll")]
+        step: usize,
+        /// Milliseconds each position is shown for.
+        #[arg(long, default_value_t = 500, requires = "scroll")]
+        frame_ms: u32,
+        /// Milliseconds to hold the start and the end of the text.
+        #[arg(long, default_value_t = 2000, requires = "scroll")]
+        pause_ms: u32,
+        /// What happens at the end: stop, restart or continuous[:<gap>].
+        #[arg(long, default_value = "restart", value_parser = scroll::parse_wrap, requires = "scroll")]
+        wrap: ScrollWrap,
+    },
+    /// Print the state of every bit.
+    Status,
+    /// Find the home position of every bit again.
+    Home,
+    /// Measure the home sensor of one bit, or of every bit.
+    Calibrate {
+        bit: Option<u8>,
+        /// Seconds to wait for each sweep.
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Spin every bit through two revolutions and check its motor and sensor.
+    SelfTest {
+        /// Seconds to wait for every bit to finish.
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+    },
+    /// Nudge where a flap stops by a number of steps.
+    Trim {
+        bit: u8,
+        /// The character on the flap.
+        flap: char,
+        #[arg(allow_negative_numbers = true)]
+        delta: i32,
+    },
+    /// Set the effects used whenever the display changes, applied in order.  With none, every bit
+    /// goes straight to its new flap.
+    Transition {
+        /// Up to four of wave:<ms>, random:<ms>, spin or cascade:<every>[:<dwell ms>].
+        #[arg(value_parser = transition::parse_effect)]
+        effects: Vec<Effect>,
+        /// Seeds the random effects, which play out the same way for the same seed.
+        #[arg(long, default_value_t = 0)]
+        seed: u32,
+    },
+    /// Live view of every bit.
+    Watch {
+        /// Milliseconds between polls.
+        #[arg(long, default_value_t = 250)]
+        interval: u64,
+    },
+    /// Print wear counters for every bit and flag motors that look to be failing.
+    Counters {
+        /// Print CSV rows stamped with the time, for appending to a log.
+        #[arg(long)]
+        csv: bool,
+        /// Leave out the CSV header.
+        #[arg(long, requires = "csv")]
+        no_header: bool,
+        /// Zero the counters after reading them, such as after replacing a motor.
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Home a bit and record its sensor readings and decisions as CSV.
+    Trace {
+        bit: u8,
+        /// Readings to record.
+        #[arg(long, default_value_t = 2000)]
+        samples: usize,
+        /// Defaults to standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check that the bit logic makes the same decisions as in a recorded trace.
+    Replay { file: PathBuf },
+    /// Save or restore calibration and trims.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage the messages the display cycles through.
+    #[command(subcommand)]
+    Playlist(PlaylistCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write the settings of every bit as TOML.
+    Dump {
+        /// Defaults to standard output.
+        file: Option<PathBuf>,
+    },
+    /// Apply settings written by `config dump`.
+    Load { file: PathBuf },
+    /// Keep the current settings after the device restarts.
+    Save,
+}
+
+#[derive(Subcommand)]
+enum PlaylistCommand {
+    /// Replace the playlist on the device with one from a TOML file, and start it.
+    Push {
+        file: PathBuf,
+        /// Reorder the entries so the drums turn less on each pass.
+        #[arg(long)]
+        plan: bool,
+        /// Also clear the display between entries where that adds no turning.
+        #[arg(long, requires = "plan")]
+        blanks: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Replaying only needs the file
+    let result = match cli.command {
+        Command::Replay { file } => replay(&file),
+        command => Client::open(&cli.port)
+            .map_err(Error::from)
+            .and_then(|mut client| run(&mut client, command)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("splitflap: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run<P: Read + Write>(client: &mut Client<P>, command: Command) -> Result<(), Error> {
+    match command {
+        Command::Show {
+            text,
+            scroll,
+            step,
+            frame_ms,
+            pause_ms,
+            wrap,
+        } => {
+            if scroll {
+                let config = ScrollConfig {
+                    characters_per_frame: step,
+                    frame_ms,
+                    start_pause_ms: pause_ms,
+                    end_pause_ms: pause_ms,
+                    wrap,
+                };
+
+                client.scroll(&text.join(" "), config)?
+            } else {
+                client.show(&text.join(" "))?
+            }
+        }
+        Command::Status => print!("{}", watch::render(&client.status()?)),
+        Command::Home => client.home_all()?,
+        Command::Calibrate { bit, timeout } => {
+            let bits = match bit {
+                Some(bit) => vec![bit],
+                None => client.status()?.iter().map(|status| status.bit).collect(),
+            };
+
+            for bit in bits {
+                let calibration = client.calibrate(bit, Duration::from_secs(timeout))?;
+                println!(
+                    "bit {}: trigger {}, untrigger {}",
+                    bit, calibration.trigger_value, calibration.untrigger_value
+                );
+            }
+        }
+        Command::SelfTest { timeout } => {
+            let reports = client.self_test(Duration::from_secs(timeout))?;
+            print!("{}", self_test::render(&reports));
+
+            let failed = reports
+                .iter()
+                .filter(|(_, report)| !report.passed())
+                .count();
+            if failed > 0 {
+                return Err(Error::SelfTestFailed(failed));
+            }
+        }
+        Command::Trim { bit, flap, delta } => {
+            let flap = flap_index(flap)?;
+            let trim = client.flap_trim(bit, flap)? + delta;
+
+            client.set_flap_trim(bit, flap, trim)?;
+            println!(
+                "bit {} flap {}: trim {}",
+                bit, CHARACTER_SET[flap as usize] as char, trim
+            );
+        }
+        Command::Transition { effects, seed } => {
+            let config = TransitionConfig::new(&effects, seed)
+                .ok_or(Error::TooManyEffects(effects.len(), MAX_EFFECTS))?;
+            client.set_transition(config)?;
+        }
+        Command::Watch { interval } => watch::watch(client, Duration::from_millis(interval))?,
+        Command::Counters {
+            csv,
+            no_header,
+            reset,
+        } => {
+            let mut bits = Vec::new();
+            for status in client.status()? {
+                bits.push((status.bit, client.counters(status.bit)?));
+            }
+
+            if csv {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+
+                if !no_header {
+                    println!("{}", wear::CSV_HEADER);
+                }
+                print!("{}", wear::csv_rows(timestamp, &bits));
+            } else {
+                print!("{}", wear::render(&bits));
+            }
+
+            if reset {
+                for (bit, _) in &bits {
+                    client.reset_counters(*bit)?;
+                }
+            }
+        }
+        Command::Trace {
+            bit,
+            samples,
+            output,
+        } => {
+            let (trace, dropped) = TraceFile::record(client, bit, samples)?;
+
+            if dropped > 0 {
+                eprintln!(
+                    "splitflap: {} samples were dropped, the trace won't replay",
+                    dropped
+                );
+            }
+
+            match output {
+                Some(path) => fs::write(&path, trace.to_csv())
+                    .map_err(|source| Error::File { path, source })?,
+                None => print!("{}", trace.to_csv()),
+            }
+        }
+        Command::Replay { file } => replay(&file)?,
+        Command::Config(ConfigCommand::Dump { file }) => {
+            let text = toml::to_string(&config::dump(client)?)?;
+
+            match file {
+                Some(path) => {
+                    fs::write(&path, text).map_err(|source| Error::File { path, source })?
+                }
+                None => print!("{}", text),
+            }
+        }
+        Command::Config(ConfigCommand::Load { file }) => {
+            let config: ConfigFile = read_toml(&file)?;
+            config::load(client, &config)?;
+        }
+        Command::Config(ConfigCommand::Save) => client.save_config()?,
+        Command::Playlist(PlaylistCommand::Push { file, plan, blanks }) => {
+            let mut playlist: PlaylistFile = read_toml(&file)?;
+
+            if plan {
+                let (before, after) = playlist.plan(client.status()?.len(), blanks);
+                println!(
+                    "flaps turned per pass: {} planned, {} as written",
+                    after, before
+                );
+            }
+
+            client.push_playlist(&playlist.entries())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn replay(path: &Path) -> Result<(), Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::File {
+        path: path.to_owned(),
+        source,
+    })?;
+    let trace = TraceFile::parse(&text).map_err(|source| Error::Trace {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let count = trace.replay().map_err(|divergence| Error::Diverged {
+        path: path.to_owned(),
+        index: divergence.index,
+        recorded: divergence.recorded,
+        replayed: divergence.replayed,
+    })?;
+
+    println!("{} samples replayed", count);
+    Ok(())
+}
+
+fn flap_index(flap: char) -> Result<u8, Error> {
+    let upper = flap.to_ascii_uppercase();
+
+    CHARACTER_SET
+        .iter()
+        .position(|&character| character as char == upper)
+        .map(|idx| idx as u8)
+        .ok_or(Error::UnknownFlap(flap))
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::File {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    toml::from_str(&text).map_err(|source| Error::Parse {
+        path: path.to_owned(),
+        source,
+    })
+}
@@ -0,0 +1,141 @@
+//! Playlists written as TOML, for example:
+//!
+//! ```toml
+//! [[entries]]
+//! text = "PLATFORM 1"
+//! dwell_seconds = 30
+//! ```
+
+use serde::Deserialize;
+use split_flap_device::device::{text_targets, MAX_PLAYLIST_ENTRIES};
+use split_flap_device::travel::{blank_is_free, cycle_travel, plan_order};
+
+/// Long enough to see the display clear between messages.
+const BLANK_DWELL_SECONDS: u16 = 1;
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct PlaylistFile {
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct PlaylistEntry {
+    pub text: String,
+    #[serde(default = "default_dwell_seconds")]
+    pub dwell_seconds: u16,
+}
+
+fn default_dwell_seconds() -> u16 {
+    10
+}
+
+impl PlaylistFile {
+    /// Entries in the form `Client::push_playlist` takes.
+    pub fn entries(&self) -> Vec<(&str, u16)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.text.as_str(), entry.dwell_seconds))
+            .collect()
+    }
+
+    /// Reorders the entries to cut how far the drums turn on each pass through the playlist,
+    /// keeping the first entry first.  With `blanks`, the display is also cleared between entries
+    /// wherever that adds no travel, while the playlist fits on the device.  Returns the flaps
+    /// turned per pass before and after.
+    pub fn plan(&mut self, width: usize, blanks: bool) -> (u32, u32) {
+        let messages: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let mut targets = vec![b' '; width];
+                text_targets(entry.text.as_bytes(), &mut targets);
+                targets
+            })
+            .collect();
+        let messages: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+        let mut order: Vec<usize> = (0..messages.len()).collect();
+
+        let before = cycle_travel(&messages, &order);
+        plan_order(&messages, &mut order);
+        let after = cycle_travel(&messages, &order);
+
+        let mut entries = Vec::new();
+        let mut spare = MAX_PLAYLIST_ENTRIES.saturating_sub(order.len());
+
+        for (idx, &entry) in order.iter().enumerate() {
+            entries.push(self.entries[entry].clone());
+
+            let (from, to) = (messages[entry], messages[order[(idx + 1) % order.len()]]);
+            let clears = |message: &[u8]| message.iter().all(|&c| c == b' ');
+
+            if blanks && spare > 0 && !clears(from) && !clears(to) && blank_is_free(from, to) {
+                entries.push(PlaylistEntry {
+                    text: String::new(),
+                    dwell_seconds: BLANK_DWELL_SECONDS,
+                });
+                spare -= 1;
+            }
+        }
+
+        self.entries = entries;
+        (before, after)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PlaylistEntry, PlaylistFile};
+
+    fn playlist(texts: &[&str]) -> PlaylistFile {
+        PlaylistFile {
+            entries: texts
+                .iter()
+                .map(|&text| PlaylistEntry {
+                    text: text.to_owned(),
+                    dwell_seconds: 10,
+                })
+                .collect(),
+        }
+    }
+
+    fn texts(playlist: &PlaylistFile) -> Vec<&str> {
+        playlist.entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    #[test]
+    fn dwell_defaults_when_omitted() {
+        let playlist: PlaylistFile = toml::from_str(
+            r#"
+            [[entries]]
+            text = "PLATFORM 1"
+            dwell_seconds = 30
+
+            [[entries]]
+            text = "ON TIME"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(playlist.entries(), [("PLATFORM 1", 30), ("ON TIME", 10)]);
+    }
+
+    #[test]
+    fn plan_orders_entries_by_flap() {
+        let mut playlist = playlist(&["a1", "c3", "b2"]);
+
+        let (before, after) = playlist.plan(2, false);
+
+        assert_eq!(texts(&playlist), ["a1", "b2", "c3"]);
+        assert!(after < before);
+        assert_eq!(after, 55 * 2);
+    }
+
+    #[test]
+    fn plan_adds_blanks_where_they_cost_nothing() {
+        let mut playlist = playlist(&["zz", "ab"]);
+
+        assert_eq!(playlist.plan(2, true), (110, 110));
+        assert_eq!(texts(&playlist), ["zz", "", "ab"]);
+        assert_eq!(playlist.entries[1].dwell_seconds, 1);
+    }
+}
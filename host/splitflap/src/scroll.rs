@@ -0,0 +1,57 @@
+//! How long text scrolls once it reaches its end, as typed on the command line.
+
+use split_flap_device::marquee::ScrollWrap;
+
+const SYNTAX: &str = "stop, restart or continuous[:<gap>]";
+
+/// Parses a wrap mode, for use as a clap value parser.
+pub fn parse_wrap(text: &str) -> Result<ScrollWrap, String> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or_default();
+    let args: Vec<&str> = parts.collect();
+
+    match (name, args.len()) {
+        ("stop", 0) => Ok(ScrollWrap::Stop),
+        ("restart", 0) => Ok(ScrollWrap::Restart),
+        ("continuous", 0) => Ok(ScrollWrap::Continuous { gap: 1 }),
+        ("continuous", 1) => {
+            let gap = args[0]
+                .parse()
+                .map_err(|_| format!("{:?} is not a number", args[0]))?;
+
+            Ok(ScrollWrap::Continuous { gap })
+        }
+        _ => Err(format!("expected {}", SYNTAX)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_device::marquee::ScrollWrap;
+
+    use super::parse_wrap;
+
+    #[test]
+    fn wraps_parse() {
+        assert_eq!(parse_wrap("stop"), Ok(ScrollWrap::Stop));
+        assert_eq!(parse_wrap("restart"), Ok(ScrollWrap::Restart));
+        assert_eq!(
+            parse_wrap("continuous"),
+            Ok(ScrollWrap::Continuous { gap: 1 })
+        );
+        assert_eq!(
+            parse_wrap("continuous:4"),
+            Ok(ScrollWrap::Continuous { gap: 4 })
+        );
+    }
+
+    #[test]
+    fn mistakes_are_explained() {
+        assert!(parse_wrap("loop").unwrap_err().starts_with("expected"));
+        assert!(parse_wrap("stop:2").unwrap_err().starts_with("expected"));
+        assert_eq!(
+            parse_wrap("continuous:far"),
+            Err("\"far\" is not a number".to_owned())
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Pass or fail for each bit after a self-test, with the reasons it failed.
+
+use std::fmt::Write as _;
+
+use split_flap_device::self_test::{SelfTestFailure, SelfTestReport};
+
+pub fn render(reports: &[(u8, SelfTestReport)]) -> String {
+    let mut out = String::new();
+
+    for (bit, report) in reports {
+        let revolution = match report.revolution_steps {
+            Some(steps) => steps.to_string(),
+            None => "-".to_owned(),
+        };
+        let verdict = if report.passed() { "pass" } else { "FAIL" };
+
+        let _ = writeln!(
+            out,
+            "bit {:>2}  {}  sensor {}..{}, {} home triggers, revolution {} steps",
+            bit, verdict, report.min, report.max, report.home_triggers, revolution
+        );
+
+        for failure in report.failures() {
+            let _ = writeln!(out, "        {}", describe(failure));
+        }
+    }
+
+    out
+}
+
+fn describe(failure: SelfTestFailure) -> &'static str {
+    match failure {
+        SelfTestFailure::NoSignal => {
+            "sensor barely changed: motor stalled, or sensor or magnet missing"
+        }
+        SelfTestFailure::NeverReleased => "sensor never dropped below the untrigger value",
+        SelfTestFailure::NeverTriggered => "sensor never rose above the trigger value",
+        SelfTestFailure::HomeTriggers => "magnet wasn't seen once per revolution",
+        SelfTestFailure::RevolutionLength => {
+            "revolution length is off: missed steps or wrong steps per flap"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_device::self_test::SelfTest;
+    use split_flap_device::split_flap_bit_state::SensorCalibration;
+    use split_flap_device::steps::{Revolution, Steps};
+
+    use super::render;
+
+    #[test]
+    fn failures_are_listed_under_their_bit() {
+        let calibration = SensorCalibration {
+            trigger_value: 2000,
+            untrigger_value: 1800,
+        };
+        let revolution = Revolution::new(Steps::new(2)).unwrap();
+
+        let run = |reading: &dyn Fn(u32) -> u32| {
+            let mut test = SelfTest::new(calibration, revolution);
+            (0..)
+                .find_map(|step| test.sample(reading(step % revolution.steps().get())))
+                .unwrap()
+        };
+
+        let reports = [
+            (0, run(&|step| if step == 5 { 3000 } else { 500 })),
+            (1, run(&|_| 600)),
+        ];
+
+        assert_eq!(
+            render(&reports),
+            "bit  0  pass  sensor 500..3000, 2 home triggers, revolution 110 steps\n\
+             bit  1  FAIL  sensor 600..600, 0 home triggers, revolution - steps\n        \
+             sensor barely changed: motor stalled, or sensor or magnet missing\n        \
+             sensor never rose above the trigger value\n        \
+             magnet wasn't seen once per revolution\n        \
+             revolution length is off: missed steps or wrong steps per flap\n"
+        );
+    }
+}
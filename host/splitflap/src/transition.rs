@@ -0,0 +1,81 @@
+//! Transition effects as typed on the command line, such as `wave:80` or `cascade:5:100`.
+
+use split_flap_device::transition::Effect;
+
+const SYNTAX: &str = "wave:<ms>, random:<ms>, spin or cascade:<every>[:<dwell ms>]";
+
+/// Parses one effect, for use as a clap value parser.
+pub fn parse_effect(text: &str) -> Result<Effect, String> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or_default();
+    let args: Vec<&str> = parts.collect();
+
+    let number = |idx: usize| -> Result<u16, String> {
+        args[idx]
+            .parse()
+            .map_err(|_| format!("{:?} is not a number", args[idx]))
+    };
+
+    match (name, args.len()) {
+        ("wave", 1) => Ok(Effect::Wave {
+            delay_ms: number(0)?,
+        }),
+        ("random", 1) => Ok(Effect::RandomOrder {
+            delay_ms: number(0)?,
+        }),
+        ("spin", 0) => Ok(Effect::Spin),
+        ("cascade", 1 | 2) => {
+            let every = u8::try_from(number(0)?)
+                .ok()
+                .filter(|&every| every > 0)
+                .ok_or("cascade needs to stop every 1 to 255 flaps")?;
+            let dwell_ms = if args.len() == 2 { number(1)? } else { 0 };
+
+            Ok(Effect::Cascade { every, dwell_ms })
+        }
+        _ => Err(format!("expected {}", SYNTAX)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_device::transition::Effect;
+
+    use super::parse_effect;
+
+    #[test]
+    fn effects_parse() {
+        assert_eq!(parse_effect("wave:80"), Ok(Effect::Wave { delay_ms: 80 }));
+        assert_eq!(
+            parse_effect("random:20"),
+            Ok(Effect::RandomOrder { delay_ms: 20 })
+        );
+        assert_eq!(parse_effect("spin"), Ok(Effect::Spin));
+        assert_eq!(
+            parse_effect("cascade:5"),
+            Ok(Effect::Cascade {
+                every: 5,
+                dwell_ms: 0
+            })
+        );
+        assert_eq!(
+            parse_effect("cascade:5:150"),
+            Ok(Effect::Cascade {
+                every: 5,
+                dwell_ms: 150
+            })
+        );
+    }
+
+    #[test]
+    fn mistakes_are_explained() {
+        assert!(parse_effect("wave").unwrap_err().starts_with("expected"));
+        assert!(parse_effect("spin:2").unwrap_err().starts_with("expected"));
+        assert_eq!(
+            parse_effect("wave:soon"),
+            Err("\"soon\" is not a number".to_owned())
+        );
+        assert!(parse_effect("cascade:0").is_err());
+        assert!(parse_effect("cascade:300").is_err());
+    }
+}
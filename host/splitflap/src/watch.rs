@@ -0,0 +1,107 @@
+//! Live view of every bit, redrawn in place.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use split_flap_client::client::Client;
+use split_flap_device::protocol::BitStatus;
+use split_flap_device::split_flap_bit_state::{BitState, CHARACTER_SET};
+
+use crate::error::Error;
+
+// Home the cursor and clear the screen
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Polls the device every `interval` until it stops answering.
+pub fn watch<P: Read + Write>(client: &mut Client<P>, interval: Duration) -> Result<(), Error> {
+    let mut stdout = io::stdout();
+
+    loop {
+        let statuses = client.status()?;
+
+        // A closed terminal ends the watch like Ctrl-C would
+        if write!(stdout, "{}{}", CLEAR, render(&statuses))
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+/// The display as it looks now, then a line per bit.
+pub fn render(statuses: &[BitStatus]) -> String {
+    let mut out = String::new();
+
+    let showing: String = statuses
+        .iter()
+        .map(|status| shown(status) as char)
+        .collect();
+    let _ = writeln!(out, "[{}]\n", showing);
+
+    for status in statuses {
+        let state = match status.state {
+            _ if status.calibrating => "calibrating",
+            BitState::UNINITIALIZED => "homing",
+            BitState::SEEKING => "seeking",
+            BitState::SETTLED => "settled",
+        };
+
+        let _ = writeln!(
+            out,
+            "bit {:>2}  {:<11}  {:?} -> {:?}",
+            status.bit,
+            state,
+            shown(status) as char,
+            CHARACTER_SET[status.target_flap as usize] as char
+        );
+    }
+
+    out
+}
+
+fn shown(status: &BitStatus) -> u8 {
+    match status.state {
+        BitState::UNINITIALIZED => b'?',
+        _ => CHARACTER_SET[status.flap as usize],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_device::protocol::BitStatus;
+    use split_flap_device::split_flap_bit_state::BitState;
+
+    use super::render;
+
+    #[test]
+    fn render_shows_display_and_each_bit() {
+        let statuses = [
+            BitStatus {
+                bit: 0,
+                state: BitState::SETTLED,
+                flap: 1,
+                target_flap: 1,
+                calibrating: false,
+            },
+            BitStatus {
+                bit: 1,
+                state: BitState::UNINITIALIZED,
+                flap: 0,
+                target_flap: 2,
+                calibrating: true,
+            },
+        ];
+
+        assert_eq!(
+            render(&statuses),
+            "[A?]\n\n\
+             bit  0  settled      'A' -> 'A'\n\
+             bit  1  calibrating  '?' -> 'B'\n"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Wear of each bit judged from its telemetry counters.
+
+use std::fmt::Write as _;
+
+use split_flap_device::split_flap_bit_state::BitCounters;
+
+/// Below this many revolutions the step loss rate says little about the motor.
+const MIN_REVOLUTIONS: u32 = 100;
+/// Step losses per thousand revolutions at which a bit counts as worn, then failing.
+const WORN_LOSS_RATE: u32 = 10;
+const FAILING_LOSS_RATE: u32 = 50;
+
+const BAR_WIDTH: usize = 20;
+
+pub const CSV_HEADER: &str = "timestamp,bit,steps,revolutions,home_detections,rehomes,\
+step_loss_corrections,homing_timeouts,restore_mismatches,characters_displayed";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Health {
+    /// Too few revolutions to judge.
+    Unknown,
+    Ok,
+    /// Losing steps more often than a healthy motor, or forgetting its position.
+    Worn,
+    /// Losing steps often or unable to find home, likely to stop working soon.
+    Failing,
+}
+
+impl Health {
+    fn label(self) -> &'static str {
+        match self {
+            Health::Unknown => "unknown",
+            Health::Ok => "ok",
+            Health::Worn => "worn",
+            Health::Failing => "failing",
+        }
+    }
+}
+
+/// Step losses per thousand revolutions.
+pub fn loss_rate(counters: &BitCounters) -> u32 {
+    let rate = counters.step_loss_corrections as u64 * 1000 / counters.revolutions.max(1) as u64;
+
+    rate.min(u32::MAX as u64) as u32
+}
+
+pub fn assess(counters: &BitCounters) -> Health {
+    if counters.faults.homing_timeouts > 0 {
+        return Health::Failing;
+    }
+
+    if counters.revolutions < MIN_REVOLUTIONS {
+        return match counters.faults.restore_mismatches {
+            0 => Health::Unknown,
+            _ => Health::Worn,
+        };
+    }
+
+    match loss_rate(counters) {
+        rate if rate >= FAILING_LOSS_RATE => Health::Failing,
+        rate if rate >= WORN_LOSS_RATE => Health::Worn,
+        _ if counters.faults.restore_mismatches > 0 => Health::Worn,
+        _ => Health::Ok,
+    }
+}
+
+/// A line per bit, with revolutions charted against the most travelled bit.
+pub fn render(bits: &[(u8, BitCounters)]) -> String {
+    let mut out = String::new();
+    let most = bits
+        .iter()
+        .map(|(_, counters)| counters.revolutions)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let _ = writeln!(
+        out,
+        "bit  {:>10}  {:<width$}  {:>8}  {:>6}  health",
+        "revs",
+        "",
+        "loss/1k",
+        "faults",
+        width = BAR_WIDTH
+    );
+
+    for (bit, counters) in bits {
+        let filled = (counters.revolutions as u64 * BAR_WIDTH as u64 / most as u64) as usize;
+        let bar = "#".repeat(filled) + &".".repeat(BAR_WIDTH - filled);
+        let faults = counters.faults.homing_timeouts + counters.faults.restore_mismatches;
+
+        let _ = writeln!(
+            out,
+            "{:>3}  {:>10}  {}  {:>8}  {:>6}  {}",
+            bit,
+            counters.revolutions,
+            bar,
+            loss_rate(counters),
+            faults,
+            assess(counters).label()
+        );
+    }
+
+    out
+}
+
+/// A CSV row per bit, to be appended to a log and charted over time.
+pub fn csv_rows(timestamp: u64, bits: &[(u8, BitCounters)]) -> String {
+    let mut out = String::new();
+
+    for (bit, counters) in bits {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            timestamp,
+            bit,
+            counters.steps,
+            counters.revolutions,
+            counters.home_detections,
+            counters.rehomes,
+            counters.step_loss_corrections,
+            counters.faults.homing_timeouts,
+            counters.faults.restore_mismatches,
+            counters.characters_displayed
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use split_flap_device::split_flap_bit_state::{BitCounters, FaultCounters};
+
+    use super::{assess, csv_rows, render, Health};
+
+    fn counters(revolutions: u32, step_loss_corrections: u32) -> BitCounters {
+        BitCounters {
+            steps: revolutions as u64 * 4096,
+            revolutions,
+            step_loss_corrections,
+            ..BitCounters::default()
+        }
+    }
+
+    #[test]
+    fn health_follows_step_loss_rate() {
+        assert_eq!(assess(&counters(20, 5)), Health::Unknown);
+        assert_eq!(assess(&counters(1000, 2)), Health::Ok);
+        assert_eq!(assess(&counters(1000, 10)), Health::Worn);
+        assert_eq!(assess(&counters(1000, 80)), Health::Failing);
+    }
+
+    #[test]
+    fn faults_override_a_clean_record() {
+        let mut timed_out = counters(1000, 0);
+        timed_out.faults = FaultCounters {
+            homing_timeouts: 1,
+            restore_mismatches: 0,
+        };
+        assert_eq!(assess(&timed_out), Health::Failing);
+
+        let mut mismatched = counters(0, 0);
+        mismatched.faults.restore_mismatches = 1;
+        assert_eq!(assess(&mismatched), Health::Worn);
+    }
+
+    #[test]
+    fn render_charts_revolutions() {
+        let bits = [(0, counters(1000, 2)), (1, counters(500, 40))];
+
+        assert_eq!(
+            render(&bits),
+            "bit        revs                         loss/1k  faults  health\n  \
+             0        1000  ####################         2       0  ok\n  \
+             1         500  ##########..........        80       0  failing\n"
+        );
+    }
+
+    #[test]
+    fn csv_has_a_row_per_bit() {
+        let bits = [(2, counters(3, 1))];
+
+        assert_eq!(
+            csv_rows(1700000000, &bits),
+            "1700000000,2,12288,3,0,0,1,0,0,0\n"
+        );
+    }
+}
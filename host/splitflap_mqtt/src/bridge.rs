@@ -0,0 +1,194 @@
+//! The bridge itself: one thread per display, and the MQTT connection on the calling thread.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rumqttc::{Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use split_flap_client::client::Client;
+
+use crate::config::BridgeConfig;
+use crate::discovery::{availability_topic, discovery_messages, Topics};
+use crate::error::Error;
+use crate::state::StateTracker;
+
+/// Anything a client can talk through.
+pub trait Port: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Port for T {}
+
+pub type DeviceClient = Client<Box<dyn Port>>;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// How often `stop` is checked
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Queued requests before publishing from a display thread blocks
+const REQUEST_CAPACITY: usize = 64;
+
+struct Worker {
+    texts: mpsc::Sender<String>,
+    thread: JoinHandle<()>,
+}
+
+/// Runs until `stop` is set, reconnecting to the broker whenever the connection drops.
+pub fn run(
+    config: &BridgeConfig,
+    displays: Vec<(String, DeviceClient)>,
+    stop: &AtomicBool,
+) -> Result<(), Error> {
+    let availability = availability_topic(&config.topic_prefix);
+
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        &availability,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    let (mqtt, mut connection) = rumqttc::Client::new(options, REQUEST_CAPACITY);
+    let mut workers = HashMap::new();
+    let mut discovery = Vec::new();
+
+    for (name, mut client) in displays {
+        if name.is_empty() || name.contains(['/', '+', '#']) {
+            return Err(Error::InvalidName(name));
+        }
+
+        let bits = client
+            .status()
+            .map_err(|source| Error::Display {
+                name: name.clone(),
+                source,
+            })?
+            .len();
+        discovery.extend(discovery_messages(config, &name, bits));
+
+        let (texts, text_rx) = mpsc::channel();
+        let thread = {
+            let mqtt = mqtt.clone();
+            let topics = Topics::new(&config.topic_prefix, &name);
+            let tracker = StateTracker::new(Duration::from_secs(config.fault_timeout_secs));
+            let poll_interval = Duration::from_millis(config.poll_interval_ms);
+
+            thread::spawn(move || drive(client, text_rx, mqtt, topics, tracker, poll_interval))
+        };
+
+        workers.insert(name, Worker { texts, thread });
+    }
+
+    let set_filter = format!("{}/+/set", config.topic_prefix);
+
+    while !stop.load(Ordering::Relaxed) {
+        let event = match connection.recv_timeout(STOP_CHECK_INTERVAL) {
+            Ok(Ok(event)) => event,
+            Ok(Err(error)) => {
+                eprintln!("splitflap-mqtt: {}", error);
+                thread::sleep(RECONNECT_DELAY);
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        match event {
+            // Subscriptions and retained messages are renewed on every connection
+            Event::Incoming(Packet::ConnAck(_)) => {
+                mqtt.try_subscribe(&set_filter, QoS::AtLeastOnce)?;
+
+                for (topic, payload) in &discovery {
+                    mqtt.try_publish(topic, QoS::AtLeastOnce, true, payload.to_string())?;
+                }
+
+                mqtt.try_publish(&availability, QoS::AtLeastOnce, true, "online")?;
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                let name = publish
+                    .topic
+                    .strip_prefix(&config.topic_prefix)
+                    .and_then(|topic| topic.strip_prefix('/'))
+                    .and_then(|topic| topic.strip_suffix("/set"));
+
+                if let Some(worker) = name.and_then(|name| workers.get(name)) {
+                    let text = String::from_utf8_lossy(&publish.payload).into_owned();
+                    let _ = worker.texts.send(text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    mqtt.try_publish(&availability, QoS::AtLeastOnce, true, "offline")?;
+    mqtt.try_disconnect()?;
+
+    // Let the connection flush the availability update before going
+    let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+    while Instant::now() < deadline {
+        match connection.recv_timeout(STOP_CHECK_INTERVAL) {
+            Ok(Ok(Event::Outgoing(Outgoing::Disconnect))) | Ok(Err(_)) => break,
+            _ => {}
+        }
+    }
+
+    for (_, worker) in workers {
+        drop(worker.texts);
+        let _ = worker.thread.join();
+    }
+
+    Ok(())
+}
+
+/// Shows texts as they arrive and publishes the display's state whenever it changes.  Stops when
+/// the bridge drops `texts`.
+fn drive(
+    mut client: DeviceClient,
+    texts: mpsc::Receiver<String>,
+    mqtt: rumqttc::Client,
+    topics: Topics,
+    mut tracker: StateTracker,
+    poll_interval: Duration,
+) {
+    let mut next_poll = Instant::now();
+
+    loop {
+        match texts.recv_timeout(next_poll.saturating_duration_since(Instant::now())) {
+            Ok(text) => {
+                if let Err(error) = client.show(&text) {
+                    eprintln!("splitflap-mqtt: {}: {}", topics.set, error);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if Instant::now() < next_poll {
+            continue;
+        }
+
+        next_poll = Instant::now() + poll_interval;
+
+        let statuses = match client.status() {
+            Ok(statuses) => statuses,
+            Err(error) => {
+                eprintln!("splitflap-mqtt: {}: {}", topics.state, error);
+                continue;
+            }
+        };
+
+        if let Some(payload) = tracker.update(&statuses, Instant::now()) {
+            let payload = serde_json::to_string(&payload).unwrap_or_default();
+
+            if mqtt
+                .publish(&topics.state, QoS::AtLeastOnce, true, payload)
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
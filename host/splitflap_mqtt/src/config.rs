@@ -0,0 +1,120 @@
+//! The bridge's configuration file, for example:
+//!
+//! ```toml
+//! host = "localhost"
+//!
+//! [[displays]]
+//! name = "hall"
+//! backend = "serial"
+//! port = "/dev/ttyACM0"
+//! ```
+
+use serde::Deserialize;
+use split_flap_client::client::{open_port, Client};
+use split_flap_client::error::Error as ClientError;
+use split_flap_client::fake::FakePort;
+
+use crate::bridge::{DeviceClient, Port};
+use crate::error::Error;
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct BridgeConfig {
+    /// The broker.
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// A bit still moving after this long is reported as faulty.
+    #[serde(default = "default_fault_timeout_secs")]
+    pub fault_timeout_secs: u64,
+    pub displays: Vec<DisplayConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct DisplayConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub backend: Backend,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Backend {
+    Serial {
+        port: String,
+    },
+    /// The in-process fake device.
+    Simulated,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "splitflap-bridge".into()
+}
+
+fn default_topic_prefix() -> String {
+    "splitflap".into()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".into()
+}
+
+fn default_poll_interval_ms() -> u64 {
+    250
+}
+
+fn default_fault_timeout_secs() -> u64 {
+    30
+}
+
+impl BridgeConfig {
+    pub fn parse(text: &str) -> Result<BridgeConfig, Error> {
+        Ok(toml::from_str(text)?)
+    }
+}
+
+impl DisplayConfig {
+    pub fn connect(&self) -> Result<DeviceClient, ClientError> {
+        let port: Box<dyn Port> = match &self.backend {
+            Backend::Serial { port } => Box::new(open_port(port)?),
+            Backend::Simulated => Box::new(FakePort::spawn()),
+        };
+
+        Ok(Client::new(port))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, BridgeConfig};
+
+    #[test]
+    fn defaults_fill_in_broker_settings() {
+        let config = BridgeConfig::parse(
+            r#"
+            host = "broker.local"
+
+            [[displays]]
+            name = "hall"
+            backend = "simulated"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 1883);
+        assert_eq!(config.topic_prefix, "splitflap");
+        assert_eq!(config.discovery_prefix, "homeassistant");
+        assert_eq!(config.displays[0].backend, Backend::Simulated);
+    }
+}
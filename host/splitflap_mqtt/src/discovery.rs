@@ -0,0 +1,137 @@
+//! Home Assistant MQTT discovery.  Each display appears as a device with a text entity for the
+//! message, a moving sensor and a problem sensor.
+
+use serde_json::{json, Value};
+
+use crate::config::BridgeConfig;
+
+/// Where a display's topics live.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Topics {
+    pub set: String,
+    pub state: String,
+}
+
+impl Topics {
+    pub fn new(prefix: &str, name: &str) -> Topics {
+        Topics {
+            set: format!("{}/{}/set", prefix, name),
+            state: format!("{}/{}/state", prefix, name),
+        }
+    }
+}
+
+pub fn availability_topic(prefix: &str) -> String {
+    format!("{}/bridge/availability", prefix)
+}
+
+/// Retained config messages announcing the display called `name`, as (topic, payload).
+pub fn discovery_messages(config: &BridgeConfig, name: &str, bits: usize) -> Vec<(String, Value)> {
+    let topics = Topics::new(&config.topic_prefix, name);
+    let object_id = format!("splitflap_{}", name);
+    let device = json!({
+        "identifiers": [object_id],
+        "name": format!("Split-flap {}", name),
+        "model": format!("{}-bit split-flap display", bits),
+    });
+    let common = |suffix: &str| {
+        json!({
+            "unique_id": format!("{}_{}", object_id, suffix),
+            "object_id": format!("{}_{}", object_id, suffix),
+            "state_topic": topics.state,
+            "availability_topic": availability_topic(&config.topic_prefix),
+            "device": device.clone(),
+        })
+    };
+
+    let mut text = common("text");
+    merge(
+        &mut text,
+        json!({
+            "name": "Message",
+            "command_topic": topics.set,
+            "value_template": "{{ value_json.text }}",
+            "max": bits,
+        }),
+    );
+
+    let mut moving = common("moving");
+    merge(
+        &mut moving,
+        json!({
+            "name": "Moving",
+            "device_class": "moving",
+            "value_template": "{{ 'OFF' if value_json.settled else 'ON' }}",
+        }),
+    );
+
+    let mut problem = common("problem");
+    merge(
+        &mut problem,
+        json!({
+            "name": "Fault",
+            "device_class": "problem",
+            "value_template":
+                "{{ 'ON' if value_json.bits | selectattr('fault') | list else 'OFF' }}",
+            "json_attributes_topic": topics.state,
+            "json_attributes_template":
+                "{{ {'faults': value_json.bits | selectattr('fault') | list} | tojson }}",
+        }),
+    );
+
+    let topic = |component: &str, suffix: &str| {
+        format!(
+            "{}/{}/{}_{}/config",
+            config.discovery_prefix, component, object_id, suffix
+        )
+    };
+
+    vec![
+        (topic("text", "text"), text),
+        (topic("binary_sensor", "moving"), moving),
+        (topic("binary_sensor", "problem"), problem),
+    ]
+}
+
+fn merge(into: &mut Value, from: Value) {
+    if let (Value::Object(into), Value::Object(from)) = (into, from) {
+        into.extend(from);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::discovery_messages;
+    use crate::config::BridgeConfig;
+
+    #[test]
+    fn text_entity_commands_the_set_topic() {
+        let config = BridgeConfig::parse("host = \"localhost\"\ndisplays = []").unwrap();
+        let messages = discovery_messages(&config, "hall", 4);
+
+        let (topic, text) = &messages[0];
+        assert_eq!(topic, "homeassistant/text/splitflap_hall_text/config");
+        assert_eq!(text["command_topic"], json!("splitflap/hall/set"));
+        assert_eq!(text["state_topic"], json!("splitflap/hall/state"));
+        assert_eq!(text["max"], json!(4));
+        assert_eq!(
+            text["availability_topic"],
+            json!("splitflap/bridge/availability")
+        );
+
+        let ids: Vec<_> = messages
+            .iter()
+            .map(|(_, payload)| payload["unique_id"].clone())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                json!("splitflap_hall_text"),
+                json!("splitflap_hall_moving"),
+                json!("splitflap_hall_problem")
+            ]
+        );
+    }
+}
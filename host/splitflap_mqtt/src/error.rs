@@ -0,0 +1,16 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("config: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("display {name}: {source}")]
+    Display {
+        name: String,
+        source: split_flap_client::error::Error,
+    },
+    #[error("mqtt: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error("display name {0:?} can't be used in a topic")]
+    InvalidName(String),
+}
@@ -0,0 +1,11 @@
+//! Bridge between serial-attached displays and an MQTT broker, with Home Assistant discovery.
+//!
+//! Text published to `<prefix>/<name>/set` is shown on the display called `name`.  Its state is
+//! published, retained, to `<prefix>/<name>/state` whenever it changes.  The bridge publishes
+//! `online` and `offline` to `<prefix>/bridge/availability`.
+
+pub mod bridge;
+pub mod config;
+pub mod discovery;
+pub mod error;
+pub mod state;
@@ -0,0 +1,40 @@
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::{env, fs};
+
+use splitflap_mqtt::bridge;
+use splitflap_mqtt::config::BridgeConfig;
+use splitflap_mqtt::error::Error;
+
+const DEFAULT_CONFIG: &str = "splitflap-mqtt.toml";
+
+fn start(config_path: &str) -> Result<(), Error> {
+    let config = BridgeConfig::parse(&fs::read_to_string(config_path)?)?;
+    let displays = config
+        .displays
+        .iter()
+        .map(|display| {
+            let client = display.connect().map_err(|source| Error::Display {
+                name: display.name.clone(),
+                source,
+            })?;
+
+            Ok((display.name.clone(), client))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // Runs until killed, the broker marks the bridge offline through its will
+    bridge::run(&config, displays, &AtomicBool::new(false))
+}
+
+fn main() -> ExitCode {
+    let config_path = env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG.into());
+
+    match start(&config_path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("splitflap-mqtt: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Turns polled bit statuses into the payloads published on the state topic.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use split_flap_device::protocol::BitStatus;
+use split_flap_device::split_flap_bit_state::{BitState, CHARACTER_SET};
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Never saw the home magnet.
+    HomingTimeout,
+    /// Homed, but never reached its target.
+    SeekTimeout,
+    /// Still measuring its flap positions.
+    CalibrationTimeout,
+}
+
+/// Why a state was published.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateEvent {
+    Changed,
+    /// Every bit has come to rest.
+    Settled,
+    /// A bit has developed a fault.
+    Fault,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct BitPayload {
+    pub bit: u8,
+    pub state: &'static str,
+    pub flap: char,
+    pub target: char,
+    pub fault: Option<Fault>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct StatePayload {
+    pub event: StateEvent,
+    /// What the display shows now, `?` for bits still homing.
+    pub text: String,
+    pub settled: bool,
+    pub bits: Vec<BitPayload>,
+}
+
+/// Remembers what was last published, so only changes go out.
+pub struct StateTracker {
+    fault_timeout: Duration,
+    /// When each bit last started moving, `None` while at rest.
+    moving_since: Vec<Option<Instant>>,
+    last: Option<StatePayload>,
+}
+
+impl StateTracker {
+    pub fn new(fault_timeout: Duration) -> StateTracker {
+        StateTracker {
+            fault_timeout,
+            moving_since: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// The payload to publish, if anything changed since the last one.
+    pub fn update(&mut self, statuses: &[BitStatus], now: Instant) -> Option<StatePayload> {
+        self.moving_since.resize(statuses.len(), None);
+
+        let bits: Vec<BitPayload> = statuses
+            .iter()
+            .zip(self.moving_since.iter_mut())
+            .map(|(status, moving_since)| {
+                let settled = status.state == BitState::SETTLED && !status.calibrating;
+
+                *moving_since = match (settled, *moving_since) {
+                    (true, _) => None,
+                    (false, None) => Some(now),
+                    (false, since) => since,
+                };
+
+                let timed_out = moving_since.is_some_and(|since| now - since >= self.fault_timeout);
+                bit_payload(status, timed_out)
+            })
+            .collect();
+
+        let settled = self.moving_since.iter().all(Option::is_none);
+        let text: String = bits.iter().map(|bit| bit.flap).collect();
+        // Counts as settling again if the bits moved between polls
+        let was_settled = self
+            .last
+            .as_ref()
+            .is_some_and(|last| last.settled && last.text == text);
+        let faults = bits.iter().filter(|bit| bit.fault.is_some()).count();
+        let had_faults = self.last.as_ref().map_or(0, |last| {
+            last.bits.iter().filter(|bit| bit.fault.is_some()).count()
+        });
+
+        let event = if faults > had_faults {
+            StateEvent::Fault
+        } else if settled && !was_settled {
+            StateEvent::Settled
+        } else {
+            StateEvent::Changed
+        };
+
+        let payload = StatePayload {
+            event,
+            text,
+            settled,
+            bits,
+        };
+
+        let unchanged = self
+            .last
+            .as_ref()
+            .is_some_and(|last| last.settled == payload.settled && last.bits == payload.bits);
+
+        if unchanged {
+            return None;
+        }
+
+        self.last = Some(payload.clone());
+        Some(payload)
+    }
+}
+
+fn bit_payload(status: &BitStatus, timed_out: bool) -> BitPayload {
+    let flap = flap_char(status.flap);
+    let (state, flap, fault) = match status.state {
+        _ if status.calibrating => ("calibrating", flap, Fault::CalibrationTimeout),
+        BitState::UNINITIALIZED => ("homing", '?', Fault::HomingTimeout),
+        BitState::SEEKING => ("seeking", flap, Fault::SeekTimeout),
+        BitState::SETTLED => ("settled", flap, Fault::SeekTimeout),
+    };
+
+    BitPayload {
+        bit: status.bit,
+        state,
+        flap,
+        target: flap_char(status.target_flap),
+        fault: timed_out.then_some(fault),
+    }
+}
+
+// A device on newer firmware may report flaps this build doesn't know
+fn flap_char(flap: u8) -> char {
+    CHARACTER_SET
+        .get(flap as usize)
+        .map_or('?', |&character| character as char)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use split_flap_device::protocol::BitStatus;
+    use split_flap_device::split_flap_bit_state::BitState;
+
+    use super::{Fault, StateEvent, StateTracker};
+
+    fn status(bit: u8, state: BitState, flap: u8) -> BitStatus {
+        BitStatus {
+            bit,
+            state,
+            flap,
+            target_flap: 2,
+            calibrating: false,
+        }
+    }
+
+    #[test]
+    fn settling_is_published_once() {
+        let mut tracker = StateTracker::new(Duration::from_secs(30));
+        let start = Instant::now();
+        let moving = [
+            status(0, BitState::SEEKING, 1),
+            status(1, BitState::SETTLED, 2),
+        ];
+        let settled = [
+            status(0, BitState::SETTLED, 2),
+            status(1, BitState::SETTLED, 2),
+        ];
+
+        let first = tracker.update(&moving, start).unwrap();
+        assert_eq!(first.event, StateEvent::Changed);
+        assert_eq!(first.text, "AB");
+        assert!(!first.settled);
+
+        assert_eq!(tracker.update(&moving, start), None);
+
+        let last = tracker.update(&settled, start).unwrap();
+        assert_eq!(last.event, StateEvent::Settled);
+        assert_eq!(last.text, "BB");
+
+        assert_eq!(tracker.update(&settled, start), None);
+
+        let unseen_move = [
+            status(0, BitState::SETTLED, 3),
+            status(1, BitState::SETTLED, 2),
+        ];
+        assert_eq!(
+            tracker.update(&unseen_move, start).unwrap().event,
+            StateEvent::Settled
+        );
+    }
+
+    #[test]
+    fn bits_moving_too_long_are_faulty() {
+        let mut tracker = StateTracker::new(Duration::from_secs(30));
+        let start = Instant::now();
+        let statuses = [
+            status(0, BitState::UNINITIALIZED, 0),
+            status(1, BitState::SETTLED, 2),
+        ];
+
+        assert!(tracker.update(&statuses, start).is_some());
+        assert_eq!(
+            tracker.update(&statuses, start + Duration::from_secs(29)),
+            None
+        );
+
+        let faulty = tracker
+            .update(&statuses, start + Duration::from_secs(30))
+            .unwrap();
+
+        assert_eq!(faulty.event, StateEvent::Fault);
+        assert_eq!(faulty.bits[0].fault, Some(Fault::HomingTimeout));
+        assert_eq!(faulty.bits[1].fault, None);
+        assert_eq!(faulty.text, "?B");
+    }
+
+    #[test]
+    fn long_calibrations_have_their_own_fault() {
+        let mut tracker = StateTracker::new(Duration::from_secs(30));
+        let start = Instant::now();
+        let statuses = [BitStatus {
+            calibrating: true,
+            ..status(0, BitState::SETTLED, 2)
+        }];
+
+        assert!(tracker.update(&statuses, start).is_some());
+
+        let faulty = tracker
+            .update(&statuses, start + Duration::from_secs(30))
+            .unwrap();
+
+        assert_eq!(faulty.bits[0].state, "calibrating");
+        assert_eq!(faulty.bits[0].fault, Some(Fault::CalibrationTimeout));
+    }
+
+    #[test]
+    fn unknown_flaps_are_shown_as_question_marks() {
+        let mut tracker = StateTracker::new(Duration::from_secs(30));
+        let statuses = [BitStatus {
+            target_flap: u8::MAX,
+            ..status(0, BitState::SEEKING, 200)
+        }];
+
+        let payload = tracker.update(&statuses, Instant::now()).unwrap();
+
+        assert_eq!(payload.text, "?");
+        assert_eq!(payload.bits[0].target, '?');
+    }
+}
@@ -0,0 +1,137 @@
+mod broker;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rumqttc::{Event, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use split_flap_client::client::Client;
+use split_flap_client::fake::FakePort;
+use splitflap_mqtt::bridge::{self, DeviceClient};
+use splitflap_mqtt::config::BridgeConfig;
+
+use broker::Broker;
+
+struct Running {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+fn start_bridge(broker: &Broker) -> Running {
+    let config = BridgeConfig::parse(&format!(
+        "host = \"127.0.0.1\"\nport = {}\npoll_interval_ms = 10\ndisplays = []",
+        broker.port
+    ))
+    .unwrap();
+    let client: DeviceClient = Client::new(Box::new(FakePort::spawn()));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let stop = stop.clone();
+        thread::spawn(move || bridge::run(&config, vec![("hall".into(), client)], &stop).unwrap())
+    };
+
+    Running { stop, thread }
+}
+
+/// Subscribes to `filter`, returning the client to publish with and every message received.
+fn listen(broker: &Broker, filter: &str) -> (rumqttc::Client, Receiver<(String, String)>) {
+    let options = MqttOptions::new("test", "127.0.0.1", broker.port);
+    let (client, mut connection) = rumqttc::Client::new(options, 16);
+    let (tx, rx) = mpsc::channel();
+
+    client.subscribe(filter, QoS::AtMostOnce).unwrap();
+
+    thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                    if tx.send((publish.topic, payload)).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+    });
+
+    (client, rx)
+}
+
+/// Waits for a message on `topic` that satisfies `accept`.
+fn wait_for(
+    messages: &Receiver<(String, String)>,
+    topic: &str,
+    accept: impl Fn(&str) -> bool,
+) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let (received, payload) = messages
+            .recv_timeout(remaining)
+            .unwrap_or_else(|_| panic!("Nothing matching on {}", topic));
+
+        if received == topic && accept(&payload) {
+            return payload;
+        }
+    }
+}
+
+#[test]
+fn discovery_is_announced() {
+    let broker = Broker::start();
+    let bridge = start_bridge(&broker);
+    let (_client, messages) = listen(&broker, "homeassistant/#");
+
+    let config = wait_for(
+        &messages,
+        "homeassistant/text/splitflap_hall_text/config",
+        |_| true,
+    );
+    let config: Value = serde_json::from_str(&config).unwrap();
+
+    assert_eq!(config["command_topic"], "splitflap/hall/set");
+
+    bridge.stop.store(true, Ordering::Relaxed);
+    bridge.thread.join().unwrap();
+}
+
+#[test]
+fn text_is_shown_and_settled_event_published() {
+    let broker = Broker::start();
+    let bridge = start_bridge(&broker);
+    let (client, messages) = listen(&broker, "splitflap/#");
+
+    wait_for(&messages, "splitflap/bridge/availability", |payload| {
+        payload == "online"
+    });
+    client
+        .publish("splitflap/hall/set", QoS::AtLeastOnce, false, "hi")
+        .unwrap();
+
+    let state = wait_for(&messages, "splitflap/hall/state", |payload| {
+        let state: Value = serde_json::from_str(payload).unwrap();
+        state["text"] == "HI  " && state["settled"] == true
+    });
+    let state: Value = serde_json::from_str(&state).unwrap();
+
+    assert_eq!(state["event"], "settled");
+    assert!(state["bits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|bit| bit["fault"].is_null()));
+
+    bridge.stop.store(true, Ordering::Relaxed);
+    bridge.thread.join().unwrap();
+
+    wait_for(&messages, "splitflap/bridge/availability", |payload| {
+        payload == "offline"
+    });
+}
@@ -0,0 +1,164 @@
+//! A minimal MQTT 3.1.1 broker for tests.  Everything is delivered at QoS 0, and retained
+//! messages are kept for later subscribers.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bytes::BytesMut;
+use rumqttc::mqttbytes::v4::{
+    read, ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, Publish, SubAck,
+    SubscribeReasonCode,
+};
+use rumqttc::mqttbytes::{Error, QoS};
+
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Shared {
+    subscriptions: Vec<(String, TcpStream)>,
+    retained: HashMap<String, Publish>,
+}
+
+pub struct Broker {
+    pub port: u16,
+}
+
+impl Broker {
+    pub fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = shared.clone();
+                thread::spawn(move || serve(stream, &shared));
+            }
+        });
+
+        Broker { port }
+    }
+}
+
+/// Whether `topic` matches a filter with `+` and `#` wildcards.
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+
+    levels.next().is_none()
+}
+
+fn send(stream: &mut TcpStream, write: impl FnOnce(&mut BytesMut) -> Result<usize, Error>) {
+    let mut buf = BytesMut::new();
+
+    if write(&mut buf).is_ok() {
+        let _ = stream.write_all(&buf);
+    }
+}
+
+fn deliver(stream: &mut TcpStream, publish: &Publish) {
+    let mut publish = publish.clone();
+    publish.qos = QoS::AtMostOnce;
+    publish.pkid = 0;
+
+    send(stream, |buf| publish.write(buf));
+}
+
+fn route(shared: &Mutex<Shared>, publish: Publish) {
+    let mut shared = shared.lock().unwrap();
+
+    for (filter, stream) in shared.subscriptions.iter_mut() {
+        if matches(filter, &publish.topic) {
+            deliver(stream, &publish);
+        }
+    }
+
+    if publish.retain {
+        let mut retained = publish.clone();
+        retained.retain = true;
+        shared.retained.insert(publish.topic.clone(), retained);
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: &Mutex<Shared>) {
+    let mut buf = BytesMut::new();
+    let mut chunk = [0u8; 1024];
+    let mut will = None;
+
+    loop {
+        let packet = match read(&mut buf, MAX_PACKET_SIZE) {
+            Ok(packet) => packet,
+            Err(Error::InsufficientBytes(_)) => match stream.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => {
+                    buf.extend_from_slice(&chunk[..count]);
+                    continue;
+                }
+            },
+            Err(_) => break,
+        };
+
+        match packet {
+            Packet::Connect(connect) => {
+                will = connect.last_will.map(|will| {
+                    let mut publish = Publish::new(will.topic, will.qos, will.message.to_vec());
+                    publish.retain = will.retain;
+                    publish
+                });
+                send(&mut stream, |buf| {
+                    ConnAck::new(ConnectReturnCode::Success, false).write(buf)
+                });
+            }
+            Packet::Publish(publish) => {
+                if publish.qos == QoS::AtLeastOnce {
+                    send(&mut stream, |buf| PubAck::new(publish.pkid).write(buf));
+                }
+
+                route(shared, publish);
+            }
+            Packet::Subscribe(subscribe) => {
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                    .collect();
+                send(&mut stream, |buf| {
+                    SubAck::new(subscribe.pkid, codes).write(buf)
+                });
+
+                let mut shared = shared.lock().unwrap();
+                for filter in subscribe.filters {
+                    for publish in shared.retained.values() {
+                        if matches(&filter.path, &publish.topic) {
+                            deliver(&mut stream, publish);
+                        }
+                    }
+
+                    let writer = stream.try_clone().unwrap();
+                    shared.subscriptions.push((filter.path, writer));
+                }
+            }
+            Packet::PingReq => send(&mut stream, |buf| PingResp.write(buf)),
+            Packet::Disconnect => {
+                will = None;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(will) = will {
+        route(shared, will);
+    }
+}
@@ -0,0 +1,198 @@
+//! REST API over the displays.
+//!
+//! | Route                                 | Method             | Body                          |
+//! |---------------------------------------|--------------------|-------------------------------|
+//! | `/displays`                           | GET                |                               |
+//! | `/displays/{id}/text`                 | POST               | `{"text", "align"}`           |
+//! | `/displays/{id}/status`               | GET                |                               |
+//! | `/displays/{id}/events`               | GET                | Server-sent `status` events   |
+//! | `/displays/{id}/playlist`             | GET, POST, DELETE  | `PlaylistEntry` to append     |
+//! | `/displays/{id}/playlist/{index}`     | GET, PUT, DELETE   | `PlaylistEntry` to replace    |
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::display::{Align, Display, DisplayStatus, PlaylistEntry};
+use crate::error::ApiError;
+
+type Displays = Arc<HashMap<String, Display>>;
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct DisplayInfo {
+    pub id: String,
+    pub rows: usize,
+    pub columns: usize,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct ShowText {
+    pub text: String,
+    #[serde(default)]
+    pub align: Align,
+}
+
+pub fn router(displays: HashMap<String, Display>) -> Router {
+    Router::new()
+        .route("/displays", get(list_displays))
+        .route("/displays/{id}/text", post(show_text))
+        .route("/displays/{id}/status", get(status))
+        .route("/displays/{id}/events", get(events))
+        .route(
+            "/displays/{id}/playlist",
+            get(get_playlist).post(append_entry).delete(clear_playlist),
+        )
+        .route(
+            "/displays/{id}/playlist/{index}",
+            get(get_entry).put(replace_entry).delete(remove_entry),
+        )
+        .with_state(Arc::new(displays))
+}
+
+fn display<'a>(displays: &'a Displays, id: &str) -> Result<&'a Display, ApiError> {
+    displays
+        .get(id)
+        .ok_or_else(|| ApiError::UnknownDisplay(id.into()))
+}
+
+async fn list_displays(State(displays): State<Displays>) -> Json<Vec<DisplayInfo>> {
+    let mut list: Vec<_> = displays
+        .iter()
+        .map(|(id, display)| DisplayInfo {
+            id: id.clone(),
+            rows: display.geometry().rows,
+            columns: display.geometry().columns,
+        })
+        .collect();
+    list.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Json(list)
+}
+
+async fn show_text(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+    Json(body): Json<ShowText>,
+) -> Result<StatusCode, ApiError> {
+    display(&displays, &id)?
+        .show(&body.text, body.align)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn status(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+) -> Result<Json<DisplayStatus>, ApiError> {
+    Ok(Json(display(&displays, &id)?.status().await?))
+}
+
+/// The current status straight away, then each change as the display is polled.
+async fn events(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let updates = WatchStream::new(display(&displays, &id)?.subscribe());
+    let stream = updates.filter_map(|status| {
+        let event = Event::default().event("status").json_data(status?).ok()?;
+        Some(Ok(event))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn get_playlist(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<PlaylistEntry>>, ApiError> {
+    Ok(Json(display(&displays, &id)?.playlist().await))
+}
+
+/// Responds with the index of the new entry.
+async fn append_entry(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+    Json(entry): Json<PlaylistEntry>,
+) -> Result<(StatusCode, Json<usize>), ApiError> {
+    let index = display(&displays, &id)?
+        .edit_playlist(|playlist| {
+            playlist.push(entry);
+            Ok(playlist.len() - 1)
+        })
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(index)))
+}
+
+async fn clear_playlist(
+    State(displays): State<Displays>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    display(&displays, &id)?
+        .edit_playlist(|playlist| {
+            playlist.clear();
+            Ok(())
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_entry(
+    State(displays): State<Displays>,
+    Path((id, index)): Path<(String, usize)>,
+) -> Result<Json<PlaylistEntry>, ApiError> {
+    display(&displays, &id)?
+        .playlist()
+        .await
+        .get(index)
+        .cloned()
+        .map(Json)
+        .ok_or(ApiError::UnknownEntry(index))
+}
+
+async fn replace_entry(
+    State(displays): State<Displays>,
+    Path((id, index)): Path<(String, usize)>,
+    Json(entry): Json<PlaylistEntry>,
+) -> Result<StatusCode, ApiError> {
+    display(&displays, &id)?
+        .edit_playlist(|playlist| {
+            let slot = playlist
+                .get_mut(index)
+                .ok_or(ApiError::UnknownEntry(index))?;
+            *slot = entry;
+            Ok(())
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_entry(
+    State(displays): State<Displays>,
+    Path((id, index)): Path<(String, usize)>,
+) -> Result<StatusCode, ApiError> {
+    display(&displays, &id)?
+        .edit_playlist(|playlist| {
+            if index >= playlist.len() {
+                return Err(ApiError::UnknownEntry(index));
+            }
+
+            playlist.remove(index);
+            Ok(())
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
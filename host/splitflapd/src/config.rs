@@ -0,0 +1,126 @@
+//! The daemon's configuration file, for example:
+//!
+//! ```toml
+//! listen = "127.0.0.1:8080"
+//!
+//! [[displays]]
+//! id = "lobby"
+//! rows = 1
+//! columns = 4
+//! backend = "serial"
+//! port = "/dev/ttyACM0"
+//!
+//! [[displays]]
+//! id = "test"
+//! rows = 2
+//! columns = 2
+//! backend = "simulated"
+//! ```
+
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use split_flap_client::client::{open_port, Client};
+use split_flap_client::error::Error as ClientError;
+use split_flap_client::fake::FakePort;
+use split_flap_device::text_layout::BoardGeometry;
+
+use crate::display::{DeviceClient, Port};
+use crate::error::Error;
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct DaemonConfig {
+    /// Only local addresses make sense, there is no authentication.
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// How often displays are polled for the event stream.
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    pub displays: Vec<DisplayConfig>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct DisplayConfig {
+    pub id: String,
+    pub rows: usize,
+    pub columns: usize,
+    #[serde(flatten)]
+    pub backend: Backend,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Backend {
+    Serial {
+        port: String,
+    },
+    /// The in-process fake device, which has `FAKE_BITS` bits.
+    Simulated,
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+fn default_poll_interval_ms() -> u64 {
+    250
+}
+
+impl DaemonConfig {
+    pub fn parse(text: &str) -> Result<DaemonConfig, Error> {
+        Ok(toml::from_str(text)?)
+    }
+}
+
+impl DisplayConfig {
+    pub fn geometry(&self) -> BoardGeometry {
+        BoardGeometry::new(self.rows, self.columns)
+    }
+
+    pub fn connect(&self) -> Result<DeviceClient, ClientError> {
+        let port: Box<dyn Port> = match &self.backend {
+            Backend::Serial { port } => Box::new(open_port(port)?),
+            Backend::Simulated => Box::new(FakePort::spawn()),
+        };
+
+        Ok(Client::new(port))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{Backend, DaemonConfig};
+
+    #[test]
+    fn backends_are_tagged() {
+        let config = DaemonConfig::parse(
+            r#"
+            [[displays]]
+            id = "lobby"
+            rows = 2
+            columns = 16
+            backend = "serial"
+            port = "/dev/ttyACM0"
+
+            [[displays]]
+            id = "test"
+            rows = 1
+            columns = 4
+            backend = "simulated"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(
+            config.displays[0].backend,
+            Backend::Serial {
+                port: "/dev/ttyACM0".into()
+            }
+        );
+        assert_eq!(config.displays[0].geometry().bit_count(), 32);
+        assert_eq!(config.displays[1].backend, Backend::Simulated);
+    }
+}
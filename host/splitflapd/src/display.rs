@@ -0,0 +1,339 @@
+//! One display, driven from its own thread since the client blocks on the serial port.
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use split_flap_client::client::Client;
+use split_flap_client::error::Error as ClientError;
+use split_flap_device::device::MAX_PLAYLIST_ENTRIES;
+use split_flap_device::glyphs::{expand_glyphs, DEFAULT_GLYPHS};
+use split_flap_device::normalize::{normalize, NormalizeOptions};
+use split_flap_device::protocol::BitStatus;
+use split_flap_device::split_flap_bit_state::{BitState, CHARACTER_SET};
+use split_flap_device::text_layout::{layout_lines, BoardGeometry, Justification, LayoutOptions};
+use tokio::sync::{oneshot, watch, Mutex};
+
+use crate::error::{ApiError, Error};
+
+/// Anything a client can talk through.
+pub trait Port: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Port for T {}
+
+pub type DeviceClient = Client<Box<dyn Port>>;
+
+type Job = Box<dyn FnOnce(&mut DeviceClient) + Send>;
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct BitView {
+    pub bit: u8,
+    pub state: &'static str,
+    pub flap: char,
+    pub target: char,
+    pub calibrating: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct DisplayStatus {
+    /// What each row shows now, `?` for bits still homing.
+    pub rows: Vec<String>,
+    pub settled: bool,
+    pub bits: Vec<BitView>,
+}
+
+impl DisplayStatus {
+    pub fn new(geometry: BoardGeometry, statuses: &[BitStatus]) -> DisplayStatus {
+        let bits: Vec<BitView> = statuses.iter().map(bit_view).collect();
+        let rows = (0..geometry.rows)
+            .map(|row| {
+                let start = geometry.bit_index(row, 0);
+                bits[start..start + geometry.columns]
+                    .iter()
+                    .map(|bit| bit.flap)
+                    .collect()
+            })
+            .collect();
+
+        DisplayStatus {
+            rows,
+            settled: statuses
+                .iter()
+                .all(|status| status.state == BitState::SETTLED && !status.calibrating),
+            bits,
+        }
+    }
+}
+
+fn bit_view(status: &BitStatus) -> BitView {
+    let (state, flap) = match status.state {
+        BitState::UNINITIALIZED => ("homing", '?'),
+        BitState::SEEKING => ("seeking", flap_char(status.flap)),
+        BitState::SETTLED => ("settled", flap_char(status.flap)),
+    };
+
+    BitView {
+        bit: status.bit,
+        state,
+        flap,
+        target: flap_char(status.target_flap),
+        calibrating: status.calibrating,
+    }
+}
+
+// A device on newer firmware may report flaps this build doesn't know
+fn flap_char(flap: u8) -> char {
+    CHARACTER_SET
+        .get(flap as usize)
+        .map_or('?', |&character| character as char)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl From<Align> for Justification {
+    fn from(align: Align) -> Justification {
+        match align {
+            Align::Left => Justification::Left,
+            Align::Center => Justification::Center,
+            Align::Right => Justification::Right,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub text: String,
+    #[serde(default)]
+    pub align: Align,
+    pub dwell_seconds: u16,
+}
+
+pub struct Display {
+    geometry: BoardGeometry,
+    jobs: mpsc::Sender<Job>,
+    status: watch::Receiver<Option<DisplayStatus>>,
+    /// Kept here since the device can't report it back.
+    playlist: Mutex<Vec<PlaylistEntry>>,
+}
+
+impl Display {
+    /// Checks the device matches `geometry`, then polls it every `poll_interval` until dropped.
+    pub fn start(
+        id: &str,
+        geometry: BoardGeometry,
+        mut client: DeviceClient,
+        poll_interval: Duration,
+    ) -> Result<Display, Error> {
+        let device_bits = client
+            .status()
+            .map_err(|source| Error::Display {
+                id: id.into(),
+                source,
+            })?
+            .len();
+
+        if device_bits != geometry.bit_count() {
+            return Err(Error::BitCount {
+                id: id.into(),
+                configured: geometry.bit_count(),
+                device: device_bits,
+            });
+        }
+
+        let (jobs, job_rx) = mpsc::channel();
+        let (status_tx, status) = watch::channel(None);
+
+        thread::spawn(move || run(client, geometry, job_rx, status_tx, poll_interval));
+
+        Ok(Display {
+            geometry,
+            jobs,
+            status,
+            playlist: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn geometry(&self) -> BoardGeometry {
+        self.geometry
+    }
+
+    /// Changes as the display is polled.  `None` until the first poll.
+    pub fn subscribe(&self) -> watch::Receiver<Option<DisplayStatus>> {
+        self.status.clone()
+    }
+
+    /// Runs `f` on the display's thread.
+    pub async fn request<T, F>(&self, f: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut DeviceClient) -> Result<T, ClientError> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+
+        self.jobs
+            .send(Box::new(move |client| {
+                let _ = reply.send(f(client));
+            }))
+            .map_err(|_| ApiError::Stopped)?;
+
+        Ok(result.await.map_err(|_| ApiError::Stopped)??)
+    }
+
+    pub async fn status(&self) -> Result<DisplayStatus, ApiError> {
+        let geometry = self.geometry;
+        let statuses = self.request(|client| client.status()).await?;
+
+        Ok(DisplayStatus::new(geometry, &statuses))
+    }
+
+    pub async fn show(&self, text: &str, align: Align) -> Result<(), ApiError> {
+        let positions = self
+            .targets(text, align)
+            .iter()
+            .map(|&target| flap_index(target))
+            .collect::<Vec<_>>();
+
+        self.request(move |client| client.show_positions(&positions))
+            .await
+    }
+
+    pub async fn playlist(&self) -> Vec<PlaylistEntry> {
+        self.playlist.lock().await.clone()
+    }
+
+    /// Applies `edit` to the playlist and sends the result to the device.  Nothing changes if
+    /// the edit fails or the device rejects the playlist.
+    pub async fn edit_playlist<T, F>(&self, edit: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut Vec<PlaylistEntry>) -> Result<T, ApiError>,
+    {
+        let mut playlist = self.playlist.lock().await;
+        let mut edited = playlist.clone();
+        let result = edit(&mut edited)?;
+
+        if edited.len() > MAX_PLAYLIST_ENTRIES {
+            return Err(ApiError::PlaylistFull(MAX_PLAYLIST_ENTRIES));
+        }
+
+        let entries = edited
+            .iter()
+            .map(|entry| {
+                let targets = self.targets(&entry.text, entry.align);
+                (
+                    String::from_utf8_lossy(&targets).into_owned(),
+                    entry.dwell_seconds,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        self.request(move |client| {
+            if entries.is_empty() {
+                return client.clear_playlist();
+            }
+
+            let entries = entries
+                .iter()
+                .map(|(text, dwell)| (text.as_str(), *dwell))
+                .collect::<Vec<_>>();
+            client.push_playlist(&entries)
+        })
+        .await?;
+
+        *playlist = edited;
+        Ok(result)
+    }
+
+    // One character per bit, laid out for this board.  Glyph escapes and accents are handled as
+    // they are on the device, before the text is broken into rows.
+    fn targets(&self, text: &str, align: Align) -> Vec<u8> {
+        let options = LayoutOptions {
+            justification: align.into(),
+            ..LayoutOptions::default()
+        };
+        let mut targets = vec![b' '; self.geometry.bit_count()];
+
+        // Glyphs can share a byte with the line break, so lines are split before expanding
+        let lines: Vec<String> = text
+            .split('\n')
+            .map(|line| line.trim_end_matches('\r'))
+            .map(prepare_line)
+            .collect();
+
+        // The buffer always matches the geometry
+        let _ = layout_lines(
+            lines.iter().map(String::as_str),
+            self.geometry,
+            &options,
+            &mut targets,
+        );
+
+        targets
+    }
+}
+
+/// Expands the glyph escapes in one line of text and normalizes it to the character set.
+fn prepare_line(line: &str) -> String {
+    // Neither step makes the text longer, so buffers the size of the input always do
+    let mut expanded = vec![0; line.len()];
+    let line = match expand_glyphs(line, &DEFAULT_GLYPHS, &mut expanded) {
+        Ok(len) => std::str::from_utf8(&expanded[..len]).unwrap_or(line),
+        // Shown as typed, like the device does
+        Err(_) => line,
+    };
+
+    let mut normalized = vec![0; line.len()];
+    let summary = normalize(line, &NormalizeOptions::default(), &mut normalized);
+
+    String::from_utf8_lossy(&normalized[..summary.len]).into_owned()
+}
+
+fn flap_index(character: u8) -> u8 {
+    CHARACTER_SET
+        .iter()
+        .position(|&flap| flap == character)
+        .unwrap_or(0) as u8
+}
+
+fn run(
+    mut client: DeviceClient,
+    geometry: BoardGeometry,
+    jobs: mpsc::Receiver<Job>,
+    status: watch::Sender<Option<DisplayStatus>>,
+    poll_interval: Duration,
+) {
+    let mut next_poll = Instant::now();
+
+    loop {
+        match jobs.recv_timeout(next_poll.saturating_duration_since(Instant::now())) {
+            Ok(job) => job(&mut client),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if Instant::now() < next_poll {
+            continue;
+        }
+
+        next_poll = Instant::now() + poll_interval;
+
+        // A failed poll is retried next time round, requests report their own errors
+        if let Ok(statuses) = client.status() {
+            let polled = DisplayStatus::new(geometry, &statuses);
+
+            status.send_if_modified(|current| {
+                let changed = current.as_ref() != Some(&polled);
+                *current = Some(polled);
+                changed
+            });
+        }
+    }
+}
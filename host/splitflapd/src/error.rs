@@ -0,0 +1,54 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+
+/// Errors starting the daemon.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("config: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("display {id}: {source}")]
+    Display {
+        id: String,
+        source: split_flap_client::error::Error,
+    },
+    #[error("display {id}: configured for {configured} bits but the device has {device}")]
+    BitCount {
+        id: String,
+        configured: usize,
+        device: usize,
+    },
+    #[error("display {0} is configured twice")]
+    DuplicateDisplay(String),
+}
+
+/// Errors answering a request, returned as JSON `{"error": "..."}`.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("no display {0:?}")]
+    UnknownDisplay(String),
+    #[error("no playlist entry {0}")]
+    UnknownEntry(usize),
+    #[error("the playlist holds at most {0} entries")]
+    PlaylistFull(usize),
+    #[error("device: {0}")]
+    Device(#[from] split_flap_client::error::Error),
+    #[error("display is not running")]
+    Stopped,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ApiError::UnknownDisplay(_) | ApiError::UnknownEntry(_) => StatusCode::NOT_FOUND,
+            ApiError::PlaylistFull(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Device(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Stopped => StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
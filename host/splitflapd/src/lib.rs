@@ -0,0 +1,6 @@
+//! Daemon that owns serial-attached displays and exposes them over HTTP.
+
+pub mod api;
+pub mod config;
+pub mod display;
+pub mod error;
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::{env, fs};
+
+use splitflapd::api;
+use splitflapd::config::DaemonConfig;
+use splitflapd::display::Display;
+use splitflapd::error::Error;
+
+const DEFAULT_CONFIG: &str = "splitflapd.toml";
+
+/// Connects to every configured display before serving anything.
+fn start(config: &DaemonConfig) -> Result<HashMap<String, Display>, Error> {
+    let mut displays = HashMap::new();
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+
+    for display in &config.displays {
+        if displays.contains_key(&display.id) {
+            return Err(Error::DuplicateDisplay(display.id.clone()));
+        }
+
+        let client = display.connect().map_err(|source| Error::Display {
+            id: display.id.clone(),
+            source,
+        })?;
+        let started = Display::start(&display.id, display.geometry(), client, poll_interval)?;
+
+        displays.insert(display.id.clone(), started);
+    }
+
+    Ok(displays)
+}
+
+async fn serve(config_path: &str) -> Result<(), Error> {
+    let config = DaemonConfig::parse(&fs::read_to_string(config_path)?)?;
+    let displays = start(&config)?;
+    let listener = tokio::net::TcpListener::bind(config.listen).await?;
+
+    eprintln!("splitflapd: listening on {}", config.listen);
+
+    axum::serve(listener, api::router(displays))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config_path = env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG.into());
+
+    match serve(&config_path).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("splitflapd: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use split_flap_device::device::MAX_PLAYLIST_ENTRIES;
+use split_flap_device::text_layout::BoardGeometry;
+use splitflapd::api::router;
+use splitflapd::config::{Backend, DisplayConfig};
+use splitflapd::display::Display;
+use splitflapd::error::Error;
+use tower::ServiceExt;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn simulated(rows: usize, columns: usize) -> DisplayConfig {
+    DisplayConfig {
+        id: "test".into(),
+        rows,
+        columns,
+        backend: Backend::Simulated,
+    }
+}
+
+fn app() -> Router {
+    let config = simulated(2, 2);
+    let display = Display::start(
+        &config.id,
+        config.geometry(),
+        config.connect().unwrap(),
+        POLL_INTERVAL,
+    )
+    .unwrap();
+
+    router(HashMap::from([(config.id, display)]))
+}
+
+async fn call(app: &Router, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+
+    (
+        status,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+async fn wait_for_rows(app: &Router, rows: Value) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        let (_, status) = call(app, Method::GET, "/displays/test/status", None).await;
+
+        if status["settled"] == json!(true) && status["rows"] == rows {
+            return;
+        }
+
+        assert!(Instant::now() < deadline, "Display never showed {}", rows);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn text_is_laid_out_on_the_board() {
+    let app = app();
+
+    let (status, _) = call(
+        &app,
+        Method::POST,
+        "/displays/test/text",
+        Some(json!({ "text": "no go", "align": "right" })),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    wait_for_rows(&app, json!(["NO", "GO"])).await;
+
+    let (_, displays) = call(&app, Method::GET, "/displays", None).await;
+    assert_eq!(displays, json!([{ "id": "test", "rows": 2, "columns": 2 }]));
+}
+
+#[tokio::test]
+async fn glyphs_and_accents_are_shown() {
+    let app = app();
+
+    let (status, _) = call(
+        &app,
+        Method::POST,
+        "/displays/test/text",
+        Some(json!({ "text": "é{HEART}\nßx" })),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    wait_for_rows(&app, json!(["E\u{1}", "SS"])).await;
+}
+
+#[tokio::test]
+async fn unknown_display_is_not_found() {
+    let app = app();
+
+    let (status, body) = call(&app, Method::GET, "/displays/lobby/status", None).await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], json!("no display \"lobby\""));
+}
+
+#[tokio::test]
+async fn playlist_entries_can_be_edited() {
+    let app = app();
+    let entry = |text: &str| json!({ "text": text, "align": "left", "dwell_seconds": 60 });
+
+    for (text, index) in [("AB", 0), ("CD", 1)] {
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/displays/test/playlist",
+            Some(entry(text)),
+        )
+        .await;
+        assert_eq!((status, body), (StatusCode::CREATED, json!(index)));
+    }
+
+    let (status, _) = call(
+        &app,
+        Method::PUT,
+        "/displays/test/playlist/1",
+        Some(entry("EF")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = call(&app, Method::DELETE, "/displays/test/playlist/0", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (_, playlist) = call(&app, Method::GET, "/displays/test/playlist", None).await;
+    assert_eq!(playlist, json!([entry("EF")]));
+    wait_for_rows(&app, json!(["EF", "  "])).await;
+
+    let (status, _) = call(&app, Method::GET, "/displays/test/playlist/1", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn playlist_is_limited_to_device_capacity() {
+    let app = app();
+    let entry = json!({ "text": "X", "dwell_seconds": 1 });
+
+    for _ in 0..MAX_PLAYLIST_ENTRIES {
+        call(
+            &app,
+            Method::POST,
+            "/displays/test/playlist",
+            Some(entry.clone()),
+        )
+        .await;
+    }
+
+    let (status, _) = call(&app, Method::POST, "/displays/test/playlist", Some(entry)).await;
+    let (_, playlist) = call(&app, Method::GET, "/displays/test/playlist", None).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(playlist.as_array().unwrap().len(), MAX_PLAYLIST_ENTRIES);
+}
+
+#[tokio::test]
+async fn events_stream_status() {
+    let app = app();
+    let request = Request::get("/displays/test/events")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(
+        response.headers()["content-type"].to_str().unwrap(),
+        "text/event-stream"
+    );
+
+    let mut body = response.into_body();
+    let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let text = String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap();
+
+    assert!(
+        text.starts_with("event: status\ndata: {\"rows\":"),
+        "{}",
+        text
+    );
+}
+
+#[test]
+fn geometry_must_match_device() {
+    let config = simulated(2, 4);
+
+    assert!(matches!(
+        Display::start(
+            &config.id,
+            BoardGeometry::new(2, 4),
+            config.connect().unwrap(),
+            POLL_INTERVAL
+        ),
+        Err(Error::BitCount {
+            configured: 8,
+            device: 4,
+            ..
+        })
+    ));
+}